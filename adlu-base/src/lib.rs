@@ -21,7 +21,10 @@ use std::collections::HashMap;
 use eyre::{Result, WrapErr};
 use serde_json::Value;
 
-pub use certificate::{load_pem_files, load_pfx_file, CertificateData};
+pub use certificate::{
+    certificate_issuer, load_pem_files, load_pfx_file, CertificateData,
+    CertificateRequest,
+};
 pub use credential::get_saved_credential;
 #[cfg(any(target_os = "macos", target_os = "windows"))]
 pub use ngl::get_adobe_device_id;
@@ -46,6 +49,22 @@ pub fn u64encode(s: &str) -> Result<String> {
     Ok(base64::encode_config(s, base64::URL_SAFE_NO_PAD))
 }
 
+/// URL-safe (unpadded) base64 of arbitrary bytes, as used in the
+/// signatures of JSON web tokens.
+pub fn u64encode_bytes(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Standard (padded) base64, as used in MIME and SMTP authentication.
+pub fn b64encode(bytes: &[u8]) -> String {
+    base64::encode(bytes)
+}
+
+/// Decode standard (padded) base64, as used in HTTP basic authentication.
+pub fn b64decode(s: &str) -> Result<Vec<u8>> {
+    base64::decode(s).wrap_err("Illegal base64 encoding")
+}
+
 pub fn json_from_base64(s: &str) -> Result<JsonMap> {
     serde_json::from_str(&u64decode(s)?).wrap_err("Illegal payload data")
 }
@@ -66,6 +85,9 @@ pub mod base64_encoded_json {
     use serde::de::DeserializeOwned;
     use serde::Deserialize;
 
+    /// The start of the message in errors from decoding the base64.
+    pub const ILLEGAL_BASE64: &str = "Illegal base64";
+
     pub fn serialize<S, T>(val: &T, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -87,7 +109,7 @@ pub mod base64_encoded_json {
         // println!("base64 string starts: {:?}", &base64_string);
         let json_bytes = base64::decode_config(&base64_string, base64::URL_SAFE_NO_PAD)
             .map_err(|e| {
-            serde::de::Error::custom(format!("Illegal base64: {:?}", e))
+            serde::de::Error::custom(format!("{}: {:?}", ILLEGAL_BASE64, e))
         })?;
         // println!("JSON bytes start: {:?}", &json_bytes);
         serde_json::from_reader(json_bytes.as_slice()).map_err(|e| {
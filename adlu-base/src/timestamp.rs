@@ -102,7 +102,7 @@ impl Timestamp {
             Ok(ts) => Self::from_millis(ts.timestamp_millis()),
             Err(_) => {
                 warn!("Unexpected time format in log: {}", s);
-                Self::from_db(s)
+                Self::from_db_string(s)
             }
         }
     }
@@ -118,41 +118,49 @@ impl Timestamp {
             Ok(ts) => Self::from_millis(ts.timestamp_millis()),
             Err(_) => {
                 warn!("Unexpected time format in device date: {}", s);
-                Self::from_db(s)
+                Self::from_db_string(s)
             }
         }
     }
 
-    /// When you need to store a timestamp as a string field in a database.
-    pub fn to_db(&self) -> String {
-        self.to_string()
+    /// When you need to store a timestamp in a database.  Timestamps
+    /// are stored as integer epoch millis, so they sort and compare
+    /// as numbers and can be indexed for range queries.
+    pub fn to_db(&self) -> i64 {
+        self.millis
     }
 
-    /// When you need to store an optional timestamp as a string field in a database.
-    pub fn optional_to_db(t: &Option<Self>) -> String {
+    /// When you need to store an optional timestamp in a database.
+    /// A missing timestamp is stored as 0 (the start of the epoch).
+    pub fn optional_to_db(t: &Option<Self>) -> i64 {
         match t {
-            Some(timestamp) => timestamp.to_string(),
-            None => String::new(),
+            Some(timestamp) => timestamp.millis,
+            None => 0,
         }
     }
 
-    /// When you've stored a timestamp as a string and want it back.
-    /// This handles both millisecond storage and various forms of date
-    /// formatting, so it's backwards compatible with JSON storage
-    /// prepared by different front ends.
-    pub fn from_db(s: &str) -> Self {
-        match s.parse::<Self>() {
-            Ok(ts) => ts,
-            Err(_) => Self::now(),
-        }
+    /// When you've stored a timestamp in a database and want it back.
+    pub fn from_db(millis: i64) -> Self {
+        Self { millis }
     }
 
-    /// When you've stored an optional timestamp as a string and want it back.
-    pub fn optional_from_db(s: &str) -> Option<Self> {
-        if s.is_empty() {
+    /// When you've stored an optional timestamp in a database and want it back.
+    pub fn optional_from_db(millis: i64) -> Option<Self> {
+        if millis == 0 {
             None
         } else {
-            Some(Self::from_db(s))
+            Some(Self { millis })
+        }
+    }
+
+    /// When you've stored a timestamp as a string (as databases did before
+    /// they stored epoch millis) and want it back.  This handles both
+    /// millisecond storage and various forms of date formatting, so it's
+    /// backwards compatible with JSON storage prepared by different front ends.
+    pub fn from_db_string(s: &str) -> Self {
+        match s.parse::<Self>() {
+            Ok(ts) => ts,
+            Err(_) => Self::now(),
         }
     }
 
@@ -209,11 +217,15 @@ mod tests {
     #[test]
     fn test_timestamp_from_storage() {
         let ts1 = Timestamp { millis: 0 };
-        let ts2 = Timestamp::from_db("1970-01-01T00:00:00.000+00:00");
+        let ts2 = Timestamp::from_db_string("1970-01-01T00:00:00.000+00:00");
         assert_eq!(&ts1, &ts2);
-        let ts3 = Timestamp::from_db("1970-01-01T00:00:00:000+00:00");
+        let ts3 = Timestamp::from_db_string("1970-01-01T00:00:00:000+00:00");
         assert_eq!(&ts1, &ts3);
-        let ts4 = Timestamp::from_db("0000");
+        let ts4 = Timestamp::from_db_string("0000");
         assert_eq!(&ts1, &ts4);
+        assert_eq!(&ts1, &Timestamp::from_db(ts1.to_db()));
+        let ts5 = Timestamp::from_db_string("2022-10-01T00:00:00.000+0000");
+        assert_eq!(ts5.to_db(), 1_664_582_400_000);
+        assert_eq!(Timestamp::optional_from_db(Timestamp::optional_to_db(&None)), None);
     }
 }
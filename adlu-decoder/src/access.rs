@@ -0,0 +1,201 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+use std::io::ErrorKind;
+use std::path::Path;
+
+use eyre::{Result, WrapErr};
+
+/// The extensions of the files the decoder reads.
+const CONFIG_EXTENSIONS: [&str; 3] = ["json", "ccp", "operatingconfig"];
+
+/// Whether (and how much of) a file or directory of license files can be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    Missing,
+    Denied,
+    Failed(String),
+    Readable { files: usize, denied: usize },
+}
+
+/// The access this process has to a path that the decoder was asked to read.
+#[derive(Debug, Clone)]
+pub struct PathAccess {
+    pub path: String,
+    pub access: Access,
+}
+
+impl PathAccess {
+    /// Check how much of the file or directory at `path` can be read.
+    pub fn check(path: &str) -> Self {
+        let access = match std::fs::metadata(path) {
+            Err(err) => from_error(err),
+            Ok(info) if info.is_dir() => check_directory(Path::new(path)),
+            Ok(_) => match std::fs::File::open(path) {
+                Ok(_) => Access::Readable { files: 1, denied: 0 },
+                Err(err) => from_error(err),
+            },
+        };
+        PathAccess { path: path.to_string(), access }
+    }
+
+    /// Whether some of the license files can't be read without admin rights.
+    pub fn is_denied(&self) -> bool {
+        match self.access {
+            Access::Denied => true,
+            Access::Readable { denied, .. } => denied > 0,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for PathAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.access {
+            Access::Missing => write!(f, "{}: not found", self.path),
+            Access::Denied => write!(f, "{}: access denied", self.path),
+            Access::Failed(err) => write!(f, "{}: can't be read ({})", self.path, err),
+            Access::Readable { files, denied: 0 } => {
+                write!(f, "{}: {} license file(s) readable", self.path, files)
+            }
+            Access::Readable { files, denied } => write!(
+                f,
+                "{}: {} license file(s) readable, {} need admin rights",
+                self.path,
+                files - denied,
+                denied
+            ),
+        }
+    }
+}
+
+fn from_error(err: std::io::Error) -> Access {
+    match err.kind() {
+        ErrorKind::NotFound => Access::Missing,
+        ErrorKind::PermissionDenied => Access::Denied,
+        _ => Access::Failed(err.to_string()),
+    }
+}
+
+fn check_directory(path: &Path) -> Access {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) => return from_error(err),
+    };
+    let (mut files, mut denied) = (0, 0);
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        if !CONFIG_EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext)) {
+            continue;
+        }
+        files += 1;
+        if let Err(err) = std::fs::File::open(&path) {
+            if err.kind() == ErrorKind::PermissionDenied {
+                denied += 1;
+            }
+        }
+    }
+    Access::Readable { files, denied }
+}
+
+/// How to give the decoder the admin rights it needs.
+pub fn elevation_hint() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "Run the decoder from an administrator command prompt, \
+        or add --elevate to approve a UAC prompt."
+    } else {
+        "Run the decoder again with sudo (e.g., sudo adlu-decoder), \
+        or add --elevate to be prompted for your password."
+    }
+}
+
+/// The arguments this process was run with, marked for an elevated re-run.
+fn elevated_args() -> Vec<String> {
+    let mut args: Vec<String> =
+        std::env::args().skip(1).filter(|arg| arg != "--elevate").collect();
+    args.push("--elevated".to_string());
+    args
+}
+
+/// Run the decoder again, with admin rights, in this terminal,
+/// returning its exit status.
+#[cfg(unix)]
+pub fn run_elevated() -> Result<i32> {
+    let exe = std::env::current_exe().wrap_err("Can't find the decoder executable")?;
+    let status = std::process::Command::new("sudo")
+        .arg("--")
+        .arg(exe)
+        .args(elevated_args())
+        .status()
+        .wrap_err("Can't run sudo")?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Run the decoder again, with admin rights, returning its exit status.  The
+/// elevated process can't write to this console, so its output is captured
+/// in a temporary file and then copied here.
+#[cfg(windows)]
+pub fn run_elevated() -> Result<i32> {
+    let exe = std::env::current_exe().wrap_err("Can't find the decoder executable")?;
+    let output =
+        std::env::temp_dir().join(format!("adlu-decoder-{}.txt", std::process::id()));
+    let quote = |s: &str| format!("\"{}\"", s);
+    let args: Vec<String> = elevated_args().iter().map(|arg| quote(arg)).collect();
+    let command = format!(
+        "/c \"{} {} > {} 2>&1\"",
+        quote(&exe.to_string_lossy()),
+        args.join(" "),
+        quote(&output.to_string_lossy()),
+    );
+    // PowerShell quotes single quotes by doubling them
+    let script = format!(
+        "$p = Start-Process -FilePath cmd.exe -ArgumentList '{}' -Verb RunAs \
+        -WindowStyle Hidden -Wait -PassThru; exit $p.ExitCode",
+        command.replace('\'', "''")
+    );
+    let status = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .status()
+        .wrap_err("Can't run PowerShell")?;
+    match std::fs::read_to_string(&output) {
+        Ok(text) => print!("{}", text),
+        Err(_) => {
+            return Err(eyre::eyre!("The elevated decoder didn't run (was it approved?)"))
+        }
+    }
+    std::fs::remove_file(&output).ok();
+    Ok(status.code().unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, PathAccess};
+
+    #[test]
+    fn test_path_access() {
+        let access = PathAccess::check("../rsrc/OperatingConfigs");
+        assert!(
+            matches!(access.access, Access::Readable { files, denied: 0 } if files > 0)
+        );
+        assert!(!access.is_denied());
+        let access = PathAccess::check("../rsrc/no-such-directory");
+        assert_eq!(access.access, Access::Missing);
+        assert!(access.to_string().ends_with("not found"));
+    }
+}
@@ -16,7 +16,7 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 pub const DEFAULT_CONFIG_DIR: &str = if cfg!(target_os = "macos") {
     "/Library/Application Support/Adobe/OperatingConfigs"
@@ -39,9 +39,53 @@ pub struct Opt {
     #[clap(short, long, parse(from_occurrences))]
     pub verbose: i32,
 
+    /// Post a summary of the decoded licenses to the proxy at this address
+    /// (e.g., proxy.example.com:8443 or http://proxy.example.com:8080),
+    /// so the proxy can keep an inventory of deployed packages.
+    #[clap(long, value_name = "PROXY")]
+    pub post_to: Option<String>,
+
+    /// The token that authorizes posting to the proxy (one of its
+    /// inventory tokens, or an admin token).
+    #[clap(long, value_name = "TOKEN", requires = "post-to")]
+    pub post_token: Option<String>,
+
+    /// (Windows only) Write a summary of the decoded licenses to the registry,
+    /// under HKLM\SOFTWARE\clickonetwo\ADLU\Licenses, so endpoint-management
+    /// tools (e.g., SCCM hardware inventory) can collect it.  Needs admin rights.
+    #[clap(long)]
+    pub write_registry: bool,
+
+    /// If some license files can only be read with admin rights, run again
+    /// with them (after a UAC prompt on Windows, or sudo on Mac).
+    #[clap(long)]
+    pub elevate: bool,
+
+    /// (Marks a run that already has admin rights.)
+    #[clap(long, hide = true)]
+    pub elevated: bool,
+
     /// path to directory or file to decode
     #[clap(default_value = DEFAULT_CONFIG_DIR)]
     pub path: String,
+
+    #[clap(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Summarize the sessions in NGL log files, with their errors
+    /// (and, if -v is given, their warnings)
+    Logs {
+        /// Also save the summary to this file
+        #[clap(long, value_name = "PATH")]
+        save: Option<String>,
+
+        /// log files to summarize (wildcards are allowed)
+        #[clap(required = true)]
+        paths: Vec<String>,
+    },
 }
 
 #[cfg(test)]
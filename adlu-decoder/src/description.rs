@@ -18,6 +18,7 @@ released.  That license is reproduced here in the LICENSE-MIT file.
 */
 use adlu_base::Timestamp;
 use adlu_parse::admin::{ActivationType, Configuration, OcFileSpec, PreconditioningData};
+use adlu_parse::CachedLicenseState;
 
 pub fn describe_configuration(config: &Configuration, verbose: i32) {
     match config {
@@ -55,22 +56,40 @@ fn describe_operating_configs(ocs: &[OcFileSpec], verbose: i32) {
         }
         // if -vv is given, check for locally cached licenses
         if verbose > 1 {
-            if let Some(s) = oc.cached_expiry() {
-                if let Ok(ts) = s.parse::<Timestamp>() {
-                    println!(
-                        "    Cached activation expires: {}",
-                        ts.as_local_datetime().format("%Y-%m-%d")
-                    )
-                } else {
-                    println!("    Invalid cached expiration")
-                }
-            } else {
-                println!("    No cached activation")
+            match oc.cached_license_state() {
+                Some(state) => describe_cached_license(&state),
+                None => println!("    No cached activation"),
             }
         }
     }
 }
 
+fn describe_cached_license(state: &CachedLicenseState) {
+    let date = |millis: i64| {
+        Timestamp::from_millis(millis).as_local_datetime().format("%Y-%m-%d").to_string()
+    };
+    let days = |millis: i64| millis / (24 * 60 * 60 * 1000);
+    println!("    Cached activation expires: {}", date(state.effective_end_timestamp));
+    println!("    Cached license state:");
+    println!("        License ID: {}", state.license_id);
+    println!("        Response type: {}", state.response_type);
+    println!("        Created: {}", date(state.creation_timestamp));
+    println!(
+        "        Cache lifetime: {} days (until {})",
+        days(state.cache_lifetime),
+        date(state.cache_end_timestamp())
+    );
+    if state.grace_time > 0 {
+        println!("        Grace period: {} days", days(state.grace_time));
+    }
+    if !state.device_id.is_empty() {
+        println!("        Device ID: {}", state.device_id);
+    }
+    if !state.device_date.is_empty() {
+        println!("        Device date: {}", state.device_date);
+    }
+}
+
 fn describe_preconditioning_data(pc_data: &PreconditioningData, verbose: i32) {
     let mut oc_data = pc_data.operating_configs.clone();
     oc_data.sort_by_key(|oc1| oc1.app_id());
@@ -91,10 +110,13 @@ fn describe_package(oc: &OcFileSpec, verbose: i32) {
     println!("    License type: {}", oc.activation_type());
     if verbose > 0 {
         if let ActivationType::FrlIsolated(codes) = oc.activation_type() {
-            if codes.len() == 1 {
-                println!("    Census code: {}", codes[0]);
-            } else {
-                println!("    Census codes: {}", codes.join(", "));
+            // one code per line, so they can be copied and pasted
+            println!("    Census code{}:", if codes.len() == 1 { "" } else { "s" });
+            for code in codes.iter() {
+                match code.problem() {
+                    None => println!("        {}", code),
+                    Some(problem) => println!("        {} (INVALID: {})", code, problem),
+                }
             }
         }
     }
@@ -0,0 +1,68 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+use adlu_parse::admin::Configuration;
+use adlu_parse::protocol::InventoryReport;
+use eyre::{eyre, Result, WrapErr};
+
+/// Upload a summary of the configuration to the inventory endpoint of a proxy.
+/// The proxy can be given as just a host (and port), or as a URL.  The token,
+/// if any, authorizes the upload.
+pub fn post_inventory(
+    config: &Configuration,
+    proxy: &str,
+    token: Option<&str>,
+) -> Result<()> {
+    let base = if proxy.contains("://") {
+        proxy.trim_end_matches('/').to_string()
+    } else {
+        format!("https://{}", proxy.trim_end_matches('/'))
+    };
+    let url = format!("{}/inventory/v1", base);
+    let report = InventoryReport::from_configuration(
+        config,
+        &hostname(),
+        env!("CARGO_PKG_VERSION"),
+    );
+    let mut request = reqwest::blocking::Client::new().post(&url).json(&report);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response =
+        request.send().wrap_err(format!("Can't reach the proxy at {}", &base))?;
+    if response.status().is_success() {
+        println!("Posted {} package(s) to the proxy at {}", report.packages.len(), &base);
+        Ok(())
+    } else {
+        let status = response.status();
+        let body = response.text().unwrap_or_default();
+        Err(eyre!("The proxy refused the inventory ({}): {}", status, body))
+    }
+}
+
+pub fn hostname() -> String {
+    if let Ok(name) = std::env::var("COMPUTERNAME") {
+        return name;
+    }
+    std::process::Command::new("hostname")
+        .output()
+        .ok()
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
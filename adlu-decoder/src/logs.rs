@@ -0,0 +1,142 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use adlu_base::Timestamp;
+use adlu_parse::protocol::{parse_log_data, parse_log_events, LogEvent, LogSession};
+use eyre::{eyre, Result, WrapErr};
+
+/// Summarize the sessions in NGL log files, as the proxy does for uploaded
+/// logs.  Help desk techs gather these files from end users, and a session
+/// can span several of them (when the log has been rotated), so sessions are
+/// merged across the files before they are summarized.
+pub fn summarize_logs(paths: &[String], verbose: i32) -> Result<String> {
+    let mut sessions: Vec<LogSession> = Vec::new();
+    let mut events: BTreeMap<String, Vec<LogEvent>> = BTreeMap::new();
+    for path in expand_paths(paths)? {
+        let data =
+            std::fs::read(&path).wrap_err(format!("Can't read log file {path}"))?;
+        for fragment in parse_log_data(&path, &data) {
+            match sessions.iter_mut().find(|s| s.session_id == fragment.session_id) {
+                Some(session) => *session = session.merge(&fragment)?,
+                None => sessions.push(fragment),
+            }
+        }
+        for event in parse_log_events(&data) {
+            events.entry(event.session_id.clone()).or_default().push(event);
+        }
+    }
+    if sessions.is_empty() {
+        return Err(eyre!("There are no NGL log sessions in the given files"));
+    }
+    sessions.sort_by(|s1, s2| s1.initial_entry.cmp(&s2.initial_entry));
+    let mut summary = String::new();
+    for session in sessions.iter() {
+        let events = events.remove(&session.session_id).unwrap_or_default();
+        describe_session(&mut summary, session, &events, verbose);
+    }
+    Ok(summary)
+}
+
+fn describe_session(
+    out: &mut String,
+    session: &LogSession,
+    events: &[LogEvent],
+    verbose: i32,
+) {
+    let value =
+        |val: &Option<String>| val.clone().unwrap_or_else(|| "unknown".to_string());
+    let errors = events.iter().filter(|e| e.level == "ERROR").count();
+    let warnings = events.len() - errors;
+    // writing to a string can't fail
+    writeln!(out, "Session {} (in {}):", session.session_id, session.source_addr).ok();
+    writeln!(out, "    App: {} {}", value(&session.app_id), value(&session.app_version))
+        .ok();
+    if verbose > 0 {
+        writeln!(out, "    App locale: {}", value(&session.app_locale)).ok();
+        writeln!(out, "    User ID: {}", value(&session.user_id)).ok();
+    }
+    writeln!(out, "    NGL version: {}", value(&session.ngl_version)).ok();
+    writeln!(out, "    OS: {} {}", value(&session.os_name), value(&session.os_version))
+        .ok();
+    let start = session.session_start.as_ref().unwrap_or(&session.initial_entry);
+    let end = session.session_end.as_ref().unwrap_or(&session.final_entry);
+    let (start_note, end_note) = (
+        if session.session_start.is_some() { "" } else { " (or earlier)" },
+        if session.session_end.is_some() { "" } else { " (or later)" },
+    );
+    writeln!(out, "    Started: {}{start_note}", date_time(start)).ok();
+    writeln!(out, "    Ended: {}{end_note}", date_time(end)).ok();
+    writeln!(out, "    Errors: {errors}, warnings: {warnings}").ok();
+    for event in events.iter().filter(|e| verbose > 0 || e.level == "ERROR") {
+        let code = match &event.error_code {
+            Some(code) => format!(" (code {code})"),
+            None => "".to_string(),
+        };
+        writeln!(
+            out,
+            "        {} [{}] {}{code}: {}",
+            date_time(&event.timestamp),
+            event.level,
+            event.workflow,
+            event.message
+        )
+        .ok();
+    }
+}
+
+fn date_time(ts: &Timestamp) -> String {
+    ts.as_local_datetime().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// The files named by the paths, expanding any wildcards (which shells on
+/// Windows leave to the program).
+fn expand_paths(paths: &[String]) -> Result<Vec<String>> {
+    let mut result = vec![];
+    for path in paths {
+        if !path.contains(['*', '?', '[']) {
+            result.push(path.clone());
+            continue;
+        }
+        let matches = glob::glob(path).wrap_err(format!("Invalid wildcard: {path}"))?;
+        let before = result.len();
+        for entry in matches.flatten() {
+            result.push(entry.to_string_lossy().to_string());
+        }
+        if result.len() == before {
+            return Err(eyre!("No log files match {path}"));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::summarize_logs;
+
+    #[test]
+    fn test_summarize_logs() {
+        let path = "../rsrc/logs/mac/NGLClient_PremierePro122.5.0.log.bin";
+        let summary = summarize_logs(&[path.to_string()], 0).unwrap();
+        assert_eq!(summary.matches("Session ").count(), 1);
+        assert!(summary.contains("    App: PremierePro1 22.5.0\n"), "{}", summary);
+        assert!(summarize_logs(&["no-such-file.log".to_string()], 0).is_err());
+    }
+}
@@ -16,20 +16,89 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
+mod access;
 mod cli;
 mod description;
+mod inventory;
+mod logs;
+mod registry;
 
+use access::{elevation_hint, run_elevated, Access, PathAccess};
 use adlu_parse::admin::Configuration;
 use clap::Parser;
-use cli::{Opt, DEFAULT_CONFIG_DIR};
+use cli::{Command, Opt, DEFAULT_CONFIG_DIR};
 use description::describe_configuration;
+use inventory::post_inventory;
+use logs::summarize_logs;
+use registry::write_registry;
 
 fn main() {
     let opt: Opt = Opt::parse();
-    match Configuration::from_path(&opt.path) {
-        Ok(config) => describe_configuration(&config, opt.verbose),
+    if let Some(Command::Logs { save, paths }) = &opt.cmd {
+        match summarize_logs(paths, opt.verbose) {
+            Ok(summary) => {
+                print!("{}", summary);
+                if let Some(save) = save {
+                    if let Err(err) = std::fs::write(save, &summary) {
+                        eprintln!("Error: Can't save the summary to {}: {}", save, err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("Error: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let path = match shellexpand::env(&opt.path) {
+        Ok(path) => path.to_string(),
+        Err(_) => opt.path.clone(),
+    };
+    let access = PathAccess::check(&path);
+    if access.is_denied() && !opt.elevated {
+        if opt.elevate {
+            match run_elevated() {
+                Ok(code) => std::process::exit(code),
+                Err(err) => {
+                    eprintln!("Error: Can't run with admin rights: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        eprintln!("Warning: Some license files can't be read without admin rights");
+        eprintln!("    {}", access);
+        eprintln!("{}", elevation_hint());
+        if access.access == Access::Denied {
+            std::process::exit(1);
+        }
+    }
+    if opt.verbose > 0 {
+        println!("Access to license files:\n    {}", access);
+    }
+    match Configuration::from_path(&path) {
+        Ok(config) => {
+            describe_configuration(&config, opt.verbose);
+            if let Some(proxy) = &opt.post_to {
+                if let Err(err) =
+                    post_inventory(&config, proxy, opt.post_token.as_deref())
+                {
+                    eprintln!("Error: {:?}", err);
+                    std::process::exit(1);
+                }
+            }
+            if opt.write_registry {
+                if let Err(err) = write_registry(&config) {
+                    eprintln!("Error: {:?}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
         Err(err) => {
-            if opt.path.eq_ignore_ascii_case(DEFAULT_CONFIG_DIR) {
+            if access.is_denied() {
+                eprintln!("Error: {}: {}", err, access)
+            } else if opt.path.eq_ignore_ascii_case(DEFAULT_CONFIG_DIR) {
                 eprintln!("Error: There are no licenses installed on this computer")
             } else {
                 eprintln!("Error: {}", err)
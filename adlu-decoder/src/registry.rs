@@ -0,0 +1,142 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Writes a summary of the decoded licenses to the Windows registry, where
+endpoint-management tools (such as the hardware inventory of SCCM or Intune)
+can collect it.  The summary lives under [`REGISTRY_KEY`], with the
+machine-wide values on the key itself and one `Package<n>` subkey per
+license package.  Every run replaces the previous summary.
+ */
+use adlu_parse::admin::Configuration;
+use adlu_parse::protocol::InventoryReport;
+use eyre::{eyre, Result, WrapErr};
+
+/// The key that holds the license summary.
+pub const REGISTRY_KEY: &str = r"HKLM\SOFTWARE\clickonetwo\ADLU\Licenses";
+
+/// A single registry value to be written: its key, name, type, and data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryValue {
+    pub key: String,
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub data: String,
+}
+
+impl RegistryValue {
+    fn string(key: &str, name: &'static str, data: &str) -> Self {
+        Self { key: key.to_string(), name, kind: "REG_SZ", data: data.to_string() }
+    }
+
+    fn number(key: &str, name: &'static str, data: i64) -> Self {
+        Self { key: key.to_string(), name, kind: "REG_DWORD", data: data.to_string() }
+    }
+}
+
+/// The values that describe an inventory report, in the order they are written.
+pub fn registry_values(report: &InventoryReport, updated: &str) -> Vec<RegistryValue> {
+    let mut values = vec![
+        RegistryValue::string(REGISTRY_KEY, "Hostname", &report.hostname),
+        RegistryValue::string(REGISTRY_KEY, "DecoderVersion", &report.decoder_version),
+        RegistryValue::string(REGISTRY_KEY, "LastUpdated", updated),
+        RegistryValue::number(REGISTRY_KEY, "PackageCount", report.packages.len() as i64),
+    ];
+    for (i, package) in report.packages.iter().enumerate() {
+        let key = format!(r"{}\Package{}", REGISTRY_KEY, i + 1);
+        values.push(RegistryValue::string(&key, "NpdId", &package.npd_id));
+        values.push(RegistryValue::string(&key, "LicenseType", &package.license_type));
+        values.push(RegistryValue::string(&key, "ExpiryDate", &package.expiry_date));
+        values.push(RegistryValue::number(&key, "Precedence", package.precedence as i64));
+        values.push(RegistryValue::string(&key, "AppIds", &package.app_ids.join(";")));
+        values.push(RegistryValue::string(&key, "InstallDate", &package.install_date));
+    }
+    values
+}
+
+/// Replace the license summary in the registry with one for this configuration.
+/// Writing under HKLM needs admin rights, so this is usually run by an administrator
+/// or from a management agent running as SYSTEM.
+pub fn write_registry(config: &Configuration) -> Result<()> {
+    if !cfg!(target_os = "windows") {
+        return Err(eyre!(
+            "The license summary can only be written to the registry on Windows"
+        ));
+    }
+    let report = InventoryReport::from_configuration(
+        config,
+        &crate::inventory::hostname(),
+        env!("CARGO_PKG_VERSION"),
+    );
+    let updated = chrono::Local::now().format("%Y-%m-%d %H:%M:%S %:z").to_string();
+    // remove the prior summary, which may have had more packages; it may not exist
+    reg(&["delete", REGISTRY_KEY, "/f"]).ok();
+    for value in registry_values(&report, &updated) {
+        reg(&[
+            "add",
+            &value.key,
+            "/v",
+            value.name,
+            "/t",
+            value.kind,
+            "/d",
+            &value.data,
+            "/f",
+        ])?;
+    }
+    println!(
+        "Wrote {} package(s) to the registry at {}",
+        report.packages.len(),
+        REGISTRY_KEY
+    );
+    Ok(())
+}
+
+fn reg(args: &[&str]) -> Result<()> {
+    let output = std::process::Command::new("reg")
+        .args(args)
+        .output()
+        .wrap_err("Can't run reg.exe")?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(eyre!("Can't update the registry ({}): {}", output.status, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{registry_values, REGISTRY_KEY};
+    use adlu_parse::protocol::InventoryReport;
+
+    #[test]
+    fn test_registry_values() {
+        let report = InventoryReport::mock_from_hostname("host1", "npd1");
+        let values = registry_values(&report, "2022-08-02 10:00:00 +00:00");
+        assert_eq!(values.len(), 4 + 6);
+        assert!(values[..4].iter().all(|v| v.key == REGISTRY_KEY));
+        assert_eq!(values[3].data, "1");
+        let package = format!(r"{}\Package1", REGISTRY_KEY);
+        let npd_id = values.iter().find(|v| v.name == "NpdId").unwrap();
+        assert_eq!(npd_id.key, package);
+        assert_eq!(npd_id.data, "npd1");
+        let precedence = values.iter().find(|v| v.name == "Precedence").unwrap();
+        assert_eq!((precedence.kind, precedence.data.as_str()), ("REG_DWORD", "80"));
+    }
+}
@@ -16,10 +16,10 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
-use super::user::get_cached_expiry;
+use super::user::{get_cached_expiry, get_cached_license_state, CachedLicenseState};
 use super::{AdobeSignatures, CustomerSignatures, SignatureSpecifier};
+use crate::{Error, Result};
 use adlu_base::{u64decode, Timestamp};
-use eyre::{eyre, Result, WrapErr};
 use serde::{Deserialize, Serialize};
 use std::io::Read;
 use std::path::Path;
@@ -31,7 +31,8 @@ pub enum Configuration {
 
 impl Configuration {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let info = std::fs::metadata(path.as_ref()).wrap_err("No configuration found")?;
+        let info = std::fs::metadata(path.as_ref())
+            .map_err(|e| Error::io("No configuration found", e))?;
         if info.is_dir() {
             Self::from_directory(path.as_ref())
         } else {
@@ -78,12 +79,15 @@ impl Configuration {
             if !ocs.is_empty() {
                 return Ok(Self::Installed(ocs));
             }
-            Err(eyre!("No configuration files found in directory: {}", dir_str))
+            Err(Error::NoConfiguration(format!(
+                "No configuration files found in directory: {}",
+                dir_str
+            )))
         } else {
-            Err(eyre!(
+            Err(Error::NoConfiguration(format!(
                 "Cannot search for configurations in non-UTF8 path: {}",
                 path.as_ref().to_string_lossy()
-            ))
+            )))
         }
     }
 
@@ -102,7 +106,10 @@ impl Configuration {
             let oc = OcFileSpec::from_file(path)?;
             return Ok(Configuration::Installed(vec![oc]));
         }
-        Err(eyre!("Not a configuration file: {}", path.as_ref().to_string_lossy()))
+        Err(Error::NoConfiguration(format!(
+            "Not a configuration file: {}",
+            path.as_ref().to_string_lossy()
+        )))
     }
 }
 
@@ -118,37 +125,41 @@ pub struct PreconditioningData {
 
 impl PreconditioningData {
     pub fn from_pc_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let json_data =
-            std::fs::read_to_string(path).wrap_err("Can't read preconditioning data")?;
-        let pc_data =
-            serde_json::from_str(&json_data).wrap_err("Invalid preconditioning data")?;
+        let json_data = std::fs::read_to_string(path)
+            .map_err(|e| Error::io("Can't read preconditioning data", e))?;
+        let pc_data = serde_json::from_str(&json_data)
+            .map_err(|e| Error::json("Invalid preconditioning data", e))?;
         Ok(pc_data)
     }
 
     pub fn from_package<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let bytes = std::fs::read(path).wrap_err("Cannot read package")?;
+        let bytes =
+            std::fs::read(path).map_err(|e| Error::io("Cannot read package", e))?;
         // This may be a zip file, in which case we extract the preconditioning data from
         // the "PkgConfig.xml" file contained in the zip.  Otherwise, this file just contains
         // the preconditioning data in XML form.
         let reader = std::io::Cursor::new(&bytes);
         let html = if let Ok(mut archive) = zip::ZipArchive::new(reader) {
-            let mut file = archive
-                .by_name("PkgConfig.xml")
-                .map_err(|e| eyre!(e))
-                .wrap_err("Can't find configuration data in package")?;
+            let mut file = archive.by_name("PkgConfig.xml").map_err(|e| {
+                Error::package("Can't find configuration data in package", e)
+            })?;
             let mut buffer = String::new();
-            file.read_to_string(&mut buffer).wrap_err("Can't read package")?;
+            file.read_to_string(&mut buffer)
+                .map_err(|e| Error::io("Can't read package", e))?;
             buffer
         } else {
-            std::str::from_utf8(&bytes).wrap_err("Invalid package format")?.to_string()
+            std::str::from_utf8(&bytes)
+                .map_err(|e| Error::package("Invalid package format", e))?
+                .to_string()
         };
         let doc = visdom::Vis::load(&html)
-            .map_err(|e| eyre!("{}", e))
-            .wrap_err("Cannot parse package")?;
+            .map_err(|e| Error::package("Cannot parse package", e))?;
         let data_node = doc.find("Preconditioning");
         let json_data = data_node.text();
-        let pc_data: PreconditioningData = serde_json::from_str(&json_data)
-            .wrap_err("Can't parse preconditioning data in ccp file")?;
+        let pc_data: PreconditioningData =
+            serde_json::from_str(&json_data).map_err(|e| {
+                Error::json("Can't parse preconditioning data in ccp file", e)
+            })?;
         Ok(pc_data)
     }
 }
@@ -172,14 +183,16 @@ impl OcFileSpec {
                     if let Some(extension) = extension.to_str() {
                         let mod_date: chrono::DateTime<chrono::Local> =
                             std::fs::metadata(&path)
-                                .wrap_err("Can't access operating config")?
-                                .modified()
-                                .wrap_err("Can't access operating config")?
+                                .and_then(|info| info.modified())
+                                .map_err(|e| {
+                                    Error::io("Can't access operating config", e)
+                                })?
                                 .into();
                         let json_data = std::fs::read_to_string(&path)
-                            .wrap_err("Can't read operating config")?;
-                        let oc = serde_json::from_str(&json_data)
-                            .wrap_err("Invalid operating config data")?;
+                            .map_err(|e| Error::io("Can't read operating config", e))?;
+                        let oc = serde_json::from_str(&json_data).map_err(|e| {
+                            Error::json("Invalid operating config data", e)
+                        })?;
                         return Ok(Self {
                             name: name.to_string(),
                             extension: extension.to_string(),
@@ -192,7 +205,7 @@ impl OcFileSpec {
                 }
             }
         }
-        Err(eyre!("Invalid operating config filename"))
+        Err(Error::NoConfiguration("Invalid operating config filename".to_string()))
     }
 
     pub fn npd_id(&self) -> String {
@@ -248,21 +261,7 @@ impl OcFileSpec {
                 if code0.len() > 18 {
                     ActivationType::FrlOffline
                 } else {
-                    let codes = codes
-                        .iter()
-                        .map(|code| {
-                            if code.len() != 18 {
-                                "invalid-census-code".to_string()
-                            } else {
-                                format!(
-                                    "{}-{}-{}",
-                                    &code[0..6],
-                                    &code[6..12],
-                                    &code[12..18]
-                                )
-                            }
-                        })
-                        .collect();
+                    let codes = codes.iter().map(|code| CensusCode::new(code)).collect();
                     ActivationType::FrlIsolated(codes)
                 }
             }
@@ -305,6 +304,10 @@ impl OcFileSpec {
     pub fn cached_expiry(&self) -> Option<String> {
         get_cached_expiry(self)
     }
+
+    pub fn cached_license_state(&self) -> Option<CachedLicenseState> {
+        get_cached_license_state(self)
+    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -413,10 +416,55 @@ pub struct CacheExpiryWarningControl {
     pub warning_interval: u64,
 }
 
+/// A census code from an FRL Isolated package.  These are the package's
+/// challenge codes, which are 18 letters and digits long, and which are
+/// shown (and typed) in three dash-separated groups of six.  Adobe doesn't
+/// publish how the codes are checked, so a code is taken to be valid if it
+/// has the right length and alphabet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CensusCode {
+    pub challenge_code: String,
+}
+
+impl CensusCode {
+    pub fn new(challenge_code: &str) -> Self {
+        CensusCode { challenge_code: challenge_code.to_string() }
+    }
+
+    /// What's wrong with the code, if anything.
+    pub fn problem(&self) -> Option<String> {
+        let code = &self.challenge_code;
+        if let Some(c) =
+            code.chars().find(|c| !c.is_ascii_uppercase() && !c.is_ascii_digit())
+        {
+            Some(format!("contains the invalid character {:?}", c))
+        } else if code.len() != 18 {
+            Some(format!("has {} characters instead of 18", code.len()))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.problem().is_none()
+    }
+}
+
+impl std::fmt::Display for CensusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let code = &self.challenge_code;
+        if self.is_valid() {
+            write!(f, "{}-{}-{}", &code[0..6], &code[6..12], &code[12..18])
+        } else {
+            code.fmt(f)
+        }
+    }
+}
+
 pub enum ActivationType {
     FrlOnline(String),
     FrlOffline,
-    FrlIsolated(Vec<String>),
+    FrlIsolated(Vec<CensusCode>),
     FrlLan(String),
     Sdl,
     Unknown(String),
@@ -429,10 +477,16 @@ impl std::fmt::Display for ActivationType {
                 format!("FRL Online (server: {})", server).fmt(f)
             }
             ActivationType::FrlOffline => "FRL Offline".fmt(f),
-            ActivationType::FrlIsolated(codes) => match codes.len() {
-                1 => "FRL Isolated (1 census code)".fmt(f),
-                n => format!("FRL Isolated ({} census codes)", n).fmt(f),
-            },
+            ActivationType::FrlIsolated(codes) => {
+                let count = match codes.len() {
+                    1 => "1 census code".to_string(),
+                    n => format!("{} census codes", n),
+                };
+                match codes.iter().filter(|code| !code.is_valid()).count() {
+                    0 => format!("FRL Isolated ({})", count).fmt(f),
+                    n => format!("FRL Isolated ({}, {} invalid)", count, n).fmt(f),
+                }
+            }
             ActivationType::FrlLan(server) => {
                 format!("FRL LAN (server: {})", server).fmt(f)
             }
@@ -464,6 +518,7 @@ impl std::fmt::Display for Precedence {
 mod tests {
     use super::OperatingConfig;
     use super::PreconditioningData;
+    use super::{ActivationType, CensusCode, Configuration};
 
     extern crate serde_json; // 1.0.69
 
@@ -495,6 +550,29 @@ mod tests {
         assert_eq!(decode, ref_decode);
     }
 
+    #[test]
+    fn test_isolated_census_codes() {
+        let path = "../rsrc/OperatingConfigs/SWxsdXN0cmF0b3Ixe30yMDE4MDcyMDA0-MmE0N2E4M2UtNjFmNS00NmM2LWE0N2ItOGE0Njc2MTliOTI5-80.operatingconfig";
+        let ocs = match Configuration::from_path(path).expect("Can't read isolated data")
+        {
+            Configuration::Installed(ocs) => ocs,
+            Configuration::Packaged(_) => panic!("Isolated data is not installed"),
+        };
+        let codes = match ocs[0].activation_type() {
+            ActivationType::FrlIsolated(codes) => codes,
+            other => panic!("Isolated data has activation type: {}", other),
+        };
+        let codes: Vec<String> = codes.iter().map(|code| code.to_string()).collect();
+        assert_eq!(codes, vec!["BB7BAC-WXJ2KG-366ZHJ", "BBEFWI-B79KPQ-DUIEZI"]);
+        let short = CensusCode::new("BB7BACWXJ2KG366ZH");
+        assert_eq!(short.problem().unwrap(), "has 17 characters instead of 18");
+        assert_eq!(short.to_string(), "BB7BACWXJ2KG366ZH");
+        let lower = CensusCode::new("bb7bacwxj2kg366zhj");
+        assert!(!lower.is_valid());
+        let isolated = ActivationType::FrlIsolated(vec![short, lower]);
+        assert_eq!(isolated.to_string(), "FRL Isolated (2 census codes, 2 invalid)");
+    }
+
     #[test]
     fn test_lan_oc() {
         let path = "../rsrc/OperatingConfigs/SWxsdXN0cmF0b3Ixe30yMDE4MDcyMDA0-OTUzZTViZWYtYWJmMy00NGUxLWFjYjUtZmZhN2MyMDY4YjQx-80.operatingconfig";
@@ -0,0 +1,93 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+The errors that can occur when parsing licensing data.
+
+Each error carries a description of what was being parsed when it occurred,
+and (where there is one) the underlying error that caused it.
+ */
+use adlu_base::base64_encoded_json::ILLEGAL_BASE64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A file or directory couldn't be read.
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// JSON data couldn't be parsed.  This includes JSON that is
+    /// base64-encoded in a field of the data, once it's been decoded.
+    #[error("{context}")]
+    Json {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    /// A field of JSON data that should be base64-encoded JSON
+    /// couldn't be decoded.
+    #[error("{context}: {message}")]
+    Base64 { context: String, message: String },
+    /// A query string couldn't be parsed.
+    #[error("{context}")]
+    Query {
+        context: String,
+        #[source]
+        source: serde_urlencoded::de::Error,
+    },
+    /// A package file couldn't be unpacked.
+    #[error("{context}: {message}")]
+    Package { context: String, message: String },
+    /// A file or directory doesn't contain any configuration data.
+    #[error("{0}")]
+    NoConfiguration(String),
+    /// A request is missing a header or body that it needs.
+    #[error("{request} has no {missing}")]
+    Missing { request: String, missing: &'static str },
+    /// A request was handed to the parser for a different endpoint.
+    #[error("{request} is not a {expected}; please report a bug")]
+    UnsupportedEndpoint { request: String, expected: &'static str },
+    /// Two sessions with different ids can't be merged.
+    #[error("Can't merge sessions with different IDs")]
+    SessionMismatch,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn io(context: &str, source: std::io::Error) -> Self {
+        Error::Io { context: context.to_string(), source }
+    }
+
+    /// Base64 failures surface from serde as JSON data errors,
+    /// so they are told apart by their message.
+    pub(crate) fn json(context: &str, source: serde_json::Error) -> Self {
+        let message = source.to_string();
+        if source.is_data() && message.starts_with(ILLEGAL_BASE64) {
+            Error::Base64 { context: context.to_string(), message }
+        } else {
+            Error::Json { context: context.to_string(), source }
+        }
+    }
+
+    pub(crate) fn package(context: &str, message: impl std::fmt::Display) -> Self {
+        Error::Package { context: context.to_string(), message: message.to_string() }
+    }
+}
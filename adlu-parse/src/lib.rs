@@ -17,9 +17,13 @@ materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
 pub mod admin;
+mod error;
 pub mod protocol;
 mod user;
 
+pub use error::{Error, Result};
+pub use user::CachedLicenseState;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
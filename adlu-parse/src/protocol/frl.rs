@@ -16,12 +16,11 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
-use eyre::{Result, WrapErr};
 use serde::{Deserialize, Serialize};
 
 use adlu_base::Timestamp;
 
-use crate::{AdobeSignatures, CustomerSignatures};
+use crate::{AdobeSignatures, CustomerSignatures, Error, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -35,13 +34,31 @@ pub struct FrlActivationRequestBody {
 }
 
 impl FrlActivationRequestBody {
+    /// Refreshes are keyed separately from initial activations,
+    /// so that caching a refresh doesn't replace the initial profile.
     pub fn activation_id(&self) -> String {
+        let initial_id = self.initial_activation_id();
+        if self.is_refresh() {
+            format!("{}|refresh", initial_id)
+        } else {
+            initial_id
+        }
+    }
+
+    /// The key of the initial activation, whether or not this is a refresh.
+    pub fn initial_activation_id(&self) -> String {
         let d_id = self.deactivation_id();
         let factors: Vec<&str> =
             vec![&self.app_details.ngl_app_id, &self.app_details.ngl_lib_version, &d_id];
         factors.join("|")
     }
 
+    /// Whether this is a periodic profile refresh rather than an initial activation.
+    /// NGL sends the id of the profile it already has when it refreshes.
+    pub fn is_refresh(&self) -> bool {
+        !self.app_details.current_asnp_id.is_empty()
+    }
+
     pub fn deactivation_id(&self) -> String {
         let factors: Vec<&str> = vec![
             &self.npd_id,
@@ -57,7 +74,8 @@ impl FrlActivationRequestBody {
     }
 
     pub fn from_body(body: &str) -> Result<Self> {
-        serde_json::from_str(body).wrap_err("Invalid FRL activation body")
+        serde_json::from_str(body)
+            .map_err(|e| Error::json("Invalid FRL activation body", e))
     }
 
     pub fn to_body(&self) -> String {
@@ -187,7 +205,10 @@ impl FrlDeactivationQueryParams {
     }
 
     pub fn from_query(query: &str) -> Result<Self> {
-        serde_urlencoded::from_str(query).wrap_err("Invalid deactivation query")
+        serde_urlencoded::from_str(query).map_err(|source| Error::Query {
+            context: "Invalid deactivation query".to_string(),
+            source,
+        })
     }
 
     pub fn to_query(&self) -> String {
@@ -204,7 +225,8 @@ pub struct FrlActivationResponseBody {
 
 impl FrlActivationResponseBody {
     pub fn from_body(body: &str) -> Result<Self> {
-        serde_json::from_str(body).wrap_err("Invalid FRL activation data")
+        serde_json::from_str(body)
+            .map_err(|e| Error::json("Invalid FRL activation data", e))
     }
 
     pub fn to_body(&self) -> String {
@@ -322,7 +344,8 @@ pub struct FrlDeactivationResponseBody {
 
 impl FrlDeactivationResponseBody {
     pub fn from_body(body: &str) -> Result<Self> {
-        serde_json::from_str(body).wrap_err("Invalid FRL deactivation data")
+        serde_json::from_str(body)
+            .map_err(|e| Error::json("Invalid FRL deactivation data", e))
     }
 
     pub fn to_body(&self) -> String {
@@ -378,6 +401,17 @@ mod test {
         assert_eq!(request.app_details.ngl_app_id, "MockApp1");
     }
 
+    #[test]
+    fn test_refresh_activation_id() {
+        let mut body = super::FrlActivationRequestBody::mock_from_device_id("test-id");
+        assert!(!body.is_refresh());
+        assert_eq!(body.activation_id(), body.initial_activation_id());
+        body.app_details.current_asnp_id = "221bf...elided...c23ff".to_string();
+        assert!(body.is_refresh());
+        assert_ne!(body.activation_id(), body.initial_activation_id());
+        assert!(body.activation_id().starts_with(&body.initial_activation_id()));
+    }
+
     #[test]
     fn test_parse_valid_activation_request() {
         let body = super::FrlActivationRequestBody::valid_from_device_id("test-id");
@@ -483,4 +517,18 @@ mod test {
             serde_json::from_str(&mock.to_body()).unwrap();
         assert!(response.invalidation_successful);
     }
+
+    #[test]
+    fn test_parse_errors() {
+        use crate::Error;
+        let err = super::FrlActivationRequestBody::from_body("{").unwrap_err();
+        assert!(matches!(err, Error::Json { .. }), "Wrong error: {:?}", err);
+        let body = r#"{"customerCertSignedValues":{"values":"not base64!"}}"#;
+        let err = super::FrlActivationResponseBody::from_body(body).unwrap_err();
+        assert!(matches!(err, Error::Base64 { .. }), "Wrong error: {:?}", err);
+        let err = super::FrlDeactivationQueryParams::from_query("isVirtualEnvironment=x")
+            .unwrap_err();
+        assert!(matches!(err, Error::Query { .. }), "Wrong error: {:?}", err);
+        assert_eq!(err.to_string(), "Invalid deactivation query");
+    }
 }
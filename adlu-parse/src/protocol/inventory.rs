@@ -0,0 +1,140 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::admin::{Configuration, OcFileSpec};
+use crate::{Error, Result};
+
+/// A summary of the license packages installed on a machine, as
+/// uploaded by the decoder to the proxy's inventory endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InventoryReport {
+    pub hostname: String,
+    pub os_name: String,
+    pub decoder_version: String,
+    pub packages: Vec<InventoryPackage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InventoryPackage {
+    pub npd_id: String,
+    pub license_type: String,
+    pub expiry_date: String,
+    pub precedence: i32,
+    pub app_ids: Vec<String>,
+    #[serde(default)]
+    pub install_date: String,
+}
+
+impl InventoryReport {
+    /// Summarize a decoded configuration, one entry per package.
+    pub fn from_configuration(
+        config: &Configuration,
+        hostname: &str,
+        decoder_version: &str,
+    ) -> Self {
+        let ocs: Vec<&OcFileSpec> = match config {
+            Configuration::Packaged(pcs) => {
+                pcs.iter().flat_map(|pc| pc.operating_configs.iter()).collect()
+            }
+            Configuration::Installed(ocs) => ocs.iter().collect(),
+        };
+        let mut packages: BTreeMap<String, InventoryPackage> = BTreeMap::new();
+        for oc in ocs {
+            let package =
+                packages.entry(oc.npd_id()).or_insert_with(|| InventoryPackage {
+                    npd_id: oc.npd_id(),
+                    license_type: oc.activation_type().to_string(),
+                    expiry_date: oc.expiry_date(),
+                    precedence: oc.content.payload.npd_precedence,
+                    app_ids: vec![],
+                    install_date: "".to_string(),
+                });
+            package.app_ids.push(oc.app_id());
+            // the package was installed when its first app was
+            if let Some(date) = oc.install_date() {
+                if package.install_date.is_empty() || date < package.install_date {
+                    package.install_date = date;
+                }
+            }
+        }
+        for package in packages.values_mut() {
+            package.app_ids.sort();
+            package.app_ids.dedup();
+        }
+        Self {
+            hostname: hostname.to_string(),
+            os_name: std::env::consts::OS.to_string(),
+            decoder_version: decoder_version.to_string(),
+            packages: packages.into_values().collect(),
+        }
+    }
+
+    pub fn from_body(body: &str) -> Result<Self> {
+        serde_json::from_str(body).map_err(|e| Error::json("Invalid inventory report", e))
+    }
+
+    pub fn to_body(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn mock_from_hostname(hostname: &str, npd_id: &str) -> Self {
+        Self {
+            hostname: hostname.to_string(),
+            os_name: "macos".to_string(),
+            decoder_version: "2.0.0".to_string(),
+            packages: vec![InventoryPackage {
+                npd_id: npd_id.to_string(),
+                license_type: "FRL Online (server: https://lcs-cops.adobe.io)"
+                    .to_string(),
+                expiry_date: "2023-07-01".to_string(),
+                precedence: 80,
+                app_ids: vec!["MockApp1".to_string()],
+                install_date: "2022-08-01 10:00:00 +00:00".to_string(),
+            }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::Configuration;
+
+    #[test]
+    fn test_inventory_from_configuration() {
+        let dir = "../rsrc/OperatingConfigs";
+        let path = format!("{}/UGhvdG9zaG9wMXt9MjAxODA3MjAwNA-ODU0YjU5OGQtOTE1Ni00NDZiLWFlZDYtMGQ1ZGM2ZmVhZDBi-80.operatingconfig", dir);
+        let config = Configuration::from_path(path).expect("Can't read config");
+        let report =
+            super::InventoryReport::from_configuration(&config, "host1", "2.0.0");
+        assert_eq!(report.packages.len(), 1);
+        let package = &report.packages[0];
+        assert_eq!(package.app_ids, vec!["Photoshop1"]);
+        assert_eq!(package.precedence, 80);
+        assert!(package.license_type.starts_with("FRL Online"));
+        assert!(!package.install_date.is_empty());
+        let parse = super::InventoryReport::from_body(&report.to_body()).unwrap();
+        assert_eq!(parse.hostname, "host1");
+        assert_eq!(parse.packages[0].npd_id, package.npd_id);
+    }
+}
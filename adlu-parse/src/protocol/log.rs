@@ -18,7 +18,6 @@ released.  That license is reproduced here in the LICENSE-MIT file.
 */
 use std::collections::HashMap;
 
-use eyre::{eyre, Result};
 use lazy_static::lazy_static;
 use rand::Rng;
 use regex::bytes::Regex;
@@ -26,6 +25,7 @@ use serde::{Deserialize, Serialize};
 use warp::Reply;
 
 use crate::protocol::{Request, RequestType};
+use crate::{Error, Result};
 use adlu_base::Timestamp;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -59,74 +59,52 @@ impl LogSession {
             || self.user_id.is_some()
     }
 
+    /// Merge two fragments of the same session, such as the parts of a session
+    /// that were uploaded in different (possibly rotated) log files.  Fragments
+    /// can be merged in any order: the merge has the earliest start and the
+    /// latest end of the two, and takes its other details from the earlier
+    /// fragment where it has them.
     pub fn merge(&self, other: &LogSession) -> Result<Self> {
         if self.session_id != other.session_id {
-            Err(eyre!("Can't merge sessions with different IDs"))
-        } else {
-            Ok(LogSession {
-                source_addr: if self.source_addr == "unknown" {
-                    other.source_addr.clone()
-                } else {
-                    self.source_addr.clone()
-                },
-                session_id: self.session_id.clone(),
-                initial_entry: if self.initial_entry <= other.initial_entry {
-                    self.initial_entry.clone()
-                } else {
-                    other.initial_entry.clone()
-                },
-                final_entry: if self.final_entry >= other.final_entry {
-                    self.final_entry.clone()
-                } else {
-                    other.final_entry.clone()
-                },
-                session_start: if self.session_start.is_none() {
-                    other.session_start.clone()
-                } else {
-                    self.session_start.clone()
-                },
-                session_end: if self.session_end.is_none() {
-                    other.session_end.clone()
-                } else {
-                    self.session_end.clone()
-                },
-                app_id: if self.app_id.is_none() {
-                    other.app_id.clone()
-                } else {
-                    self.app_id.clone()
-                },
-                app_version: if self.app_version.is_none() {
-                    other.app_version.clone()
-                } else {
-                    self.app_version.clone()
-                },
-                app_locale: if self.app_locale.is_none() {
-                    other.app_locale.clone()
-                } else {
-                    self.app_locale.clone()
-                },
-                ngl_version: if self.ngl_version.is_none() {
-                    other.ngl_version.clone()
-                } else {
-                    self.ngl_version.clone()
-                },
-                os_name: if self.os_name.is_none() {
-                    other.os_name.clone()
-                } else {
-                    self.os_name.clone()
-                },
-                os_version: if self.os_version.is_none() {
-                    other.os_version.clone()
-                } else {
-                    self.os_version.clone()
-                },
-                user_id: if self.user_id.is_none() {
-                    other.user_id.clone()
-                } else {
-                    self.user_id.clone()
-                },
-            })
+            return Err(Error::SessionMismatch);
         }
+        let (first, second) = if self.initial_entry <= other.initial_entry {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let either = |f: fn(&LogSession) -> &Option<String>| {
+            f(first).clone().or_else(|| f(second).clone())
+        };
+        Ok(LogSession {
+            source_addr: if first.source_addr == "unknown" {
+                second.source_addr.clone()
+            } else {
+                first.source_addr.clone()
+            },
+            session_id: self.session_id.clone(),
+            initial_entry: first.initial_entry.clone(),
+            final_entry: if first.final_entry >= second.final_entry {
+                first.final_entry.clone()
+            } else {
+                second.final_entry.clone()
+            },
+            session_start: match (&first.session_start, &second.session_start) {
+                (Some(s1), Some(s2)) => Some(s1.min(s2).clone()),
+                (s1, s2) => s1.clone().or_else(|| s2.clone()),
+            },
+            session_end: match (&first.session_end, &second.session_end) {
+                (Some(e1), Some(e2)) => Some(e1.max(e2).clone()),
+                (e1, e2) => e1.clone().or_else(|| e2.clone()),
+            },
+            app_id: either(|s| &s.app_id),
+            app_version: either(|s| &s.app_version),
+            app_locale: either(|s| &s.app_locale),
+            ngl_version: either(|s| &s.ngl_version),
+            os_name: either(|s| &s.os_name),
+            os_version: either(|s| &s.os_version),
+            user_id: either(|s| &s.user_id),
+        })
     }
 
     pub fn mock_from_session_id(session_id: &str) -> Self {
@@ -222,13 +200,27 @@ impl LogSession {
     }
 }
 
+/// A warning or error line in a log session.  NGL doesn't mark the severity
+/// of most lines, so lines are classified by the words in their description
+/// unless they carry an explicit log level.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct LogEvent {
+    pub session_id: String,
+    pub timestamp: Timestamp,
+    pub level: String,
+    pub component: String,
+    pub workflow: String,
+    pub error_code: Option<String>,
+    pub message: String,
+}
+
 lazy_static! {
     static ref RE_MAP: HashMap<&'static str, Regex> = {
         let mut map = HashMap::new();
         map.insert(
             "line",
             Regex::new(
-                r#"(?m-u)^SessionID=([^ ]+) Timestamp=([^ ]+) .*Description="(.+)"\r?$"#,
+                r#"(?m-u)^SessionID=([^ ]+) Timestamp=([^ ]+) (.*)Description="(.+)"\r?$"#,
             )
             .unwrap(),
         );
@@ -255,6 +247,23 @@ lazy_static! {
             "user",
             Regex::new(r"(?-u)LogCurrentUser:.+UserID=([^\s,]+)").unwrap(),
         );
+        map.insert("level", Regex::new(r"(?-u)\b(?:Log)?Level=([A-Za-z]+)").unwrap());
+        map.insert("component", Regex::new(r"(?-u)\bComponent=([^ ]+)").unwrap());
+        map.insert(
+            "error",
+            Regex::new(r"(?i-u)\b(?:error|exception|unhandled)\b").unwrap(),
+        );
+        map.insert(
+            "warning",
+            Regex::new(r"(?i-u)\b(?:fail(?:s|ed|ure)?|warn(?:ing)?|unable|cannot)\b")
+                .unwrap(),
+        );
+        map.insert(
+            "code",
+            Regex::new(r"(?i-u)\b(?:status|error(?: ?code)?(?: is)?)[ =:]+(-?[0-9]+)")
+                .unwrap(),
+        );
+        map.insert("workflow", Regex::new(r"(?-u)^([A-Za-z][A-Za-z0-9_]*) ?:").unwrap());
         map
     };
 }
@@ -262,20 +271,37 @@ lazy_static! {
 impl Request {
     pub fn parse_log(&self) -> Result<Vec<LogSession>> {
         if !matches!(self.request_type, RequestType::LogUpload) {
-            return Err(eyre!("{} is not a log upload, please report a bug!", self));
+            return Err(Error::UnsupportedEndpoint {
+                request: self.to_string(),
+                expected: "log upload",
+            });
         }
-        let body = bytes::Bytes::from(
-            self.body
-                .clone()
-                .ok_or_else(|| eyre!("{} has no attached log data", self))?,
-        );
+        let body = bytes::Bytes::from(self.body.clone().ok_or_else(|| {
+            Error::Missing { request: self.to_string(), missing: "attached log data" }
+        })?);
         let source_addr =
             self.source_ip.map_or_else(|| "unknown".to_string(), |a| a.to_string());
         Ok(parse_log_data(&source_addr, &body))
     }
+
+    /// The warnings and errors in a log upload.
+    pub fn parse_log_events(&self) -> Result<Vec<LogEvent>> {
+        if !matches!(self.request_type, RequestType::LogUpload) {
+            return Err(Error::UnsupportedEndpoint {
+                request: self.to_string(),
+                expected: "log upload",
+            });
+        }
+        let body = bytes::Bytes::from(self.body.clone().ok_or_else(|| {
+            Error::Missing { request: self.to_string(), missing: "attached log data" }
+        })?);
+        Ok(parse_log_events(&body))
+    }
 }
 
-fn parse_log_data(source_addr: &str, body: &bytes::Bytes) -> Vec<LogSession> {
+/// The sessions in NGL log data, such as the body of a log upload or the
+/// content of a log file.  Fragments of the same session are stitched together.
+pub fn parse_log_data(source_addr: &str, body: &[u8]) -> Vec<LogSession> {
     let line_pattern = &RE_MAP["line"];
     let mut sessions: Vec<LogSession> = Vec::new();
     let mut session: LogSession = Default::default();
@@ -295,11 +321,64 @@ fn parse_log_data(source_addr: &str, body: &bytes::Bytes) -> Vec<LogSession> {
                 ..Default::default()
             }
         }
-        parse_log_description(&mut session, &timestamp, &cap[3]);
+        parse_log_description(&mut session, &timestamp, &cap[4]);
     }
     if !session.session_id.is_empty() {
         sessions.push(session.clone())
     }
+    stitch_sessions(sessions)
+}
+
+/// The warnings and errors in NGL log data.
+pub fn parse_log_events(body: &[u8]) -> Vec<LogEvent> {
+    let as_string = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+    let mut events = Vec::new();
+    for cap in RE_MAP["line"].captures_iter(body) {
+        let (fields, description) = (&cap[3], &cap[4]);
+        let level = match RE_MAP["level"].captures(fields) {
+            Some(level) => as_string(&level[1]).to_ascii_uppercase(),
+            None if RE_MAP["error"].is_match(description) => "ERROR".to_string(),
+            None if RE_MAP["warning"].is_match(description) => "WARN".to_string(),
+            None => continue,
+        };
+        let level = match level.as_str() {
+            "ERR" | "FATAL" | "CRITICAL" => "ERROR".to_string(),
+            "WARNING" => "WARN".to_string(),
+            "ERROR" | "WARN" => level,
+            _ => continue,
+        };
+        let component = RE_MAP["component"]
+            .captures(fields)
+            .map(|c| as_string(&c[1]))
+            .unwrap_or_default();
+        let workflow = match RE_MAP["workflow"].captures(description) {
+            Some(c) => as_string(&c[1]),
+            None => component.trim_start_matches("ngl-lib_").to_string(),
+        };
+        events.push(LogEvent {
+            session_id: as_string(&cap[1]),
+            timestamp: Timestamp::from_log(&as_string(&cap[2])),
+            level,
+            component,
+            workflow,
+            error_code: RE_MAP["code"].captures(description).map(|c| as_string(&c[1])),
+            message: as_string(description),
+        });
+    }
+    events
+}
+
+/// Apps that run concurrently write interleaved lines to the same log, so one
+/// upload can contain several fragments of a session.  Stitch them together,
+/// keeping the sessions in the order they first appear.
+fn stitch_sessions(fragments: Vec<LogSession>) -> Vec<LogSession> {
+    let mut sessions: Vec<LogSession> = Vec::with_capacity(fragments.len());
+    for fragment in fragments {
+        match sessions.iter_mut().find(|s| s.session_id == fragment.session_id) {
+            Some(session) => *session = session.merge(&fragment).unwrap(),
+            None => sessions.push(fragment),
+        }
+    }
     sessions
 }
 
@@ -383,8 +462,8 @@ mod test {
         assert_eq!(sessions.len(), 1);
         let session = &sessions[0];
         let session_id = "4f7c3960-48da-49bb-9359-e0f040ecae66.1660326622129";
-        let start = Timestamp::from_db("2022-08-12T10:50:22:129-0700");
-        let end = Timestamp::from_db("2022-08-12T10:50:53:807-0700");
+        let start = Timestamp::from_db_string("2022-08-12T10:50:22:129-0700");
+        let end = Timestamp::from_db_string("2022-08-12T10:50:53:807-0700");
         assert_eq!(session.session_id, session_id);
         assert_eq!(session.initial_entry, start);
         assert_eq!(session.session_start.as_ref().unwrap(), &session.initial_entry);
@@ -408,8 +487,8 @@ mod test {
         assert_eq!(sessions.len(), 1);
         let session = &sessions[0];
         let session_id = "e6ab2d44-5909-4838-a79f-5091f5736073.1659806990834";
-        let start = Timestamp::from_db("2022-08-08T09:25:33:720-0700");
-        let end = Timestamp::from_db("2022-08-08T09:25:33:720-0700");
+        let start = Timestamp::from_db_string("2022-08-08T09:25:33:720-0700");
+        let end = Timestamp::from_db_string("2022-08-08T09:25:33:720-0700");
         assert_eq!(session.session_id, session_id);
         assert_eq!(session.initial_entry, start);
         assert!(session.session_start.is_none());
@@ -430,8 +509,8 @@ mod test {
         assert_eq!(sessions.len(), 1);
         let session = &sessions[0];
         let session_id = "bc532766-d56c-43fe-aaba-eb5f4323a53c.1660495166236";
-        let start = Timestamp::from_db("2022-08-14T09:39:26:236-0700");
-        let end = Timestamp::from_db("2022-08-14T09:39:45:536-0700");
+        let start = Timestamp::from_db_string("2022-08-14T09:39:26:236-0700");
+        let end = Timestamp::from_db_string("2022-08-14T09:39:45:536-0700");
         assert_eq!(session.session_id, session_id);
         assert_eq!(session.initial_entry, start);
         assert_eq!(session.session_start.as_ref().unwrap(), &session.initial_entry);
@@ -501,4 +580,73 @@ mod test {
         result = result.merge(&next).expect("Couldn't merge");
         assert!(result.session_end.is_some(), "No session end in merge of last!");
     }
+
+    #[test]
+    fn test_merge_out_of_order() {
+        fn path(s: &str) -> String {
+            format!("../rsrc/logs/mac/NGLClient_AcrobatDC122.1.20169.7{}.log.bin", s)
+        }
+        let mut sessions: Vec<LogSession> = vec![];
+        for date in [" 2022-08-06 15-59-29-579", " 2022-08-07 06-16-59-994", ""] {
+            let data = bytes::Bytes::from(read_to_string(path(date)).unwrap());
+            sessions.append(&mut super::parse_log_data("unknown", &data));
+        }
+        let forward =
+            sessions[0].merge(&sessions[1]).unwrap().merge(&sessions[2]).unwrap();
+        let backward =
+            sessions[2].merge(&sessions[1]).unwrap().merge(&sessions[0]).unwrap();
+        for merged in [&forward, &backward] {
+            assert_eq!(merged.initial_entry, sessions[0].initial_entry);
+            assert_eq!(merged.final_entry, sessions[2].final_entry);
+            assert_eq!(merged.session_start, sessions[0].session_start);
+            assert_eq!(merged.session_end, sessions[2].session_end);
+            assert_eq!(merged.app_id, sessions[0].app_id);
+        }
+    }
+
+    #[test]
+    fn test_parse_log_events() {
+        let path =
+            "../rsrc/logs/mac/NGLClient_AcrobatDC122.1.20169.7 2022-08-07 22-55-02-818.log.bin";
+        let data = bytes::Bytes::from(read_to_string(path).unwrap());
+        let events = super::parse_log_events(&data);
+        assert_eq!(events.len(), 13);
+        assert!(events.iter().all(|e| e.level == "ERROR" || e.level == "WARN"));
+        let post = events
+            .iter()
+            .find(|e| e.message.starts_with("PostLogFile: Failed"))
+            .expect("No log post failure found");
+        assert_eq!(post.level, "WARN");
+        assert_eq!(post.workflow, "PostLogFile");
+        assert_eq!(post.error_code.as_deref(), Some("401"));
+        let line = "SessionID=s1 Timestamp=2022-08-12T10:50:22:129-0700 \
+            LogLevel=Error Component=ngl-lib_Mock Description=\"Something odd\"\n\
+            SessionID=s1 Timestamp=2022-08-12T10:50:22:130-0700 \
+            Component=ngl-lib_Mock Description=\"All is well\"";
+        let events = super::parse_log_events(&bytes::Bytes::from(line));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, "ERROR");
+        assert_eq!(events[0].workflow, "Mock");
+        assert!(events[0].error_code.is_none());
+        let mock = LogSession::mock_from_session_id("quiet").to_body();
+        assert!(super::parse_log_events(&bytes::Bytes::from(mock)).is_empty());
+    }
+
+    #[test]
+    fn test_stitch_interleaved_sessions() {
+        let first = LogSession::mock_from_session_id("stitch-1").to_body();
+        let second = LogSession::mock_from_session_id("stitch-2").to_body();
+        let (first, second): (Vec<&str>, Vec<&str>) =
+            (first.lines().collect(), second.lines().collect());
+        let (mid1, mid2) = (first.len() / 2, second.len() / 2);
+        let body = [&first[..mid1], &second[..mid2], &first[mid1..], &second[mid2..]]
+            .concat()
+            .join("\n");
+        let sessions = super::parse_log_data("unknown", &bytes::Bytes::from(body));
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].session_id, "stitch-1");
+        assert!(sessions[0].session_start.is_some());
+        assert!(sessions[0].session_end.is_some());
+        assert_eq!(sessions[1].session_id, "stitch-2");
+    }
 }
@@ -20,14 +20,20 @@ pub use frl::{
     FrlActivationRequestBody, FrlActivationResponseBody, FrlAppDetails,
     FrlDeactivationQueryParams, FrlDeactivationResponseBody, FrlDeviceDetails,
 };
-pub use log::{LogSession, LogUploadResponse};
+pub use inventory::{InventoryPackage, InventoryReport};
+pub use log::{
+    parse_log_data, parse_log_events, LogEvent, LogSession, LogUploadResponse,
+};
 pub use named_user::{
     LicenseSession, NulAppDetails, NulDeviceDetails, NulLicenseRequestBody,
     NulLicenseResponseBody,
 };
-pub use request::{Request, RequestType};
+pub use request::{peer_addr, ProxiedPeer, Request, RequestType};
+pub use validate::FieldProblem;
 
 mod frl;
+mod inventory;
 mod log;
 mod named_user;
 mod request;
+mod validate;
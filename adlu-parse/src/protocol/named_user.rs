@@ -16,13 +16,12 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
-use eyre::{eyre, Result, WrapErr};
 use serde::{Deserialize, Serialize};
 
 use adlu_base::Timestamp;
 
 use crate::protocol::{Request, RequestType};
-use crate::{AdobeSignatures, CustomerSignatures};
+use crate::{AdobeSignatures, CustomerSignatures, Error, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -35,7 +34,7 @@ pub struct NulLicenseRequestBody {
 
 impl NulLicenseRequestBody {
     pub fn from_body(body: &str) -> Result<Self> {
-        serde_json::from_str(body).wrap_err("Invalid license data")
+        serde_json::from_str(body).map_err(|e| Error::json("Invalid license data", e))
     }
 
     pub fn to_body(&self) -> String {
@@ -170,30 +169,63 @@ pub struct LicenseSession {
     pub os_version: String,
     pub device_name: String,
     pub user_id: String,
+    /// The user the session is licensed to: the Adobe user in the request's
+    /// access token, if it can be read, else the OS user.  Sessions are keyed
+    /// on this as well as their id, so users sharing a machine stay apart.
+    pub user_key: String,
 }
 
 impl Request {
     pub fn parse_license(&self) -> Result<LicenseSession> {
         if !matches!(self.request_type, RequestType::NulLicense) {
-            return Err(eyre!("{} is not a license request; please report a bug", self));
+            return Err(Error::UnsupportedEndpoint {
+                request: self.to_string(),
+                expected: "license request",
+            });
         }
         let source_addr =
             self.source_ip.map_or_else(|| "unknown".to_string(), |a| a.to_string());
-        let session_id = self
-            .session_id
-            .as_ref()
-            .ok_or_else(|| eyre!("{} has no session id", self))?;
-        let body =
-            self.body.as_ref().ok_or_else(|| eyre!("{} has no license data", self))?;
-        let parse = NulLicenseRequestBody::from_body(body).wrap_err(self.to_string())?;
-        Ok(LicenseSession::from_parts(&self.timestamp, &source_addr, session_id, &parse))
+        let session_id = self.session_id.as_ref().ok_or_else(|| Error::Missing {
+            request: self.to_string(),
+            missing: "session id",
+        })?;
+        let body = self.body.as_ref().ok_or_else(|| Error::Missing {
+            request: self.to_string(),
+            missing: "license data",
+        })?;
+        let parse = NulLicenseRequestBody::from_body(body).map_err(|err| match err {
+            Error::Json { source, .. } => {
+                Error::Json { context: self.to_string(), source }
+            }
+            Error::Base64 { message, .. } => {
+                Error::Base64 { context: self.to_string(), message }
+            }
+            err => err,
+        })?;
+        let user_key = self
+            .authorization
+            .as_deref()
+            .and_then(token_user_id)
+            .unwrap_or_else(|| parse.device_details.os_user_id.clone());
+        let mut session =
+            LicenseSession::from_parts(&self.timestamp, &source_addr, session_id, &parse);
+        session.user_key = user_key;
+        Ok(session)
     }
 }
 
+/// The Adobe user id in an IMS access token, which is a JWT whose claims
+/// include the user id.  The token is not verified; that's Adobe's job.
+fn token_user_id(authorization: &str) -> Option<String> {
+    let token = authorization.strip_prefix("Bearer ").unwrap_or(authorization);
+    let claims = adlu_base::json_from_base64(token.trim().split('.').nth(1)?).ok()?;
+    claims.get("user_id")?.as_str().map(|id| id.to_string())
+}
+
 impl LicenseSession {
     pub fn merge(&self, other: LicenseSession) -> Result<Self> {
-        if self.session_id != other.session_id {
-            Err(eyre!("Can't merge sessions with different IDs"))
+        if self.session_id != other.session_id || self.user_key != other.user_key {
+            Err(Error::SessionMismatch)
         } else {
             let mut result = self.clone();
             result.session_end = other.session_end;
@@ -225,6 +257,7 @@ impl LicenseSession {
             os_version: body.device_details.os_version.clone(),
             device_name: body.device_details.device_name.clone(),
             user_id: body.device_details.os_user_id.clone(),
+            user_key: body.device_details.os_user_id.clone(),
         }
     }
 }
@@ -238,7 +271,7 @@ pub struct NulLicenseResponseBody {
 
 impl NulLicenseResponseBody {
     pub fn from_body(body: &str) -> Result<Self> {
-        serde_json::from_str(body).wrap_err("Invalid NUL license data")
+        serde_json::from_str(body).map_err(|e| Error::json("Invalid NUL license data", e))
     }
 
     pub fn to_body(&self) -> String {
@@ -392,6 +425,33 @@ mod test {
         assert!(!request.device_details.is_os_user_account_in_domain);
     }
 
+    #[tokio::test]
+    async fn test_license_session_user_key() {
+        let body = super::NulLicenseRequestBody::mock_from_device_id("test-id");
+        let claims = r#"{"user_id":"ABC123@AdobeID","type":"access_token"}"#;
+        let token =
+            format!("eyJhbGciOiJSUzI1NiJ9.{}.sig", adlu_base::u64encode(claims).unwrap());
+        let filter = crate::protocol::Request::nul_license_filter(32_000);
+        let mut req = warp::test::request()
+            .method("POST")
+            .path("/asnp/nud/v4")
+            .header("Content-Type", "application/json")
+            .header("X-Api-Key", "ngl_photoshop1")
+            .header("X-Request-Id", "request1")
+            .header("X-Session-Id", "session1")
+            .header("Authorization", "Bearer not-a-jwt")
+            .body(body.to_body())
+            .filter(&filter)
+            .await
+            .expect("License request was rejected");
+        let session = req.parse_license().unwrap();
+        assert_eq!(session.user_key, body.device_details.os_user_id);
+        req.authorization = Some(format!("Bearer {}", token));
+        let other = req.parse_license().unwrap();
+        assert_eq!(other.user_key, "ABC123@AdobeID");
+        assert!(session.merge(other).is_err(), "Merged sessions of different users");
+    }
+
     #[test]
     fn test_parse_mock_activation_request() {
         let body = super::NulLicenseRequestBody::mock_from_device_id("test-id");
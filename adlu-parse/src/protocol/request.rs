@@ -16,6 +16,7 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
+use rand::Rng;
 use warp::{filters::BoxedFilter, Filter, Rejection};
 
 use adlu_base::Timestamp;
@@ -47,6 +48,8 @@ pub struct Request {
     pub request_type: RequestType,
     pub source_ip: Option<std::net::IpAddr>,
     pub method: http::method::Method,
+    /// The host the client addressed, from its `Host` header (or request URI).
+    pub host: Option<String>,
     pub path: String,
     pub query: Option<String>,
     pub body: Option<String>,
@@ -59,11 +62,18 @@ pub struct Request {
     pub request_id: Option<String>,
     pub session_id: Option<String>,
     pub authorization: Option<String>,
+    pub correlation_id: String,
 }
 
 impl std::fmt::Display for Request {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} request {}", self.request_type, self.with_id())
+        write!(
+            f,
+            "{} request {} [{}]",
+            self.request_type,
+            self.with_id(),
+            self.correlation_id
+        )
     }
 }
 
@@ -128,6 +138,16 @@ impl Request {
             .and(Self::request_boxed_filter(RequestType::LogUpload, body_limit))
     }
 
+    /// Log uploads from custom scripts, which authorize themselves with a
+    /// bearer token (checked by the caller) rather than an Adobe api key.
+    pub fn script_log_upload_boxed_filter(body_limit: u64) -> BoxedFilter<(Self,)> {
+        warp::post()
+            .and(warp::path!("ulecs" / "v1"))
+            .and(required_header("Authorization"))
+            .and(Self::request_boxed_filter(RequestType::LogUpload, body_limit))
+            .boxed()
+    }
+
     pub fn unknown_boxed_filter(body_limit: u64) -> BoxedFilter<(Self,)> {
         Self::unknown_filter(body_limit).boxed()
     }
@@ -151,6 +171,7 @@ impl Request {
     ) -> impl Filter<Extract = (Self,), Error = Rejection> + Clone {
         proxied_remote_addr()
             .and(warp::method())
+            .and(warp::host::optional())
             .and(warp::path::full())
             .and(optional_raw_query())
             .and(warp::filters::header::optional::<String>("Content-Type"))
@@ -166,6 +187,7 @@ impl Request {
             .map(
                 move |source_ip,
                       method,
+                      host: Option<http::uri::Authority>,
                       path: warp::path::FullPath,
                       query,
                       content_type,
@@ -183,6 +205,7 @@ impl Request {
                         request_type: request_type.clone(),
                         source_ip,
                         method,
+                        host: host.map(|h| h.to_string()),
                         path: path.as_str().to_string(),
                         query,
                         content_type,
@@ -195,11 +218,36 @@ impl Request {
                         session_id,
                         authorization,
                         body,
+                        correlation_id: Self::new_correlation_id(),
                     }
                 },
             )
     }
 
+    /// A new id for correlating an inbound request with the log lines,
+    /// cache entries, and replies that it produces.  These ids sort by
+    /// arrival time, and are short enough to be read over the phone.
+    pub fn new_correlation_id() -> String {
+        let millis = Timestamp::now().millis;
+        let suffix: u32 = rand::thread_rng().gen_range(0..0x1000000);
+        format!("{:x}-{:06x}", millis, suffix)
+    }
+
+    /// The version of the NGL client library that sent this request, as
+    /// reported in its `User-Agent` header (which looks like
+    /// `NGL Client/1.30.0.1 (MAC/12.4.0) [...]`).  Requests from other
+    /// agents, such as the apps' own log uploads, have no client version.
+    pub fn ngl_client_version(&self) -> Option<String> {
+        let agent = self.user_agent.as_deref()?;
+        let rest = agent.trim_start().strip_prefix("NGL Client/")?;
+        let version = rest.split_whitespace().next().unwrap_or_default();
+        if version.is_empty() || version.starts_with('(') {
+            None
+        } else {
+            Some(version.to_string())
+        }
+    }
+
     pub fn with_id(&self) -> String {
         if let Some(request_id) = &self.request_id {
             format!("with X-Request-Id: {}", request_id)
@@ -236,7 +284,7 @@ fn proxied_remote_addr(
         .or_else(|_| async {
             Ok::<(Option<std::net::IpAddr>,), std::convert::Infallible>((None,))
         })
-        .and(warp::filters::addr::remote())
+        .and(peer_addr())
         .map(
             |i: Option<std::net::IpAddr>,
              s: Option<std::net::SocketAddr>|
@@ -246,6 +294,25 @@ fn proxied_remote_addr(
         )
 }
 
+/// The address of a client, as seen by the server that accepted its
+/// connection.  Behind a load balancer that uses the PROXY protocol, every
+/// connection comes from the load balancer, so this is the client address
+/// the load balancer reported.  The server attaches it to each request it reads.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxiedPeer(pub std::net::SocketAddr);
+
+/// The address of the client at the other end of the connection: the one
+/// reported by the PROXY protocol, if there was one, else the peer address.
+pub fn peer_addr(
+) -> impl Filter<Extract = (Option<std::net::SocketAddr>,), Error = std::convert::Infallible>
+       + Clone {
+    warp::filters::ext::optional::<ProxiedPeer>().and(warp::filters::addr::remote()).map(
+        |peer: Option<ProxiedPeer>, remote: Option<std::net::SocketAddr>| {
+            peer.map(|peer| peer.0).or(remote)
+        },
+    )
+}
+
 fn optional_raw_query(
 ) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
     warp::query::raw()
@@ -304,6 +371,44 @@ mod tests {
             .expect("Req with no content-type was rejected");
     }
 
+    #[tokio::test]
+    async fn protocol_proxied_peer_address() {
+        let filter = super::Request::unknown_filter(32_000);
+        let peer = "192.0.2.7:5555".parse::<std::net::SocketAddr>().unwrap();
+        let req = warp::test::request()
+            .remote_addr("127.0.0.1:18040".parse::<std::net::SocketAddr>().unwrap())
+            .extension(super::ProxiedPeer(peer))
+            .method("GET")
+            .path("/")
+            .filter(&filter)
+            .await
+            .expect("Request with proxied peer was rejected");
+        assert_eq!(req.source_ip, Some(peer.ip()));
+    }
+
+    #[tokio::test]
+    async fn protocol_ngl_client_version() {
+        let filter = super::Request::unknown_filter(32_000);
+        let agents = [
+            (
+                "NGL Client/1.30.0.1 (MAC/12.4.0) [2022-06-28T17:08:01.895-0700]",
+                Some("1.30.0.1"),
+            ),
+            ("NGL Client/ (MAC/10.16.0) [2022-06-28T17:08:01.895-0700]", None),
+            ("Illustrator/26.4.1 CFNetwork/1335.0.3 Darwin/21.6.0", None),
+        ];
+        for (agent, version) in agents {
+            let req = warp::test::request()
+                .method("GET")
+                .path("/")
+                .header("User-Agent", agent)
+                .filter(&filter)
+                .await
+                .expect("Request with user agent was rejected");
+            assert_eq!(req.ngl_client_version().as_deref(), version, "{}", agent);
+        }
+    }
+
     #[tokio::test]
     async fn protocol_missing_content_length_accept() {
         let filter = super::Request::unknown_filter(32_000);
@@ -0,0 +1,391 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::protocol::{Request, RequestType};
+
+/// A field of a client request that is missing or has the wrong type.
+/// Fields inside of objects are named by their dotted path, e.g.,
+/// `deviceDetails.osUserId`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldProblem {
+    pub field: String,
+    pub problem: String,
+}
+
+impl FieldProblem {
+    fn new(field: &str, problem: &str) -> Self {
+        FieldProblem { field: field.to_string(), problem: problem.to_string() }
+    }
+}
+
+impl std::fmt::Display for FieldProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.problem)
+    }
+}
+
+impl Request {
+    /// Check the body (or, for deactivations, the query) of an FRL or NUL
+    /// request against the fields that Adobe requires, returning every
+    /// field that is missing or invalid.  Other requests are not checked.
+    pub fn validate_body(&self) -> Vec<FieldProblem> {
+        match self.request_type {
+            RequestType::FrlActivation => validate_json(&self.body, &FRL_ACTIVATION),
+            RequestType::NulLicense => validate_json(&self.body, &NUL_LICENSE),
+            RequestType::FrlDeactivation => validate_query(&self.query),
+            _ => vec![],
+        }
+    }
+
+    /// Rewrite the body of an FRL activation or NUL license request into the
+    /// form that Adobe documents, to tolerate minor drift in the protocol.
+    /// Fields whose names differ only in case or by underscores and hyphens
+    /// (e.g., `deviceID` or `device_id` for `deviceId`) are renamed, and
+    /// values of the wrong type are converted where that's unambiguous
+    /// (e.g., `"true"` or `1` for `true`).  Unknown fields are kept as is.
+    /// Returns `None` if the body isn't a JSON object or needs no rewriting.
+    pub fn lenient_body(&self) -> Option<String> {
+        let fields: &[Field] = match self.request_type {
+            RequestType::FrlActivation => &FRL_ACTIVATION,
+            RequestType::NulLicense => &NUL_LICENSE,
+            _ => return None,
+        };
+        lenient_json(self.body.as_deref()?, fields)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    String,
+    Bool,
+    Integer,
+    Object(&'static [Field]),
+}
+
+impl Kind {
+    fn describe(&self) -> &'static str {
+        match self {
+            Kind::String => "must be a string",
+            Kind::Bool => "must be true or false",
+            Kind::Integer => "must be an integer",
+            Kind::Object(_) => "must be an object",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn required(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: true }
+}
+
+const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: false }
+}
+
+const FRL_ACTIVATION: [Field; 5] = [
+    required(
+        "appDetails",
+        Kind::Object(&[
+            optional("currentAsnpId", Kind::String),
+            required("nglAppId", Kind::String),
+            required("nglAppVersion", Kind::String),
+            required("nglLibVersion", Kind::String),
+        ]),
+    ),
+    required("asnpTemplateId", Kind::String),
+    required(
+        "deviceDetails",
+        Kind::Object(&[
+            required("currentDate", Kind::String),
+            required("deviceId", Kind::String),
+            required("enableVdiMarkerExists", Kind::Bool),
+            required("isOsUserAccountInDomain", Kind::Bool),
+            required("isVirtualEnvironment", Kind::Bool),
+            required("osName", Kind::String),
+            required("osUserId", Kind::String),
+            required("osVersion", Kind::String),
+        ]),
+    ),
+    required("npdId", Kind::String),
+    optional("npdPrecedence", Kind::Integer),
+];
+
+const NUL_LICENSE: [Field; 3] = [
+    required(
+        "appDetails",
+        Kind::Object(&[
+            optional("appNameForLocale", Kind::String),
+            optional("appVersionForLocale", Kind::String),
+            optional("currentAsnpId", Kind::String),
+            optional("eTag", Kind::String),
+            required("locale", Kind::String),
+            required("nglAppId", Kind::String),
+            optional("nglAppLaunchState", Kind::String),
+            optional("nglAppProfileScope", Kind::String),
+            required("nglAppVersion", Kind::String),
+            optional("nglLibRuntimeMode", Kind::String),
+            required("nglLibVersion", Kind::String),
+        ]),
+    ),
+    required(
+        "deviceDetails",
+        Kind::Object(&[
+            required("currentDate", Kind::String),
+            optional("currentTimestamp", Kind::Integer),
+            required("deviceId", Kind::String),
+            required("deviceName", Kind::String),
+            optional("embeddedBrowserVersion", Kind::String),
+            optional("enableVdiMarkerExists", Kind::Bool),
+            optional("isOsUserAccountInDomain", Kind::Bool),
+            optional("isVirtualEnvironment", Kind::Bool),
+            required("osName", Kind::String),
+            required("osUserId", Kind::String),
+            required("osVersion", Kind::String),
+        ]),
+    ),
+    optional("deviceTokenHash", Kind::String),
+];
+
+const FRL_DEACTIVATION: [Field; 6] = [
+    required("npdId", Kind::String),
+    required("deviceId", Kind::String),
+    required("osUserId", Kind::String),
+    required("enableVdiMarkerExists", Kind::Integer),
+    required("isVirtualEnvironment", Kind::Integer),
+    required("isOsUserAccountInDomain", Kind::Integer),
+];
+
+fn validate_json(body: &Option<String>, fields: &[Field]) -> Vec<FieldProblem> {
+    let body = match body.as_deref() {
+        Some(body) if !body.trim().is_empty() => body,
+        _ => return vec![FieldProblem::new("(body)", "is missing")],
+    };
+    match serde_json::from_str::<Value>(body) {
+        Ok(val @ Value::Object(_)) => {
+            let mut problems = vec![];
+            validate_object("", &val, fields, &mut problems);
+            problems
+        }
+        Ok(_) => vec![FieldProblem::new("(body)", "must be a JSON object")],
+        Err(err) => vec![FieldProblem::new("(body)", &format!("is not JSON: {}", err))],
+    }
+}
+
+fn validate_object(
+    prefix: &str,
+    val: &Value,
+    fields: &[Field],
+    problems: &mut Vec<FieldProblem>,
+) {
+    for field in fields {
+        let path = format!("{}{}", prefix, field.name);
+        let ok = match (val.get(field.name), field.kind) {
+            (None, _) => {
+                if field.required {
+                    problems.push(FieldProblem::new(&path, "is missing"));
+                }
+                true
+            }
+            (Some(val @ Value::Object(_)), Kind::Object(fields)) => {
+                validate_object(&format!("{}.", path), val, fields, problems);
+                true
+            }
+            (Some(val), Kind::String) => val.is_string(),
+            (Some(val), Kind::Bool) => val.is_boolean(),
+            (Some(val), Kind::Integer) => val.is_i64(),
+            (Some(_), Kind::Object(_)) => false,
+        };
+        if !ok {
+            problems.push(FieldProblem::new(&path, field.kind.describe()));
+        }
+    }
+}
+
+fn lenient_json(body: &str, fields: &[Field]) -> Option<String> {
+    let mut val: Value = serde_json::from_str(body).ok()?;
+    if normalize_object(val.as_object_mut()?, fields) {
+        Some(val.to_string())
+    } else {
+        None
+    }
+}
+
+/// Rename and convert the fields of an object as described in
+/// [`Request::lenient_body`], returning whether anything changed.
+fn normalize_object(map: &mut Map<String, Value>, fields: &[Field]) -> bool {
+    let fold = |name: &str| name.replace(['_', '-'], "").to_ascii_lowercase();
+    let mut changed = false;
+    for field in fields {
+        if !map.contains_key(field.name) {
+            let key = map.keys().find(|key| fold(key) == fold(field.name)).cloned();
+            if let Some(val) = key.and_then(|key| map.remove(&key)) {
+                map.insert(field.name.to_string(), val);
+                changed = true;
+            }
+        }
+        let val = match map.get_mut(field.name) {
+            Some(val) => val,
+            None => continue,
+        };
+        let converted = match (field.kind, &*val) {
+            (Kind::Object(fields), _) => {
+                if let Some(map) = val.as_object_mut() {
+                    changed |= normalize_object(map, fields);
+                }
+                None
+            }
+            (Kind::Bool, Value::String(s)) => match s.to_ascii_lowercase().as_str() {
+                "true" | "1" => Some(Value::Bool(true)),
+                "false" | "0" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (Kind::Bool, Value::Number(n)) => match n.as_i64() {
+                Some(1) => Some(Value::Bool(true)),
+                Some(0) => Some(Value::Bool(false)),
+                _ => None,
+            },
+            (Kind::Integer, Value::String(s)) => {
+                s.trim().parse::<i64>().ok().map(Value::from)
+            }
+            (Kind::String, Value::Number(n)) => Some(Value::String(n.to_string())),
+            _ => None,
+        };
+        if let Some(converted) = converted {
+            *val = converted;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn validate_query(query: &Option<String>) -> Vec<FieldProblem> {
+    let query = query.as_deref().unwrap_or_default();
+    let pairs: Vec<(String, String)> = match serde_urlencoded::from_str(query) {
+        Ok(pairs) => pairs,
+        Err(err) => {
+            return vec![FieldProblem::new("(query)", &format!("is malformed: {}", err))]
+        }
+    };
+    let mut problems = vec![];
+    for field in FRL_DEACTIVATION.iter() {
+        match pairs.iter().find(|(name, _)| name == field.name) {
+            None => problems.push(FieldProblem::new(field.name, "is missing")),
+            Some((_, val)) if matches!(field.kind, Kind::Integer) => {
+                if val.parse::<i8>().is_err() {
+                    problems.push(FieldProblem::new(field.name, field.kind.describe()));
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        lenient_json, validate_json, validate_query, FRL_ACTIVATION, NUL_LICENSE,
+    };
+    use crate::protocol::{
+        FrlActivationRequestBody, FrlDeactivationQueryParams, NulLicenseRequestBody,
+    };
+
+    #[test]
+    fn test_valid_mock_bodies() {
+        let body = FrlActivationRequestBody::mock_from_device_id("id").to_body();
+        assert!(validate_json(&Some(body), &FRL_ACTIVATION).is_empty());
+        let body = NulLicenseRequestBody::mock_from_device_id("id").to_body();
+        assert!(validate_json(&Some(body), &NUL_LICENSE).is_empty());
+        let query = FrlDeactivationQueryParams::mock_from_device_id("id").to_query();
+        assert!(validate_query(&Some(query)).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_activation_body() {
+        let body = FrlActivationRequestBody::mock_from_device_id("id");
+        let mut val = serde_json::to_value(body).unwrap();
+        val["deviceDetails"].as_object_mut().unwrap().remove("osUserId");
+        val["deviceDetails"]["isVirtualEnvironment"] = serde_json::json!("no");
+        val.as_object_mut().unwrap().remove("npdId");
+        let problems: Vec<String> =
+            validate_json(&Some(val.to_string()), &FRL_ACTIVATION)
+                .iter()
+                .map(|p| p.to_string())
+                .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "deviceDetails.isVirtualEnvironment: must be true or false",
+                "deviceDetails.osUserId: is missing",
+                "npdId: is missing",
+            ]
+        );
+        let problems = validate_json(&Some("not json".to_string()), &FRL_ACTIVATION);
+        assert_eq!(problems[0].field, "(body)");
+        let problems = validate_json(&None, &FRL_ACTIVATION);
+        assert_eq!(problems[0].problem, "is missing");
+    }
+
+    #[test]
+    fn test_invalid_deactivation_query() {
+        let query = "npdId=abc&deviceId=def&enableVdiMarkerExists=yes".to_string();
+        let problems: Vec<String> =
+            validate_query(&Some(query)).iter().map(|p| p.field.clone()).collect();
+        assert_eq!(
+            problems,
+            vec![
+                "osUserId",
+                "enableVdiMarkerExists",
+                "isVirtualEnvironment",
+                "isOsUserAccountInDomain"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lenient_activation_body() {
+        let body = FrlActivationRequestBody::mock_from_device_id("id");
+        let expected = serde_json::to_value(&body).unwrap();
+        assert!(lenient_json(&body.to_body(), &FRL_ACTIVATION).is_none());
+        let mut val = expected.clone();
+        let details = val["deviceDetails"].as_object_mut().unwrap();
+        let device_id = details.remove("deviceId").unwrap();
+        details.insert("deviceID".to_string(), device_id);
+        details.insert("is_virtual_environment".to_string(), serde_json::json!(0));
+        details.remove("isVirtualEnvironment");
+        details.insert("enableVdiMarkerExists".to_string(), serde_json::json!("false"));
+        val["npdPrecedence"] = serde_json::json!("80");
+        val["extraField"] = serde_json::json!("kept");
+        let body = lenient_json(&val.to_string(), &FRL_ACTIVATION).unwrap();
+        assert!(validate_json(&Some(body.clone()), &FRL_ACTIVATION).is_empty());
+        let mut val: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(val["extraField"], "kept");
+        val.as_object_mut().unwrap().remove("extraField");
+        assert_eq!(val, expected);
+        assert!(FrlActivationRequestBody::from_body(&body).is_ok());
+    }
+}
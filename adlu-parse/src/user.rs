@@ -60,9 +60,11 @@ pub struct CachedOnlineAsnpPayload {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LegacyProfile {
-    license_id: String,
-    license_type: i32,
-    effective_end_timestamp: i64,
+    pub license_id: String,
+    pub license_type: i32,
+    pub effective_end_timestamp: i64,
+    #[serde(default)]
+    pub grace_time: i64,
     // others
 }
 
@@ -83,10 +85,73 @@ pub struct CachedOnlineCustAsnpPayload {
     pub creation_timestamp: i64,
     pub cache_lifetime: i64,
     pub response_type: String,
+    #[serde(default)]
+    pub device_id: String,
+    #[serde(default)]
+    pub device_date: String,
+    #[serde(default)]
+    pub session_id: String,
     // others
 }
 
+/// The state of a locally-cached license, as found in the NGL license store.
+#[derive(Default, Debug, Clone)]
+pub struct CachedLicenseState {
+    pub asnp_id: String,
+    pub license_id: String,
+    pub license_type: i32,
+    pub response_type: String,
+    pub device_id: String,
+    pub device_date: String,
+    pub session_id: String,
+    /// when the cached response was created (epoch millis)
+    pub creation_timestamp: i64,
+    /// how long the cached response can be used (millis)
+    pub cache_lifetime: i64,
+    /// when the cached license expires (epoch millis)
+    pub effective_end_timestamp: i64,
+    /// how long after expiration the license can still be used (millis)
+    pub grace_time: i64,
+}
+
+impl CachedLicenseState {
+    /// When the cached response can no longer be used (epoch millis).
+    pub fn cache_end_timestamp(&self) -> i64 {
+        self.creation_timestamp + self.cache_lifetime
+    }
+}
+
+impl From<&CachedOnlineLicense> for CachedLicenseState {
+    fn from(license: &CachedOnlineLicense) -> Self {
+        let legacy = &license.asnp.payload.legacy_profile;
+        let cust = &license.cust_asnp.payload;
+        CachedLicenseState {
+            asnp_id: cust.asnp_id.clone(),
+            license_id: legacy.license_id.clone(),
+            license_type: legacy.license_type,
+            response_type: cust.response_type.clone(),
+            device_id: cust.device_id.clone(),
+            device_date: cust.device_date.clone(),
+            session_id: cust.session_id.clone(),
+            creation_timestamp: cust.creation_timestamp,
+            cache_lifetime: cust.cache_lifetime,
+            effective_end_timestamp: legacy.effective_end_timestamp,
+            grace_time: legacy.grace_time,
+        }
+    }
+}
+
 pub fn get_cached_expiry(oc_spec: &OcFileSpec) -> Option<String> {
+    get_cached_license(oc_spec)
+        .map(|license| license.asnp.payload.legacy_profile.effective_end_timestamp)
+        .map(|timestamp| timestamp.to_string())
+}
+
+pub fn get_cached_license_state(oc_spec: &OcFileSpec) -> Option<CachedLicenseState> {
+    get_cached_license(oc_spec).as_ref().map(CachedLicenseState::from)
+}
+
+fn get_cached_license(oc_spec: &OcFileSpec) -> Option<CachedOnlineLicense> {
     let npd_id = oc_spec.npd_id();
     let app_name = oc_spec.app_id();
     let cert_group_id = oc_spec.cert_group_id();
@@ -105,9 +170,7 @@ pub fn get_cached_expiry(oc_spec: &OcFileSpec) -> Option<String> {
     if let Ok(json) = get_saved_credential(&note_key) {
         if let Ok(license) = serde_json::from_str::<CachedOnlineLicense>(&json) {
             if npd_id.eq(&license.cust_asnp.payload.npd_id) {
-                let timestamp =
-                    license.asnp.payload.legacy_profile.effective_end_timestamp;
-                return Some(timestamp.to_string());
+                return Some(license);
             }
         }
     }
@@ -134,4 +197,20 @@ mod tests {
             panic!("Couldn't read or parse ")
         }
     }
+
+    #[test]
+    fn test_cached_license_state() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let path = path.join("../rsrc/credentials/ps-online-mac.json");
+        let json = std::fs::read_to_string(path).expect("Couldn't read test json");
+        let license = serde_json::from_str::<CachedOnlineLicense>(&json)
+            .expect("Couldn't read cached license");
+        let state = CachedLicenseState::from(&license);
+        assert_eq!(state.license_id, "8A935605037F4F02B7BA");
+        assert_eq!(state.response_type, "FRL_INITIAL");
+        assert_eq!(state.creation_timestamp, 1647565876882);
+        assert_eq!(state.cache_end_timestamp(), 1647565876882 + 101890124118);
+        assert_eq!(state.grace_time, 8553600000);
+        assert!(state.device_id.starts_with("2c93c8798aa2b625"));
+    }
 }
@@ -0,0 +1,29 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // the gRPC API is generated from its protocol definition, which
+    // requires the protobuf compiler (`protoc`)
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto");
+        tonic_build::compile_protos("proto/adlu/proxy/v1/admin.proto")
+            .expect("Can't compile the gRPC protocol definition");
+    }
+}
@@ -0,0 +1,228 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Authorization of the proxy's admin and reporting endpoints.
+
+Every endpoint that isn't called by Adobe apps (status, dashboards, fault
+injection, uninstall hooks, decoder inventories, cache transfers, and the
+gRPC API) authorizes its callers the same way.
+A caller presents either a bearer token (in the `Authorization` header, or,
+for endpoints that browsers use as event sources, in a `token` query
+parameter) or a username and password with basic authentication.  The
+caller is authorized if that's one of the endpoint's own tokens, or an admin
+credential (see [`crate::settings::Admin`]) whose role is at least the one
+the endpoint requires.
+
+The [`authorize`] filter rejects requests that aren't authorized as
+[`Unauthorized`].  The routes are combined with `or`, so each protected route
+recovers that rejection into a 401 reply rather than letting the request fall
+through to the next route.  Handlers only ever see authorized requests.
+ */
+use std::collections::HashMap;
+
+use subtle::ConstantTimeEq;
+use warp::{Filter, Rejection};
+
+use crate::proxy::Config;
+use crate::settings::{AdminEndpoint, SettingsVal};
+
+/// The credentials presented with a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    None,
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl Credentials {
+    /// The credentials in an `Authorization` header, if it has any, else
+    /// the given query token.
+    pub fn new(authorization: Option<&str>, query_token: Option<&str>) -> Self {
+        if let Some(auth) = authorization {
+            if let Some(token) = auth.strip_prefix("Bearer ") {
+                return Credentials::Bearer(token.trim().to_string());
+            }
+            if let Some(encoded) = auth.strip_prefix("Basic ") {
+                let decoded = adlu_base::b64decode(encoded.trim()).unwrap_or_default();
+                let decoded = String::from_utf8_lossy(&decoded);
+                if let Some((username, password)) = decoded.split_once(':') {
+                    return Credentials::Basic {
+                        username: username.to_string(),
+                        password: password.to_string(),
+                    };
+                }
+            }
+        }
+        match query_token {
+            Some(token) => Credentials::Bearer(token.to_string()),
+            None => Credentials::None,
+        }
+    }
+
+    /// Whether these credentials authorize a call to the endpoint.
+    pub fn authorize(&self, settings: &SettingsVal, endpoint: AdminEndpoint) -> bool {
+        let admin = &settings.admin;
+        let role = admin.required_role(endpoint);
+        match self {
+            Credentials::None => false,
+            Credentials::Bearer(token) => {
+                let own = endpoint_tokens(settings, endpoint);
+                own.iter().any(|t| !t.is_empty() && secret_eq(t, token))
+                    || admin.tokens.iter().any(|t| {
+                        !t.token.is_empty()
+                            && secret_eq(&t.token, token)
+                            && t.role >= role
+                    })
+            }
+            Credentials::Basic { username, password } => admin.users.iter().any(|u| {
+                !u.username.is_empty()
+                    && !u.password.is_empty()
+                    && &u.username == username
+                    && secret_eq(&u.password, password)
+                    && u.role >= role
+            }),
+        }
+    }
+}
+
+/// Compare secrets in constant time, so callers can't guess them by timing.
+fn secret_eq(expected: &str, given: &str) -> bool {
+    expected.as_bytes().ct_eq(given.as_bytes()).into()
+}
+
+/// The rejection of a request that isn't authorized to call an endpoint,
+/// with the challenge (if any) to send back.
+#[derive(Debug)]
+pub struct Unauthorized {
+    pub endpoint: AdminEndpoint,
+    pub challenge: Option<&'static str>,
+}
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Pass only requests that are authorized to call the endpoint, rejecting the
+/// others as [`Unauthorized`].
+pub fn authorize(
+    conf: Config,
+    endpoint: AdminEndpoint,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let query = warp::query::<HashMap<String, String>>()
+        .or(warp::any().map(HashMap::new))
+        .unify();
+    warp::header::optional::<String>("Authorization")
+        .and(query)
+        .and_then(move |auth: Option<String>, query: HashMap<String, String>| {
+            let query_token = if allows_query_token(endpoint) {
+                query.get("token").map(String::as_str)
+            } else {
+                None
+            };
+            let authorized = Credentials::new(auth.as_deref(), query_token)
+                .authorize(&conf.settings, endpoint);
+            let challenge = challenge(&conf.settings);
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized { endpoint, challenge }))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// The `WWW-Authenticate` challenge for unauthorized callers, if there are
+/// users who could answer it.
+pub fn challenge(settings: &SettingsVal) -> Option<&'static str> {
+    if settings.admin.users.is_empty() {
+        None
+    } else {
+        Some("Basic realm=\"adlu-proxy\"")
+    }
+}
+
+/// The tokens that an endpoint's own settings give it.  Event stream tokens
+/// only authorize the status and dashboard endpoints, which just read; the
+/// endpoints that change what the proxy does take admin credentials with the
+/// required role.
+fn endpoint_tokens(settings: &SettingsVal, endpoint: AdminEndpoint) -> &[String] {
+    match endpoint {
+        AdminEndpoint::Status
+        | AdminEndpoint::Events
+        | AdminEndpoint::Activity
+        | AdminEndpoint::Notes => &settings.events.tokens,
+        AdminEndpoint::Uninstall => &settings.frl.uninstall_tokens,
+        AdminEndpoint::Inventory => &settings.frl.inventory_tokens,
+        AdminEndpoint::Transfer => &settings.transfer.tokens,
+        AdminEndpoint::Grpc => &settings.grpc.tokens,
+        AdminEndpoint::Faults | AdminEndpoint::Canary => &[],
+    }
+}
+
+/// Browsers can't set headers on an event source, so dashboard endpoints
+/// also take their token as a query parameter.
+fn allows_query_token(endpoint: AdminEndpoint) -> bool {
+    matches!(
+        endpoint,
+        AdminEndpoint::Events | AdminEndpoint::Activity | AdminEndpoint::Notes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{AdminRole, AdminToken, AdminUser, SettingsVal};
+
+    use super::*;
+
+    #[test]
+    fn test_authorize_by_role() {
+        let mut settings = SettingsVal::default();
+        settings.events.tokens = vec!["dash".to_string()];
+        settings.admin.tokens =
+            vec![AdminToken { token: "view".to_string(), role: AdminRole::Viewer }];
+        settings.admin.users = vec![AdminUser {
+            username: "ops".to_string(),
+            password: "secret".to_string(),
+            role: AdminRole::Operator,
+        }];
+        let bearer = |t: &str| Credentials::new(Some(&format!("Bearer {t}")), None);
+        let basic = |up: &str| {
+            let encoded = adlu_base::b64encode(up.as_bytes());
+            Credentials::new(Some(&format!("Basic {encoded}")), None)
+        };
+        assert!(bearer("dash").authorize(&settings, AdminEndpoint::Activity));
+        assert!(!bearer("dash").authorize(&settings, AdminEndpoint::Faults));
+        assert!(!bearer("dash").authorize(&settings, AdminEndpoint::Canary));
+        assert!(basic("ops:secret").authorize(&settings, AdminEndpoint::Faults));
+        assert!(bearer("view").authorize(&settings, AdminEndpoint::Events));
+        assert!(bearer("view").authorize(&settings, AdminEndpoint::Status));
+        assert!(bearer("dash").authorize(&settings, AdminEndpoint::Status));
+        settings.grpc.tokens = vec!["grpc".to_string()];
+        assert!(bearer("grpc").authorize(&settings, AdminEndpoint::Grpc));
+        assert!(!bearer("view").authorize(&settings, AdminEndpoint::Grpc));
+        assert!(basic("ops:secret").authorize(&settings, AdminEndpoint::Grpc));
+        assert!(!bearer("view").authorize(&settings, AdminEndpoint::Uninstall));
+        assert!(basic("ops:secret").authorize(&settings, AdminEndpoint::Uninstall));
+        assert!(!basic("ops:wrong").authorize(&settings, AdminEndpoint::Uninstall));
+        assert!(!basic("ops:secret").authorize(&settings, AdminEndpoint::Transfer));
+        assert!(!Credentials::None.authorize(&settings, AdminEndpoint::Events));
+        let query = Credentials::new(None, Some("view"));
+        assert_eq!(query, Credentials::Bearer("view".to_string()));
+    }
+}
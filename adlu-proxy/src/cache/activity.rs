@@ -0,0 +1,157 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Counts of activity in the cache, binned by hour or by day.
+
+Dashboards chart these counts, so they are computed with SQL aggregation
+rather than by fetching rows.  Timestamps are stored as epoch millis, so
+a bin is the timestamp rounded down to a multiple of the bin length, and a
+time range can use the timestamp indexes.
+ */
+use eyre::Result;
+use serde::Serialize;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use super::ReportFilter;
+
+/// The size of a bin of activity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityBin {
+    Hour,
+    Day,
+}
+
+impl ActivityBin {
+    /// The length of a bin, in milliseconds.
+    pub fn millis(&self) -> i64 {
+        match self {
+            ActivityBin::Hour => 3600 * 1000,
+            ActivityBin::Day => 24 * 3600 * 1000,
+        }
+    }
+}
+
+impl TryFrom<&str> for ActivityBin {
+    type Error = String;
+
+    fn try_from(s: &str) -> std::result::Result<Self, Self::Error> {
+        match s.to_ascii_lowercase().as_str() {
+            "hour" => Ok(ActivityBin::Hour),
+            "day" => Ok(ActivityBin::Day),
+            _ => Err(format!("Activity bins are by hour or day, not by {}", s)),
+        }
+    }
+}
+
+/// The activity in one bin.  Bins with no activity are omitted.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivityCount {
+    pub start: String,
+    pub activations: i64,
+    pub license_sessions: i64,
+    pub log_sessions: i64,
+}
+
+/// The activity from `since` (inclusive) to `until` (exclusive).
+pub async fn histogram(
+    pool: &SqlitePool,
+    bin: ActivityBin,
+    since: &Timestamp,
+    until: &Timestamp,
+) -> Result<Vec<ActivityCount>> {
+    filtered_histogram(pool, bin, since, until, &ReportFilter::default()).await
+}
+
+async fn filtered_histogram(
+    pool: &SqlitePool,
+    bin: ActivityBin,
+    since: &Timestamp,
+    until: &Timestamp,
+    filter: &ReportFilter,
+) -> Result<Vec<ActivityCount>> {
+    let rows = sqlx::query(&filter.apply(ACTIVITY_HISTOGRAM, "bin"))
+        .bind(bin.millis())
+        .bind(since.to_db())
+        .bind(until.to_db())
+        .fetch_all(pool)
+        .await?;
+    let counts = rows
+        .iter()
+        .map(|row| ActivityCount {
+            start: start(Timestamp::from_db(row.get("bin"))),
+            activations: row.get("activations"),
+            license_sessions: row.get("license_sessions"),
+            log_sessions: row.get("log_sessions"),
+        })
+        .collect();
+    Ok(counts)
+}
+
+/// All of the activity in the cache, binned by day.
+pub async fn report(pool: &SqlitePool, path: &str, filter: &ReportFilter) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Day (UTC)",
+        "FRL Activations",
+        "NUL License Sessions",
+        "Log Sessions",
+    ])?;
+    // a timestamp of 0 means there isn't one
+    let since = Timestamp::from_millis(1);
+    let until = Timestamp::from_millis(Timestamp::now().to_millis() + 1);
+    for count in
+        filtered_histogram(pool, ActivityBin::Day, &since, &until, filter).await?
+    {
+        writer.write_record([
+            count.start[..10].to_string(),
+            count.activations.to_string(),
+            count.license_sessions.to_string(),
+            count.log_sessions.to_string(),
+        ])?;
+    }
+    Ok(())
+}
+
+/// The start of a bin, in RFC-3339 form.
+fn start(bin: Timestamp) -> String {
+    bin.as_utc_datetime().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+const ACTIVITY_HISTOGRAM: &str = r#"
+    select
+        (timestamp / ?1) * ?1 as bin,
+        sum(kind = 'frl') as activations,
+        sum(kind = 'nul') as license_sessions,
+        sum(kind = 'log') as log_sessions
+    from (
+        select timestamp, 'frl' as kind from activation_requests
+        where timestamp >= ?2 and timestamp < ?3
+        union all
+        select session_start, 'nul' from license_sessions
+        where session_start >= ?2 and session_start < ?3
+        union all
+        select session_start, 'log' from log_sessions
+        where session_start >= ?2 and session_start < ?3
+    )
+    group by bin
+    order by bin
+    "#;
@@ -0,0 +1,159 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+The user agents of clients, stored with their requests.
+
+Like locations (see [`super::location`]), user agents are stored by updating
+whichever rows have the correlation id of the request that carried them.
+Requests from the NGL client library also report the library's version
+in their user agent, which gives every request type an NGL version even when
+its body doesn't have one.
+ */
+use eyre::Result;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use crate::proxy::{Request, RequestType};
+
+use super::ReportFilter;
+
+pub async fn store_agent(pool: &SqlitePool, req: &Request) -> Result<()> {
+    let table = match req.request_type {
+        RequestType::FrlActivation => "activation_requests",
+        RequestType::FrlDeactivation => "deactivation_requests",
+        RequestType::NulLicense => "license_sessions",
+        RequestType::LogUpload => "log_sessions",
+        RequestType::Unknown => return Ok(()),
+    };
+    let u_str = format!(
+        "update {table} set user_agent = ?, ngl_client_version = ? where correlation_id = ?"
+    );
+    sqlx::query(&u_str)
+        .bind(req.user_agent.as_deref().unwrap_or_default())
+        .bind(req.ngl_client_version().unwrap_or_default())
+        .bind(&req.correlation_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The number of stored records and clients of each request type for each
+/// version of the NGL library, together with when each version was first
+/// and last seen.  The records are activations, deactivations, and sessions,
+/// so each can stand for more than one request.  Versions are listed newest
+/// first.
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "NGL Version".to_string(),
+        "Request Type".to_string(),
+        "Records".to_string(),
+        "Clients".to_string(),
+        format!("First Seen{time_suffix}"),
+        format!("Last Seen{time_suffix}"),
+        "Sample User Agent".to_string(),
+    ])?;
+    let format = |ts: Timestamp| {
+        if rfc3339 {
+            ts.format_rfc_3339(timezone)
+        } else {
+            ts.format_iso_8601(timezone)
+        }
+    };
+    let q_str = filter.apply(VERSION_SUMMARY, "first_seen");
+    let mut rows = sqlx::query(&q_str).fetch_all(pool).await?;
+    rows.sort_by_cached_key(|row| {
+        let version: String = row.get("version");
+        let request_type: String = row.get("request_type");
+        (std::cmp::Reverse(version_key(&version)), request_type)
+    });
+    for row in rows.iter() {
+        let records: i64 = row.get("records");
+        let clients: i64 = row.get("clients");
+        writer.write_record([
+            row.get("version"),
+            row.get("request_type"),
+            records.to_string(),
+            clients.to_string(),
+            format(Timestamp::from_db(row.get("first_seen"))),
+            format(Timestamp::from_db(row.get("last_seen"))),
+            row.get("user_agent"),
+        ])?;
+    }
+    Ok(())
+}
+
+/// The version reported in a request body is preferred to the one in its
+/// user agent, because bodies have reported versions for longer.  Clients
+/// are devices for FRL requests and users for everything else.
+const VERSION_SUMMARY: &str = r#"
+    select
+        version, request_type,
+        count(*) as records,
+        count(distinct client) as clients,
+        min(timestamp) as first_seen,
+        max(timestamp) as last_seen,
+        max(user_agent) as user_agent
+    from (
+        select coalesce(nullif(ngl_version, ''), ngl_client_version) as version,
+            'FRL Activation' as request_type, device_id as client,
+            timestamp, user_agent
+        from activation_requests
+        union all
+        select ngl_client_version, 'FRL Deactivation', device_id, timestamp, user_agent
+        from deactivation_requests
+        union all
+        select coalesce(nullif(ngl_version, ''), ngl_client_version),
+            'NUL License', user_id, session_start, user_agent
+        from license_sessions
+        union all
+        select coalesce(nullif(ngl_version, ''), ngl_client_version),
+            'Log Upload', user_id, final_entry, user_agent
+        from log_sessions
+    )
+    group by version, request_type"#;
+
+/// Versions are compared by their dot-separated parts as numbers, so 1.10
+/// comes after 1.9.  Parts that aren't numbers count as 0, and versions that
+/// are otherwise equal are compared as text.
+fn version_key(version: &str) -> (Vec<u64>, String) {
+    let parts = version.split('.').map(|part| part.parse().unwrap_or(0)).collect();
+    (parts, version.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::version_key;
+
+    #[test]
+    fn test_version_key() {
+        assert!(version_key("1.10.0.1") > version_key("1.9.2.3"));
+        assert!(version_key("1.30.0.1") > version_key("1.30.0"));
+        assert!(version_key("2") > version_key("1.99"));
+        assert!(version_key("") < version_key("0.1"));
+    }
+}
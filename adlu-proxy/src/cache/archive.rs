@@ -0,0 +1,436 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Portable archives of FRL exports.
+
+An export database is compact, but it's opaque, and a database that was only
+partly copied can be hard to tell from a whole one.  An archive holds the same
+export as a single zstd-compressed tar file, `PATH.tar.zst`, whose contents can
+be inspected with standard tools:
+
+- `manifest.json` comes first, and lists every other file in the archive with
+  its size and SHA-256 checksum (and, for a table, its row count);
+- `tables/TABLE.ndjson` holds each of the tables that import reads, as one
+  JSON object per row;
+- `payloads/SHA256.json` holds the raw body of a response from Adobe, which
+  the rows of the response tables name in place of their `body` column.
+
+When an archive is unpacked, every file is checked against the manifest, so
+an archive that was cut short or damaged in transit is detected, and (if
+allowed) the tables that are intact can still be restored and imported.
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use super::integrity::{self, Seal, SEALED_TABLES};
+
+const MANIFEST_FORMAT: &str = "adlu-proxy-archive";
+
+const MANIFEST_VERSION: u32 = 1;
+
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// The extension of an archive.
+const EXTENSION: &str = ".tar.zst";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    format: String,
+    version: u32,
+    proxy_version: String,
+    created: String,
+    files: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    name: String,
+    bytes: u64,
+    sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rows: Option<u64>,
+}
+
+/// Whether a path names an archive.
+pub fn is_archive(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(EXTENSION)
+}
+
+/// The name of the archive of `path`: the given name, with the archive
+/// extension added if it doesn't already have it.
+pub fn archive_path(path: &str) -> String {
+    if is_archive(path) {
+        path.to_string()
+    } else {
+        format!("{}{}", path, EXTENSION)
+    }
+}
+
+/// The name of the database unpacked from the archive at `path`.
+pub fn unpacked_path(path: &str) -> Option<String> {
+    if is_archive(path) {
+        Some(path[..path.len() - EXTENSION.len()].to_string())
+    } else {
+        None
+    }
+}
+
+/// Pack the export database at `source` into a new archive at `path`,
+/// returning the number of tables and payloads it holds.
+pub async fn pack(source: &str, path: &str) -> Result<(usize, usize)> {
+    if std::fs::metadata(path).is_ok() {
+        return Err(eyre!("Cannot pack into an existing file: {}", path));
+    }
+    std::fs::metadata(source).wrap_err(format!("Can't find export: {}", source))?;
+    let pool = super::db_init(source, "rw", 1).await?;
+    let result = pack_pool(&pool, path).await;
+    pool.close().await;
+    result
+}
+
+async fn pack_pool(pool: &SqlitePool, path: &str) -> Result<(usize, usize)> {
+    if let Seal::Damaged(damage) = integrity::verify(pool).await? {
+        let tables: Vec<&str> = damage.iter().map(|d| d.table.as_str()).collect();
+        return Err(eyre!("Can't pack a damaged export (see {})", tables.join(", ")));
+    }
+    let mut files: Vec<(String, Vec<u8>, Option<u64>)> = vec![];
+    let mut payloads: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for table in SEALED_TABLES {
+        let rows = table_rows(pool, table).await?;
+        let mut lines = vec![];
+        for mut row in rows.iter().cloned() {
+            if let Some(Value::String(body)) = row.remove("body") {
+                let name = format!("payloads/{}.json", sha256(body.as_bytes()));
+                row.insert("body_file".to_string(), name.clone().into());
+                payloads.insert(name, body.into_bytes());
+            }
+            serde_json::to_writer(&mut lines, &row)?;
+            lines.push(b'\n');
+        }
+        files.push((format!("tables/{}.ndjson", table), lines, Some(rows.len() as u64)));
+    }
+    let counts = (files.len(), payloads.len());
+    files.extend(payloads.into_iter().map(|(name, data)| (name, data, None)));
+    let manifest = Manifest {
+        format: MANIFEST_FORMAT.to_string(),
+        version: MANIFEST_VERSION,
+        proxy_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: Timestamp::now().format_rfc_3339(true),
+        files: files
+            .iter()
+            .map(|(name, data, rows)| Entry {
+                name: name.clone(),
+                bytes: data.len() as u64,
+                sha256: sha256(data),
+                rows: *rows,
+            })
+            .collect(),
+    };
+    // the archive only gets its name once it's complete
+    let partial = format!("{}.partial", path);
+    let result = write_archive(&partial, &manifest, &files)
+        .and_then(|_| std::fs::rename(&partial, path).map_err(|e| eyre!(e)));
+    if result.is_err() {
+        std::fs::remove_file(&partial).ok();
+    }
+    result.wrap_err(format!("Can't write archive: {}", path))?;
+    Ok(counts)
+}
+
+/// Check the archive at `path` and restore the export it holds to a new
+/// database at `target`.  If any tables are missing or damaged, the error
+/// names all of them.  If `allow_damaged` is set, damaged tables are
+/// reported but left empty, and the rest are restored.
+pub async fn unpack(path: &str, target: &str, allow_damaged: bool) -> Result<()> {
+    if std::fs::metadata(target).is_ok() {
+        return Err(eyre!("Cannot unpack into an existing file: {}", target));
+    }
+    let file = File::open(path).wrap_err(format!("Can't read archive: {}", path))?;
+    let mut data = vec![];
+    let mut decoder = zstd::Decoder::new(file)?;
+    // what was read before a failure is kept, so intact tables can be found
+    if let Err(err) = decoder.read_to_end(&mut data) {
+        eprintln!("Archive {} is cut short or damaged: {}", path, err);
+    }
+    let mut entries = read_entries(&data).into_iter();
+    let manifest: Manifest = match entries.next() {
+        Some((name, body)) if name == MANIFEST_NAME => serde_json::from_slice(&body)
+            .wrap_err(format!("Invalid manifest in archive: {}", path))?,
+        _ => return Err(eyre!("Not an archive, or its manifest is damaged: {}", path)),
+    };
+    if manifest.format != MANIFEST_FORMAT || manifest.version > MANIFEST_VERSION {
+        return Err(eyre!("Unsupported archive format: {}", path));
+    }
+    let intact: HashMap<String, Vec<u8>> = entries
+        .filter(|(name, body)| {
+            manifest.files.iter().any(|entry| {
+                &entry.name == name
+                    && entry.bytes == body.len() as u64
+                    && entry.sha256 == sha256(body)
+            })
+        })
+        .collect();
+    let mut tables = vec![];
+    let mut damage = vec![];
+    for table in SEALED_TABLES {
+        let name = format!("tables/{}.ndjson", table);
+        match intact.get(&name).map(|data| rows_from_ndjson(data, &intact)) {
+            Some(Ok(rows)) => tables.push((table, rows)),
+            Some(Err(err)) => damage.push(format!("{}: {}", name, err)),
+            None => damage.push(format!("{}: missing or damaged", name)),
+        }
+    }
+    for problem in damage.iter() {
+        eprintln!("Damaged table in {}: {}", path, problem);
+    }
+    if !damage.is_empty() && !allow_damaged {
+        return Err(eyre!(
+            "{} of {} table(s) are damaged (use --skip-damaged to import the rest)",
+            damage.len(),
+            SEALED_TABLES.len()
+        ));
+    }
+    let pool = super::db_init(target, "rwc", 1).await?;
+    let result = restore(&pool, &tables).await;
+    pool.close().await;
+    if result.is_err() {
+        std::fs::remove_file(target).ok();
+    }
+    result
+}
+
+async fn restore(
+    pool: &SqlitePool,
+    tables: &[(&str, Vec<Map<String, Value>>)],
+) -> Result<()> {
+    for (table, rows) in tables.iter() {
+        insert_rows(pool, table, rows).await?;
+    }
+    // the restored tables are sealed, so import can check them as usual
+    integrity::seal(pool).await
+}
+
+/// The rows of a table, in order, as JSON objects.
+async fn table_rows(pool: &SqlitePool, table: &str) -> Result<Vec<Map<String, Value>>> {
+    let columns = integrity::table_columns(pool, table).await?;
+    let fields: Vec<String> =
+        columns.iter().map(|column| format!("'{0}', \"{0}\"", column)).collect();
+    let q_str = format!(
+        "select json_object({}) as line from \"{}\" order by rowid",
+        fields.join(", "),
+        table
+    );
+    let rows = sqlx::query(&q_str).fetch_all(pool).await?;
+    let mut result = vec![];
+    for row in rows.iter() {
+        let line: String = row.get("line");
+        result.push(serde_json::from_str(&line)?);
+    }
+    Ok(result)
+}
+
+/// Insert rows into a table, setting the columns each row has values for.
+async fn insert_rows(
+    pool: &SqlitePool,
+    table: &str,
+    rows: &[Map<String, Value>],
+) -> Result<()> {
+    let columns = integrity::table_columns(pool, table).await?;
+    let mut tx = pool.begin().await?;
+    for row in rows.iter() {
+        let present: Vec<&String> =
+            columns.iter().filter(|column| row.contains_key(*column)).collect();
+        let names: Vec<String> = present.iter().map(|c| format!("\"{}\"", c)).collect();
+        let values: Vec<String> =
+            present.iter().map(|c| format!("json_extract(?1, '$.\"{}\"')", c)).collect();
+        let q_str = format!(
+            "insert into \"{}\" ({}) values ({})",
+            table,
+            names.join(", "),
+            values.join(", ")
+        );
+        let line = serde_json::to_string(row)?;
+        sqlx::query(&q_str).bind(line).execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The rows in a table file, with their bodies restored from their payloads.
+fn rows_from_ndjson(
+    data: &[u8],
+    payloads: &HashMap<String, Vec<u8>>,
+) -> Result<Vec<Map<String, Value>>> {
+    let text = std::str::from_utf8(data)?;
+    let mut rows = vec![];
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let mut row: Map<String, Value> = serde_json::from_str(line)?;
+        if let Some(Value::String(name)) = row.remove("body_file") {
+            let body = payloads
+                .get(&name)
+                .ok_or_else(|| eyre!("payload {} is missing or damaged", name))?;
+            row.insert("body".to_string(), String::from_utf8_lossy(body).into());
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn write_archive(
+    path: &str,
+    manifest: &Manifest,
+    files: &[(String, Vec<u8>, Option<u64>)],
+) -> Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = zstd::Encoder::new(writer, 0)?;
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let manifest = serde_json::to_vec_pretty(manifest)?;
+    write_entry(&mut encoder, MANIFEST_NAME, &manifest, mtime)?;
+    for (name, data, _) in files.iter() {
+        write_entry(&mut encoder, name, data, mtime)?;
+    }
+    // a tar archive ends with two empty blocks
+    encoder.write_all(&[0u8; 1024])?;
+    encoder.finish()?.flush()?;
+    Ok(())
+}
+
+/// Write a file to a tar archive, as a header block and then its data,
+/// padded to a whole number of blocks.
+fn write_entry(
+    writer: &mut impl Write,
+    name: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<()> {
+    if name.len() > 100 {
+        return Err(eyre!("Archive file name is too long: {}", name));
+    }
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    // mode, owner, group, size, and modification time, in octal
+    let fields = [
+        (100, 8, 0o644),
+        (108, 8, 0),
+        (116, 8, 0),
+        (124, 12, data.len() as u64),
+        (136, 12, mtime),
+    ];
+    for (offset, len, value) in fields {
+        let octal = format!("{:0width$o}", value, width = len - 1);
+        if octal.len() != len - 1 {
+            return Err(eyre!("Archive file is too large: {}", name));
+        }
+        header[offset..offset + len - 1].copy_from_slice(octal.as_bytes());
+    }
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|b| *b as u64).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(data)?;
+    writer.write_all(&vec![0u8; (512 - data.len() % 512) % 512])?;
+    Ok(())
+}
+
+/// The whole files at the start of a tar archive.  Reading stops at the end
+/// of the archive, or at the first file that's damaged or cut short.
+fn read_entries(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut entries = vec![];
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..pos + 512) {
+        let (name, size) = match parse_header(header) {
+            Some(entry) => entry,
+            None => break,
+        };
+        let start = pos + 512;
+        match data.get(start..start + size) {
+            Some(body) => entries.push((name, body.to_vec())),
+            None => break,
+        }
+        pos = start + size.div_ceil(512) * 512;
+    }
+    entries
+}
+
+/// The name and size in a tar header, if it's a valid header.
+fn parse_header(header: &[u8]) -> Option<(String, usize)> {
+    let stored = parse_octal(&header[148..156])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' as u64 } else { *b as u64 })
+        .sum();
+    if sum != stored {
+        return None;
+    }
+    let end = header[..100].iter().position(|b| *b == 0).unwrap_or(100);
+    let name = std::str::from_utf8(&header[..end]).ok()?.to_string();
+    let size = parse_octal(&header[124..136])? as usize;
+    Some((name, size))
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(field).ok()?;
+    u64::from_str_radix(text.trim_matches(|c: char| c == '\0' || c == ' '), 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_entries, write_entry};
+
+    #[test]
+    fn test_tar_entries() {
+        let mut data = vec![];
+        write_entry(&mut data, "manifest.json", b"{}", 0).unwrap();
+        write_entry(&mut data, "tables/t.ndjson", &[b'x'; 600], 0).unwrap();
+        data.extend_from_slice(&[0u8; 1024]);
+        assert_eq!(data.len(), 512 * 7);
+        let entries = read_entries(&data);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], ("manifest.json".to_string(), b"{}".to_vec()));
+        assert_eq!(entries[1].1.len(), 600);
+        // a file that's cut short is dropped, along with everything after it
+        let entries = read_entries(&data[..512 * 3]);
+        assert_eq!(entries.len(), 1);
+        // as is a file whose header is damaged
+        data[1024] ^= 1;
+        assert_eq!(read_entries(&data).len(), 1);
+    }
+}
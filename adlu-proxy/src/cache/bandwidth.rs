@@ -0,0 +1,178 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Counts of the bytes that pass through the proxy, by request type and day.
+
+Each request a client sends to an Adobe endpoint adds its size, and the size
+of the response it gets, to the totals for its type on the (UTC) day it was
+received.  Sizes are of the bodies only; headers add a roughly constant
+amount per request, so the request counts cover them.  The totals are kept
+as they go, rather than computed from the cached requests, so that they
+aren't lost when the requests are purged or evicted.
+ */
+use eyre::Result;
+use serde::Serialize;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+use adlu_parse::protocol::Request;
+
+use super::{ActivityBin, ReportFilter};
+
+/// The traffic of one request type (on one day, or in all).
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bandwidth {
+    pub request_type: String,
+    pub requests: u64,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(BANDWIDTH_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn clear(pool: &SqlitePool) -> Result<()> {
+    sqlx::query("delete from bandwidth").execute(pool).await?;
+    eprintln!("Bandwidth cache has been cleared.");
+    Ok(())
+}
+
+pub async fn store_bandwidth(
+    pool: &SqlitePool,
+    req: &Request,
+    response_bytes: u64,
+) -> Result<()> {
+    let day = ActivityBin::Day.millis();
+    let request_bytes = req.body.as_ref().map_or(0, |body| body.len());
+    sqlx::query(BANDWIDTH_UPSERT)
+        .bind(req.timestamp.to_db() / day * day)
+        .bind(req.request_type.to_string())
+        .bind(request_bytes as i64)
+        .bind(response_bytes as i64)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The totals for each request type over all days.
+pub async fn totals(pool: &SqlitePool) -> Result<Vec<Bandwidth>> {
+    let q_str = r#"select request_type, sum(requests) as requests,
+            sum(request_bytes) as request_bytes, sum(response_bytes) as response_bytes
+        from bandwidth group by request_type order by request_type"#;
+    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    Ok(rows.iter().map(bandwidth_from_row).collect())
+}
+
+/// The totals for each request type on each day.
+pub async fn report(pool: &SqlitePool, path: &str, filter: &ReportFilter) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Day (UTC)",
+        "Request Type",
+        "Requests",
+        "Request Bytes",
+        "Response Bytes",
+    ])?;
+    let q_str = "select * from bandwidth order by day, request_type";
+    for row in sqlx::query(&filter.apply(q_str, "day")).fetch_all(pool).await?.iter() {
+        let day = Timestamp::from_db(row.get("day"));
+        let bandwidth = bandwidth_from_row(row);
+        writer.write_record([
+            day.as_utc_datetime().format("%Y-%m-%d").to_string(),
+            bandwidth.request_type,
+            bandwidth.requests.to_string(),
+            bandwidth.request_bytes.to_string(),
+            bandwidth.response_bytes.to_string(),
+        ])?;
+    }
+    Ok(())
+}
+
+fn bandwidth_from_row(row: &sqlx::sqlite::SqliteRow) -> Bandwidth {
+    let get = |name: &str| row.get::<i64, _>(name) as u64;
+    Bandwidth {
+        request_type: row.get("request_type"),
+        requests: get("requests"),
+        request_bytes: get("request_bytes"),
+        response_bytes: get("response_bytes"),
+    }
+}
+
+const BANDWIDTH_SCHEMA: &str = r#"
+    create table if not exists bandwidth (
+        day integer not null,
+        request_type text not null,
+        requests integer not null,
+        request_bytes integer not null,
+        response_bytes integer not null,
+        unique(day, request_type)
+    );"#;
+
+const BANDWIDTH_UPSERT: &str = r#"
+    insert into bandwidth
+        (day, request_type, requests, request_bytes, response_bytes)
+        values (?1, ?2, 1, ?3, ?4)
+    on conflict (day, request_type) do update set
+        requests = requests + 1,
+        request_bytes = request_bytes + excluded.request_bytes,
+        response_bytes = response_bytes + excluded.response_bytes
+    "#;
+
+#[cfg(test)]
+mod tests {
+    use adlu_base::Timestamp;
+
+    use super::{report, store_bandwidth, totals};
+
+    #[tokio::test]
+    async fn test_bandwidth() {
+        let dir = std::env::temp_dir().join("adlu-proxy-bandwidth-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("bw1");
+        let mut req = crate::testing::frl::mock_cache_activation_request(&body);
+        let size = req.body.as_ref().unwrap().len() as u64;
+        req.timestamp = Timestamp::from_millis(1664582400000);
+        store_bandwidth(&pool, &req, 100).await.unwrap();
+        req.timestamp = Timestamp::from_millis(1664582400000 + 3600 * 1000);
+        store_bandwidth(&pool, &req, 50).await.unwrap();
+        req.timestamp = Timestamp::from_millis(1664582400000 + 24 * 3600 * 1000);
+        store_bandwidth(&pool, &req, 25).await.unwrap();
+        let totals = totals(&pool).await.unwrap();
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[0].request_type, "FRL Activation");
+        assert_eq!(totals[0].requests, 3);
+        assert_eq!(totals[0].request_bytes, 3 * size);
+        assert_eq!(totals[0].response_bytes, 175);
+        let csv_path = dir.join("bandwidth.csv");
+        report(&pool, csv_path.to_str().unwrap(), &Default::default()).await.unwrap();
+        let content = std::fs::read_to_string(&csv_path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3, "Wrong report: {}", content);
+        assert!(lines[1].starts_with("2022-10-01,FRL Activation,2,"), "{}", lines[1]);
+        assert!(lines[2].ends_with(",25"), "{}", lines[2]);
+        pool.close().await;
+    }
+}
@@ -0,0 +1,235 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Chunked exports, for moving large exports over unreliable networks.
+
+A chunked export of `PATH` is a manifest file `PATH.manifest` together with
+numbered chunk files `PATH.001`, `PATH.002`, and so on.  The manifest records
+the size and SHA-256 checksum of each chunk and of the whole export.  If a
+transfer is interrupted, only the chunks that are missing or damaged need to
+be copied again: import checks every chunk against the manifest before it
+reassembles them, and names any chunks that need to be re-copied.
+ */
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const MANIFEST_FORMAT: &str = "adlu-proxy-chunked-export";
+
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    format: String,
+    version: u32,
+    total_bytes: u64,
+    sha256: String,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Chunk {
+    file: String,
+    bytes: u64,
+    sha256: String,
+}
+
+/// The manifest path for a chunked export of `path`.
+pub fn manifest_path(path: &str) -> String {
+    format!("{}.manifest", path)
+}
+
+/// If `path` names a chunked export (either by its manifest or by the
+/// path it was exported to), the path it was exported to.
+pub fn chunked_base(path: &str) -> Option<String> {
+    if let Some(base) = path.strip_suffix(".manifest") {
+        Some(base.to_string())
+    } else if Path::new(&manifest_path(path)).is_file() {
+        Some(path.to_string())
+    } else {
+        None
+    }
+}
+
+/// Split the file at `source` into chunks of at most `chunk_bytes` alongside `base`,
+/// writing the manifest last so an interrupted split is never mistaken for a whole one.
+pub fn split(source: &str, base: &str, chunk_bytes: u64) -> Result<usize> {
+    if chunk_bytes == 0 {
+        return Err(eyre!("Chunk size must be greater than zero"));
+    }
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut whole = Sha256::new();
+    let (mut chunks, mut total_bytes) = (vec![], 0u64);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let path = chunk_path(base, chunks.len() + 1);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let (mut hasher, mut bytes) = (Sha256::new(), 0u64);
+        while bytes < chunk_bytes {
+            let want = buf.len().min((chunk_bytes - bytes) as usize);
+            let count = reader.read(&mut buf[..want])?;
+            if count == 0 {
+                break;
+            }
+            writer.write_all(&buf[..count])?;
+            hasher.update(&buf[..count]);
+            whole.update(&buf[..count]);
+            bytes += count as u64;
+        }
+        writer.flush()?;
+        if bytes == 0 && !chunks.is_empty() {
+            // the source ended exactly at a chunk boundary
+            std::fs::remove_file(&path)?;
+            break;
+        }
+        total_bytes += bytes;
+        chunks.push(Chunk {
+            file: file_name(&path),
+            bytes,
+            sha256: format!("{:x}", hasher.finalize()),
+        });
+        if bytes < chunk_bytes {
+            break;
+        }
+    }
+    let manifest = Manifest {
+        format: MANIFEST_FORMAT.to_string(),
+        version: MANIFEST_VERSION,
+        total_bytes,
+        sha256: format!("{:x}", whole.finalize()),
+        chunks,
+    };
+    let count = manifest.chunks.len();
+    std::fs::write(manifest_path(base), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(count)
+}
+
+/// Check the chunks of the export at `base` and reassemble them into `target`.
+/// If any chunks are missing or damaged, the error names all of them.  If
+/// `allow_damaged` is set, damaged chunks are reported but reassembled anyway,
+/// so the export's own checksums can pick out the tables they spoiled.
+pub fn assemble(base: &str, target: &str, allow_damaged: bool) -> Result<()> {
+    let m_path = manifest_path(base);
+    let manifest: Manifest = serde_json::from_str(
+        &std::fs::read_to_string(&m_path)
+            .wrap_err(format!("Can't read manifest: {}", &m_path))?,
+    )
+    .wrap_err(format!("Invalid manifest: {}", &m_path))?;
+    if manifest.format != MANIFEST_FORMAT || manifest.version > MANIFEST_VERSION {
+        return Err(eyre!("Unsupported manifest format: {}", &m_path));
+    }
+    let dir = Path::new(base).parent().unwrap_or_else(|| Path::new(""));
+    let (mut bad, mut damaged) = (vec![], vec![]);
+    for chunk in manifest.chunks.iter() {
+        let path = dir.join(&chunk.file);
+        match checksum(&path) {
+            Ok((bytes, sha256)) if bytes == chunk.bytes && sha256 == chunk.sha256 => {}
+            Ok(_) if allow_damaged => damaged.push(chunk.file.clone()),
+            Ok(_) => bad.push(format!("{} (damaged)", &chunk.file)),
+            Err(_) => bad.push(format!("{} (missing)", &chunk.file)),
+        }
+    }
+    if !bad.is_empty() {
+        return Err(eyre!(
+            "{} of {} chunk(s) must be copied again: {}",
+            bad.len(),
+            manifest.chunks.len(),
+            bad.join(", ")
+        ));
+    }
+    let mut writer = BufWriter::new(File::create(target)?);
+    let mut whole = Sha256::new();
+    for chunk in manifest.chunks.iter() {
+        let data = std::fs::read(dir.join(&chunk.file))?;
+        whole.update(&data);
+        writer.write_all(&data)?;
+    }
+    writer.flush()?;
+    if !damaged.is_empty() {
+        eprintln!("Reassembled damaged chunk(s) anyway: {}", damaged.join(", "));
+    } else if format!("{:x}", whole.finalize()) != manifest.sha256 {
+        std::fs::remove_file(target).ok();
+        return Err(eyre!("Reassembled export does not match its manifest"));
+    }
+    Ok(())
+}
+
+fn chunk_path(base: &str, index: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{:03}", base, index))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+fn checksum(path: &Path) -> Result<(u64, String)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut hasher, mut bytes) = (Sha256::new(), 0u64);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let count = reader.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buf[..count]);
+        bytes += count as u64;
+    }
+    Ok((bytes, format!("{:x}", hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, chunk_path, chunked_base, manifest_path, split};
+
+    #[test]
+    fn test_split_and_assemble() {
+        let dir = std::env::temp_dir().join("adlu-proxy-chunks-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.sqlite");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        let base = dir.join("export.sqlite").to_string_lossy().to_string();
+        let count = split(source.to_str().unwrap(), &base, 4096).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(chunked_base(&manifest_path(&base)), Some(base.clone()));
+        assert_eq!(chunked_base(&base), Some(base.clone()));
+        let target = dir.join("assembled.sqlite").to_string_lossy().to_string();
+        assemble(&base, &target, false).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), data);
+        // a damaged chunk and a missing chunk are both reported
+        std::fs::write(chunk_path(&base, 1), b"garbage").unwrap();
+        std::fs::remove_file(chunk_path(&base, 3)).unwrap();
+        let err = assemble(&base, &target, false).unwrap_err().to_string();
+        assert!(err.starts_with("2 of 3 chunk(s)"), "{}", err);
+        assert!(err.contains("export.sqlite.001 (damaged)"), "{}", err);
+        assert!(err.contains("export.sqlite.003 (missing)"), "{}", err);
+        // damaged chunks can be allowed, but missing ones can't
+        let err = assemble(&base, &target, true).unwrap_err().to_string();
+        assert!(err.starts_with("1 of 3 chunk(s)"), "{}", err);
+        assert!(!err.contains("export.sqlite.001"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Usernames imported from a directory, keyed by their NGL OS user IDs.
+
+FRL requests identify their OS user only by a hash of the user's login name,
+so it can't be read, but it can be computed.  Importing a list of usernames
+(one per line, such as an export of `sAMAccountName` from Active Directory or
+`uid` from LDAP) records each name under the hashes it might appear as, so
+that reports can show the username for each OS user ID they contain.
+ */
+use eyre::{Result, WrapErr};
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use super::ReportFilter;
+
+/// The OS user ID that NGL computes for a login name: the lower-case
+/// hex digest of its SHA-256 hash.
+pub fn os_user_id(username: &str) -> String {
+    format!("{:x}", Sha256::digest(username.as_bytes()))
+}
+
+/// The forms in which a directory username may be hashed by clients.
+/// Platforms differ both in the case of login names and in whether
+/// they are qualified by domain, so each form is hashed.
+fn login_names(username: &str) -> Vec<String> {
+    let mut result = vec![username.to_string()];
+    if let Some((_, name)) = username.rsplit_once('\\') {
+        result.push(name.to_string());
+    }
+    for name in result.clone() {
+        result.push(name.to_lowercase());
+    }
+    result.sort();
+    result.dedup();
+    result
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(OS_USERS_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn clear(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(CLEAR_ALL).execute(&mut tx).await?;
+    tx.commit().await?;
+    eprintln!("Directory user cache has been cleared.");
+    Ok(())
+}
+
+/// Import the usernames listed in a file, one per line.  Blank lines
+/// and lines starting with `#` are ignored.  Usernames that were
+/// imported before are replaced.
+pub async fn import(pool: &SqlitePool, path: &str) -> Result<()> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err(format!("Can't read usernames from: {}", path))?;
+    let usernames: Vec<&str> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    store_usernames(pool, &usernames).await?;
+    eprintln!("Imported {} directory username(s) from {}", usernames.len(), path);
+    Ok(())
+}
+
+pub async fn store_usernames(pool: &SqlitePool, usernames: &[&str]) -> Result<()> {
+    let i_str = r#"
+        insert or replace into os_users (os_user_id, username, timestamp)
+            values (?, ?, ?)"#;
+    let timestamp = Timestamp::now();
+    let mut tx = pool.begin().await?;
+    for username in usernames.iter() {
+        for name in login_names(username) {
+            sqlx::query(i_str)
+                .bind(os_user_id(&name))
+                .bind(username)
+                .bind(timestamp.to_db())
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Username".to_string(),
+        "OS User ID".to_string(),
+        format!("Imported{time_suffix}"),
+    ])?;
+    let q_str = "select * from os_users order by username, os_user_id";
+    let rows = sqlx::query(&filter.apply(q_str, "timestamp")).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let timestamp = Timestamp::from_db(row.get("timestamp"));
+        let timestamp = if rfc3339 {
+            timestamp.format_rfc_3339(timezone)
+        } else {
+            timestamp.format_iso_8601(timezone)
+        };
+        writer.write_record([row.get("username"), row.get("os_user_id"), timestamp])?;
+    }
+    Ok(())
+}
+
+/// The username (if any) for the OS user of a request.
+pub const USER_COLUMNS: &str = "usr.username as os_user_name";
+
+pub const USER_JOIN: &str = "left join os_users usr on usr.os_user_id = req.os_user_id";
+
+const OS_USERS_SCHEMA: &str = r#"
+    create table if not exists os_users (
+        os_user_id text not null unique,
+        username text not null,
+        timestamp integer not null
+    );"#;
+
+const CLEAR_ALL: &str = r#"
+    delete from os_users;
+    "#;
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_os_user_ids() {
+        assert_eq!(
+            super::os_user_id("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            super::login_names("EXAMPLE\\Abc"),
+            ["Abc", "EXAMPLE\\Abc", "abc", "example\\abc"]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Counts of how requests were answered, by request type and day.
+
+Each reply to a client adds one to the count of its outcome on the (UTC) day
+the request was received: served from the cache, served by Adobe, stored for
+later forwarding, or not served at all (because Adobe was throttling, failing,
+or unreachable and there was nothing cached).  A reply is counted once, even if
+its request is also sent upstream in the background or forwarded later, and
+requests that the proxy sends itself (such as canaries) aren't counted.
+Comparing the cache's share of responses on the days that Adobe or the WAN
+was down with its share on other days shows how much the cache saved.  Like
+the bandwidth totals, the counts are kept as they go, so they survive purges.
+ */
+use eyre::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+use adlu_parse::protocol::Request;
+
+use super::{ActivityBin, ReportFilter};
+use crate::proxy::RequestOutcome;
+
+/// How the requests of one type (on one day, or in all) were answered.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Effectiveness {
+    pub request_type: String,
+    pub cache_served: u64,
+    pub upstream_served: u64,
+    pub stored: u64,
+    pub unserved: u64,
+}
+
+impl Effectiveness {
+    /// The percentage of served requests that were served from the cache.
+    pub fn cache_share(&self) -> f64 {
+        let served = self.cache_served + self.upstream_served;
+        if served == 0 {
+            0.0
+        } else {
+            (self.cache_served * 100) as f64 / served as f64
+        }
+    }
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(EFFECTIVENESS_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn clear(pool: &SqlitePool) -> Result<()> {
+    sqlx::query("delete from effectiveness").execute(pool).await?;
+    eprintln!("Cache effectiveness counts have been cleared.");
+    Ok(())
+}
+
+pub async fn count_reply(
+    pool: &SqlitePool,
+    req: &Request,
+    outcome: &RequestOutcome,
+) -> Result<()> {
+    let column = match outcome {
+        RequestOutcome::CacheHit => "cache_served",
+        RequestOutcome::ForwardedSuccess => "upstream_served",
+        RequestOutcome::IsolatedStored => "stored",
+        RequestOutcome::UpstreamThrottled | RequestOutcome::UpstreamError => "unserved",
+    };
+    let i_str = format!(
+        r#"insert into effectiveness (day, request_type, {column}) values (?, ?, 1)
+        on conflict (day, request_type) do update set {column} = {column} + 1"#
+    );
+    let day = ActivityBin::Day.millis();
+    sqlx::query(&i_str)
+        .bind(req.timestamp.to_db() / day * day)
+        .bind(req.request_type.to_string())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The counts for each request type since the given day (or over all days).
+pub async fn totals(
+    pool: &SqlitePool,
+    since: Option<&Timestamp>,
+) -> Result<Vec<Effectiveness>> {
+    let q_str = r#"select request_type, sum(cache_served) as cache_served,
+            sum(upstream_served) as upstream_served, sum(stored) as stored,
+            sum(unserved) as unserved
+        from effectiveness where day >= ?
+        group by request_type order by request_type"#;
+    let since = since.map_or(0, Timestamp::to_db);
+    let rows = sqlx::query(q_str).bind(since).fetch_all(pool).await?;
+    Ok(rows.iter().map(effectiveness_from_row).collect())
+}
+
+/// The counts for today and for all time, by request type, as shown
+/// in the status endpoint.
+pub async fn status(pool: &SqlitePool) -> Result<Value> {
+    let day = ActivityBin::Day.millis();
+    let today = Timestamp::from_millis(Timestamp::now().to_millis() / day * day);
+    let to_json = |totals: Vec<Effectiveness>| -> Value {
+        totals
+            .into_iter()
+            .map(|e| {
+                let mut val = json!(e);
+                val["cacheSharePercent"] = json!((e.cache_share() * 10.0).round() / 10.0);
+                (e.request_type, val)
+            })
+            .collect::<serde_json::Map<String, Value>>()
+            .into()
+    };
+    Ok(json!({
+        "today": to_json(totals(pool, Some(&today)).await?),
+        "allTime": to_json(totals(pool, None).await?),
+    }))
+}
+
+/// The counts for each request type on each day.
+pub async fn report(pool: &SqlitePool, path: &str, filter: &ReportFilter) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Day (UTC)",
+        "Request Type",
+        "Served from Cache",
+        "Served by Adobe",
+        "Stored for Forwarding",
+        "Not Served",
+        "Cache Share (%)",
+    ])?;
+    let q_str = "select * from effectiveness order by day, request_type";
+    for row in sqlx::query(&filter.apply(q_str, "day")).fetch_all(pool).await?.iter() {
+        let day = Timestamp::from_db(row.get("day"));
+        let counts = effectiveness_from_row(row);
+        writer.write_record([
+            day.as_utc_datetime().format("%Y-%m-%d").to_string(),
+            counts.request_type.clone(),
+            counts.cache_served.to_string(),
+            counts.upstream_served.to_string(),
+            counts.stored.to_string(),
+            counts.unserved.to_string(),
+            format!("{:.1}", counts.cache_share()),
+        ])?;
+    }
+    Ok(())
+}
+
+fn effectiveness_from_row(row: &sqlx::sqlite::SqliteRow) -> Effectiveness {
+    let get = |name: &str| row.get::<i64, _>(name) as u64;
+    Effectiveness {
+        request_type: row.get("request_type"),
+        cache_served: get("cache_served"),
+        upstream_served: get("upstream_served"),
+        stored: get("stored"),
+        unserved: get("unserved"),
+    }
+}
+
+const EFFECTIVENESS_SCHEMA: &str = r#"
+    create table if not exists effectiveness (
+        day integer not null,
+        request_type text not null,
+        cache_served integer not null default 0,
+        upstream_served integer not null default 0,
+        stored integer not null default 0,
+        unserved integer not null default 0,
+        unique(day, request_type)
+    );"#;
+
+#[cfg(test)]
+mod tests {
+    use adlu_base::Timestamp;
+
+    use super::{count_reply, report, status};
+    use crate::proxy::RequestOutcome;
+
+    #[tokio::test]
+    async fn test_effectiveness() {
+        let dir = std::env::temp_dir().join("adlu-proxy-effectiveness-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("ef1");
+        let mut req = crate::testing::frl::mock_cache_activation_request(&body);
+        req.timestamp = Timestamp::from_millis(1664582400000);
+        count_reply(&pool, &req, &RequestOutcome::ForwardedSuccess).await.unwrap();
+        req.timestamp = Timestamp::from_millis(1664582400000 + 24 * 3600 * 1000);
+        count_reply(&pool, &req, &RequestOutcome::CacheHit).await.unwrap();
+        count_reply(&pool, &req, &RequestOutcome::CacheHit).await.unwrap();
+        count_reply(&pool, &req, &RequestOutcome::ForwardedSuccess).await.unwrap();
+        count_reply(&pool, &req, &RequestOutcome::UpstreamError).await.unwrap();
+        let status = status(&pool).await.unwrap();
+        let all_time = &status["allTime"]["FRL Activation"];
+        assert_eq!(all_time["cacheServed"], 2);
+        assert_eq!(all_time["upstreamServed"], 2);
+        assert_eq!(all_time["unserved"], 1);
+        assert_eq!(all_time["cacheSharePercent"], 50.0);
+        assert!(status["today"].as_object().unwrap().is_empty());
+        let csv_path = dir.join("effectiveness.csv");
+        report(&pool, csv_path.to_str().unwrap(), &Default::default()).await.unwrap();
+        let content = std::fs::read_to_string(&csv_path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3, "Wrong report: {}", content);
+        assert_eq!(lines[1], "2022-10-01,FRL Activation,0,1,0,0,0.0");
+        assert_eq!(lines[2], "2022-10-02,FRL Activation,2,1,0,1,66.7");
+        pool.close().await;
+    }
+}
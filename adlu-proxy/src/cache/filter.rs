@@ -0,0 +1,199 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Filters on the rows of a report.
+
+A filter is a conjunction of comparisons, such as
+`app_id=Photoshop1 && os_name=MAC && start>2024-01-01`.  Each comparison
+names a database column of the report's data, an operator (one of `=`, `!=`,
+`<`, `<=`, `>`, `>=`, or `~` for "contains"), and a value.  The name `start`
+stands for the time column of each datasource (e.g., the time of an
+activation or the start of a session).  Values that are dates
+(`2024-01-01`) or RFC-3339 times are compared as times, numbers as numbers,
+and anything else (optionally quoted) as text.
+
+Filters are evaluated by the database: each report query is wrapped in an
+outer query whose `where` clause is the filter.
+ */
+use eyre::{eyre, Result};
+
+/// A filter on the rows of a report.  The default filter matches every row.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportFilter {
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    column: String,
+    op: &'static str,
+    value: String,
+}
+
+const OPERATORS: [&str; 8] = ["==", "!=", "<=", ">=", "=", "<", ">", "~"];
+
+impl ReportFilter {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let mut conditions = vec![];
+        for term in expr.split("&&") {
+            conditions.push(Condition::parse(term.trim())?);
+        }
+        Ok(ReportFilter { conditions })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    /// Whether the filter only compares the given columns (and `start`),
+    /// so it can be applied to a query that has just those columns.
+    pub fn only_uses(&self, columns: &[&str]) -> bool {
+        self.conditions
+            .iter()
+            .all(|c| c.column == "start" || columns.contains(&c.column.as_str()))
+    }
+
+    /// Narrow the filter to the rows of one customer org.  Only reports
+    /// whose rows have an `org_id` column can be narrowed this way.
+    pub fn for_org(mut self, org_id: &str) -> Self {
+        let value = quote(org_id);
+        self.conditions.push(Condition { column: "org_id".to_string(), op: "=", value });
+        self
+    }
+
+    /// Wrap a report query so it only returns the rows matching the filter.
+    /// The `start` column of the filter is the given time column.
+    pub fn apply(&self, query: &str, time_column: &str) -> String {
+        if self.conditions.is_empty() {
+            return query.to_string();
+        }
+        let clauses: Vec<String> =
+            self.conditions.iter().map(|c| c.to_sql(time_column)).collect();
+        format!("select * from ({}) where {}", query, clauses.join(" and "))
+    }
+}
+
+impl Condition {
+    fn parse(term: &str) -> Result<Self> {
+        let pos = term
+            .find(|c| "=!<>~".contains(c))
+            .ok_or_else(|| eyre!("Filter term has no operator: '{}'", term))?;
+        let column = term[..pos].trim();
+        if column.is_empty()
+            || column.starts_with(|c: char| c.is_ascii_digit())
+            || !column.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(eyre!("Filter term has an invalid column name: '{}'", term));
+        }
+        let rest = &term[pos..];
+        let op = OPERATORS
+            .iter()
+            .find(|op| rest.starts_with(*op))
+            .ok_or_else(|| eyre!("Filter term has an invalid operator: '{}'", term))?;
+        let value = rest[op.len()..].trim();
+        if value.is_empty() {
+            return Err(eyre!("Filter term has no value: '{}'", term));
+        }
+        let op = if *op == "==" { "=" } else { op };
+        let value = if op == "~" { quote(unquote(value)) } else { literal(value) };
+        Ok(Condition { column: column.to_ascii_lowercase(), op, value })
+    }
+
+    fn to_sql(&self, time_column: &str) -> String {
+        let column = if self.column == "start" && !time_column.is_empty() {
+            time_column
+        } else {
+            &self.column
+        };
+        // bracketed, because SQLite takes an unknown double-quoted name as a string
+        if self.op == "~" {
+            format!("instr([{}], {}) > 0", column, self.value)
+        } else {
+            format!("[{}] {} {}", column, self.op, self.value)
+        }
+    }
+}
+
+/// The SQL literal for a filter value.  Times are stored as epoch millis.
+fn literal(value: &str) -> String {
+    if value.parse::<i64>().is_ok() {
+        return value.to_string();
+    }
+    if let Ok(val) = value.parse::<f64>() {
+        if val.is_finite() && value.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return value.to_string();
+        }
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        if let Some(time) = date.and_hms_opt(0, 0, 0) {
+            return time.timestamp_millis().to_string();
+        }
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return time.timestamp_millis().to_string();
+    }
+    quote(unquote(value))
+}
+
+fn unquote(value: &str) -> &str {
+    for q in ['\'', '"'] {
+        if value.len() >= 2 && value.starts_with(q) && value.ends_with(q) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        let filter = ReportFilter::parse(
+            "app_id=Photoshop1 && os_name == 'MAC' && start>2024-01-01",
+        )
+        .unwrap();
+        assert_eq!(
+            filter.apply("select * from t", "timestamp"),
+            "select * from (select * from t) where [app_id] = 'Photoshop1' \
+            and [os_name] = 'MAC' and [timestamp] > 1704067200000"
+        );
+        let filter = ReportFilter::parse("message ~ it's && count<=3").unwrap();
+        assert_eq!(
+            filter.apply("q", ""),
+            "select * from (q) where instr([message], 'it''s') > 0 and [count] <= 3"
+        );
+        assert!(ReportFilter::default().apply("q", "timestamp").eq("q"));
+        assert!(ReportFilter::parse("app_id").is_err());
+        assert!(ReportFilter::parse("app_id=").is_err());
+        assert!(ReportFilter::parse("app id=x").is_err());
+        assert!(ReportFilter::parse("1=1; drop table x").is_err());
+        assert!(ReportFilter::parse("a=1 && ").is_err());
+        let filter = ReportFilter::parse("app_id=x").unwrap().for_org("Acme's");
+        assert_eq!(
+            filter.apply("q", ""),
+            "select * from (q) where [app_id] = 'x' and [org_id] = 'Acme''s'"
+        );
+    }
+}
@@ -16,19 +16,30 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
 use eyre::{eyre, Result, WrapErr};
-use log::debug;
+use log::{debug, info};
 use sqlx::{
     sqlite::{SqlitePool, SqliteRow},
-    Row,
+    Column, Row, Sqlite, Transaction,
 };
 
-use crate::proxy::{Request, RequestType, Response};
+use crate::proxy::{Request, RequestOutcome, RequestType, Response};
 use adlu_base::Timestamp;
 use adlu_parse::protocol::{
     FrlActivationRequestBody, FrlAppDetails, FrlDeactivationQueryParams, FrlDeviceDetails,
 };
 
+use super::directory::{USER_COLUMNS, USER_JOIN};
+use super::integrity::{self, Seal};
+use super::location::{self, location_from_row};
+use super::notes;
+use super::orgs;
+use super::schema_upgrade;
+use super::ReportFilter;
+
 pub async fn clear(pool: &SqlitePool) -> Result<()> {
     let mut tx = pool.begin().await?;
     sqlx::query(CLEAR_ALL).execute(&mut tx).await?;
@@ -37,77 +48,607 @@ pub async fn clear(pool: &SqlitePool) -> Result<()> {
     Ok(())
 }
 
-pub async fn import(pool: &SqlitePool, path: &str) -> Result<()> {
+/// Import the forwarded pairs from an export.  If the export is sealed, its
+/// tables are checked first: an export with damaged tables is refused, unless
+/// `skip_damaged` is set, in which case only the pairs whose tables are
+/// intact are imported.
+pub async fn import(pool: &SqlitePool, path: &str, skip_damaged: bool) -> Result<()> {
     std::fs::metadata(path)?;
-    // first read the forwarded pairs
-    let in_pool = super::db_init(path, "rw").await?;
+    let in_pool = super::db_init(path, "rw", 1).await?;
     db_init(&in_pool).await?;
-    let activations = fetch_answered_activations(&in_pool).await?;
-    let deactivations = fetch_answered_deactivations(&in_pool).await?;
-    let total = activations.len() + deactivations.len();
+    let damage = match integrity::verify(&in_pool).await {
+        Ok(Seal::Unsealed) => {
+            eprintln!("{path} has no checksums, so it can't be checked for damage");
+            vec![]
+        }
+        Ok(Seal::Intact) => {
+            eprintln!("Verified the checksums of {path}");
+            vec![]
+        }
+        Ok(Seal::Damaged(damage)) => damage,
+        Err(err) => {
+            in_pool.close().await;
+            return Err(err.wrap_err(format!("Can't check the checksums of {path}")));
+        }
+    };
+    for damaged in damage.iter() {
+        eprintln!("Damaged table in {path}: {damaged}");
+    }
+    if !damage.is_empty() && !skip_damaged {
+        in_pool.close().await;
+        return Err(eyre!(
+            "{} of {} table(s) are damaged (use --skip-damaged to import the rest)",
+            damage.len(),
+            integrity::SEALED_TABLES.len()
+        ));
+    }
+    let is_damaged = |prefix: &str| damage.iter().any(|d| d.table.starts_with(prefix));
+    // then read the forwarded pairs whose tables are intact
+    let activations = if is_damaged("activation_") {
+        eprintln!("Skipping the activations in {path}, because they are damaged");
+        vec![]
+    } else {
+        fetch_answered_activations(&in_pool).await?
+    };
+    let deactivations = if is_damaged("deactivation_") {
+        eprintln!("Skipping the deactivations in {path}, because they are damaged");
+        vec![]
+    } else {
+        fetch_answered_deactivations(&in_pool).await?
+    };
+    in_pool.close().await;
+    import_pairs(pool, &activations, &deactivations).await?;
+    eprintln!("Completed import of request/response pairs from {path}");
+    Ok(())
+}
+
+/// Import from the cache of the frl-online-proxy that this proxy replaces.
+/// Its database has the same columns as ours, less the ones we've added
+/// since, but its tables have different names and its timestamps were
+/// stored as device dates rather than epoch millis.  Legacy pairs have
+/// no dedupe keys, so they are keyed by request id.
+pub async fn import_legacy(pool: &SqlitePool, path: &str) -> Result<()> {
+    std::fs::metadata(path)?;
+    let in_pool = SqlitePool::connect(&format!("sqlite:{}?mode=ro", path)).await?;
+    let q_str = "select 1 from sqlite_master where type = 'table' and name = ?";
+    for table in ["activation_request_log", "deactivation_request_log"] {
+        if sqlx::query(q_str).bind(table).fetch_optional(&in_pool).await?.is_none() {
+            in_pool.close().await;
+            return Err(eyre!("Not an frl-online-proxy cache: {}", path));
+        }
+    }
+    let activations = fetch_legacy_activations(&in_pool).await?;
+    let deactivations = fetch_legacy_deactivations(&in_pool).await?;
     in_pool.close().await;
+    import_pairs(pool, &activations, &deactivations).await?;
+    eprintln!("Completed import of legacy request/response pairs from {path}");
+    Ok(())
+}
+
+/// Import answered requests, each list sorted in timestamp order.
+async fn import_pairs(
+    pool: &SqlitePool,
+    activations: &[KeyedRequest],
+    deactivations: &[KeyedRequest],
+) -> Result<()> {
+    let total = activations.len() + deactivations.len();
     eprintln!("Found {} forwarded request/response pair(s) to import", total);
+    // Pairs are identified by their dedupe keys, and we remember which keys
+    // we have imported, so importing the same pairs again has no effect.
+    let (mut new, mut present, mut conflicts) = (0u64, 0u64, 0u64);
     // now add them to the cache:
     // the activations and deactivations are each sorted in timestamp order.
     // we need to do a merge of the two in timestamp order, because activations
-    // and deactivations interact with each other.  The assumption here is that
-    // there are no answered requests in the local database that might conflict
-    // with what we are getting from the imported set.  If that turns out to not
-    // be true for some situations, more code will be needed here to check for that.
+    // and deactivations interact with each other.  The local database may
+    // already have answers for some of the same activations or deactivations
+    // (for example, if a device was deactivated while the requests were being
+    // forwarded elsewhere).  Those conflicts are settled by timestamp, so the
+    // latest answer wins, and each one is recorded for the conflicts report.
     let mut acts = activations.iter();
     let mut deacts = deactivations.iter();
     let mut act = acts.next();
     let mut deact = deacts.next();
     loop {
-        if let Some(actp) = act {
+        let pair = if let Some(actp) = act {
             if let Some(deactp) = deact {
-                if actp.0.timestamp <= deactp.0.timestamp {
-                    store_activation_request(pool, &actp.0).await?;
-                    store_activation_response(pool, &actp.0, &actp.1).await?;
+                if actp.req.timestamp <= deactp.req.timestamp {
                     act = acts.next();
+                    actp
                 } else {
-                    store_deactivation_request(pool, &deactp.0).await?;
-                    store_deactivation_response(pool, &deactp.0, &deactp.1).await?;
                     deact = deacts.next();
+                    deactp
                 }
             } else {
-                store_activation_request(pool, &actp.0).await?;
-                store_activation_response(pool, &actp.0, &actp.1).await?;
                 act = acts.next();
+                actp
             }
         } else if let Some(deactp) = deact {
-            store_deactivation_request(pool, &deactp.0).await?;
-            store_deactivation_response(pool, &deactp.0, &deactp.1).await?;
             deact = deacts.next();
+            deactp
         } else {
             break;
+        };
+        match import_pair(pool, pair).await? {
+            PairImport::New => new += 1,
+            PairImport::Present => present += 1,
+            PairImport::Replaced => {
+                new += 1;
+                conflicts += 1;
+            }
+            PairImport::Stale => {
+                present += 1;
+                conflicts += 1;
+            }
         }
     }
-    eprintln!("Completed import of request/response pairs from {path}");
+    eprintln!("Imported {} new pair(s); {} pair(s) were already present", new, present);
+    if conflicts > 0 {
+        eprintln!(
+            "Resolved {} conflict(s) with cached responses by timestamp \
+            (see the conflicts report for details)",
+            conflicts
+        );
+    }
+    Ok(())
+}
+
+/// A cached request, its response (if it's been answered), and its dedupe key.
+struct KeyedRequest {
+    key: String,
+    req: Request,
+    resp: Option<Response>,
+}
+
+/// What happened when a pair was imported.
+enum PairImport {
+    /// The pair was imported.
+    New,
+    /// The pair had been imported before.
+    Present,
+    /// The pair was imported over an older cached response.
+    Replaced,
+    /// The pair was skipped because a newer cached response conflicts with it.
+    Stale,
+}
+
+/// Import an answered request, unless it has been imported before
+/// or a cached response for the same device is newer than it is.
+async fn import_pair(pool: &SqlitePool, pair: &KeyedRequest) -> Result<PairImport> {
+    let q_str = "select 1 from imported_keys where dedupe_key = ?";
+    if sqlx::query(q_str).bind(&pair.key).fetch_optional(pool).await?.is_some() {
+        debug!("Skipping import of {} with key: {}", &pair.req, &pair.key);
+        return Ok(PairImport::Present);
+    }
+    let resp =
+        pair.resp.as_ref().ok_or_else(|| eyre!("{} has no response", &pair.req))?;
+    let conflict = find_conflict(pool, &pair.req).await?;
+    let stale = matches!(&conflict, Some(c) if c.cached_timestamp > pair.req.timestamp);
+    if let Some(conflict) = &conflict {
+        record_conflict(pool, pair, conflict, stale).await?;
+    }
+    if !stale {
+        let key = Some(pair.key.as_str());
+        if let RequestType::FrlActivation = pair.req.request_type {
+            insert_activation_request(pool, &pair.req, key).await?;
+            insert_activation_response(pool, &pair.req, resp, key).await?;
+        } else {
+            insert_deactivation_request(pool, &pair.req, key).await?;
+            insert_deactivation_response(pool, &pair.req, resp, key).await?;
+        }
+    } else {
+        info!("Kept the newer cached response instead of importing {}", &pair.req);
+    }
+    // stale pairs count as imported, so importing again doesn't re-report them
+    let i_str = "insert or ignore into imported_keys (dedupe_key) values (?)";
+    sqlx::query(i_str).bind(&pair.key).execute(pool).await?;
+    Ok(match (conflict, stale) {
+        (None, _) => PairImport::New,
+        (Some(_), false) => PairImport::Replaced,
+        (Some(_), true) => PairImport::Stale,
+    })
+}
+
+/// The newest cached response that an imported request would contradict.
+struct Conflict {
+    package_id: String,
+    device_id: String,
+    cached_type: String,
+    cached_timestamp: Timestamp,
+}
+
+/// Find the newest cached response that answers the same request as an
+/// imported one, but at a different time, or that answers the opposite
+/// request for the same device later than the imported one.  (Older
+/// answers to the opposite request are just earlier state, which the
+/// import will replace as usual.)
+async fn find_conflict(pool: &SqlitePool, req: &Request) -> Result<Option<Conflict>> {
+    let is_activation = matches!(req.request_type, RequestType::FrlActivation);
+    let (package_id, device_id, a_key, d_key) = if is_activation {
+        let body = req.body.as_ref().ok_or_else(|| eyre!("{} has no body", req))?;
+        let parse =
+            FrlActivationRequestBody::from_body(body).wrap_err(req.to_string())?;
+        let (a_key, d_key) = (parse.activation_id(), parse.deactivation_id());
+        (parse.npd_id, parse.device_details.device_id, a_key, d_key)
+    } else {
+        let query = req.query.as_ref().ok_or_else(|| eyre!("{} has no query", req))?;
+        let parse =
+            FrlDeactivationQueryParams::from_query(query).wrap_err(req.to_string())?;
+        let d_key = parse.deactivation_id();
+        (parse.npd_id, parse.device_id, String::new(), d_key)
+    };
+    let q_str = if is_activation {
+        r#"select 'Activation' as kind, timestamp from activation_responses
+                where activation_key = ?1 and timestamp != ?3
+            union all
+            select 'Deactivation' as kind, timestamp from deactivation_responses
+                where deactivation_key = ?2 and timestamp > ?3
+            order by timestamp desc limit 1"#
+    } else {
+        r#"select 'Activation' as kind, timestamp from activation_responses
+                where deactivation_key = ?2 and timestamp > ?3
+            union all
+            select 'Deactivation' as kind, timestamp from deactivation_responses
+                where deactivation_key = ?2 and timestamp != ?3
+            order by timestamp desc limit 1"#
+    };
+    let row = sqlx::query(q_str)
+        .bind(&a_key)
+        .bind(&d_key)
+        .bind(req.timestamp.to_db())
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|row| Conflict {
+        package_id,
+        device_id,
+        cached_type: row.get("kind"),
+        cached_timestamp: Timestamp::from_db(row.get("timestamp")),
+    }))
+}
+
+async fn record_conflict(
+    pool: &SqlitePool,
+    pair: &KeyedRequest,
+    conflict: &Conflict,
+    stale: bool,
+) -> Result<()> {
+    let imported_type = if let RequestType::FrlActivation = pair.req.request_type {
+        "Activation"
+    } else {
+        "Deactivation"
+    };
+    let resolution = if stale { "Kept Cached" } else { "Imported" };
+    debug!(
+        "{} response for {} conflicts with cached {} response: {}",
+        imported_type, &pair.req, conflict.cached_type, resolution
+    );
+    let i_str = r#"
+        insert into import_conflicts (
+            timestamp, package_id, device_id, imported_type, imported_timestamp,
+            cached_type, cached_timestamp, resolution, dedupe_key
+        ) values (?, ?, ?, ?, ?, ?, ?, ?, ?)"#;
+    sqlx::query(i_str)
+        .bind(Timestamp::now().to_db())
+        .bind(&conflict.package_id)
+        .bind(&conflict.device_id)
+        .bind(imported_type)
+        .bind(pair.req.timestamp.to_db())
+        .bind(&conflict.cached_type)
+        .bind(conflict.cached_timestamp.to_db())
+        .bind(resolution)
+        .bind(&pair.key)
+        .execute(pool)
+        .await?;
     Ok(())
 }
 
-pub async fn export(pool: &SqlitePool, path: &str) -> Result<()> {
+/// Export the unanswered requests, or (if there's an org) just the
+/// unanswered requests for that org's packages.
+pub async fn export(pool: &SqlitePool, path: &str, org_id: Option<&str>) -> Result<()> {
     if std::fs::metadata(path).is_ok() {
         return Err(eyre!("Cannot export to an existing file: {}", path));
     }
     // first read the unanswered requests
     let in_pool = pool;
-    let activations = fetch_unanswered_activations(in_pool).await?;
-    let deactivations = fetch_unanswered_deactivations(in_pool).await?;
+    let mut activations = fetch_unanswered_activations(in_pool).await?;
+    let mut deactivations = fetch_unanswered_deactivations(in_pool).await?;
+    if let Some(org_id) = org_id {
+        let q_str = r#"select dedupe_key from activation_requests where org_id = ?1
+            union select dedupe_key from deactivation_requests where org_id = ?1"#;
+        let rows = sqlx::query(q_str).bind(org_id).fetch_all(in_pool).await?;
+        let keys: HashSet<String> =
+            rows.iter().map(|row| row.get("dedupe_key")).collect();
+        activations.retain(|act| keys.contains(&act.key));
+        deactivations.retain(|deact| keys.contains(&deact.key));
+    }
     let total = activations.len() + deactivations.len();
     eprintln!("Found {} unanswered request(s) to export", total);
     // now store them to the export database
-    let out_pool = super::db_init(path, "rwc").await?;
+    let out_pool = super::db_init(path, "rwc", 1).await?;
     db_init(&out_pool).await?;
+    // exported requests keep their dedupe keys, so their responses can be
+    // matched up with them when they are imported
     for act in activations.iter() {
-        store_activation_request(&out_pool, act).await?;
+        insert_activation_request(&out_pool, &act.req, Some(&act.key)).await?;
     }
     for deact in deactivations.iter() {
-        store_deactivation_request(&out_pool, deact).await?;
+        insert_deactivation_request(&out_pool, &deact.req, Some(&deact.key)).await?;
     }
+    integrity::seal(&out_pool).await?;
     out_pool.close().await;
-    eprintln!("Completed export of request(s) to {path}");
+    eprintln!("Completed export of request(s), with checksums, to {path}");
+    Ok(())
+}
+
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record(report_headers(timezone))?;
+    let q_str = |table: &str| {
+        format!(
+            "select req.*, {PACKAGE_COLUMNS}, {USER_COLUMNS}, {} from {table} req \
+            {PACKAGE_JOIN} {USER_JOIN}",
+            notes::notes_column("req")
+        )
+    };
+    let a_str = filter.apply(&q_str("activation_requests"), "timestamp");
+    let mut rows = sqlx::query(&a_str).fetch_all(pool).await?;
+    // deactivations don't have the app and os details that activations do
+    let d_str = q_str("deactivation_requests");
+    if filterable(pool, &d_str, filter).await? {
+        let d_str = filter.apply(&d_str, "timestamp");
+        rows.extend(sqlx::query(&d_str).fetch_all(pool).await?);
+    }
+    let mut records: Vec<(Timestamp, Vec<String>)> = rows
+        .iter()
+        .map(|row| {
+            let timestamp = Timestamp::from_db(row.get("timestamp"));
+            let record = report_record(row, &timestamp, timezone, rfc3339);
+            (timestamp, record)
+        })
+        .collect();
+    // nor do the outcomes of requests that aren't stored
+    if filterable(pool, ORPHAN_OUTCOMES, filter).await? {
+        let rows = sqlx::query(&filter.apply(ORPHAN_OUTCOMES, "timestamp"))
+            .fetch_all(pool)
+            .await?;
+        let headers = report_headers(timezone);
+        records.extend(rows.iter().map(|row| {
+            let timestamp = Timestamp::from_db(row.get("timestamp"));
+            let record = outcome_record(row, &headers, &timestamp, timezone, rfc3339);
+            (timestamp, record)
+        }));
+    }
+    records.sort_by_key(|(timestamp, _)| timestamp.to_millis());
+    for (_, record) in records {
+        writer.write_record(record)?;
+    }
+    Ok(())
+}
+
+fn report_headers(timezone: bool) -> Vec<String> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut result = vec![];
+    result.push("Request Type".to_string());
+    result.push(format!("Timestamp{time_suffix}"));
+    result.push("Package ID".to_string());
+    result.push("Package Name".to_string());
+    result.push("Deployment Mode".to_string());
+    result.push("Precedence".to_string());
+    result.push("Org ID".to_string());
+    result.push("Device ID".to_string());
+    result.push("OS User ID".to_string());
+    result.push("OS User Name".to_string());
+    result.push("VDI Marker".to_string());
+    result.push("Virtual Environment".to_string());
+    result.push("Domain User".to_string());
+    result.push("Keyed By".to_string());
+    result.push("App ID".to_string());
+    result.push("App Version".to_string());
+    result.push("NGL Version".to_string());
+    result.push("OS Name".to_string());
+    result.push("OS Version".to_string());
+    result.extend(location::report_headers());
+    result.push("Outcome".to_string());
+    result.push("Refresh Count".to_string());
+    result.push("Correlation ID".to_string());
+    result.push("Notes".to_string());
+    result
+}
+
+fn report_record(
+    row: &SqliteRow,
+    timestamp: &Timestamp,
+    timezone: bool,
+    rfc3339: bool,
+) -> Vec<String> {
+    // deactivation rows have no app or os details
+    let optional = |name: &str| -> String { row.try_get(name).unwrap_or_default() };
+    let refresh_count: Option<i64> = row.try_get("refresh_count").ok();
+    // packages whose metadata hasn't been imported have no name
+    let precedence: Option<i64> = row.try_get("npd_precedence").unwrap_or_default();
+    let request_type = match refresh_count {
+        Some(0) => "Activation",
+        Some(_) => "Refresh",
+        None => "Deactivation",
+    };
+    let timestamp = if rfc3339 {
+        timestamp.format_rfc_3339(timezone)
+    } else {
+        timestamp.format_iso_8601(timezone)
+    };
+    let flag = |name: &str| -> bool { row.get(name) };
+    let yes_no = |val: bool| -> String { if val { "Yes" } else { "No" }.to_string() };
+    // NGL keys by os user only when both VDI flags are set (see `deactivation_id`)
+    let keyed_by =
+        if flag("is_vdi") && flag("is_virtual") { "OS User" } else { "Device" };
+    let mut result = vec![
+        request_type.to_string(),
+        timestamp,
+        row.get("package_id"),
+        optional("package_name"),
+        optional("deployment_mode"),
+        precedence.map_or_else(String::new, |p| p.to_string()),
+        row.get("org_id"),
+        row.get("device_id"),
+        row.get("os_user_id"),
+        optional("os_user_name"),
+        yes_no(flag("is_vdi")),
+        yes_no(flag("is_virtual")),
+        yes_no(flag("is_domain_user")),
+        keyed_by.to_string(),
+        optional("app_id"),
+        optional("app_version"),
+        optional("ngl_version"),
+        optional("os_name"),
+        optional("os_version"),
+    ];
+    result.extend(location::report_record(&location_from_row(row)));
+    result.push(row.get("outcome"));
+    result.push(refresh_count.map_or_else(String::new, |count| count.to_string()));
+    result.push(row.get("correlation_id"));
+    result.push(row.get::<Option<String>, _>("notes").unwrap_or_default());
+    result
+}
+
+/// Whether the rows of a query have all the columns a filter compares.
+/// (A query with no rows is never worth filtering.)
+async fn filterable(
+    pool: &SqlitePool,
+    q_str: &str,
+    filter: &ReportFilter,
+) -> Result<bool> {
+    let q_str = format!("select * from ({q_str}) limit 1");
+    match sqlx::query(&q_str).fetch_optional(pool).await? {
+        Some(row) => {
+            let columns: Vec<&str> = row.columns().iter().map(|c| c.name()).collect();
+            Ok(filter.only_uses(&columns))
+        }
+        None => Ok(false),
+    }
+}
+
+/// The report record for the outcome of a request that isn't stored, which
+/// only has the columns that the outcomes table knows.
+fn outcome_record(
+    row: &SqliteRow,
+    headers: &[String],
+    timestamp: &Timestamp,
+    timezone: bool,
+    rfc3339: bool,
+) -> Vec<String> {
+    let timestamp = if rfc3339 {
+        timestamp.format_rfc_3339(timezone)
+    } else {
+        timestamp.format_iso_8601(timezone)
+    };
+    let package_name: Option<String> = row.get("package_name");
+    let values = [
+        ("Request Type", row.get("request_type")),
+        ("Package ID", row.get("package_id")),
+        ("Package Name", package_name.unwrap_or_default()),
+        ("Device ID", row.get("device_id")),
+        ("OS User ID", row.get("os_user_id")),
+        ("Outcome", row.get("outcome")),
+        ("Correlation ID", row.get("correlation_id")),
+    ];
+    let mut result = vec![String::new(); headers.len()];
+    result[1] = timestamp;
+    for (header, value) in values {
+        if let Some(i) = headers.iter().position(|h| h == header) {
+            result[i] = value;
+        }
+    }
+    result
+}
+
+/// Summarize, for each package, how many activations were keyed by
+/// os user (VDI seats) versus by device (physical machines).
+pub async fn vdi_report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Package ID",
+        "VDI Activations",
+        "Physical Activations",
+        "Distinct VDI OS Users",
+        "Distinct Physical Devices",
+    ])?;
+    // refreshes are stored under their own key, so only count initial activations
+    let q_str = r#"
+        select package_id,
+            sum(is_vdi and is_virtual) as vdi_activations,
+            sum(not (is_vdi and is_virtual)) as physical_activations,
+            count(distinct case when is_vdi and is_virtual then os_user_id end)
+                as vdi_users,
+            count(distinct case when is_vdi and is_virtual then null else device_id end)
+                as physical_devices
+        from activation_requests where current_asnp_id = ''
+        group by package_id order by package_id"#;
+    let rows = sqlx::query(&filter.apply(q_str, "")).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let count = |name: &str| -> String { row.get::<i64, _>(name).to_string() };
+        writer.write_record([
+            row.get("package_id"),
+            count("vdi_activations"),
+            count("physical_activations"),
+            count("vdi_users"),
+            count("physical_devices"),
+        ])?;
+    }
+    Ok(())
+}
+
+/// List the conflicts between imported and cached responses,
+/// and how each was resolved.
+pub async fn conflict_report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        format!("Resolved{time_suffix}"),
+        "Package ID".to_string(),
+        "Device ID".to_string(),
+        "Imported Type".to_string(),
+        format!("Imported Timestamp{time_suffix}"),
+        "Cached Type".to_string(),
+        format!("Cached Timestamp{time_suffix}"),
+        "Resolution".to_string(),
+    ])?;
+    let q_str = "select * from import_conflicts order by timestamp, rowid";
+    let rows = sqlx::query(&filter.apply(q_str, "timestamp")).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let time = |name: &str| -> String {
+            let timestamp = Timestamp::from_db(row.get(name));
+            if rfc3339 {
+                timestamp.format_rfc_3339(timezone)
+            } else {
+                timestamp.format_iso_8601(timezone)
+            }
+        };
+        writer.write_record([
+            time("timestamp"),
+            row.get("package_id"),
+            row.get("device_id"),
+            row.get("imported_type"),
+            time("imported_timestamp"),
+            row.get("cached_type"),
+            time("cached_timestamp"),
+            row.get("resolution"),
+        ])?;
+    }
     Ok(())
 }
 
@@ -123,19 +664,19 @@ pub async fn fetch_unanswered_requests(pool: &SqlitePool) -> Result<Vec<Request>
     loop {
         if let Some(actr) = act {
             if let Some(deactr) = deact {
-                if actr.timestamp <= deactr.timestamp {
-                    result.push(actr.clone());
+                if actr.req.timestamp <= deactr.req.timestamp {
+                    result.push(actr.req.clone());
                     act = acts.next();
                 } else {
-                    result.push(deactr.clone());
+                    result.push(deactr.req.clone());
                     deact = deacts.next();
                 }
             } else {
-                result.push(actr.clone());
+                result.push(actr.req.clone());
                 act = acts.next();
             }
         } else if let Some(deactr) = deact {
-            result.push(deactr.clone());
+            result.push(deactr.req.clone());
             deact = deacts.next();
         } else {
             break;
@@ -149,31 +690,209 @@ pub async fn db_init(pool: &SqlitePool) -> Result<()> {
     sqlx::query(DEACTIVATION_REQUEST_SCHEMA).execute(pool).await?;
     sqlx::query(ACTIVATION_RESPONSE_SCHEMA).execute(pool).await?;
     sqlx::query(DEACTIVATION_RESPONSE_SCHEMA).execute(pool).await?;
+    sqlx::query(IMPORTED_KEYS_SCHEMA).execute(pool).await?;
+    sqlx::query(IMPORT_CONFLICTS_SCHEMA).execute(pool).await?;
+    sqlx::query(REQUEST_OUTCOMES_SCHEMA).execute(pool).await?;
+    schema_upgrade("frl", FRL_SCHEMA_VERSION, &SCHEMA_ALTERATIONS_BY_VERSION, pool)
+        .await?;
     Ok(())
 }
 
+/// The number of devices holding activations for a package,
+/// and whether the given device is one of them.
+pub async fn package_usage(
+    pool: &SqlitePool,
+    npd_id: &str,
+    device_id: &str,
+) -> Result<(u64, bool)> {
+    let q_str = r#"select count(distinct device_id) from activation_requests
+        where package_id = ?"#;
+    let count: i64 = sqlx::query(q_str).bind(npd_id).fetch_one(pool).await?.get(0);
+    let q_str = r#"select count(*) from activation_requests
+        where package_id = ? and device_id = ?"#;
+    let mine: i64 =
+        sqlx::query(q_str).bind(npd_id).bind(device_id).fetch_one(pool).await?.get(0);
+    Ok((count as u64, mine > 0))
+}
+
+/// The number of devices holding activations for any package of an org,
+/// and whether the given device is one of them.
+pub async fn org_usage(
+    pool: &SqlitePool,
+    org_id: &str,
+    device_id: &str,
+) -> Result<(u64, bool)> {
+    let q_str = r#"select count(distinct device_id) from activation_requests
+        where org_id = ?"#;
+    let count: i64 = sqlx::query(q_str).bind(org_id).fetch_one(pool).await?.get(0);
+    let q_str = r#"select count(*) from activation_requests
+        where org_id = ? and device_id = ?"#;
+    let mine: i64 =
+        sqlx::query(q_str).bind(org_id).bind(device_id).fetch_one(pool).await?.get(0);
+    Ok((count as u64, mine > 0))
+}
+
+/// The (package, device) pairs whose latest activation has not been
+/// followed by a deactivation.
+pub async fn activated_devices(pool: &SqlitePool) -> Result<Vec<(String, String)>> {
+    let q_str = r#"select distinct package_id, device_id from activation_requests a
+        where not exists (select 1 from deactivation_requests d
+            where d.package_id = a.package_id and d.device_id = a.device_id
+            and d.timestamp >= a.timestamp)
+        order by package_id, device_id"#;
+    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    Ok(rows.iter().map(|row| (row.get("package_id"), row.get("device_id"))).collect())
+}
+
+/// A license that a device still holds: its latest activation of a package,
+/// which hasn't been followed by a deactivation.
+#[derive(Debug, Clone, Default)]
+pub struct HeldLicense {
+    pub package_id: String,
+    pub package_name: String,
+    pub org_id: String,
+    pub device_id: String,
+    pub os_user_id: String,
+    pub os_name: String,
+    pub os_version: String,
+    pub app_id: String,
+    pub app_version: String,
+    pub activated: Timestamp,
+}
+
+/// The licenses that devices hold, or (if there's an org) just the ones for
+/// that org's packages, sorted by package and device.
+pub async fn held_licenses(
+    pool: &SqlitePool,
+    org_id: Option<&str>,
+) -> Result<Vec<HeldLicense>> {
+    let q_str = format!(
+        r#"select req.*, {PACKAGE_COLUMNS} from activation_requests req {PACKAGE_JOIN}
+        where req.timestamp = (select max(a.timestamp) from activation_requests a
+                where a.package_id = req.package_id and a.device_id = req.device_id)
+            and not exists (select 1 from deactivation_requests d
+                where d.package_id = req.package_id and d.device_id = req.device_id
+                and d.timestamp >= req.timestamp)
+            and (?1 is null or req.org_id = ?1)
+        order by req.package_id, req.device_id"#
+    );
+    let rows = sqlx::query(&q_str).bind(org_id).fetch_all(pool).await?;
+    let licenses = rows
+        .iter()
+        .map(|row| HeldLicense {
+            package_id: row.get("package_id"),
+            package_name: row
+                .get::<Option<String>, _>("package_name")
+                .unwrap_or_default(),
+            org_id: row.get("org_id"),
+            device_id: row.get("device_id"),
+            os_user_id: row.get("os_user_id"),
+            os_name: row.get("os_name"),
+            os_version: row.get("os_version"),
+            app_id: row.get("app_id"),
+            app_version: row.get("app_version"),
+            activated: Timestamp::from_db(row.get("timestamp")),
+        })
+        .collect();
+    Ok(licenses)
+}
+
+/// A deactivation that returns the license a device holds for a package,
+/// built from the device's most recent activation of the package.  Returns
+/// `None` if the device has never been seen to activate the package.
+pub async fn deactivation_for_device(
+    pool: &SqlitePool,
+    npd_id: &str,
+    device_id: &str,
+) -> Result<Option<Request>> {
+    let q_str = r#"select * from activation_requests
+        where package_id = ? and device_id = ?
+        order by timestamp desc limit 1"#;
+    let row =
+        sqlx::query(q_str).bind(npd_id).bind(device_id).fetch_optional(pool).await?;
+    Ok(row.map(|row| {
+        let params = FrlDeactivationQueryParams {
+            npd_id: row.get("package_id"),
+            device_id: row.get("device_id"),
+            os_user_id: row.get("os_user_id"),
+            enable_vdi_marker_exists: row.get("is_vdi"),
+            is_virtual_environment: row.get("is_virtual"),
+            is_os_user_account_in_domain: row.get("is_domain_user"),
+        };
+        let correlation_id = Request::new_correlation_id();
+        Request {
+            timestamp: Timestamp::now(),
+            request_type: RequestType::FrlDeactivation,
+            source_ip: None,
+            method: http::Method::DELETE,
+            host: None,
+            path: "/asnp/frl_connected/v1".to_string(),
+            query: Some(params.to_query()),
+            body: None,
+            content_type: None,
+            accept_type: Some("application/json".to_string()),
+            accept_language: Some("en_US".to_string()),
+            user_agent: Some(crate::proxy::proxy_id()),
+            via: None,
+            api_key: Some(crate::security::TOOLKIT_API_KEY.to_string()),
+            request_id: Some(format!("uninstall-{}", &correlation_id)),
+            session_id: None,
+            authorization: None,
+            correlation_id,
+        }
+    }))
+}
+
 pub async fn store_activation_request(pool: &SqlitePool, req: &Request) -> Result<()> {
+    insert_activation_request(pool, req, None).await
+}
+
+/// Store an activation request with the given dedupe key, or
+/// with a new key if it's being stored for the first time.
+async fn insert_activation_request(
+    pool: &SqlitePool,
+    req: &Request,
+    key: Option<&str>,
+) -> Result<()> {
     let body = req.body.as_ref().ok_or_else(|| eyre!("{} has no body", req))?;
     let parse = FrlActivationRequestBody::from_body(body).wrap_err(req.to_string())?;
     let field_list = r#"
         (
             activation_key, deactivation_key, api_key, request_id, session_id, device_date,
             package_id, asnp_id, device_id, os_user_id, is_vdi, is_domain_user, is_virtual,
-            os_name, os_version, app_id, app_version, ngl_version, timestamp
+            os_name, os_version, app_id, app_version, ngl_version, timestamp,
+            current_asnp_id, refresh_count, correlation_id, dedupe_key, org_id
         )"#;
-    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let value_list =
+        "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
     let i_str = format!(
         "insert or replace into activation_requests {} values {}",
         field_list, value_list
     );
+    let request_id =
+        req.request_id.as_ref().ok_or_else(|| eyre!("{} has no request id", req))?;
+    let dedupe_key = match key {
+        Some(key) => key.to_string(),
+        None => super::dedupe_key(pool, request_id).await?,
+    };
     let a_key = parse.activation_id();
+    // keep a running count of the refreshes seen for each activation
+    let refresh_count: i64 = if parse.is_refresh() {
+        let q_str =
+            "select refresh_count from activation_requests where activation_key = ?";
+        let row = sqlx::query(q_str).bind(&a_key).fetch_optional(pool).await?;
+        row.map_or(0, |row| row.get("refresh_count")) + 1
+    } else {
+        0
+    };
+    let org_id = orgs::org_for_package(pool, &parse.npd_id).await?;
     debug!("Storing {} with key: {}", req, &a_key);
     let mut tx = pool.begin().await?;
     let result = sqlx::query(&i_str)
         .bind(&a_key)
         .bind(&parse.deactivation_id())
         .bind(req.api_key.as_ref().ok_or_else(|| eyre!("{} has no api key", req))?)
-        .bind(req.request_id.as_ref().ok_or_else(|| eyre!("{} has no request id", req))?)
+        .bind(request_id)
         .bind(req.session_id.as_ref().ok_or_else(|| eyre!("{} has no session id", req))?)
         .bind(&parse.device_details.current_date)
         .bind(&parse.npd_id)
@@ -189,6 +908,11 @@ pub async fn store_activation_request(pool: &SqlitePool, req: &Request) -> Resul
         .bind(&parse.app_details.ngl_app_version)
         .bind(&parse.app_details.ngl_lib_version)
         .bind(req.timestamp.to_db())
+        .bind(&parse.app_details.current_asnp_id)
+        .bind(refresh_count)
+        .bind(&req.correlation_id)
+        .bind(&dedupe_key)
+        .bind(&org_id)
         .execute(&mut tx)
         .await?;
     tx.commit().await?;
@@ -197,6 +921,16 @@ pub async fn store_activation_request(pool: &SqlitePool, req: &Request) -> Resul
 }
 
 pub async fn store_deactivation_request(pool: &SqlitePool, req: &Request) -> Result<()> {
+    insert_deactivation_request(pool, req, None).await
+}
+
+/// Store a deactivation request with the given dedupe key, or
+/// with a new key if it's being stored for the first time.
+async fn insert_deactivation_request(
+    pool: &SqlitePool,
+    req: &Request,
+    key: Option<&str>,
+) -> Result<()> {
     let query = req.query.as_ref().ok_or_else(|| eyre!("{} has no query", req))?;
     let parse =
         FrlDeactivationQueryParams::from_query(query).wrap_err(req.to_string())?;
@@ -204,20 +938,27 @@ pub async fn store_deactivation_request(pool: &SqlitePool, req: &Request) -> Res
             (
                 deactivation_key, api_key, request_id, package_id,
                 device_id, os_user_id, is_vdi, is_domain_user, is_virtual,
-                timestamp
+                timestamp, correlation_id, dedupe_key, org_id
             )"#;
-    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
     let i_str = format!(
         "insert or replace into deactivation_requests {} values {}",
         field_list, value_list
     );
+    let request_id =
+        req.request_id.as_ref().ok_or_else(|| eyre!("{} has no request id", req))?;
+    let dedupe_key = match key {
+        Some(key) => key.to_string(),
+        None => super::dedupe_key(pool, request_id).await?,
+    };
     let d_key = parse.deactivation_id();
+    let org_id = orgs::org_for_package(pool, &parse.npd_id).await?;
     debug!("Storing {} with key: {}", req, &d_key);
     let mut tx = pool.begin().await?;
     let result = sqlx::query(&i_str)
         .bind(&d_key)
         .bind(req.api_key.as_ref().ok_or_else(|| eyre!("{} has no api key", req))?)
-        .bind(req.request_id.as_ref().ok_or_else(|| eyre!("{} has no request id", req))?)
+        .bind(request_id)
         .bind(&parse.npd_id)
         .bind(&parse.device_id)
         .bind(&parse.os_user_id)
@@ -225,6 +966,9 @@ pub async fn store_deactivation_request(pool: &SqlitePool, req: &Request) -> Res
         .bind(parse.is_os_user_account_in_domain)
         .bind(parse.is_virtual_environment)
         .bind(req.timestamp.to_db())
+        .bind(&req.correlation_id)
+        .bind(&dedupe_key)
+        .bind(&org_id)
         .execute(&mut tx)
         .await?;
     tx.commit().await?;
@@ -232,21 +976,96 @@ pub async fn store_deactivation_request(pool: &SqlitePool, req: &Request) -> Res
     Ok(())
 }
 
+/// An in-memory index of the cached activation responses, built when the cache
+/// is opened, so that a cache hit doesn't need a lookup by key in the database.
+///
+/// The index is only a hint.  Its entries are checked against the database
+/// when they are used, and keys that aren't in it are looked up in the database,
+/// so it's fine for responses to be removed (or added by another process, such
+/// as an import) without the index knowing.
+#[derive(Debug, Default)]
+pub struct ActivationIndex {
+    entries: Mutex<HashMap<String, IndexEntry>>,
+}
+
+#[derive(Debug, Clone)]
+struct IndexEntry {
+    rowid: i64,
+    timestamp: Timestamp,
+}
+
+impl ActivationIndex {
+    pub async fn load(pool: &SqlitePool) -> Result<Self> {
+        let q_str = "select rowid, activation_key, timestamp from activation_responses";
+        let rows = sqlx::query(q_str).fetch_all(pool).await?;
+        let entries: HashMap<String, IndexEntry> = rows
+            .iter()
+            .map(|row| {
+                let entry = IndexEntry {
+                    rowid: row.get("rowid"),
+                    timestamp: Timestamp::from_db(row.get("timestamp")),
+                };
+                (row.get("activation_key"), entry)
+            })
+            .collect();
+        info!("Indexed {} cached activation response(s)", entries.len());
+        Ok(ActivationIndex { entries: Mutex::new(entries) })
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn get(&self, key: &str) -> Option<IndexEntry> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn insert(&self, key: &str, rowid: i64, timestamp: Timestamp) {
+        let entry = IndexEntry { rowid, timestamp };
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+/// Store an activation response, indexing it if there's an index.
 pub async fn store_activation_response(
     pool: &SqlitePool,
+    index: Option<&ActivationIndex>,
     req: &Request,
     resp: &Response,
 ) -> Result<()> {
+    let (a_key, rowid) = insert_activation_response(pool, req, resp, None).await?;
+    if let Some(index) = index {
+        index.insert(&a_key, rowid, req.timestamp.clone());
+    }
+    Ok(())
+}
+
+/// Store an activation response with the given dedupe key, or
+/// with the key of the request it answers.  Returns the activation
+/// key and rowid of the stored response.
+async fn insert_activation_response(
+    pool: &SqlitePool,
+    req: &Request,
+    resp: &Response,
+    key: Option<&str>,
+) -> Result<(String, i64)> {
     let body = req.body.as_ref().ok_or_else(|| eyre!("{} has no body", req))?;
     let parse = FrlActivationRequestBody::from_body(body).wrap_err(req.to_string())?;
-    let field_list = "(activation_key, deactivation_key, body, timestamp)";
-    let value_list = "(?, ?, ?, ?)";
+    let field_list =
+        "(activation_key, deactivation_key, body, timestamp, dedupe_key, headers)";
+    let value_list = "(?, ?, ?, ?, ?, ?)";
     let i_str = format!(
         "insert or replace into activation_responses {} values {}",
         field_list, value_list
     );
     let a_key = parse.activation_id();
     let d_key = parse.deactivation_id();
+    let q_str = "select dedupe_key from activation_requests where activation_key = ?";
+    let dedupe_key = request_dedupe_key(pool, req, q_str, &a_key, key).await?;
     let mut tx = pool.begin().await?;
     debug!("Storing response for {} with key: {}", &req, &a_key);
     let result = sqlx::query(&i_str)
@@ -258,9 +1077,12 @@ pub async fn store_activation_response(
                 .ok_or_else(|| eyre!("Response for {} has no body", req))?,
         )
         .bind(req.timestamp.to_db())
+        .bind(&dedupe_key)
+        .bind(headers_to_db(&resp.headers))
         .execute(&mut tx)
         .await?;
-    debug!("Stored activation response has rowid {}", result.last_insert_rowid());
+    let rowid = result.last_insert_rowid();
+    debug!("Stored activation response has rowid {}", rowid);
     // remove all matching deactivation requests/responses as they are now invalid
     debug!("Removing deactivation requests with key: {}", d_key);
     let d_str = "delete from deactivation_requests where deactivation_key = ?";
@@ -269,21 +1091,35 @@ pub async fn store_activation_response(
     let d_str = "delete from deactivation_responses where deactivation_key = ?";
     sqlx::query(d_str).bind(&d_key).execute(&mut tx).await?;
     tx.commit().await?;
-    Ok(())
+    Ok((a_key, rowid))
 }
 
 pub async fn store_deactivation_response(
     pool: &SqlitePool,
     req: &Request,
     resp: &Response,
+) -> Result<()> {
+    insert_deactivation_response(pool, req, resp, None).await
+}
+
+/// Store a deactivation response with the given dedupe key, or
+/// with the key of the request it answers.
+async fn insert_deactivation_response(
+    pool: &SqlitePool,
+    req: &Request,
+    resp: &Response,
+    key: Option<&str>,
 ) -> Result<()> {
     let query = req.query.as_ref().ok_or_else(|| eyre!("{} has no query", req))?;
     let parse =
         FrlDeactivationQueryParams::from_query(query).wrap_err(req.to_string())?;
     debug!("Processing successful response to {}", req);
+    let d_key = parse.deactivation_id();
+    // find the request's key before the request is removed
+    let q_str = "select dedupe_key from deactivation_requests where deactivation_key = ?";
+    let dedupe_key = request_dedupe_key(pool, req, q_str, &d_key, key).await?;
     let mut tx = pool.begin().await?;
     // first remove all earlier matching requests/responses as they are now invalid
-    let d_key = parse.deactivation_id();
     debug!("Removing activation requests with deactivation key: {}", d_key);
     let d_str = "delete from activation_requests where deactivation_key = ?";
     sqlx::query(d_str).bind(&d_key).execute(&mut tx).await?;
@@ -298,8 +1134,8 @@ pub async fn store_deactivation_response(
     let d_str = "delete from deactivation_responses where deactivation_key = ?";
     sqlx::query(d_str).bind(&d_key).execute(&mut tx).await?;
     // then the response
-    let field_list = "(deactivation_key, body, timestamp)";
-    let value_list = "(?, ?, ?)";
+    let field_list = "(deactivation_key, body, timestamp, dedupe_key, headers)";
+    let value_list = "(?, ?, ?, ?, ?)";
     let i_str = format!(
         "insert or replace into deactivation_responses {} values {}",
         field_list, value_list
@@ -309,6 +1145,8 @@ pub async fn store_deactivation_response(
         .bind(&d_key)
         .bind(&resp.body)
         .bind(req.timestamp.to_db())
+        .bind(&dedupe_key)
+        .bind(headers_to_db(&resp.headers))
         .execute(&mut tx)
         .await?;
     tx.commit().await?;
@@ -316,32 +1154,127 @@ pub async fn store_deactivation_response(
     Ok(())
 }
 
+/// The dedupe key for a response: either the given key, or the key of
+/// the stored request it answers, or (if that request is gone) a new key.
+async fn request_dedupe_key(
+    pool: &SqlitePool,
+    req: &Request,
+    q_str: &str,
+    row_key: &str,
+    key: Option<&str>,
+) -> Result<String> {
+    if let Some(key) = key {
+        return Ok(key.to_string());
+    }
+    if let Some(row) = sqlx::query(q_str).bind(row_key).fetch_optional(pool).await? {
+        return Ok(row.get("dedupe_key"));
+    }
+    let request_id =
+        req.request_id.as_ref().ok_or_else(|| eyre!("{} has no request id", req))?;
+    super::dedupe_key(pool, request_id).await
+}
+
+/// Store the outcome of an activation request, both on the stored request
+/// and in the outcomes table.  The outcomes table is what keeps the outcome
+/// of a request that isn't stored, such as one received while isolated.
+pub async fn store_activation_outcome(
+    pool: &SqlitePool,
+    req: &Request,
+    outcome: &RequestOutcome,
+) -> Result<()> {
+    let body = req.body.as_ref().ok_or_else(|| eyre!("{} has no body", req))?;
+    let parse = FrlActivationRequestBody::from_body(body).wrap_err(req.to_string())?;
+    let a_key = parse.activation_id();
+    let request_type = if parse.is_refresh() { "Refresh" } else { "Activation" };
+    let details = &parse.device_details;
+    let u_str = "update activation_requests set outcome = ? where activation_key = ?";
+    debug!("Storing outcome {} for {} with key: {}", outcome, req, &a_key);
+    let mut tx = pool.begin().await?;
+    sqlx::query(u_str).bind(outcome.to_string()).bind(&a_key).execute(&mut tx).await?;
+    let ids =
+        (parse.npd_id.as_str(), details.device_id.as_str(), details.os_user_id.as_str());
+    insert_outcome(&mut tx, req, request_type, ids, outcome).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Store the outcome of a deactivation request, both on the stored request
+/// and in the outcomes table.  The outcomes table is what keeps the outcome
+/// of a successful deactivation, whose stored request is removed when its
+/// response arrives.
+pub async fn store_deactivation_outcome(
+    pool: &SqlitePool,
+    req: &Request,
+    outcome: &RequestOutcome,
+) -> Result<()> {
+    let query = req.query.as_ref().ok_or_else(|| eyre!("{} has no query", req))?;
+    let parse =
+        FrlDeactivationQueryParams::from_query(query).wrap_err(req.to_string())?;
+    let d_key = parse.deactivation_id();
+    let u_str = "update deactivation_requests set outcome = ? where deactivation_key = ?";
+    debug!("Storing outcome {} for {} with key: {}", outcome, req, &d_key);
+    let mut tx = pool.begin().await?;
+    sqlx::query(u_str).bind(outcome.to_string()).bind(&d_key).execute(&mut tx).await?;
+    let ids =
+        (parse.npd_id.as_str(), parse.device_id.as_str(), parse.os_user_id.as_str());
+    insert_outcome(&mut tx, req, "Deactivation", ids, outcome).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Record the outcome of a request, given its package, device, and OS user ids.
+async fn insert_outcome(
+    tx: &mut Transaction<'_, Sqlite>,
+    req: &Request,
+    request_type: &str,
+    (package_id, device_id, os_user_id): (&str, &str, &str),
+    outcome: &RequestOutcome,
+) -> Result<()> {
+    let i_str = r#"insert or replace into request_outcomes
+        (correlation_id, request_type, package_id, device_id, os_user_id, timestamp, outcome)
+        values (?, ?, ?, ?, ?, ?, ?)"#;
+    sqlx::query(i_str)
+        .bind(&req.correlation_id)
+        .bind(request_type)
+        .bind(package_id)
+        .bind(device_id)
+        .bind(os_user_id)
+        .bind(req.timestamp.to_db())
+        .bind(outcome.to_string())
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
 pub async fn fetch_activation_response(
     pool: &SqlitePool,
+    index: &ActivationIndex,
     req: &Request,
 ) -> Result<Option<Response>> {
     let body = req.body.as_ref().ok_or_else(|| eyre!("{} has no body", req))?;
     let parse = FrlActivationRequestBody::from_body(body).wrap_err(req.to_string())?;
     let a_key = parse.activation_id();
-    let q_str =
-        "select body, timestamp from activation_responses where activation_key = ?";
     debug!("Finding activation response with key: {}", &a_key);
-    let result = sqlx::query(q_str).bind(&a_key).fetch_optional(pool).await?;
+    let mut result = fetch_indexed_response(pool, index, &a_key).await?;
+    if result.is_none() && parse.is_refresh() {
+        // a refresh that's never been answered can use the initial profile
+        let i_key = parse.initial_activation_id();
+        debug!("No refresh response found, trying initial key: {}", &i_key);
+        result = fetch_indexed_response(pool, index, &i_key).await?;
+    }
     match result {
-        Some(row) => {
-            let body: String = row.get("body");
-            Ok(Some(Response {
-                timestamp: Timestamp::from_db(row.get("timestamp")),
-                request_type: RequestType::FrlActivation,
-                status: http::StatusCode::OK,
-                body: Some(body),
-                content_type: Some("application/json".to_string()),
-                server: Some(crate::proxy::proxy_id()),
-                via: None,
-                request_id: req.request_id.clone(),
-                session_id: None,
-            }))
-        }
+        Some((body, timestamp, headers)) => Ok(Some(Response {
+            timestamp,
+            request_type: RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: Some(body),
+            content_type: Some("application/json".to_string()),
+            server: Some(crate::proxy::proxy_id()),
+            via: None,
+            request_id: req.request_id.clone(),
+            session_id: None,
+            headers,
+        })),
         None => {
             debug!("No activation response found for key: {}", &a_key);
             Ok(None)
@@ -357,8 +1290,8 @@ pub async fn fetch_deactivation_response(
     let parse =
         FrlDeactivationQueryParams::from_query(query).wrap_err(req.to_string())?;
     let d_key = parse.deactivation_id();
-    let q_str =
-        "select body, timestamp from deactivation_responses where deactivation_key = ?";
+    let q_str = r#"select body, timestamp, headers from deactivation_responses
+        where deactivation_key = ?"#;
     debug!("Finding deactivation response with key: {}", &d_key);
     let result = sqlx::query(q_str).bind(&d_key).fetch_optional(pool).await?;
     match result {
@@ -367,6 +1300,7 @@ pub async fn fetch_deactivation_response(
             Timestamp::from_db(row.get("timestamp")),
             req.request_id.clone().ok_or_else(|| eyre!("{} has no request id", req))?,
             row.get("body"),
+            headers_from_db(row.get("headers")),
         ))),
         None => {
             debug!("No deactivation response found for key: {}", &d_key);
@@ -375,33 +1309,72 @@ pub async fn fetch_deactivation_response(
     }
 }
 
-async fn fetch_unanswered_activations(pool: &SqlitePool) -> Result<Vec<Request>> {
+/// The body, timestamp, and headers of the activation response with the given key.
+/// Indexed responses are read by rowid; others are looked up by key (and
+/// then indexed).
+async fn fetch_indexed_response(
+    pool: &SqlitePool,
+    index: &ActivationIndex,
+    a_key: &str,
+) -> Result<Option<(String, Timestamp, Vec<(String, String)>)>> {
+    if let Some(entry) = index.get(a_key) {
+        let q_str = r#"select body, headers from activation_responses
+            where rowid = ? and activation_key = ? and not invalid"#;
+        let row =
+            sqlx::query(q_str).bind(entry.rowid).bind(a_key).fetch_optional(pool).await?;
+        if let Some(row) = row {
+            let headers = headers_from_db(row.get("headers"));
+            return Ok(Some((row.get("body"), entry.timestamp, headers)));
+        }
+        debug!("Index entry for key {} is out of date", a_key);
+        index.remove(a_key);
+    }
+    // responses marked invalid (see the `patch` module) are never served
+    let q_str = r#"select rowid, body, timestamp, headers from activation_responses
+        where activation_key = ? and not invalid"#;
+    match sqlx::query(q_str).bind(a_key).fetch_optional(pool).await? {
+        Some(row) => {
+            let timestamp = Timestamp::from_db(row.get("timestamp"));
+            index.insert(a_key, row.get("rowid"), timestamp.clone());
+            Ok(Some((row.get("body"), timestamp, headers_from_db(row.get("headers")))))
+        }
+        None => Ok(None),
+    }
+}
+
+async fn fetch_unanswered_activations(pool: &SqlitePool) -> Result<Vec<KeyedRequest>> {
     let mut result = Vec::new();
     let q_str = r#"select * from activation_requests req where not exists
                     (select 1 from activation_responses where
                         activation_key = req.activation_key and
-                        timestamp >= req.timestamp.to_storage()
+                        timestamp >= req.timestamp and not invalid
                     )"#;
     let rows = sqlx::query(q_str).fetch_all(pool).await?;
     for row in rows.iter() {
-        result.push(request_from_activation_row(row))
+        result.push(KeyedRequest {
+            key: row.get("dedupe_key"),
+            req: request_from_activation_row(row),
+            resp: None,
+        })
     }
     Ok(result)
 }
 
-async fn fetch_unanswered_deactivations(pool: &SqlitePool) -> Result<Vec<Request>> {
+async fn fetch_unanswered_deactivations(pool: &SqlitePool) -> Result<Vec<KeyedRequest>> {
     let mut result = Vec::new();
     let q_str = r#"select * from deactivation_requests"#;
     let rows = sqlx::query(q_str).fetch_all(pool).await?;
     for row in rows.iter() {
-        result.push(request_from_deactivation_row(row))
+        result.push(KeyedRequest {
+            key: row.get("dedupe_key"),
+            req: request_from_deactivation_row(row),
+            resp: None,
+        })
     }
     Ok(result)
 }
 
-async fn fetch_answered_activations(
-    pool: &SqlitePool,
-) -> Result<Vec<(Request, Response)>> {
+async fn fetch_answered_activations(pool: &SqlitePool) -> Result<Vec<KeyedRequest>> {
     let mut result = Vec::new();
     let q_str = r#"
         select req.*, resp.body from activation_requests req 
@@ -409,15 +1382,16 @@ async fn fetch_answered_activations(
             on req.activation_key = resp.activation_key"#;
     let rows = sqlx::query(q_str).fetch_all(pool).await?;
     for row in rows.iter() {
-        result
-            .push((request_from_activation_row(row), response_from_activation_row(row)?));
+        result.push(KeyedRequest {
+            key: row.get("dedupe_key"),
+            req: request_from_activation_row(row),
+            resp: Some(response_from_activation_row(row)?),
+        });
     }
     Ok(result)
 }
 
-async fn fetch_answered_deactivations(
-    pool: &SqlitePool,
-) -> Result<Vec<(Request, Response)>> {
+async fn fetch_answered_deactivations(pool: &SqlitePool) -> Result<Vec<KeyedRequest>> {
     let mut result = Vec::new();
     let q_str = r#"
         select req.*, resp.body from deactivation_requests req 
@@ -425,14 +1399,72 @@ async fn fetch_answered_deactivations(
             on req.deactivation_key = resp.deactivation_key"#;
     let rows = sqlx::query(q_str).fetch_all(pool).await?;
     for row in rows.iter() {
-        result.push((
-            request_from_deactivation_row(row),
-            response_from_deactivation_row(row)?,
-        ));
+        result.push(KeyedRequest {
+            key: row.get("dedupe_key"),
+            req: request_from_deactivation_row(row),
+            resp: Some(response_from_deactivation_row(row)?),
+        });
+    }
+    Ok(result)
+}
+
+async fn fetch_legacy_activations(pool: &SqlitePool) -> Result<Vec<KeyedRequest>> {
+    let mut result = Vec::new();
+    let q_str = r#"
+        select req.activation_key, req.deactivation_key, api_key, request_id, session_id,
+            device_date, package_id, asnp_id, device_id, os_user_id, is_vdi,
+            is_domain_user, is_virtual, os_name, os_version, app_id, app_version,
+            ngl_version, req.timestamp as legacy_timestamp, 0 as timestamp, resp.body,
+            '' as current_asnp_id, '' as correlation_id
+            from activation_request_log req
+            inner join activation_response_cache resp
+            on req.activation_key = resp.activation_key"#;
+    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let timestamp = legacy_timestamp(row.get("legacy_timestamp"));
+        let mut req = request_from_activation_row(row);
+        let mut resp = response_from_activation_row(row)?;
+        req.timestamp = timestamp.clone();
+        resp.timestamp = timestamp;
+        result.push(KeyedRequest { key: legacy_key(&req), req, resp: Some(resp) });
+    }
+    result.sort_by_key(|pair| pair.req.timestamp.to_millis());
+    Ok(result)
+}
+
+async fn fetch_legacy_deactivations(pool: &SqlitePool) -> Result<Vec<KeyedRequest>> {
+    let mut result = Vec::new();
+    let q_str = r#"
+        select req.deactivation_key, api_key, request_id, package_id, device_id,
+            os_user_id, is_domain_user, is_vdi, is_virtual,
+            req.timestamp as legacy_timestamp, 0 as timestamp, resp.body,
+            '' as correlation_id
+            from deactivation_request_log req
+            inner join deactivation_response_cache resp
+            on req.deactivation_key = resp.deactivation_key"#;
+    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let timestamp = legacy_timestamp(row.get("legacy_timestamp"));
+        let mut req = request_from_deactivation_row(row);
+        let mut resp = response_from_deactivation_row(row)?;
+        req.timestamp = timestamp.clone();
+        resp.timestamp = timestamp;
+        result.push(KeyedRequest { key: legacy_key(&req), req, resp: Some(resp) });
     }
+    result.sort_by_key(|pair| pair.req.timestamp.to_millis());
     Ok(result)
 }
 
+/// Legacy caches stored timestamps as device dates, but be tolerant
+/// of any other format that we know how to read.
+fn legacy_timestamp(s: &str) -> Timestamp {
+    s.parse::<Timestamp>().unwrap_or_else(|_| Timestamp::from_device_date(s))
+}
+
+fn legacy_key(req: &Request) -> String {
+    format!("legacy|{}", req.request_id.as_deref().unwrap_or_default())
+}
+
 fn request_from_activation_row(row: &SqliteRow) -> Request {
     let device_details = FrlDeviceDetails {
         current_date: row.get("device_date"),
@@ -445,7 +1477,7 @@ fn request_from_activation_row(row: &SqliteRow) -> Request {
         os_version: row.get("os_version"),
     };
     let app_details = FrlAppDetails {
-        current_asnp_id: "".to_string(),
+        current_asnp_id: row.get("current_asnp_id"),
         ngl_app_id: row.get("app_id"),
         ngl_app_version: row.get("app_version"),
         ngl_lib_version: row.get("ngl_version"),
@@ -466,6 +1498,7 @@ fn request_from_activation_row(row: &SqliteRow) -> Request {
         request_type: RequestType::FrlActivation,
         source_ip: None,
         method: http::Method::POST,
+        host: None,
         path: "/asnp/frl_connected/values/v2".to_string(),
         query: None,
         body: Some(body),
@@ -478,6 +1511,7 @@ fn request_from_activation_row(row: &SqliteRow) -> Request {
         request_id: Some(request_id),
         session_id: Some(session_id),
         authorization: None,
+        correlation_id: row.get("correlation_id"),
     }
 }
 
@@ -498,6 +1532,7 @@ fn request_from_deactivation_row(row: &SqliteRow) -> Request {
         request_type: RequestType::FrlDeactivation,
         source_ip: None,
         method: http::Method::DELETE,
+        host: None,
         path: "/asnp/frl_connected/v1".to_string(),
         query: Some(query),
         body: None,
@@ -510,6 +1545,7 @@ fn request_from_deactivation_row(row: &SqliteRow) -> Request {
         request_id: Some(request_id),
         session_id: None,
         authorization: None,
+        correlation_id: row.get("correlation_id"),
     }
 }
 
@@ -519,6 +1555,7 @@ fn response_from_activation_row(row: &SqliteRow) -> Result<Response> {
         Timestamp::from_db(row.get("timestamp")),
         row.get("request_id"),
         row.get("body"),
+        headers_from_db(row.try_get("headers").unwrap_or_default()),
     ))
 }
 
@@ -528,6 +1565,7 @@ fn response_from_deactivation_row(row: &SqliteRow) -> Result<Response> {
         Timestamp::from_db(row.get("timestamp")),
         row.get("request_id"),
         row.get("body"),
+        headers_from_db(row.try_get("headers").unwrap_or_default()),
     ))
 }
 
@@ -536,6 +1574,7 @@ fn response_from_parts(
     timestamp: Timestamp,
     request_id: String,
     body: String,
+    headers: Vec<(String, String)>,
 ) -> Response {
     Response {
         timestamp,
@@ -547,9 +1586,23 @@ fn response_from_parts(
         via: None,
         request_id: Some(request_id),
         session_id: None,
+        headers,
     }
 }
 
+/// Passed-through headers are stored as a JSON list of name/value pairs.
+fn headers_to_db(headers: &[(String, String)]) -> String {
+    if headers.is_empty() {
+        String::new()
+    } else {
+        serde_json::to_string(headers).unwrap_or_default()
+    }
+}
+
+fn headers_from_db(s: &str) -> Vec<(String, String)> {
+    serde_json::from_str(s).unwrap_or_default()
+}
+
 const ACTIVATION_REQUEST_SCHEMA: &str = r#"
     create table if not exists activation_requests (
         activation_key text not null unique,
@@ -570,7 +1623,7 @@ const ACTIVATION_REQUEST_SCHEMA: &str = r#"
         app_id text not null,
         app_version text not null,
         ngl_version text not null,
-        timestamp string not null
+        timestamp integer not null
     );
     create index if not exists deactivation_request_index on activation_requests (
         deactivation_key
@@ -581,7 +1634,7 @@ const ACTIVATION_RESPONSE_SCHEMA: &str = r#"
         activation_key text not null unique,
         deactivation_key text not null,
         body text not null,
-        timestamp string not null
+        timestamp integer not null
     );
     create index if not exists deactivation_response_index on activation_responses (
         deactivation_key
@@ -598,19 +1651,220 @@ const DEACTIVATION_REQUEST_SCHEMA: &str = r#"
         is_domain_user boolean not null,
         is_vdi boolean not null,
         is_virtual boolean not null,
-        timestamp string not null
+        timestamp integer not null
     )"#;
 
 const DEACTIVATION_RESPONSE_SCHEMA: &str = r#"
     create table if not exists deactivation_responses (
         deactivation_key text not null unique,
         body text not null,
-        timestamp string not null
+        timestamp integer not null
+    );"#;
+
+const IMPORTED_KEYS_SCHEMA: &str = r#"
+    create table if not exists imported_keys (
+        dedupe_key text not null unique
+    );"#;
+
+const IMPORT_CONFLICTS_SCHEMA: &str = r#"
+    create table if not exists import_conflicts (
+        timestamp integer not null,
+        package_id text not null,
+        device_id text not null,
+        imported_type text not null,
+        imported_timestamp integer not null,
+        cached_type text not null,
+        cached_timestamp integer not null,
+        resolution text not null,
+        dedupe_key text not null
+    );"#;
+
+/// The outcome of each FRL request, kept apart from the requests because
+/// not every request is stored, and stored requests are removed once they
+/// are superseded.
+const REQUEST_OUTCOMES_SCHEMA: &str = r#"
+    create table if not exists request_outcomes (
+        correlation_id text not null unique,
+        request_type text not null,
+        package_id text not null,
+        device_id text not null,
+        os_user_id text not null,
+        timestamp integer not null,
+        outcome text not null
     );"#;
 
+/// The outcomes of requests that aren't (or are no longer) stored.
+const ORPHAN_OUTCOMES: &str = r#"
+    select oc.*, pkg.package_name as package_name from request_outcomes oc
+        left join packages pkg on pkg.npd_id = oc.package_id
+        where not exists (select 1 from activation_requests a
+            where a.correlation_id = oc.correlation_id)
+        and not exists (select 1 from deactivation_requests d
+            where d.correlation_id = oc.correlation_id)"#;
+
+const FRL_SCHEMA_VERSION: usize = 29;
+
+const SCHEMA_ALTERATIONS_BY_VERSION: [&str; FRL_SCHEMA_VERSION] = [
+    "alter table activation_requests add column outcome not null default ''",
+    "alter table deactivation_requests add column outcome not null default ''",
+    "alter table activation_requests add column current_asnp_id not null default ''",
+    "alter table activation_requests add column refresh_count not null default 0",
+    "alter table activation_requests add column correlation_id not null default ''",
+    "alter table deactivation_requests add column correlation_id not null default ''",
+    "alter table activation_requests add column dedupe_key not null default ''",
+    "alter table deactivation_requests add column dedupe_key not null default ''",
+    "alter table activation_responses add column dedupe_key not null default ''",
+    "alter table deactivation_responses add column dedupe_key not null default ''",
+    r#"update activation_requests set dedupe_key =
+        (select instance_id from proxy_instance) || '|' || request_id"#,
+    r#"update deactivation_requests set dedupe_key =
+        (select instance_id from proxy_instance) || '|' || request_id"#,
+    r#"update activation_responses set dedupe_key = coalesce(
+        (select dedupe_key from activation_requests req
+            where req.activation_key = activation_responses.activation_key),
+        (select instance_id from proxy_instance) || '|' || activation_key)"#,
+    r#"update deactivation_responses set dedupe_key =
+        (select instance_id from proxy_instance) || '|' || deactivation_key"#,
+    "alter table activation_requests add column country not null default ''",
+    "alter table activation_requests add column city not null default ''",
+    "alter table activation_requests add column campus not null default ''",
+    "alter table deactivation_requests add column country not null default ''",
+    "alter table deactivation_requests add column city not null default ''",
+    "alter table deactivation_requests add column campus not null default ''",
+    "alter table activation_requests add column user_agent not null default ''",
+    "alter table activation_requests add column ngl_client_version not null default ''",
+    "alter table deactivation_requests add column user_agent not null default ''",
+    "alter table deactivation_requests add column ngl_client_version not null default ''",
+    "alter table activation_responses add column headers not null default ''",
+    "alter table deactivation_responses add column headers not null default ''",
+    "alter table activation_responses add column invalid boolean not null default 0",
+    "alter table activation_requests add column org_id not null default ''",
+    "alter table deactivation_requests add column org_id not null default ''",
+];
+
+/// The imported metadata (if any) for the package of a request.
+const PACKAGE_COLUMNS: &str = r#"pkg.package_name as package_name,
+    pkg.deployment_mode as deployment_mode, pkg.precedence as npd_precedence"#;
+
+const PACKAGE_JOIN: &str = "left join packages pkg on pkg.npd_id = req.package_id";
+
 const CLEAR_ALL: &str = r#"
     delete from deactivation_responses;
     delete from deactivation_requests;
     delete from activation_responses;
     delete from activation_requests;
+    delete from imported_keys;
+    delete from import_conflicts;
+    delete from request_outcomes;
     "#;
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fetch_activation_response, report, store_activation_outcome,
+        store_activation_request, store_activation_response, store_deactivation_outcome,
+        store_deactivation_request, store_deactivation_response, ActivationIndex,
+    };
+    use crate::cache::ReportFilter;
+    use crate::proxy::{RequestOutcome, RequestType, Response};
+    use crate::testing::frl::{
+        mock_cache_activation_request, mock_cache_deactivation_request,
+    };
+    use adlu_parse::protocol::{FrlActivationRequestBody, FrlDeactivationQueryParams};
+
+    #[tokio::test]
+    async fn test_activation_index() {
+        let dir = std::env::temp_dir().join("adlu-proxy-activation-index-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let index = ActivationIndex::default();
+        let body = FrlActivationRequestBody::mock_from_device_id("idx1");
+        let req = mock_cache_activation_request(&body);
+        let a_key = body.activation_id();
+        let resp = Response {
+            timestamp: req.timestamp.clone(),
+            request_type: RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: Some("first".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        store_activation_request(&pool, &req).await.unwrap();
+        store_activation_response(&pool, Some(&index), &req, &resp).await.unwrap();
+        assert!(index.get(&a_key).is_some());
+        let fetched = fetch_activation_response(&pool, &index, &req).await.unwrap();
+        assert_eq!(fetched.unwrap().body.as_deref(), Some("first"));
+        // an index built when the cache is opened finds the same response
+        let loaded = ActivationIndex::load(&pool).await.unwrap();
+        assert_eq!(loaded.get(&a_key).unwrap().rowid, index.get(&a_key).unwrap().rowid);
+        // a response replaced behind the index's back is still found
+        let u_str = r#"insert or replace into activation_responses
+            (activation_key, deactivation_key, body, timestamp, dedupe_key)
+            select activation_key, deactivation_key, 'second', timestamp, dedupe_key
+            from activation_responses where activation_key = ?"#;
+        sqlx::query(u_str).bind(&a_key).execute(&pool).await.unwrap();
+        let fetched = fetch_activation_response(&pool, &index, &req).await.unwrap();
+        assert_eq!(fetched.unwrap().body.as_deref(), Some("second"));
+        // and one removed behind its back is not
+        sqlx::query("delete from activation_responses").execute(&pool).await.unwrap();
+        assert!(fetch_activation_response(&pool, &index, &req).await.unwrap().is_none());
+        assert!(index.get(&a_key).is_none());
+        pool.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_outcomes() {
+        let dir = std::env::temp_dir().join("adlu-proxy-request-outcomes-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        // an activation received while isolated is never stored
+        let body = FrlActivationRequestBody::mock_from_device_id("out1");
+        let req = mock_cache_activation_request(&body);
+        let outcome = RequestOutcome::IsolatedStored;
+        store_activation_outcome(&pool, &req, &outcome).await.unwrap();
+        // a successful deactivation is removed when its response is stored
+        let params = FrlDeactivationQueryParams::mock_from_device_id("out2");
+        let req = mock_cache_deactivation_request(&params);
+        let resp = Response {
+            timestamp: req.timestamp.clone(),
+            request_type: RequestType::FrlDeactivation,
+            status: http::StatusCode::OK,
+            body: Some("{}".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        store_deactivation_request(&pool, &req).await.unwrap();
+        store_deactivation_response(&pool, &req, &resp).await.unwrap();
+        let outcome = RequestOutcome::ForwardedSuccess;
+        store_deactivation_outcome(&pool, &req, &outcome).await.unwrap();
+        // both outcomes are still reported
+        let csv = dir.join("report.csv").to_string_lossy().to_string();
+        report(&pool, &csv, &ReportFilter::default(), false, false).await.unwrap();
+        let content = std::fs::read_to_string(&csv).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("Activation,"));
+        assert!(lines[1].contains("out1") && lines[1].contains("isolated-stored"));
+        assert!(lines[2].starts_with("Deactivation,"));
+        assert!(lines[2].contains("out2") && lines[2].contains("forwarded-success"));
+        // but not when filtered on a column they don't have
+        let filter = ReportFilter::parse("os_name=MAC").unwrap();
+        report(&pool, &csv, &filter, false, false).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&csv).unwrap().lines().count(), 1);
+        pool.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
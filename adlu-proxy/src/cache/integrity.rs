@@ -0,0 +1,226 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Checksums that let an import detect damage to an FRL export.
+
+Exports are often carried between networks on removable media, and a damaged
+file can look like a perfectly good database.  So each export database holds
+a manifest with the row count and SHA-256 checksum of each of the tables that
+import reads, and import checks the tables against the manifest before it
+reads them.  The proxy that forwards an export's requests adds responses to
+it, so forwarding re-seals a sealed export with its new contents.
+
+The checksum of a table covers the columns it had when it was sealed, in row
+order, so a table that gains columns when a newer proxy opens the export
+still matches its manifest.  Databases without a manifest (such as exports
+from older proxies) are unsealed, and can't be checked.
+ */
+use eyre::Result;
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+/// The tables that import reads, and so the tables that are sealed.
+pub const SEALED_TABLES: [&str; 4] = [
+    "activation_requests",
+    "activation_responses",
+    "deactivation_requests",
+    "deactivation_responses",
+];
+
+/// A sealed table that doesn't match its manifest entry.
+#[derive(Debug, Clone)]
+pub struct Damage {
+    pub table: String,
+    pub problem: String,
+}
+
+impl std::fmt::Display for Damage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.table, self.problem)
+    }
+}
+
+/// The result of checking a database against its manifest.
+#[derive(Debug, Clone)]
+pub enum Seal {
+    Unsealed,
+    Intact,
+    Damaged(Vec<Damage>),
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(MANIFEST_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+/// Record the current contents of the sealed tables in the manifest.
+pub async fn seal(pool: &SqlitePool) -> Result<()> {
+    let sealed_at = Timestamp::now();
+    for table in SEALED_TABLES {
+        let columns = table_columns(pool, table).await?;
+        let (row_count, sha256) = checksum(pool, table, &columns).await?;
+        sqlx::query(MANIFEST_UPSERT)
+            .bind(table)
+            .bind(columns.join(","))
+            .bind(row_count)
+            .bind(sha256)
+            .bind(sealed_at.to_millis())
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Seal the database again if it's already sealed, returning whether it was.
+pub async fn reseal(pool: &SqlitePool) -> Result<bool> {
+    if is_sealed(pool).await? {
+        seal(pool).await?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Check the sealed tables against the manifest.
+pub async fn verify(pool: &SqlitePool) -> Result<Seal> {
+    if !is_sealed(pool).await? {
+        return Ok(Seal::Unsealed);
+    }
+    let q_str = "select * from export_manifest order by table_name";
+    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    let mut damage = vec![];
+    for row in rows.iter() {
+        let table: String = row.get("table_name");
+        let columns: String = row.get("columns");
+        let columns: Vec<String> = columns.split(',').map(String::from).collect();
+        let expected: (i64, String) = (row.get("row_count"), row.get("sha256"));
+        let problem = match checksum(pool, &table, &columns).await {
+            Err(err) => format!("unreadable: {}", err),
+            Ok((count, _)) if count != expected.0 => {
+                format!("has {} row(s) but was sealed with {}", count, expected.0)
+            }
+            Ok((_, sha256)) if sha256 != expected.1 => "checksum mismatch".to_string(),
+            Ok(_) => continue,
+        };
+        damage.push(Damage { table, problem });
+    }
+    if damage.is_empty() {
+        Ok(Seal::Intact)
+    } else {
+        Ok(Seal::Damaged(damage))
+    }
+}
+
+async fn is_sealed(pool: &SqlitePool) -> Result<bool> {
+    let q_str = "select count(*) from export_manifest";
+    let count: i64 = sqlx::query(q_str).fetch_one(pool).await?.get(0);
+    Ok(count > 0)
+}
+
+pub(super) async fn table_columns(pool: &SqlitePool, table: &str) -> Result<Vec<String>> {
+    let q_str = format!("pragma table_info(\"{}\")", table);
+    let rows = sqlx::query(&q_str).fetch_all(pool).await?;
+    Ok(rows.iter().map(|row| row.get("name")).collect())
+}
+
+/// The row count and checksum of the given columns of a table.  Each row is
+/// hashed as the SQL literals of its values, so every type hashes exactly.
+async fn checksum(
+    pool: &SqlitePool,
+    table: &str,
+    columns: &[String],
+) -> Result<(i64, String)> {
+    let values: Vec<String> =
+        columns.iter().map(|column| format!("quote(\"{}\")", column)).collect();
+    let q_str = format!(
+        "select {} as line from \"{}\" order by rowid",
+        values.join(" || ',' || "),
+        table
+    );
+    let rows = sqlx::query(&q_str).fetch_all(pool).await?;
+    let mut hasher = Sha256::new();
+    for row in rows.iter() {
+        let line: String = row.get("line");
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok((rows.len() as i64, format!("{:x}", hasher.finalize())))
+}
+
+const MANIFEST_SCHEMA: &str = r#"
+    create table if not exists export_manifest (
+        table_name text not null unique,
+        columns text not null,
+        row_count integer not null,
+        sha256 text not null,
+        sealed_at integer not null
+    );"#;
+
+const MANIFEST_UPSERT: &str = r#"
+    insert or replace into export_manifest
+        (table_name, columns, row_count, sha256, sealed_at)
+        values (?, ?, ?, ?, ?)"#;
+
+#[cfg(test)]
+mod tests {
+    use super::{reseal, seal, verify, Seal};
+
+    #[tokio::test]
+    async fn test_seal_and_verify() {
+        let dir = std::env::temp_dir().join("adlu-proxy-integrity-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("export.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("in1");
+        let req = crate::testing::frl::mock_cache_activation_request(&body);
+        super::super::frl::store_activation_request(&pool, &req).await.unwrap();
+        assert!(matches!(verify(&pool).await.unwrap(), Seal::Unsealed));
+        assert!(!reseal(&pool).await.unwrap());
+        seal(&pool).await.unwrap();
+        assert!(matches!(verify(&pool).await.unwrap(), Seal::Intact));
+        // added columns are ignored, but changed values are not
+        sqlx::query("alter table activation_requests add column extra text")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(verify(&pool).await.unwrap(), Seal::Intact));
+        sqlx::query("update activation_requests set device_id = 'tampered'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        match verify(&pool).await.unwrap() {
+            Seal::Damaged(damage) => {
+                assert_eq!(damage.len(), 1);
+                assert_eq!(
+                    damage[0].to_string(),
+                    "activation_requests (checksum mismatch)"
+                );
+            }
+            seal => panic!("Tampered table was not detected: {:?}", seal),
+        }
+        assert!(reseal(&pool).await.unwrap());
+        assert!(matches!(verify(&pool).await.unwrap(), Seal::Intact));
+        pool.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+use eyre::Result;
+use log::debug;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+use adlu_parse::protocol::InventoryReport;
+
+use super::ReportFilter;
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(INVENTORY_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn clear(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(CLEAR_ALL).execute(&mut tx).await?;
+    tx.commit().await?;
+    eprintln!("Package inventory cache has been cleared.");
+    Ok(())
+}
+
+/// Replace a machine's inventory with the one it just reported.
+pub async fn store_inventory(
+    pool: &SqlitePool,
+    report: &InventoryReport,
+    source_addr: &str,
+) -> Result<()> {
+    let field_list = r#"
+        (
+            hostname, source_addr, os_name, decoder_version, package_id,
+            license_type, expiry_date, precedence, app_ids, install_date, timestamp
+        )"#;
+    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let i_str = format!("insert into inventory {} values {}", field_list, value_list);
+    let timestamp = Timestamp::now();
+    let mut tx = pool.begin().await?;
+    sqlx::query("delete from inventory where hostname = ?")
+        .bind(&report.hostname)
+        .execute(&mut tx)
+        .await?;
+    for package in report.packages.iter() {
+        sqlx::query(&i_str)
+            .bind(&report.hostname)
+            .bind(source_addr)
+            .bind(&report.os_name)
+            .bind(&report.decoder_version)
+            .bind(&package.npd_id)
+            .bind(&package.license_type)
+            .bind(&package.expiry_date)
+            .bind(package.precedence)
+            .bind(package.app_ids.join(", "))
+            .bind(&package.install_date)
+            .bind(timestamp.to_db())
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    debug!(
+        "Stored inventory of {} package(s) for {}",
+        report.packages.len(),
+        &report.hostname
+    );
+    Ok(())
+}
+
+/// Report each package on each machine, along with the FRL activations
+/// the proxy has seen for that package.
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Hostname".to_string(),
+        "Source Address".to_string(),
+        "OS Name".to_string(),
+        "Decoder Version".to_string(),
+        "Package ID".to_string(),
+        "License Type".to_string(),
+        "Expiry Date".to_string(),
+        "Precedence".to_string(),
+        "App IDs".to_string(),
+        "Install Date".to_string(),
+        format!("Reported{time_suffix}"),
+        "FRL Activations".to_string(),
+        "Activated Devices".to_string(),
+    ])?;
+    let q_str = filter.apply(REPORT_QUERY, "timestamp");
+    let rows = sqlx::query(&q_str).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let timestamp = Timestamp::from_db(row.get("timestamp"));
+        let timestamp = if rfc3339 {
+            timestamp.format_rfc_3339(timezone)
+        } else {
+            timestamp.format_iso_8601(timezone)
+        };
+        let precedence: i64 = row.get("precedence");
+        let activations: i64 = row.get("activations");
+        let devices: i64 = row.get("devices");
+        writer.write_record([
+            row.get("hostname"),
+            row.get("source_addr"),
+            row.get("os_name"),
+            row.get("decoder_version"),
+            row.get("package_id"),
+            row.get("license_type"),
+            row.get("expiry_date"),
+            precedence.to_string(),
+            row.get("app_ids"),
+            row.get("install_date"),
+            timestamp,
+            activations.to_string(),
+            devices.to_string(),
+        ])?;
+    }
+    Ok(())
+}
+
+const INVENTORY_SCHEMA: &str = r#"
+    create table if not exists inventory (
+        hostname text not null,
+        source_addr text not null,
+        os_name text not null,
+        decoder_version text not null,
+        package_id text not null,
+        license_type text not null,
+        expiry_date text not null,
+        precedence integer not null,
+        app_ids text not null,
+        install_date text not null,
+        timestamp integer not null,
+        unique(hostname, package_id)
+    );"#;
+
+const REPORT_QUERY: &str = r#"
+    select inv.*,
+        count(req.activation_key) as activations,
+        count(distinct req.device_id) as devices
+    from inventory inv left join activation_requests req
+        on req.package_id = inv.package_id
+    group by inv.hostname, inv.package_id
+    order by inv.hostname, inv.package_id
+    "#;
+
+const CLEAR_ALL: &str = r#"
+    delete from inventory;
+    "#;
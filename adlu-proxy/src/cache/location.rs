@@ -0,0 +1,72 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+The locations of clients (see [`crate::geoip`]), stored with their requests.
+
+Every kind of stored request keeps the correlation id of the latest request
+that updated it, so the location of a client is stored by updating whichever
+rows have the correlation id of its request.
+ */
+use eyre::Result;
+use sqlx::{
+    sqlite::{SqlitePool, SqliteRow},
+    Row,
+};
+
+use crate::geoip::Location;
+use crate::proxy::{Request, RequestType};
+
+pub async fn store_location(
+    pool: &SqlitePool,
+    req: &Request,
+    location: &Location,
+) -> Result<()> {
+    let table = match req.request_type {
+        RequestType::FrlActivation => "activation_requests",
+        RequestType::FrlDeactivation => "deactivation_requests",
+        RequestType::NulLicense => "license_sessions",
+        RequestType::LogUpload => "log_sessions",
+        RequestType::Unknown => return Ok(()),
+    };
+    let u_str = format!(
+        "update {table} set country = ?, city = ?, campus = ? where correlation_id = ?"
+    );
+    sqlx::query(&u_str)
+        .bind(&location.country)
+        .bind(&location.city)
+        .bind(&location.campus)
+        .bind(&req.correlation_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Rows stored before locations were looked up have empty locations.
+pub fn location_from_row(row: &SqliteRow) -> Location {
+    let label = |name: &str| -> String { row.try_get(name).unwrap_or_default() };
+    Location { country: label("country"), city: label("city"), campus: label("campus") }
+}
+
+pub fn report_headers() -> Vec<String> {
+    vec!["Country".to_string(), "City".to_string(), "Campus".to_string()]
+}
+
+pub fn report_record(location: &Location) -> Vec<String> {
+    vec![location.country.clone(), location.city.clone(), location.campus.clone()]
+}
@@ -0,0 +1,207 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Advisory locks that coordinate processes sharing a cache.
+
+A proxy's cache is often used by more than one process at once: the server,
+its scheduled jobs, and commands such as `forward` run from cron.  The cache
+itself is safe to share (every process reads what the others have written,
+so the server answers from responses that a `forward` command stored), but
+two processes forwarding the same stored requests, or purging while another
+forwards, would send Adobe duplicate requests and confuse the reports.
+
+So processes take a named lock in the cache before they forward or purge.
+A lock is held for a short lease that its holder keeps renewing while it
+works, so a lock whose holder crashed is freed when its lease runs out.
+ */
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use eyre::Result;
+use log::{debug, warn};
+use sqlx::{sqlite::SqlitePool, Row};
+use tokio::task::JoinHandle;
+
+use adlu_base::Timestamp;
+
+/// The lock held while stored requests are forwarded to Adobe.
+pub const FORWARD_LOCK: &str = "forward";
+
+/// The lock held while old entries are purged.
+pub const PURGE_LOCK: &str = "purge";
+
+/// How long a lock is held unless its holder renews it.
+const LEASE: Duration = Duration::from_secs(300);
+
+/// How often a holder renews its lock.
+const RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Distinguishes the locks taken by a single process.
+static NEXT_HOLDER: AtomicU64 = AtomicU64::new(1);
+
+/// The error when a lock is held by someone else.
+#[derive(Debug, Clone)]
+pub struct LockHeld {
+    pub name: String,
+    pub holder: String,
+    pub expires: Timestamp,
+}
+
+impl std::fmt::Display for LockHeld {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the {} lock is held by {} (until {}, unless renewed)",
+            self.name,
+            self.holder,
+            self.expires.format_iso_8601(false)
+        )
+    }
+}
+
+impl std::error::Error for LockHeld {}
+
+/// A held lock.  The lock is renewed in the background until it's released;
+/// if it's dropped without being released, it's freed when its lease runs out.
+#[derive(Debug)]
+pub struct CacheLock {
+    pool: SqlitePool,
+    name: String,
+    holder: String,
+    renewer: JoinHandle<()>,
+}
+
+impl CacheLock {
+    pub async fn release(self) {
+        self.renewer.abort();
+        let d_str = "delete from proxy_locks where name = ? and holder = ?";
+        let result = sqlx::query(d_str)
+            .bind(&self.name)
+            .bind(&self.holder)
+            .execute(&self.pool)
+            .await;
+        match result {
+            Ok(_) => debug!("Released the {} lock", &self.name),
+            Err(err) => warn!("Can't release the {} lock: {}", &self.name, err),
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        self.renewer.abort();
+    }
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(LOCKS_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+/// Take the named lock, failing with [`LockHeld`] if another holder has it.
+pub async fn acquire(pool: &SqlitePool, name: &str) -> Result<CacheLock> {
+    let holder = format!(
+        "{}:{}:{}",
+        sys_info::hostname().unwrap_or_else(|_| "localhost".to_string()),
+        std::process::id(),
+        NEXT_HOLDER.fetch_add(1, Ordering::Relaxed)
+    );
+    let now = Timestamp::now().to_millis();
+    let i_str = r#"
+        insert into proxy_locks (name, holder, expires) values (?, ?, ?)
+            on conflict (name) do update
+            set holder = excluded.holder, expires = excluded.expires
+            where proxy_locks.expires <= ?"#;
+    let result = sqlx::query(i_str)
+        .bind(name)
+        .bind(&holder)
+        .bind(now + LEASE.as_millis() as i64)
+        .bind(now)
+        .execute(pool)
+        .await?;
+    if result.rows_affected() == 0 {
+        let q_str = "select holder, expires from proxy_locks where name = ?";
+        let row = sqlx::query(q_str).bind(name).fetch_one(pool).await?;
+        return Err(LockHeld {
+            name: name.to_string(),
+            holder: row.get("holder"),
+            expires: Timestamp::from_millis(row.get("expires")),
+        }
+        .into());
+    }
+    debug!("Took the {} lock as {}", name, &holder);
+    let renewer = tokio::spawn(renew(pool.clone(), name.to_string(), holder.clone()));
+    Ok(CacheLock { pool: pool.clone(), name: name.to_string(), holder, renewer })
+}
+
+async fn renew(pool: SqlitePool, name: String, holder: String) {
+    let u_str = "update proxy_locks set expires = ? where name = ? and holder = ?";
+    let mut interval = tokio::time::interval(RENEW_INTERVAL);
+    // the first tick is immediate, and the lock was just taken
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let expires = Timestamp::now().to_millis() + LEASE.as_millis() as i64;
+        let result = sqlx::query(u_str)
+            .bind(expires)
+            .bind(&name)
+            .bind(&holder)
+            .execute(&pool)
+            .await;
+        match result {
+            Ok(result) if result.rows_affected() == 1 => {}
+            Ok(_) => warn!("Lost the {} lock (its lease ran out)", &name),
+            Err(err) => warn!("Can't renew the {} lock: {}", &name, err),
+        }
+    }
+}
+
+const LOCKS_SCHEMA: &str = r#"
+    create table if not exists proxy_locks (
+        name text not null unique,
+        holder text not null,
+        expires integer not null
+    );"#;
+
+#[cfg(test)]
+mod tests {
+    use super::{acquire, LockHeld, FORWARD_LOCK, PURGE_LOCK};
+
+    #[tokio::test]
+    async fn test_locks() {
+        let dir = std::env::temp_dir().join("adlu-proxy-locks-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let lock = acquire(&pool, FORWARD_LOCK).await.expect("Can't take lock");
+        let err = acquire(&pool, FORWARD_LOCK).await.expect_err("Took a held lock");
+        let held = err.downcast_ref::<LockHeld>().expect("Wrong error");
+        assert_eq!(held.name, FORWARD_LOCK);
+        // other locks are independent
+        acquire(&pool, PURGE_LOCK).await.expect("Can't take other lock").release().await;
+        lock.release().await;
+        acquire(&pool, FORWARD_LOCK).await.expect("Can't retake lock").release().await;
+        // an expired lock can be taken over
+        let i_str = "insert into proxy_locks (name, holder, expires) values (?, ?, 0)";
+        sqlx::query(i_str).bind("stale").bind("crashed").execute(&pool).await.unwrap();
+        acquire(&pool, "stale").await.expect("Can't take expired lock").release().await;
+        pool.close().await;
+    }
+}
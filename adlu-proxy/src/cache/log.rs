@@ -19,19 +19,24 @@ released.  That license is reproduced here in the LICENSE-MIT file.
 use eyre::Result;
 use log::debug;
 use sqlx::{
-    sqlite::{SqlitePool, SqliteRow},
-    Row,
+    sqlite::{Sqlite, SqlitePool, SqliteRow},
+    Row, Transaction,
 };
 
 use adlu_base::Timestamp;
-use adlu_parse::protocol::LogSession;
+use adlu_parse::protocol::{LogEvent, LogSession};
 
 use crate::proxy::{Request, RequestType, Response};
 
+use super::location::{self, location_from_row};
+use super::pipeline;
 use super::schema_upgrade;
+use super::ReportFilter;
 
 pub async fn db_init(pool: &SqlitePool) -> Result<()> {
     sqlx::query(SESSION_SCHEMA).execute(pool).await?;
+    sqlx::query(WATERMARK_SCHEMA).execute(pool).await?;
+    sqlx::query(EVENT_SCHEMA).execute(pool).await?;
     schema_upgrade("log", SESSION_SCHEMA_VERSION, &SCHEMA_ALTERATIONS_BY_VERSION, pool)
         .await?;
     Ok(())
@@ -48,24 +53,149 @@ pub async fn clear(pool: &SqlitePool) -> Result<()> {
 pub async fn report(
     pool: &SqlitePool,
     path: &str,
+    filter: &ReportFilter,
     empty: bool,
     timezone: bool,
     rfc3339: bool,
 ) -> Result<()> {
+    write_report(pool, path, filter, empty, timezone, rfc3339, None).await
+}
+
+/// Report the sessions stored (or updated) since the last incremental report
+/// to the destination.  Returns the watermark to record for the destination
+/// once the report has been delivered, so that a failed delivery is retried.
+pub async fn incremental_report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    destination: &str,
+    empty: bool,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<Timestamp> {
+    let q_str = "select watermark from log_report_watermarks where destination = ?";
+    let since: i64 = sqlx::query(q_str)
+        .bind(destination)
+        .fetch_optional(pool)
+        .await?
+        .map_or(0, |row| row.get("watermark"));
+    // sessions stored during this report are left for the next one
+    let until = Timestamp::now();
+    let window = (since, until.to_db());
+    write_report(pool, path, filter, empty, timezone, rfc3339, Some(window)).await?;
+    Ok(until)
+}
+
+pub async fn record_watermark(
+    pool: &SqlitePool,
+    destination: &str,
+    watermark: &Timestamp,
+) -> Result<()> {
+    let i_str = r#"insert or replace into log_report_watermarks
+        (destination, watermark) values (?, ?)"#;
+    sqlx::query(i_str).bind(destination).bind(watermark.to_db()).execute(pool).await?;
+    Ok(())
+}
+
+/// Report the most frequent warnings and errors found in logs, for each
+/// app and version, so that a bad deployment stands out.
+pub async fn event_report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
     let mut writer = csv::WriterBuilder::new().from_path(path)?;
-    writer.write_record(report_headers(timezone))?;
-    let sessions = fetch_log_sessions(pool, !empty).await?;
-    for session in sessions.iter() {
-        let record = report_record(session, timezone, rfc3339);
-        writer.write_record(record)?;
+    writer.write_record([
+        "App ID".to_string(),
+        "App Version".to_string(),
+        "Level".to_string(),
+        "Error Code".to_string(),
+        "Workflow".to_string(),
+        "Occurrences".to_string(),
+        "Sessions".to_string(),
+        format!("First Seen{time_suffix}"),
+        format!("Last Seen{time_suffix}"),
+        "Sample Message".to_string(),
+    ])?;
+    let format = |ts: Timestamp| {
+        if rfc3339 {
+            ts.format_rfc_3339(timezone)
+        } else {
+            ts.format_iso_8601(timezone)
+        }
+    };
+    let q_str = filter.apply(EVENT_SUMMARY, "first_seen");
+    for row in sqlx::query(&q_str).fetch_all(pool).await?.iter() {
+        let occurrences: i64 = row.get("occurrences");
+        let sessions: i64 = row.get("sessions");
+        writer.write_record([
+            row.get("app_id"),
+            row.get("app_version"),
+            row.get("level"),
+            row.get("error_code"),
+            row.get("workflow"),
+            occurrences.to_string(),
+            sessions.to_string(),
+            format(Timestamp::from_db(row.get("first_seen"))),
+            format(Timestamp::from_db(row.get("last_seen"))),
+            row.get("message"),
+        ])?;
     }
     Ok(())
 }
 
+/// Report the sessions, or just those stored in the given window (which
+/// includes its start time but not its end time), as they are fetched.
+async fn write_report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    empty: bool,
+    timezone: bool,
+    rfc3339: bool,
+    window: Option<(i64, i64)>,
+) -> Result<()> {
+    let q_str = match window {
+        None => filter.apply("select * from log_sessions", "session_start"),
+        Some(_) => {
+            let q_str = "select * from log_sessions where stored >= ? and stored < ?";
+            filter.apply(q_str, "session_start")
+        }
+    };
+    let c_str = format!("select count(*) from ({})", q_str);
+    let (mut count_query, mut query) = (sqlx::query(&c_str), sqlx::query(&q_str));
+    if let Some((since, until)) = window {
+        count_query = count_query.bind(since).bind(until);
+        query = query.bind(since).bind(until);
+    }
+    let total: i64 = count_query.fetch_one(pool).await?.get(0);
+    debug!("Reporting on {} log sessions", total);
+    let format = move |row: &SqliteRow| {
+        let session = session_from_row(row);
+        if !empty && !session.has_info() {
+            return None;
+        }
+        let source: String = row.get("upload_source");
+        let mut record = report_record(&session, &source, timezone, rfc3339);
+        record.splice(1..1, location::report_record(&location_from_row(row)));
+        Some(record)
+    };
+    let headers = report_headers(timezone);
+    let count =
+        pipeline::write_csv(path, headers, total as u64, query.fetch(pool), format)
+            .await?;
+    debug!("Reported {} log sessions", count);
+    Ok(())
+}
+
 fn report_headers(timezone: bool) -> Vec<String> {
     let time_suffix = if timezone { "" } else { " (UTC)" };
     let mut result = vec![];
     result.push("Source Address".to_string());
+    result.extend(location::report_headers());
     result.push("Session ID".to_string());
     result.push(format!("Initial Entry{time_suffix}"));
     result.push(format!("Final Entry{time_suffix}"));
@@ -78,10 +208,16 @@ fn report_headers(timezone: bool) -> Vec<String> {
     result.push("OS Name".to_string());
     result.push("OS Version".to_string());
     result.push("User ID".to_string());
+    result.push("Upload Source".to_string());
     result
 }
 
-fn report_record(session: &LogSession, timezone: bool, rfc3339: bool) -> Vec<String> {
+fn report_record(
+    session: &LogSession,
+    source: &str,
+    timezone: bool,
+    rfc3339: bool,
+) -> Vec<String> {
     let empty = "".to_string();
     let format_ts = |ts: &Timestamp| -> String {
         if rfc3339 {
@@ -111,20 +247,60 @@ fn report_record(session: &LogSession, timezone: bool, rfc3339: bool) -> Vec<Str
         session.os_name.as_ref().unwrap_or(&empty).clone(),
         session.os_version.as_ref().unwrap_or(&empty).clone(),
         session.user_id.as_ref().unwrap_or(&empty).clone(),
+        source.to_string(),
     ];
     result
 }
 
+/// The upload source of sessions uploaded by Adobe apps.
+const NGL_UPLOAD: &str = "ngl";
+
+/// The upload source of sessions uploaded by custom scripts.
+pub const SCRIPT_UPLOAD: &str = "script-uploaded";
+
 pub async fn store_upload_request(pool: &SqlitePool, req: &Request) -> Result<()> {
+    store_upload_sessions(pool, req, NGL_UPLOAD).await.map(|_| ())
+}
+
+/// Store the sessions in an upload, marking them with the given upload source.
+/// Returns the number of sessions stored.
+pub async fn store_upload_sessions(
+    pool: &SqlitePool,
+    req: &Request,
+    source: &str,
+) -> Result<usize> {
     let sessions = req.parse_log()?;
+    let events = req.parse_log_events()?;
+    // an upload can contain many sessions, so store them all in one transaction
+    let mut tx = pool.begin().await?;
     for new in sessions.iter() {
-        if let Some(existing) = fetch_log_session(pool, &new.session_id).await? {
-            store_log_session(pool, &existing.merge(new)?).await?;
+        // a session whose log was rotated arrives in several uploads, in any
+        // order, so stitch each fragment onto what we have already stored
+        if let Some(existing) = fetch_log_session(&mut tx, &new.session_id).await? {
+            store_log_session(&mut tx, &existing.merge(new)?).await?;
         } else {
-            store_log_session(pool, new).await?;
+            store_log_session(&mut tx, new).await?;
         }
+        let u_str = r#"update log_sessions set correlation_id = ?, upload_source = ?,
+            dedupe_key = (select instance_id from proxy_instance) || '|' || session_id
+            where session_id = ?"#;
+        sqlx::query(u_str)
+            .bind(&req.correlation_id)
+            .bind(source)
+            .bind(&new.session_id)
+            .execute(&mut tx)
+            .await?;
     }
-    Ok(())
+    for event in events.iter() {
+        store_log_event(&mut tx, event).await?;
+    }
+    tx.commit().await?;
+    debug!(
+        "Stored {} log sessions and {} log events from upload",
+        sessions.len(),
+        events.len()
+    );
+    Ok(sessions.len())
 }
 
 pub async fn store_upload_response(
@@ -150,16 +326,17 @@ pub async fn fetch_upload_response(
         via: None,
         request_id: None,
         session_id: None,
+        headers: vec![],
     }))
 }
 
 async fn fetch_log_session(
-    pool: &SqlitePool,
+    tx: &mut Transaction<'_, Sqlite>,
     session_id: &str,
 ) -> Result<Option<LogSession>> {
     debug!("Finding log session with id: {}", session_id);
     let q_str = "select * from log_sessions where session_id = ?";
-    let result = sqlx::query(q_str).bind(session_id).fetch_optional(pool).await?;
+    let result = sqlx::query(q_str).bind(session_id).fetch_optional(&mut *tx).await?;
     match result {
         Some(row) => {
             debug!("Found log session with id: {}", session_id);
@@ -172,25 +349,10 @@ async fn fetch_log_session(
     }
 }
 
-pub(crate) async fn fetch_log_sessions(
-    pool: &SqlitePool,
-    info_only: bool,
-) -> Result<Vec<LogSession>> {
-    debug!("Fetching all log sessions");
-    let mut result = vec![];
-    let q_str = "select * from log_sessions";
-    let rows = sqlx::query(q_str).fetch_all(pool).await?;
-    for row in rows {
-        let session = session_from_row(&row);
-        if !info_only || session.has_info() {
-            result.push(session);
-        }
-    }
-    debug!("Fetched {} sessions", result.len());
-    Ok(result)
-}
-
-async fn store_log_session(pool: &SqlitePool, session: &LogSession) -> Result<()> {
+async fn store_log_session(
+    tx: &mut Transaction<'_, Sqlite>,
+    session: &LogSession,
+) -> Result<()> {
     fn opt_val(s: &Option<String>) -> String {
         match s {
             Some(s) => s.clone(),
@@ -200,15 +362,15 @@ async fn store_log_session(pool: &SqlitePool, session: &LogSession) -> Result<()
     let field_list = r#"
         (
             source_addr, session_id, initial_entry, final_entry, session_start, session_end,
-            app_id, app_version, app_locale, ngl_version, os_name, os_version, user_id
+            app_id, app_version, app_locale, ngl_version, os_name, os_version, user_id,
+            stored
         )"#;
-    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
     let i_str = format!(
         "insert or replace into log_sessions {} values {}",
         field_list, value_list
     );
     debug!("Storing log session with id: {}", &session.session_id);
-    let mut tx = pool.begin().await?;
     let result = sqlx::query(&i_str)
         .bind(&session.source_addr)
         .bind(&session.session_id)
@@ -223,13 +385,34 @@ async fn store_log_session(pool: &SqlitePool, session: &LogSession) -> Result<()
         .bind(opt_val(&session.os_name))
         .bind(opt_val(&session.os_version))
         .bind(opt_val(&session.user_id))
-        .execute(&mut tx)
+        .bind(Timestamp::now().to_db())
+        .execute(&mut *tx)
         .await?;
-    tx.commit().await?;
     debug!("Stored log upload request has rowid {}", result.last_insert_rowid());
     Ok(())
 }
 
+async fn store_log_event(
+    tx: &mut Transaction<'_, Sqlite>,
+    event: &LogEvent,
+) -> Result<()> {
+    // a rotated log can be uploaded more than once, so ignore repeated lines
+    let i_str = r#"insert or ignore into log_events
+        (session_id, timestamp, level, component, workflow, error_code, message)
+        values (?, ?, ?, ?, ?, ?, ?)"#;
+    sqlx::query(i_str)
+        .bind(&event.session_id)
+        .bind(event.timestamp.to_db())
+        .bind(&event.level)
+        .bind(&event.component)
+        .bind(&event.workflow)
+        .bind(event.error_code.as_deref().unwrap_or_default())
+        .bind(&event.message)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
 fn session_from_row(row: &SqliteRow) -> LogSession {
     fn opt_val(s: String) -> Option<String> {
         if s.is_empty() {
@@ -258,10 +441,10 @@ fn session_from_row(row: &SqliteRow) -> LogSession {
 const SESSION_SCHEMA: &str = r#"
     create table if not exists log_sessions (
         session_id text not null unique,
-        initial_entry text not null,
-        final_entry text not null,
-        session_start text not null,
-        session_end text not null,
+        initial_entry integer not null,
+        final_entry integer not null,
+        session_start integer not null,
+        session_end integer not null,
         app_id text not null,
         app_version text not null,
         app_locale text not null,
@@ -271,11 +454,60 @@ const SESSION_SCHEMA: &str = r#"
         user_id text not null
     );"#;
 
+const WATERMARK_SCHEMA: &str = r#"
+    create table if not exists log_report_watermarks (
+        destination text not null unique,
+        watermark integer not null
+    );"#;
+
+const EVENT_SCHEMA: &str = r#"
+    create table if not exists log_events (
+        session_id text not null,
+        timestamp integer not null,
+        level text not null,
+        component text not null,
+        workflow text not null,
+        error_code text not null,
+        message text not null,
+        unique(session_id, timestamp, message)
+    );"#;
+
+/// Events are attributed to the app of their session, if it's known.
+const EVENT_SUMMARY: &str = r#"
+    select
+        coalesce(s.app_id, '') as app_id,
+        coalesce(s.app_version, '') as app_version,
+        e.level, e.error_code, e.workflow,
+        count(*) as occurrences,
+        count(distinct e.session_id) as sessions,
+        min(e.timestamp) as first_seen,
+        max(e.timestamp) as last_seen,
+        max(e.message) as message
+    from log_events e left join log_sessions s on e.session_id = s.session_id
+    group by 1, 2, e.level, e.error_code, e.workflow
+    order by 1, 2, occurrences desc, e.level, e.error_code
+    "#;
+
 const CLEAR_ALL: &str = r#"
     delete from log_sessions;
+    delete from log_events;
+    delete from log_report_watermarks;
     "#;
 
-const SESSION_SCHEMA_VERSION: usize = 1;
+const SESSION_SCHEMA_VERSION: usize = 12;
 
-const SCHEMA_ALTERATIONS_BY_VERSION: [&str; SESSION_SCHEMA_VERSION] =
-    ["alter table log_sessions add column source_addr not null default 'unknown'"];
+const SCHEMA_ALTERATIONS_BY_VERSION: [&str; SESSION_SCHEMA_VERSION] = [
+    "alter table log_sessions add column source_addr not null default 'unknown'",
+    "alter table log_sessions add column correlation_id not null default ''",
+    "alter table log_sessions add column dedupe_key not null default ''",
+    r#"update log_sessions set dedupe_key =
+        (select instance_id from proxy_instance) || '|' || session_id"#,
+    "alter table log_sessions add column upload_source not null default 'ngl'",
+    "alter table log_sessions add column country not null default ''",
+    "alter table log_sessions add column city not null default ''",
+    "alter table log_sessions add column campus not null default ''",
+    "alter table log_sessions add column stored integer not null default 0",
+    "update log_sessions set stored = final_entry",
+    "alter table log_sessions add column user_agent not null default ''",
+    "alter table log_sessions add column ngl_client_version not null default ''",
+];
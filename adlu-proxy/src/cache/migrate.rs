@@ -0,0 +1,320 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Migrations that need tables to be rebuilt.
+
+Caches used to store timestamps as formatted strings, which only compare
+correctly as long as every one of them has the same format.  Timestamps are
+now stored as epoch millis in integer columns.  SQLite can't change the type
+of a column, so each table that still has string timestamp columns is
+rebuilt (keeping its other columns, constraints, and indexes as they are),
+and then its string timestamps are parsed and replaced with their millis.
+
+Caches also used to key license sessions by session id alone, which let two
+users that share a session id overwrite each other's session.  SQLite can't
+change a unique constraint either, so the sessions table is rebuilt with one
+on the session id and user key together.
+ */
+use eyre::Result;
+use log::info;
+use sqlx::{sqlite::SqlitePool, Row, Sqlite, Transaction};
+
+use adlu_base::Timestamp;
+
+/// The tables that hold timestamps, and which of their columns hold them.
+const TIMESTAMP_COLUMNS: [(&str, &[&str]); 13] = [
+    ("activation_requests", &["timestamp"]),
+    ("activation_responses", &["timestamp"]),
+    ("deactivation_requests", &["timestamp"]),
+    ("deactivation_responses", &["timestamp"]),
+    ("license_sessions", &["session_start", "session_end"]),
+    (
+        "log_sessions",
+        &["initial_entry", "final_entry", "session_start", "session_end", "stored"],
+    ),
+    ("log_report_watermarks", &["watermark"]),
+    ("log_events", &["timestamp"]),
+    ("inventory", &["timestamp"]),
+    ("packages", &["timestamp"]),
+    ("invalid_api_keys", &["timestamp"]),
+    ("validation_failures", &["first_failure", "last_failure"]),
+    ("parse_failures", &["timestamp"]),
+];
+
+/// Migrate any tables that still have string timestamps, and make sure
+/// that the timestamps used in range queries are indexed.
+pub async fn migrate(pool: &SqlitePool) -> Result<()> {
+    for (table, columns) in TIMESTAMP_COLUMNS.iter() {
+        let mut tx = pool.begin().await?;
+        if retype_columns(&mut tx, table, columns).await? {
+            for column in columns.iter() {
+                let count = convert_strings(&mut tx, table, column).await?;
+                info!("Converted {} timestamp(s) in {}.{}", count, table, column);
+            }
+        }
+        tx.commit().await?;
+    }
+    sqlx::query(TIMESTAMP_INDEXES).execute(pool).await?;
+    let mut tx = pool.begin().await?;
+    rekey_license_sessions(&mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Rebuild the license sessions table so that it's keyed by user as well
+/// as session id, if it isn't already.
+async fn rekey_license_sessions(tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+    let table = "license_sessions";
+    let info =
+        sqlx::query(&format!("pragma table_info({table})")).fetch_all(&mut *tx).await?;
+    if !info.iter().any(|row| row.get::<String, _>("name") == "user_key") {
+        return Ok(());
+    }
+    let old_key = vec!["session_id".to_string()];
+    if !unique_keys(tx, table).await?.contains(&old_key) {
+        return Ok(());
+    }
+    info!("Upgrading '{}' table to key sessions by user", table);
+    rebuild_table(tx, table, &[], Some((&old_key, &["session_id", "user_key"]))).await
+}
+
+/// The columns of each unique constraint on a table.
+async fn unique_keys(
+    tx: &mut Transaction<'_, Sqlite>,
+    table: &str,
+) -> Result<Vec<Vec<String>>> {
+    // unique constraints show up as automatic indexes
+    let indexes =
+        sqlx::query(&format!("pragma index_list({table})")).fetch_all(&mut *tx).await?;
+    let mut result = vec![];
+    for index in indexes.iter() {
+        if index.get::<String, _>("origin") == "u" {
+            let index_name: String = index.get("name");
+            let columns: Vec<String> =
+                sqlx::query(&format!("pragma index_info(\"{index_name}\")"))
+                    .fetch_all(&mut *tx)
+                    .await?
+                    .iter()
+                    .map(|row| row.get("name"))
+                    .collect();
+            result.push(columns);
+        }
+    }
+    Ok(result)
+}
+
+/// Rebuild a table so that the given columns are integer columns, returning
+/// whether it needed rebuilding.  Values are copied as they are, so
+/// timestamps stored as strings remain strings until they are converted.
+async fn retype_columns(
+    tx: &mut Transaction<'_, Sqlite>,
+    table: &str,
+    columns: &[&str],
+) -> Result<bool> {
+    let info =
+        sqlx::query(&format!("pragma table_info({table})")).fetch_all(&mut *tx).await?;
+    let is_timestamp = |name: &str| columns.contains(&name);
+    let needs_rebuild = info.iter().any(|row| {
+        let name: String = row.get("name");
+        let decl: String = row.get("type");
+        is_timestamp(&name) && !decl.eq_ignore_ascii_case("integer")
+    });
+    if !needs_rebuild {
+        return Ok(false);
+    }
+    info!("Upgrading '{}' table to store timestamps as epoch millis", table);
+    rebuild_table(tx, table, columns, None).await?;
+    Ok(true)
+}
+
+/// Rebuild a table, keeping its columns, constraints, and indexes as they
+/// are, except that the `retype` columns become integer columns and (if
+/// there is a `rekey`) the unique constraint on its first set of columns
+/// becomes one on its second set.
+async fn rebuild_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    table: &str,
+    retype: &[&str],
+    rekey: Option<(&[String], &[&str])>,
+) -> Result<()> {
+    let info =
+        sqlx::query(&format!("pragma table_info({table})")).fetch_all(&mut *tx).await?;
+    let is_timestamp = |name: &str| retype.contains(&name);
+    let mut names = vec![];
+    let mut definitions = vec![];
+    for row in info.iter() {
+        let name: String = row.get("name");
+        let mut definition = if is_timestamp(&name) {
+            format!("{name} integer")
+        } else {
+            format!("{name} {}", row.get::<String, _>("type"))
+        };
+        if row.get::<bool, _>("notnull") {
+            definition.push_str(" not null");
+        }
+        if let Some(default) = row.get::<Option<String>, _>("dflt_value") {
+            let default = if is_timestamp(&name) { "0".to_string() } else { default };
+            definition.push_str(&format!(" default {default}"));
+        }
+        names.push(name);
+        definitions.push(definition);
+    }
+    for columns in unique_keys(tx, table).await? {
+        match rekey {
+            Some((old, new)) if columns == old => {
+                definitions.push(format!("unique({})", new.join(", ")))
+            }
+            _ => definitions.push(format!("unique({})", columns.join(", "))),
+        }
+    }
+    // other indexes are recreated from their definitions
+    let q_str = r#"select sql from sqlite_master
+        where type = 'index' and tbl_name = ? and sql is not null"#;
+    let index_sql: Vec<String> = sqlx::query(q_str)
+        .bind(table)
+        .fetch_all(&mut *tx)
+        .await?
+        .iter()
+        .map(|row| row.get("sql"))
+        .collect();
+    let new_table = format!("{table}_upgrade");
+    let names = names.join(", ");
+    let statements = [
+        format!("create table {new_table} ({})", definitions.join(", ")),
+        format!("insert into {new_table} ({names}) select {names} from {table}"),
+        format!("drop table {table}"),
+        format!("alter table {new_table} rename to {table}"),
+    ];
+    for statement in statements.iter().chain(index_sql.iter()) {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    Ok(())
+}
+
+/// Replace the timestamps in a column that are stored as strings with
+/// their epoch millis, returning how many there were.  An empty string
+/// (used for a missing timestamp) becomes 0.
+async fn convert_strings(
+    tx: &mut Transaction<'_, Sqlite>,
+    table: &str,
+    column: &str,
+) -> Result<usize> {
+    let q_str = format!(
+        "select rowid, {column} as value from {table} where typeof({column}) = 'text'"
+    );
+    let rows = sqlx::query(&q_str).fetch_all(&mut *tx).await?;
+    let u_str = format!("update {table} set {column} = ? where rowid = ?");
+    for row in rows.iter() {
+        let value: String = row.get("value");
+        let millis =
+            if value.is_empty() { 0 } else { Timestamp::from_db_string(&value).to_db() };
+        let rowid: i64 = row.get("rowid");
+        sqlx::query(&u_str).bind(millis).bind(rowid).execute(&mut *tx).await?;
+    }
+    Ok(rows.len())
+}
+
+const TIMESTAMP_INDEXES: &str = r#"
+    create index if not exists activation_timestamp_index
+        on activation_requests (timestamp);
+    create index if not exists deactivation_timestamp_index
+        on deactivation_requests (timestamp);
+    create index if not exists license_start_index on license_sessions (session_start);
+    create index if not exists log_start_index on log_sessions (session_start);
+    create index if not exists log_stored_index on log_sessions (stored);
+    create index if not exists log_event_timestamp_index on log_events (timestamp);
+    create index if not exists invalid_api_key_timestamp_index
+        on invalid_api_keys (timestamp);
+    "#;
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Row;
+
+    #[tokio::test]
+    async fn test_migrate_string_timestamps() {
+        let dir = std::env::temp_dir().join("adlu-proxy-migrate-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        // put back the string-typed table used by older caches
+        let statements = [
+            "drop table license_sessions",
+            r#"create table license_sessions (
+                session_id text not null unique,
+                session_start text not null,
+                session_end text not null,
+                app_id text not null default ''
+            )"#,
+            "create index license_app_index on license_sessions (app_id)",
+            r#"insert into license_sessions (session_id, session_start, session_end)
+                values ('s1', '2022-10-01T00:00:00.000+0000', '')"#,
+        ];
+        for statement in statements.iter() {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        pool.close().await;
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let row =
+            sqlx::query("select * from license_sessions").fetch_one(&pool).await.unwrap();
+        let start: i64 = row.get("session_start");
+        let end: i64 = row.get("session_end");
+        assert_eq!(start, 1_664_582_400_000);
+        assert_eq!(end, 0);
+        let q_str =
+            "select count(*) from sqlite_master where type = 'index' and name = ?";
+        for index in ["license_app_index", "license_start_index"] {
+            let count: i64 =
+                sqlx::query(q_str).bind(index).fetch_one(&pool).await.unwrap().get(0);
+            assert_eq!(count, 1, "Missing index {}", index);
+        }
+        // the unique constraint survives the rebuild
+        let i_str =
+            "insert into license_sessions (session_id, session_start, session_end)
+            values ('s1', 0, 0)";
+        assert!(sqlx::query(i_str).execute(&pool).await.is_err());
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_migrate_license_session_key() {
+        let dir = std::env::temp_dir().join("adlu-proxy-rekey-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let mut tx = pool.begin().await.unwrap();
+        let keys = super::unique_keys(&mut tx, "license_sessions").await.unwrap();
+        tx.commit().await.unwrap();
+        assert!(keys.contains(&vec!["session_id".to_string(), "user_key".to_string()]));
+        assert!(!keys.contains(&vec!["session_id".to_string()]));
+        // the start index survives the rebuild
+        let q_str =
+            "select count(*) from sqlite_master where type = 'index' and name = ?";
+        let count: i64 = sqlx::query(q_str)
+            .bind("license_start_index")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(count, 1);
+        pool.close().await;
+    }
+}
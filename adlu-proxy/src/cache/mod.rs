@@ -19,45 +19,100 @@ released.  That license is reproduced here in the LICENSE-MIT file.
 use std::env;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use ::log::{error, info};
+use ::log::{debug, error, info};
 use dialoguer::Confirm;
 use eyre::{eyre, Result, WrapErr};
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions},
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions,
+        SqliteSynchronous,
+    },
     ConnectOptions, Row,
 };
 
-use adlu_parse::protocol::{Request, RequestType};
+use adlu_base::Timestamp;
+use adlu_parse::protocol::{InventoryReport, Request, RequestType};
 
-use crate::cli::Datasource;
-use crate::proxy::Response;
+use crate::cli::{Datasource, PatchAction};
+use crate::compress::Compression;
+use crate::geoip::Location;
+use crate::proxy::{RequestOutcome, Response};
+use crate::security::{InvalidKeyAttempt, ParseFailure, ValidationFailure};
+use crate::settings::{CachePolicy, OrgMapping, Proxy, Retention};
 
+mod activity;
+mod agent;
+mod archive;
+mod bandwidth;
+mod chunks;
+mod directory;
+mod effectiveness;
+mod filter;
 mod frl;
+mod integrity;
+mod inventory;
+mod location;
+mod locks;
 mod log;
+mod migrate;
 mod named_user;
+mod notes;
+mod orgs;
+mod packages;
+mod patch;
+mod pipeline;
+mod platform;
+mod policy;
+mod preview;
+mod quota;
+mod security;
+mod stats;
+mod usage;
+mod verify;
+
+pub use activity::{ActivityBin, ActivityCount};
+pub use directory::os_user_id;
+pub use filter::ReportFilter;
+pub use frl::HeldLicense;
+pub use locks::{CacheLock, LockHeld, FORWARD_LOCK, PURGE_LOCK};
+pub use notes::Note;
 
 /// A cache for requests and responses.
 ///
 /// This cache uses an SQLite v3 database accessed asynchronously via `sqlx`.
 pub type Cache = Arc<Db>;
 
-pub async fn connect(path: &str) -> Result<Cache> {
-    Ok(Arc::new(Db::from(path).await?))
+/// Connect to the cache configured by the proxy settings.
+pub async fn connect(settings: &Proxy) -> Result<Cache> {
+    Ok(Arc::new(Db::from(settings).await?))
 }
 
 #[derive(Debug)]
 pub struct Db {
     pool: SqlitePool,
+    max_bytes: u64,
+    activations: frl::ActivationIndex,
+    policies: Vec<CachePolicy>,
 }
 
 impl Db {
-    async fn from(path: &str) -> Result<Self> {
-        let pool = db_init(path, "rwc")
+    async fn from(settings: &Proxy) -> Result<Self> {
+        let path = &settings.db_path;
+        let pool = db_init(path, "rwc", settings.db_max_connections)
             .await
             .wrap_err(format!("Can't connect to cache db: {}", path))?;
-        info!("Valid cache database: {}", &path);
-        Ok(Self { pool })
+        info!("Valid cache database: {}", path);
+        let activations = frl::ActivationIndex::load(&pool).await?;
+        let db = Self {
+            pool,
+            max_bytes: settings.db_max_size_kb * 1024,
+            activations,
+            policies: settings.cache_policies.clone(),
+        };
+        db.enforce_quota().await;
+        Ok(db)
     }
 
     pub async fn close(&self) {
@@ -76,26 +131,231 @@ impl Db {
         if confirm {
             let pool = &self.pool;
             frl::clear(pool).await?;
+            self.activations.clear();
             log::clear(pool).await?;
             named_user::clear(pool).await?;
+            security::clear(pool).await?;
+            inventory::clear(pool).await?;
+            packages::clear(pool).await?;
+            directory::clear(pool).await?;
+            bandwidth::clear(pool).await?;
+            effectiveness::clear(pool).await?;
+            notes::clear(pool).await?;
         }
         Ok(())
     }
 
+    /// Clear the data from one datasource, or the data older than a date, or
+    /// both, after asking for confirmation.  Only FRL, NUL, and log data can
+    /// be cleared this way, and (as with purging) requests waiting to be
+    /// forwarded are kept.  Returns how many entries were cleared.
+    pub async fn clear_selected(
+        &self,
+        source: Option<&Datasource>,
+        before: Option<&Timestamp>,
+        yes: bool,
+    ) -> Result<usize> {
+        if !matches!(
+            source,
+            None | Some(Datasource::Frl | Datasource::Nul | Datasource::Log)
+        ) {
+            return Err(eyre!("Only FRL, NUL, and log data can be cleared selectively"));
+        }
+        let which = match source {
+            Some(source) => source.to_string(),
+            None => "all data".to_string(),
+        };
+        let when = match before {
+            Some(before) => format!(" from before {}", before.format_iso_8601(false)),
+            None => "".to_string(),
+        };
+        let confirm = match yes {
+            true => true,
+            false => Confirm::new()
+                .with_prompt(format!(
+                    "Really clear {}{} from the cache? This operation cannot be undone.",
+                    which, when
+                ))
+                .default(false)
+                .show_default(true)
+                .interact()?,
+        };
+        if !confirm {
+            return Ok(0);
+        }
+        // with no date, clear everything up to (and including) now
+        let cutoff = match before {
+            Some(before) => before.clone(),
+            None => Timestamp::from_millis(Timestamp::now().to_millis() + 1),
+        };
+        let lock = self.lock(PURGE_LOCK).await.wrap_err("Can't clear data")?;
+        let result = quota::purge(&self.pool, &cutoff, source).await;
+        lock.release().await;
+        result
+    }
+
+    /// Invalidate, restore, or wipe the cached activation responses that
+    /// match a key (see the `patch` module), after listing them and asking for
+    /// confirmation.  Returns how many were patched.
+    pub async fn patch_responses(
+        &self,
+        key: &str,
+        action: &PatchAction,
+        reason: &str,
+        yes: bool,
+    ) -> Result<u64> {
+        let matches = patch::matching_responses(&self.pool, key).await?;
+        if matches.is_empty() {
+            return Err(eyre!("No cached activation responses match key: {}", key));
+        }
+        eprintln!("Cached activation response(s) matching {}:", key);
+        for (a_key, invalid) in matches.iter() {
+            eprintln!("    {}{}", a_key, if *invalid { " (invalid)" } else { "" });
+        }
+        let confirm = match yes {
+            true => true,
+            false => Confirm::new()
+                .with_prompt(format!("Really {} these responses?", action))
+                .default(false)
+                .show_default(true)
+                .interact()?,
+        };
+        if !confirm {
+            return Ok(0);
+        }
+        let keys: Vec<String> = matches.into_iter().map(|(a_key, _)| a_key).collect();
+        let count = patch::patch_responses(&self.pool, &keys, action, reason).await?;
+        info!("Patched ({}) {} cached activation response(s): {}", action, count, reason);
+        Ok(count)
+    }
+
+    /// Import from an export database (which may be compressed), from a
+    /// chunked export (named either by its manifest or by the path it was
+    /// exported to), or from an archive of an export.
+    /// Package metadata is imported from package files, and directory users
+    /// from a list of usernames, rather than a database.
     pub async fn import(&self, source: &Datasource, path: &str) -> Result<()> {
-        if let Datasource::Frl = source {
-            frl::import(&self.pool, path).await
-        } else {
-            Err(eyre!("Import of {} is not yet implemented.", &source))
+        self.import_skipping_damaged(source, path, false).await
+    }
+
+    /// Like [`import`](Self::import), but an FRL export with damaged tables
+    /// (or damaged chunks) is imported from its intact tables if
+    /// `skip_damaged` is set, rather than being refused.
+    pub async fn import_skipping_damaged(
+        &self,
+        source: &Datasource,
+        path: &str,
+        skip_damaged: bool,
+    ) -> Result<()> {
+        if matches!(source, Datasource::Packages) {
+            return packages::import(&self.pool, path).await;
+        }
+        if matches!(source, Datasource::Users) {
+            return directory::import(&self.pool, path).await;
+        }
+        if !matches!(source, Datasource::Frl) {
+            return Err(eyre!("Import of {} is not yet implemented.", &source));
+        }
+        // archives have a compression extension, so they are checked first
+        if archive::is_archive(path) {
+            let unpacked = format!("{}.unpacked", path);
+            archive::unpack(path, &unpacked, skip_damaged).await?;
+            eprintln!("Verified and unpacked the archive {}", path);
+            let result = frl::import(&self.pool, &unpacked, skip_damaged).await;
+            std::fs::remove_file(&unpacked).ok();
+            return result;
+        }
+        if let Some(compression) = Compression::from_path(path) {
+            let decompressed = format!("{}.decompressed", path);
+            compression.decompress(path, &decompressed)?;
+            let result = frl::import(&self.pool, &decompressed, skip_damaged).await;
+            std::fs::remove_file(&decompressed).ok();
+            return result;
+        }
+        match chunks::chunked_base(path) {
+            None => frl::import(&self.pool, path, skip_damaged).await,
+            Some(base) => {
+                let assembled = format!("{}.assembled", base);
+                chunks::assemble(&base, &assembled, skip_damaged)?;
+                eprintln!("Verified and reassembled the chunks of {}", &base);
+                let result = frl::import(&self.pool, &assembled, skip_damaged).await;
+                std::fs::remove_file(&assembled).ok();
+                result
+            }
         }
     }
 
-    pub async fn export(&self, source: &Datasource, path: &str) -> Result<()> {
-        if let Datasource::Frl = source {
-            frl::export(&self.pool, path).await
-        } else {
-            Err(eyre!("Export of {} is not yet implemented.", &source))
+    /// If this cache is a sealed export (see the `integrity` module), seal it
+    /// again with its current contents, returning whether it was sealed.
+    pub async fn reseal_export(&self) -> Result<bool> {
+        integrity::reseal(&self.pool).await
+    }
+
+    /// Import from the cache database of a legacy frl-online-proxy.
+    pub async fn import_legacy(&self, source: &Datasource, path: &str) -> Result<()> {
+        if !matches!(source, Datasource::Frl) {
+            return Err(eyre!("Legacy import of {} is not supported.", &source));
         }
+        frl::import_legacy(&self.pool, path).await
+    }
+
+    /// Export to a database, or to a chunked export if a chunk size is given.
+    pub async fn export(
+        &self,
+        source: &Datasource,
+        path: &str,
+        chunk_mb: Option<u64>,
+    ) -> Result<()> {
+        self.export_for_org(source, path, chunk_mb, None).await
+    }
+
+    /// Like [`export`](Self::export), but if there's an org only the requests
+    /// for its packages are exported.
+    pub async fn export_for_org(
+        &self,
+        source: &Datasource,
+        path: &str,
+        chunk_mb: Option<u64>,
+        org_id: Option<&str>,
+    ) -> Result<()> {
+        if !matches!(source, Datasource::Frl) {
+            return Err(eyre!("Export of {} is not yet implemented.", &source));
+        }
+        match chunk_mb {
+            None => frl::export(&self.pool, path, org_id).await,
+            Some(mb) => {
+                let manifest = chunks::manifest_path(path);
+                if std::fs::metadata(&manifest).is_ok() {
+                    return Err(eyre!("Cannot export to an existing file: {}", manifest));
+                }
+                let whole = format!("{}.whole", path);
+                frl::export(&self.pool, &whole, org_id).await?;
+                let result = split_into_chunks(&whole, path, mb);
+                std::fs::remove_file(&whole).ok();
+                result
+            }
+        }
+    }
+
+    /// Like [`export_for_org`](Self::export_for_org), but the export is packed
+    /// into an archive (see [`pack_archive`]) rather than left as a database.
+    pub async fn export_archive(
+        &self,
+        source: &Datasource,
+        path: &str,
+        org_id: Option<&str>,
+    ) -> Result<()> {
+        let path = archive::archive_path(path);
+        if std::fs::metadata(&path).is_ok() {
+            return Err(eyre!("Cannot export to an existing file: {}", path));
+        }
+        let db = format!("{}.sqlite", path);
+        self.export_for_org(source, &db, None, org_id).await?;
+        let result = archive::pack(&db, &path).await;
+        std::fs::remove_file(&db).ok();
+        let (tables, payloads) = result?;
+        eprintln!("Packed {} table(s) and {} payload(s) into {}", tables, payloads, path);
+        Ok(())
     }
 
     pub async fn report(
@@ -106,19 +366,120 @@ impl Db {
         timezone: bool,
         rfc3339: bool,
     ) -> Result<()> {
-        match source {
-            Datasource::Frl => {
-                Err(eyre!("Reporting of {} is not yet implemented", &source))
-            }
+        let filter = ReportFilter::default();
+        self.filtered_report(source, path, &filter, empty, timezone, rfc3339).await
+    }
+
+    /// Like [`report`](Self::report), but only reports the rows that match
+    /// the filter.
+    pub async fn filtered_report(
+        &self,
+        source: &Datasource,
+        path: &str,
+        filter: &ReportFilter,
+        empty: bool,
+        timezone: bool,
+        rfc3339: bool,
+    ) -> Result<()> {
+        let pool = &self.pool;
+        let result = match source {
+            Datasource::Frl => frl::report(pool, path, filter, timezone, rfc3339).await,
             Datasource::Nul => {
-                named_user::report(&self.pool, path, empty, timezone, rfc3339).await
+                named_user::report(pool, path, filter, empty, timezone, rfc3339).await
             }
             Datasource::Log => {
-                log::report(&self.pool, path, empty, timezone, rfc3339).await
+                log::report(pool, path, filter, empty, timezone, rfc3339).await
+            }
+            Datasource::Keys => {
+                security::report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Vdi => frl::vdi_report(pool, path, filter).await,
+            Datasource::Bodies => {
+                security::failure_report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Inventory => {
+                inventory::report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Packages => {
+                packages::report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Errors => {
+                log::event_report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Versions => {
+                agent::report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Activity => activity::report(pool, path, filter).await,
+            Datasource::Usage => {
+                usage::report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Bandwidth => bandwidth::report(pool, path, filter).await,
+            Datasource::Effectiveness => effectiveness::report(pool, path, filter).await,
+            Datasource::Payloads => {
+                security::payload_report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Patches => {
+                patch::report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Users => {
+                directory::report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Conflicts => {
+                frl::conflict_report(pool, path, filter, timezone, rfc3339).await
+            }
+            Datasource::Platforms => {
+                platform::report(pool, path, filter, timezone, rfc3339).await
             }
+        };
+        if filter.is_empty() {
+            result
+        } else {
+            result.wrap_err("Report failed (check the filter's column names)")
         }
     }
 
+    /// Like [`filtered_report`](Self::filtered_report), but only reports what
+    /// was stored since the last incremental report to the same destination.
+    /// Returns the watermark to record (see
+    /// [`record_watermark`](Self::record_watermark)) once the report has
+    /// been delivered.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn incremental_report(
+        &self,
+        source: &Datasource,
+        path: &str,
+        filter: &ReportFilter,
+        destination: &str,
+        empty: bool,
+        timezone: bool,
+        rfc3339: bool,
+    ) -> Result<Timestamp> {
+        match source {
+            Datasource::Log => {
+                let pool = &self.pool;
+                log::incremental_report(
+                    pool,
+                    path,
+                    filter,
+                    destination,
+                    empty,
+                    timezone,
+                    rfc3339,
+                )
+                .await
+            }
+            _ => Err(eyre!("Incremental reports of {} are not yet implemented.", source)),
+        }
+    }
+
+    pub async fn record_watermark(
+        &self,
+        destination: &str,
+        watermark: &Timestamp,
+    ) -> Result<()> {
+        log::record_watermark(&self.pool, destination, watermark).await
+    }
+
     pub async fn store_request(&self, req: &Request) {
         let pool = &self.pool;
         let result = match req.request_type {
@@ -132,14 +493,32 @@ impl Db {
         };
         if let Err(err) = result {
             error!("Cache store of {} failed: {}", req, err);
+        } else if let Err(err) = agent::store_agent(pool, req).await {
+            error!("Cache store of user agent for {} failed: {}", req, err);
         }
+        self.enforce_quota().await;
+    }
+
+    /// Store a log upload from a custom script, returning how many
+    /// sessions it contained.  These uploads are never forwarded.
+    pub async fn store_script_upload(&self, req: &Request) -> Result<usize> {
+        let count =
+            log::store_upload_sessions(&self.pool, req, log::SCRIPT_UPLOAD).await?;
+        agent::store_agent(&self.pool, req).await?;
+        self.enforce_quota().await;
+        Ok(count)
     }
 
+    /// Store a response.  Responses that their cache policy says are never
+    /// replayed are still stored, as a record that their request was answered,
+    /// but they aren't indexed (see the `policy` module).
     pub async fn store_response(&self, req: &Request, resp: &Response) {
         let pool = &self.pool;
+        let replays = policy::replays(policy::policy_for(&self.policies, req));
         let result = match resp.request_type {
             RequestType::FrlActivation => {
-                frl::store_activation_response(pool, req, resp).await
+                let index = replays.then_some(&self.activations);
+                frl::store_activation_response(pool, index, req, resp).await
             }
             RequestType::FrlDeactivation => {
                 frl::store_deactivation_response(pool, req, resp).await
@@ -153,22 +532,152 @@ impl Db {
         if let Err(err) = result {
             error!("Cache store of {} failed: {}", req, err);
         }
+        self.enforce_quota().await;
     }
 
-    pub async fn fetch_response(&self, req: &Request) -> Option<Response> {
+    pub async fn store_outcome(&self, req: &Request, outcome: &RequestOutcome) {
         let pool = &self.pool;
-        let result = match &req.request_type {
-            RequestType::FrlActivation => frl::fetch_activation_response(pool, req).await,
+        let result = match req.request_type {
+            RequestType::FrlActivation => {
+                frl::store_activation_outcome(pool, req, outcome).await
+            }
             RequestType::FrlDeactivation => {
-                frl::fetch_deactivation_response(pool, req).await
+                frl::store_deactivation_outcome(pool, req, outcome).await
             }
             RequestType::NulLicense => {
-                named_user::fetch_license_response(pool, req).await
+                named_user::store_license_outcome(pool, req, outcome).await
             }
-            RequestType::LogUpload => log::fetch_upload_response(pool, req).await,
-            RequestType::Unknown => Ok(None),
+            RequestType::LogUpload | RequestType::Unknown => Ok(()),
         };
-        match result {
+        if let Err(err) = result {
+            error!("Cache store of outcome for {} failed: {}", req, err);
+        }
+    }
+
+    /// Count how a client's request was answered, for the effectiveness totals.
+    /// Each reply is counted once, even if the request is also sent upstream
+    /// in the background or forwarded later.
+    pub async fn count_reply(&self, req: &Request, outcome: &RequestOutcome) {
+        if let Err(err) = effectiveness::count_reply(&self.pool, req, outcome).await {
+            error!("Cache count of reply to {} failed: {}", req, err);
+        }
+    }
+
+    /// How requests have been answered today and over all time, by type.
+    pub async fn effectiveness(&self) -> Result<serde_json::Value> {
+        effectiveness::status(&self.pool).await
+    }
+
+    /// Add a request, and the size of the response it got, to the
+    /// bandwidth totals.
+    pub async fn store_bandwidth(&self, req: &Request, response_bytes: u64) {
+        let result = bandwidth::store_bandwidth(&self.pool, req, response_bytes).await;
+        if let Err(err) = result {
+            error!("Cache store of bandwidth for {} failed: {}", req, err);
+        }
+    }
+
+    pub async fn store_location(&self, req: &Request, location: &Location) {
+        if let Err(err) = location::store_location(&self.pool, req, location).await {
+            error!("Cache store of location for {} failed: {}", req, err);
+        }
+    }
+
+    pub async fn store_invalid_key_attempt(&self, attempt: &InvalidKeyAttempt) {
+        if let Err(err) = security::store_invalid_key_attempt(&self.pool, attempt).await {
+            error!("Cache store of invalid api key attempt failed: {}", err);
+        }
+    }
+
+    pub async fn store_validation_failure(&self, failure: &ValidationFailure) {
+        if let Err(err) = security::store_validation_failure(&self.pool, failure).await {
+            error!("Cache store of request validation failure failed: {}", err);
+        }
+    }
+
+    pub async fn store_parse_failure(&self, failure: &ParseFailure) {
+        if let Err(err) = security::store_parse_failure(&self.pool, failure).await {
+            error!("Cache store of unparsed request body failed: {}", err);
+        }
+    }
+
+    pub async fn store_inventory(
+        &self,
+        report: &InventoryReport,
+        source_addr: &str,
+    ) -> Result<()> {
+        inventory::store_inventory(&self.pool, report, source_addr).await
+    }
+
+    /// The number of devices holding FRL activations for a package
+    /// (answered or not), and whether the given device is one of them.
+    pub async fn package_usage(
+        &self,
+        npd_id: &str,
+        device_id: &str,
+    ) -> Result<(u64, bool)> {
+        frl::package_usage(&self.pool, npd_id, device_id).await
+    }
+
+    /// Like [`package_usage`](Self::package_usage), but for all the
+    /// packages of an org.
+    pub async fn org_usage(&self, org_id: &str, device_id: &str) -> Result<(u64, bool)> {
+        frl::org_usage(&self.pool, org_id, device_id).await
+    }
+
+    /// The org of a package (see the `orgs` module), or an empty string
+    /// if it isn't known.
+    pub async fn package_org(&self, npd_id: &str) -> Result<String> {
+        orgs::org_for_package(&self.pool, npd_id).await
+    }
+
+    /// Use the org mappings from the config to tag stored requests.
+    pub async fn store_org_mappings(&self, mappings: &[OrgMapping]) -> Result<()> {
+        orgs::store_mappings(&self.pool, mappings).await
+    }
+
+    /// The deactivation that returns a device's license for a package,
+    /// if the device has activated the package.
+    pub async fn deactivation_for_device(
+        &self,
+        npd_id: &str,
+        device_id: &str,
+    ) -> Result<Option<Request>> {
+        frl::deactivation_for_device(&self.pool, npd_id, device_id).await
+    }
+
+    /// The package and device of every activation that hasn't been
+    /// followed by a deactivation.
+    pub async fn activated_devices(&self) -> Result<Vec<(String, String)>> {
+        frl::activated_devices(&self.pool).await
+    }
+
+    /// The licenses that devices hold, or (if there's an org) just the ones
+    /// for that org's packages.
+    pub async fn held_licenses(&self, org_id: Option<&str>) -> Result<Vec<HeldLicense>> {
+        frl::held_licenses(&self.pool, org_id).await
+    }
+
+    /// Attach a note to the activations, deactivations, and license sessions
+    /// with the given key, returning how many of them there were.
+    pub async fn annotate(&self, key: &str, note: &str) -> Result<usize> {
+        notes::annotate(&self.pool, key, note).await
+    }
+
+    /// The notes on the rows with the given key, or all notes if there's no key.
+    pub async fn notes(&self, key: Option<&str>) -> Result<Vec<Note>> {
+        notes::fetch_notes(&self.pool, key).await
+    }
+
+    /// Take the named lock, so that other processes sharing the cache don't
+    /// do the same work at the same time.  It's a [`LockHeld`] error if another
+    /// process (or task) holds the lock.
+    pub async fn lock(&self, name: &str) -> Result<CacheLock> {
+        locks::acquire(&self.pool, name).await
+    }
+
+    pub async fn fetch_response(&self, req: &Request) -> Option<Response> {
+        match self.try_fetch_response(req).await {
             Err(err) => {
                 error!("Cache fetch of response for {} failed: {}", req, err);
                 None
@@ -177,27 +686,241 @@ impl Db {
         }
     }
 
+    /// Like [`fetch_response`](Self::fetch_response), but distinguishes
+    /// a cache failure from a cache miss.  A response that its cache policy
+    /// says can't be replayed is a miss.
+    pub async fn try_fetch_response(&self, req: &Request) -> Result<Option<Response>> {
+        let policy = policy::policy_for(&self.policies, req);
+        if !policy::replays(policy) {
+            debug!("Cache policy prevents replaying a response to {}", req);
+            return Ok(None);
+        }
+        let resp = self.fetch_stored_response(req).await?;
+        match resp {
+            Some(resp) if !policy::can_replay(policy, &resp, &Timestamp::now()) => {
+                debug!(
+                    "Cached response to {} is older than its cache policy allows",
+                    req
+                );
+                Ok(None)
+            }
+            resp => Ok(resp),
+        }
+    }
+
+    async fn fetch_stored_response(&self, req: &Request) -> Result<Option<Response>> {
+        let pool = &self.pool;
+        match &req.request_type {
+            RequestType::FrlActivation => {
+                frl::fetch_activation_response(pool, &self.activations, req).await
+            }
+            RequestType::FrlDeactivation => {
+                frl::fetch_deactivation_response(pool, req).await
+            }
+            RequestType::NulLicense => {
+                named_user::fetch_license_response(pool, req).await
+            }
+            RequestType::LogUpload => log::fetch_upload_response(pool, req).await,
+            RequestType::Unknown => Ok(None),
+        }
+    }
+
     pub async fn fetch_unanswered_requests(&self) -> Result<Vec<Request>> {
         frl::fetch_unanswered_requests(&self.pool).await
     }
+
+    /// Evict entries that are more than `max_age_days` old, returning
+    /// how many were evicted.  Unanswered requests are never evicted.
+    pub async fn purge(&self, max_age_days: u32) -> Result<usize> {
+        let age = max_age_days as i64 * 24 * 3600 * 1000;
+        let cutoff = Timestamp::from_millis(Timestamp::now().to_millis() - age);
+        quota::purge(&self.pool, &cutoff, None).await
+    }
+
+    /// Evict the entries of each datasource that are older than its retention
+    /// period, returning how many were evicted from each.  Unanswered requests
+    /// are never evicted.
+    pub async fn apply_retention(
+        &self,
+        retention: &Retention,
+    ) -> Result<Vec<(Datasource, usize)>> {
+        let mut result = vec![];
+        for (source, days) in retention.policies()? {
+            let age = days as i64 * 24 * 3600 * 1000;
+            let cutoff = Timestamp::from_millis(Timestamp::now().to_millis() - age);
+            let count = quota::purge(&self.pool, &cutoff, Some(&source)).await?;
+            result.push((source, count));
+        }
+        Ok(result)
+    }
+
+    /// Counts of activations, license sessions, and log sessions in each
+    /// hour or day from `since` (inclusive) to `until` (exclusive).
+    pub async fn activity(
+        &self,
+        bin: ActivityBin,
+        since: &Timestamp,
+        until: &Timestamp,
+    ) -> Result<Vec<ActivityCount>> {
+        activity::histogram(&self.pool, bin, since, until).await
+    }
+
+    /// Print statistics about the cache contents, as a table or as JSON.
+    pub async fn stats(&self, json: bool) -> Result<()> {
+        let stats = stats::collect(&self.pool).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            print!("{}", stats);
+        }
+        Ok(())
+    }
+
+    /// Predict what deploying the package(s) at a path would do on the devices
+    /// holding activations (or only the `limit` most recently active ones).
+    pub async fn preview(
+        &self,
+        path: &str,
+        limit: Option<usize>,
+        json: bool,
+    ) -> Result<()> {
+        let previews = preview::preview(&self.pool, path, limit).await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&previews)?);
+        } else {
+            for preview in previews.iter() {
+                print!("{}", preview);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the cache for orphaned and dangling rows (and, optionally,
+    /// repair them).  It's an error if problems are found but not repaired.
+    pub async fn verify(&self, repair: bool) -> Result<()> {
+        let anomalies = verify::verify(&self.pool, repair).await?;
+        if anomalies.is_empty() {
+            eprintln!("No inconsistencies found in the cache.");
+            return Ok(());
+        }
+        for anomaly in anomalies.iter() {
+            eprintln!("Found {}", anomaly);
+        }
+        let unrepaired = anomalies.iter().filter(|a| !repair || !a.repairable).count();
+        if repair {
+            eprintln!("Repaired the cache and rebuilt its indexes.");
+        }
+        match unrepaired {
+            0 => Ok(()),
+            n if repair => Err(eyre!("{} inconsistenc(ies) could not be repaired", n)),
+            n => Err(eyre!("Found {} inconsistenc(ies); use --repair to fix them", n)),
+        }
+    }
+
+    /// Evict old entries if the cache has grown beyond its quota.
+    async fn enforce_quota(&self) {
+        if self.max_bytes > 0 {
+            if let Err(err) = quota::enforce(&self.pool, self.max_bytes).await {
+                error!("Cache quota enforcement failed: {}", err);
+            }
+        }
+    }
+}
+
+/// Split the database file at `source` into a chunked export at `base`.
+pub fn split_into_chunks(source: &str, base: &str, chunk_mb: u64) -> Result<()> {
+    if chunk_mb == 0 {
+        return Err(eyre!("Chunk size must be at least 1 MB"));
+    }
+    let count = chunks::split(source, base, chunk_mb * 1024 * 1024)?;
+    let manifest = chunks::manifest_path(base);
+    eprintln!("Split {} into {} chunk(s) listed in {}", source, count, manifest);
+    Ok(())
+}
+
+/// Pack the export database at `path` into a portable archive next to it
+/// (see the `archive` module).
+pub async fn pack_archive(path: &str) -> Result<()> {
+    let archive = archive::archive_path(path);
+    let (tables, payloads) = archive::pack(path, &archive).await?;
+    eprintln!("Packed {} table(s) and {} payload(s) into {}", tables, payloads, archive);
+    Ok(())
+}
+
+/// Verify an archive and restore the export database it holds next to it.
+pub async fn unpack_archive(path: &str) -> Result<()> {
+    let target = archive::unpacked_path(path)
+        .ok_or_else(|| eyre!("Not the name of an archive: {}", path))?;
+    archive::unpack(path, &target, false).await?;
+    eprintln!("Verified and unpacked {} into {}", path, &target);
+    Ok(())
+}
+
+/// Verify and reassemble a chunked export (named either by its manifest or
+/// by the path it was exported to) into the file it was split from.
+pub fn join_chunks(path: &str) -> Result<()> {
+    let base = chunks::chunked_base(path)
+        .ok_or_else(|| eyre!("No chunk manifest found for: {}", path))?;
+    if std::fs::metadata(&base).is_ok() {
+        return Err(eyre!("Cannot join chunks into an existing file: {}", &base));
+    }
+    chunks::assemble(&base, &base, false)?;
+    eprintln!("Verified and reassembled the chunks of {}", &base);
+    Ok(())
 }
 
-async fn db_init(db_name: &str, mode: &str) -> Result<SqlitePool> {
+/// How long a connection waits for another connection's write lock.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn db_init(db_name: &str, mode: &str, max_connections: u32) -> Result<SqlitePool> {
     let db_url = format!("sqlite:{}?mode={}", db_name, mode);
-    let mut options: SqliteConnectOptions =
-        SqliteConnectOptions::from_str(&db_url).map_err(|e| eyre!(e))?;
+    // Write-ahead logging lets readers proceed while a writer is active,
+    // and in WAL mode it's safe to sync only at checkpoints.
+    let mut options: SqliteConnectOptions = SqliteConnectOptions::from_str(&db_url)
+        .map_err(|e| eyre!(e))?
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT);
     if env::var("ADLU_PROXY_ENABLE_STATEMENT_LOGGING").is_err() {
         options.disable_statement_logging();
     }
-    let pool = SqlitePoolOptions::new().max_connections(5).connect_with(options).await?;
+    let pool = SqlitePoolOptions::new()
+        .max_connections(max_connections.max(1))
+        .connect_with(options)
+        .await?;
     sqlx::query(SCHEMA_VERSION_SCHEMA).execute(&pool).await?;
     sqlx::query(SCHEMA_VERSION_INITIALIZE).execute(&pool).await?;
+    sqlx::query(INSTANCE_SCHEMA).execute(&pool).await?;
+    sqlx::query(INSTANCE_INITIALIZE).execute(&pool).await?;
     frl::db_init(&pool).await?;
+    integrity::db_init(&pool).await?;
     log::db_init(&pool).await?;
     named_user::db_init(&pool).await?;
+    inventory::db_init(&pool).await?;
+    packages::db_init(&pool).await?;
+    directory::db_init(&pool).await?;
+    patch::db_init(&pool).await?;
+    security::db_init(&pool).await?;
+    bandwidth::db_init(&pool).await?;
+    effectiveness::db_init(&pool).await?;
+    notes::db_init(&pool).await?;
+    orgs::db_init(&pool).await?;
+    locks::db_init(&pool).await?;
+    migrate::migrate(&pool).await?;
     Ok(pool)
 }
 
+/// The globally-unique key for a row first stored by this cache, made
+/// from the cache's instance id and the row's own id (e.g., its request id).
+/// Rows keep their key when they are exported or imported, so the key
+/// identifies the same row across proxies.
+async fn dedupe_key(pool: &SqlitePool, row_id: &str) -> Result<String> {
+    let q_str = "select instance_id from proxy_instance";
+    let instance_id: String =
+        sqlx::query(q_str).fetch_one(pool).await?.get("instance_id");
+    Ok(format!("{}|{}", instance_id, row_id))
+}
+
 async fn schema_upgrade(
     data_type: &str,
     max_version: usize,
@@ -228,11 +951,24 @@ const SCHEMA_VERSION_SCHEMA: &str = r#"
         schema_version integer not null default 0
     );"#;
 
+const INSTANCE_SCHEMA: &str = r#"
+    create table if not exists proxy_instance (
+        instance_id text not null
+    );"#;
+
+const INSTANCE_INITIALIZE: &str = r#"
+    insert into proxy_instance (instance_id)
+        select lower(hex(randomblob(16)))
+        where not exists (select 1 from proxy_instance);
+    "#;
+
 const SCHEMA_VERSION_INITIALIZE: &str = r#"
     insert or ignore into schema_version
         (data_type, schema_version)
     values
         ("frl", 0),
         ("license", 0),
-        ("log", 0);
+        ("log", 0),
+        ("packages", 0),
+        ("security", 0);
     "#;
@@ -26,9 +26,13 @@ use sqlx::{
 use adlu_base::Timestamp;
 use adlu_parse::protocol::LicenseSession;
 
-use crate::proxy::{Request, Response};
+use crate::geoip::Location;
+use crate::proxy::{Request, RequestOutcome, Response};
 
+use super::location::{self, location_from_row};
+use super::notes;
 use super::schema_upgrade;
+use super::ReportFilter;
 
 pub async fn db_init(pool: &SqlitePool) -> Result<()> {
     sqlx::query(SESSION_SCHEMA).execute(pool).await?;
@@ -53,15 +57,19 @@ pub async fn clear(pool: &SqlitePool) -> Result<()> {
 pub async fn report(
     pool: &SqlitePool,
     path: &str,
+    filter: &ReportFilter,
     empty: bool,
     timezone: bool,
     rfc3339: bool,
 ) -> Result<()> {
     let mut writer = csv::WriterBuilder::new().from_path(path)?;
     writer.write_record(report_headers(timezone))?;
-    let sessions = fetch_license_sessions(pool, !empty).await?;
-    for session in sessions.iter() {
-        let record = report_record(session, timezone, rfc3339);
+    let sessions = fetch_license_sessions(pool, filter, !empty).await?;
+    for (session, outcome, location, notes) in sessions.iter() {
+        let mut record = report_record(session, timezone, rfc3339);
+        record.splice(1..1, location::report_record(location));
+        record.push(outcome.clone());
+        record.push(notes.clone());
         writer.write_record(record)?;
     }
     Ok(())
@@ -71,6 +79,7 @@ fn report_headers(timezone: bool) -> Vec<String> {
     let time_suffix = if timezone { "" } else { " (UTC)" };
     let mut result = vec![];
     result.push("Source Address".to_string());
+    result.extend(location::report_headers());
     result.push("Session ID".to_string());
     result.push(format!("Session Start{time_suffix}"));
     result.push(format!("Session End{time_suffix}"));
@@ -82,6 +91,8 @@ fn report_headers(timezone: bool) -> Vec<String> {
     result.push("OS Version".to_string());
     result.push("Machine Name".to_string());
     result.push("User ID".to_string());
+    result.push("Outcome".to_string());
+    result.push("Notes".to_string());
     result
 }
 
@@ -112,11 +123,22 @@ fn report_record(session: &LicenseSession, timezone: bool, rfc3339: bool) -> Vec
 
 pub async fn store_license_request(pool: &SqlitePool, req: &Request) -> Result<()> {
     let new = req.parse_license()?;
-    if let Some(existing) = fetch_license_session(pool, &new.session_id).await? {
+    let (session_id, user_key) = (new.session_id.clone(), new.user_key.clone());
+    if let Some(existing) = fetch_license_session(pool, &session_id, &user_key).await? {
         store_license_session(pool, &existing.merge(new)?).await?;
     } else {
         store_license_session(pool, &new).await?;
     }
+    let u_str = r#"update license_sessions set correlation_id = ?,
+        dedupe_key = (select instance_id from proxy_instance)
+            || '|' || session_id || '|' || user_key
+        where session_id = ? and user_key = ?"#;
+    sqlx::query(u_str)
+        .bind(&req.correlation_id)
+        .bind(&session_id)
+        .bind(&user_key)
+        .execute(pool)
+        .await?;
     Ok(())
 }
 
@@ -129,6 +151,24 @@ pub async fn store_license_response(
     Ok(())
 }
 
+pub async fn store_license_outcome(
+    pool: &SqlitePool,
+    req: &Request,
+    outcome: &RequestOutcome,
+) -> Result<()> {
+    let session = req.parse_license()?;
+    let u_str =
+        "update license_sessions set outcome = ? where session_id = ? and user_key = ?";
+    debug!("Storing outcome {} for license session {}", outcome, &session.session_id);
+    sqlx::query(u_str)
+        .bind(outcome.to_string())
+        .bind(&session.session_id)
+        .bind(&session.user_key)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
 pub async fn fetch_license_response(
     _pool: &SqlitePool,
     _req: &Request,
@@ -140,10 +180,12 @@ pub async fn fetch_license_response(
 async fn fetch_license_session(
     pool: &SqlitePool,
     session_id: &str,
+    user_key: &str,
 ) -> Result<Option<LicenseSession>> {
     debug!("Finding license session with id: {}", session_id);
-    let q_str = "select * from license_sessions where session_id = ?";
-    let result = sqlx::query(q_str).bind(session_id).fetch_optional(pool).await?;
+    let q_str = "select * from license_sessions where session_id = ? and user_key = ?";
+    let result =
+        sqlx::query(q_str).bind(session_id).bind(user_key).fetch_optional(pool).await?;
     match result {
         Some(row) => {
             debug!("Found license session with id: {}", session_id);
@@ -158,16 +200,25 @@ async fn fetch_license_session(
 
 pub(crate) async fn fetch_license_sessions(
     pool: &SqlitePool,
+    filter: &ReportFilter,
     _info_only: bool,
-) -> Result<Vec<LicenseSession>> {
+) -> Result<Vec<(LicenseSession, String, Location, String)>> {
     debug!("Fetching all license sessions");
     let mut result = vec![];
-    let q_str = "select * from license_sessions";
-    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    let q_str =
+        format!("select ls.*, {} from license_sessions ls", notes::notes_column("ls"));
+    let q_str = filter.apply(&q_str, "session_start");
+    let rows = sqlx::query(&q_str).fetch_all(pool).await?;
     for row in rows {
         let session = session_from_row(&row);
+        let notes: Option<String> = row.get("notes");
         // all launch sessions have info
-        result.push(session);
+        result.push((
+            session,
+            row.get("outcome"),
+            location_from_row(&row),
+            notes.unwrap_or_default(),
+        ));
     }
     debug!("Fetched {} sessions", result.len());
     Ok(result)
@@ -181,9 +232,9 @@ async fn store_license_session(
         (
             source_addr, session_id, session_start, session_end,
             app_id, app_version, app_locale, ngl_version, 
-            os_name, os_version, device_name, user_id
+            os_name, os_version, device_name, user_id, user_key
         )"#;
-    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
     let i_str = format!(
         "insert or replace into license_sessions {} values {}",
         field_list, value_list
@@ -203,6 +254,7 @@ async fn store_license_session(
         .bind(&session.os_version)
         .bind(&session.device_name)
         .bind(&session.user_id)
+        .bind(&session.user_key)
         .execute(&mut tx)
         .await?;
     tx.commit().await?;
@@ -224,14 +276,15 @@ fn session_from_row(row: &SqliteRow) -> LicenseSession {
         os_version: row.get("os_version"),
         device_name: row.get("device_name"),
         user_id: row.get("user_id"),
+        user_key: row.get("user_key"),
     }
 }
 
 const SESSION_SCHEMA: &str = r#"
     create table if not exists license_sessions (
         session_id text not null unique,
-        session_start text not null,
-        session_end text not null,
+        session_start integer not null,
+        session_end integer not null,
         app_id text not null,
         app_version text not null,
         app_locale text not null,
@@ -245,9 +298,22 @@ const CLEAR_ALL: &str = r#"
     delete from license_sessions;
     "#;
 
-const SESSION_SCHEMA_VERSION: usize = 2;
+const SESSION_SCHEMA_VERSION: usize = 13;
 
 const SCHEMA_ALTERATIONS_BY_VERSION: [&str; SESSION_SCHEMA_VERSION] = [
     "alter table license_sessions add column source_addr not null default 'unknown'",
     "alter table license_sessions add column device_name not null default ''",
+    "alter table license_sessions add column outcome not null default ''",
+    "alter table license_sessions add column correlation_id not null default ''",
+    "alter table license_sessions add column dedupe_key not null default ''",
+    r#"update license_sessions set dedupe_key =
+        (select instance_id from proxy_instance) || '|' || session_id"#,
+    "alter table license_sessions add column country not null default ''",
+    "alter table license_sessions add column city not null default ''",
+    "alter table license_sessions add column campus not null default ''",
+    "alter table license_sessions add column user_agent not null default ''",
+    "alter table license_sessions add column ngl_client_version not null default ''",
+    "alter table license_sessions add column user_key not null default ''",
+    // sessions stored before they were keyed by user are keyed by OS user
+    "update license_sessions set user_key = user_id",
 ];
@@ -0,0 +1,141 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Free-text notes that admins attach to cached activations, deactivations,
+and license sessions (e.g., "this device was reimaged" or "ticket #1234").
+
+Notes are attached to a row by its dedupe key, which every row keeps when
+it's exported or imported, but admins can name the rows to annotate by any
+key that appears in the reports: a correlation id, or a session id.  Every
+row with that key gets the note, so annotating a session id that several
+users share annotates each of their sessions.
+ */
+use eyre::{eyre, Result};
+use serde::Serialize;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+/// A note, and the dedupe key of the row it's attached to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Note {
+    pub row_key: String,
+    pub note: String,
+    pub timestamp: String,
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(NOTES_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn clear(pool: &SqlitePool) -> Result<()> {
+    sqlx::query("delete from notes").execute(pool).await?;
+    eprintln!("Notes have been cleared.");
+    Ok(())
+}
+
+/// Attach a note to every row with the given key, returning how many rows
+/// there were.  It's an error if there are none.
+pub async fn annotate(pool: &SqlitePool, key: &str, note: &str) -> Result<usize> {
+    let row_keys = row_keys(pool, key).await?;
+    if row_keys.is_empty() {
+        return Err(eyre!("There are no activations or sessions with key '{}'", key));
+    }
+    let i_str = "insert into notes (row_key, note, timestamp) values (?, ?, ?)";
+    let timestamp = Timestamp::now().to_db();
+    let mut tx = pool.begin().await?;
+    for row_key in row_keys.iter() {
+        sqlx::query(i_str)
+            .bind(row_key)
+            .bind(note)
+            .bind(timestamp)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(row_keys.len())
+}
+
+/// The notes on the rows with the given key, or all notes if there's no key,
+/// in the order they were made.
+pub async fn fetch_notes(pool: &SqlitePool, key: Option<&str>) -> Result<Vec<Note>> {
+    let rows = match key {
+        Some(key) => {
+            let q_str = format!(
+                "select * from notes where row_key in ({ROW_KEY_QUERY}) order by id"
+            );
+            sqlx::query(&q_str).bind(key).fetch_all(pool).await?
+        }
+        None => sqlx::query("select * from notes order by id").fetch_all(pool).await?,
+    };
+    let result = rows
+        .iter()
+        .map(|row| Note {
+            row_key: row.get("row_key"),
+            note: row.get("note"),
+            timestamp: Timestamp::from_db(row.get("timestamp")).format_rfc_3339(true),
+        })
+        .collect();
+    Ok(result)
+}
+
+/// A column expression that gives the notes on each row of a report query,
+/// where `alias` names the annotated table in the query.  Rows without notes
+/// get a null.
+pub fn notes_column(alias: &str) -> String {
+    format!(
+        "(select group_concat(note, '; ') from notes
+            where notes.row_key = {alias}.dedupe_key) as notes"
+    )
+}
+
+/// Removes notes whose rows have been evicted or purged.
+pub const ORPHAN_DELETE: &str = r#"
+    delete from notes where row_key not in (
+        select dedupe_key from activation_requests
+        union select dedupe_key from deactivation_requests
+        union select dedupe_key from license_sessions
+    )"#;
+
+async fn row_keys(pool: &SqlitePool, key: &str) -> Result<Vec<String>> {
+    let rows = sqlx::query(ROW_KEY_QUERY).bind(key).fetch_all(pool).await?;
+    Ok(rows.iter().map(|row| row.get("dedupe_key")).collect())
+}
+
+const ROW_KEY_QUERY: &str = r#"
+    select dedupe_key from activation_requests
+        where dedupe_key != '' and (correlation_id = ?1 or dedupe_key = ?1)
+    union select dedupe_key from deactivation_requests
+        where dedupe_key != '' and (correlation_id = ?1 or dedupe_key = ?1)
+    union select dedupe_key from license_sessions
+        where dedupe_key != ''
+        and (correlation_id = ?1 or dedupe_key = ?1 or session_id = ?1)
+    "#;
+
+const NOTES_SCHEMA: &str = r#"
+    create table if not exists notes (
+        id integer primary key,
+        row_key text not null,
+        note text not null,
+        timestamp integer not null
+    );
+    create index if not exists notes_row_key_index on notes (row_key);
+    "#;
@@ -0,0 +1,111 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+The customer orgs of FRL packages, for proxies shared by several orgs.
+
+A package's org comes from the org mappings in the config, if one of them
+lists the package, and otherwise from the org recorded when its metadata was
+imported (see the `packages` module).  The mappings are copied into the
+cache when it's opened, so that the org of each activation and deactivation
+can be stored with it, and so that the stored orgs can be brought up to date
+when the mappings (or the imported packages) change.
+ */
+use std::collections::BTreeMap;
+
+use eyre::Result;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use crate::settings::OrgMapping;
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(ORG_MAPPINGS_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+/// Replace the cached org mappings with the configured ones.  If they
+/// have changed, the orgs stored with requests are updated to match.
+pub async fn store_mappings(pool: &SqlitePool, mappings: &[OrgMapping]) -> Result<()> {
+    // a package listed by more than one mapping belongs to the first one's org
+    let mut pairs: BTreeMap<&str, &str> = BTreeMap::new();
+    for mapping in mappings.iter() {
+        for npd_id in mapping.npd_ids.iter() {
+            pairs.entry(npd_id.as_str()).or_insert(mapping.org_id.as_str());
+        }
+    }
+    let rows =
+        sqlx::query("select * from org_mappings order by npd_id").fetch_all(pool).await?;
+    let cached: Vec<(String, String)> =
+        rows.iter().map(|row| (row.get("npd_id"), row.get("org_id"))).collect();
+    let unchanged = cached
+        .iter()
+        .map(|(n, o)| (n.as_str(), o.as_str()))
+        .eq(pairs.iter().map(|(n, o)| (*n, *o)));
+    if unchanged {
+        return Ok(());
+    }
+    let i_str = "insert or replace into org_mappings (npd_id, org_id) values (?, ?)";
+    let mut tx = pool.begin().await?;
+    sqlx::query("delete from org_mappings").execute(&mut tx).await?;
+    for (npd_id, org_id) in pairs {
+        sqlx::query(i_str).bind(npd_id).bind(org_id).execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+    retag_requests(pool).await
+}
+
+/// The org of a package, or an empty string if it isn't known.
+pub async fn org_for_package(pool: &SqlitePool, npd_id: &str) -> Result<String> {
+    let q_str = format!("select {} as org_id", org_column("?"));
+    let row = sqlx::query(&q_str).bind(npd_id).bind(npd_id).fetch_one(pool).await?;
+    Ok(row.get("org_id"))
+}
+
+/// Update the orgs stored with requests to match the current mappings and
+/// package metadata.  Requests whose package has no known org keep the
+/// org they have.
+pub async fn retag_requests(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for table in ["activation_requests", "deactivation_requests"] {
+        let package_id = format!("{table}.package_id");
+        let u_str = format!(
+            "update {table} set org_id = coalesce(nullif({}, ''), org_id)",
+            org_column(&package_id)
+        );
+        sqlx::query(&u_str).execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// A column expression for the org of the package with the given id
+/// (which is an expression, such as a column or a query parameter, that
+/// appears twice).
+fn org_column(npd_id: &str) -> String {
+    format!(
+        "coalesce((select org_id from org_mappings where npd_id = {npd_id}),
+            (select org_id from packages where npd_id = {npd_id} and org_id != ''),
+            '')"
+    )
+}
+
+const ORG_MAPPINGS_SCHEMA: &str = r#"
+    create table if not exists org_mappings (
+        npd_id text not null unique,
+        org_id text not null
+    );"#;
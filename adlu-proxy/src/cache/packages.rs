@@ -0,0 +1,260 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Package metadata imported from preconditioning data.
+
+FRL requests identify their package only by its npdId, which is opaque.
+Importing the packages that were deployed (either `.ccp` files, preconditioning
+JSON, or installed operating configs) records a human-readable name for each
+npdId, along with its deployment mode and precedence, so that reports can
+show them.
+
+Each package is also tagged with an org: the certificate group of its
+operating configs, which is shared by the packages that an org signs with
+the same customer certificate.  Proxies shared by several orgs can override
+these tags with org mappings in the config (see the `orgs` module).
+ */
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use eyre::{Result, WrapErr};
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+use adlu_parse::admin::{Configuration, OcFileSpec};
+
+use super::{orgs, schema_upgrade, ReportFilter};
+
+/// The metadata recorded for one package.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub npd_id: String,
+    pub name: String,
+    pub deployment_mode: String,
+    pub precedence: i32,
+    pub app_ids: Vec<String>,
+    pub org_id: String,
+}
+
+impl PackageInfo {
+    /// Summarize a configuration, one entry per package.  Packages are
+    /// named by their branding, if they have one, and otherwise (if there
+    /// is only one package) by the file or folder they were read from.
+    pub fn from_configuration(config: &Configuration, path: &str) -> Vec<Self> {
+        let ocs: Vec<(&OcFileSpec, &str)> = match config {
+            Configuration::Packaged(pcs) => pcs
+                .iter()
+                .flat_map(|pc| {
+                    let mode = pc.deployment_mode.as_str();
+                    pc.operating_configs.iter().map(move |oc| (oc, mode))
+                })
+                .collect(),
+            Configuration::Installed(ocs) => ocs
+                .iter()
+                .map(|oc| (oc, oc.content.payload.deployment_mode.as_str()))
+                .collect(),
+        };
+        let mut packages: BTreeMap<String, PackageInfo> = BTreeMap::new();
+        for (oc, mode) in ocs {
+            let payload = &oc.content.payload;
+            let package = packages.entry(oc.npd_id()).or_insert_with(|| PackageInfo {
+                npd_id: oc.npd_id(),
+                name: String::new(),
+                deployment_mode: mode.to_string(),
+                precedence: payload.npd_precedence,
+                app_ids: vec![],
+                org_id: String::new(),
+            });
+            if package.name.is_empty() {
+                if let Some(name) = payload.branding.name.as_ref() {
+                    package.name = name.clone();
+                }
+            }
+            if package.org_id.is_empty() && oc.cert_group_id() != "Invalid" {
+                package.org_id = oc.cert_group_id();
+            }
+            package.app_ids.push(oc.app_id());
+        }
+        let mut packages: Vec<PackageInfo> = packages.into_values().collect();
+        for package in packages.iter_mut() {
+            package.app_ids.sort();
+            package.app_ids.dedup();
+        }
+        if let [package] = packages.as_mut_slice() {
+            if package.name.is_empty() {
+                package.name = name_from_path(path);
+            }
+        }
+        packages
+    }
+}
+
+/// The name of a package, as given by the path to its files.  Preconditioning
+/// data extracted from a package always has the same file name, so
+/// in that case the package is named by its folder.
+fn name_from_path(path: &str) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let is_data_file = path.is_file()
+        && path.extension().unwrap_or_default().eq_ignore_ascii_case("json");
+    if !is_data_file || !stem.eq_ignore_ascii_case("ngl-preconditioning-data") {
+        return stem.to_string();
+    }
+    let folder = path.parent().and_then(|parent| parent.file_name());
+    folder.unwrap_or_default().to_string_lossy().to_string()
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(PACKAGES_SCHEMA).execute(pool).await?;
+    schema_upgrade(
+        "packages",
+        PACKAGES_SCHEMA_VERSION,
+        &SCHEMA_ALTERATIONS_BY_VERSION,
+        pool,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn clear(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(CLEAR_ALL).execute(&mut tx).await?;
+    tx.commit().await?;
+    eprintln!("Package metadata cache has been cleared.");
+    Ok(())
+}
+
+/// Import the packages found at a path.  Packages that were imported
+/// before are replaced, so re-importing a package updates its name.  The
+/// stored requests for the packages are tagged with their orgs.
+pub async fn import(pool: &SqlitePool, path: &str) -> Result<()> {
+    let config = Configuration::from_path(path)
+        .wrap_err(format!("Can't read packages at: {}", path))?;
+    let packages = PackageInfo::from_configuration(&config, path);
+    store_packages(pool, &packages).await?;
+    orgs::retag_requests(pool).await?;
+    eprintln!("Imported metadata for {} package(s) from {}", packages.len(), path);
+    Ok(())
+}
+
+pub async fn store_packages(pool: &SqlitePool, packages: &[PackageInfo]) -> Result<()> {
+    let i_str = r#"
+        insert or replace into packages
+            (npd_id, package_name, deployment_mode, precedence, app_ids, org_id,
+            timestamp)
+            values (?, ?, ?, ?, ?, ?, ?)"#;
+    let timestamp = Timestamp::now();
+    let mut tx = pool.begin().await?;
+    for package in packages.iter() {
+        sqlx::query(i_str)
+            .bind(&package.npd_id)
+            .bind(&package.name)
+            .bind(&package.deployment_mode)
+            .bind(package.precedence)
+            .bind(package.app_ids.join(", "))
+            .bind(&package.org_id)
+            .bind(timestamp.to_db())
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Package ID".to_string(),
+        "Package Name".to_string(),
+        "Deployment Mode".to_string(),
+        "Precedence".to_string(),
+        "App IDs".to_string(),
+        "Org ID".to_string(),
+        format!("Imported{time_suffix}"),
+    ])?;
+    let q_str = "select * from packages order by package_name, npd_id";
+    let rows = sqlx::query(&filter.apply(q_str, "timestamp")).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let timestamp = Timestamp::from_db(row.get("timestamp"));
+        let timestamp = if rfc3339 {
+            timestamp.format_rfc_3339(timezone)
+        } else {
+            timestamp.format_iso_8601(timezone)
+        };
+        let precedence: i64 = row.get("precedence");
+        writer.write_record([
+            row.get("npd_id"),
+            row.get("package_name"),
+            row.get("deployment_mode"),
+            precedence.to_string(),
+            row.get("app_ids"),
+            row.get("org_id"),
+            timestamp,
+        ])?;
+    }
+    Ok(())
+}
+
+const PACKAGES_SCHEMA: &str = r#"
+    create table if not exists packages (
+        npd_id text not null unique,
+        package_name text not null,
+        deployment_mode text not null,
+        precedence integer not null,
+        app_ids text not null,
+        timestamp integer not null
+    );"#;
+
+const PACKAGES_SCHEMA_VERSION: usize = 1;
+
+const SCHEMA_ALTERATIONS_BY_VERSION: [&str; PACKAGES_SCHEMA_VERSION] =
+    ["alter table packages add column org_id not null default ''"];
+
+const CLEAR_ALL: &str = r#"
+    delete from packages;
+    "#;
+
+#[cfg(test)]
+mod tests {
+    use super::{name_from_path, PackageInfo};
+    use adlu_parse::admin::Configuration;
+
+    #[test]
+    fn test_packages_from_configuration() {
+        let path =
+            "../rsrc/packages/mac/online-default-allapps/ngl-preconditioning-data.json";
+        let config = Configuration::from_path(path).expect("Can't read config");
+        let packages = PackageInfo::from_configuration(&config, path);
+        assert_eq!(packages.len(), 1);
+        let package = &packages[0];
+        assert_eq!(package.name, "online-default-allapps");
+        assert_eq!(package.deployment_mode, "FRL_CONNECTED");
+        assert_eq!(package.precedence, 90);
+        assert!(!package.app_ids.is_empty());
+        let path = "../rsrc/packages/win/online-illustrator/online-illustrator.ccp";
+        assert_eq!(name_from_path(path), "online-illustrator");
+    }
+}
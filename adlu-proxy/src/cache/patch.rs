@@ -0,0 +1,151 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Emergency patches to cached FRL activation responses, with an audit trail.
+
+Cached responses are signed by Adobe, so their contents can't be edited.
+What can be changed is whether they are served: a response can be marked
+invalid (so it's kept, but clients get a fresh answer from Adobe, or none if
+the proxy is isolated, until it's restored) or wiped (so that the request is
+forwarded again, as if it had never been answered).  This is what's needed
+when, for example, Adobe has extended a license but an isolated proxy keeps
+handing out the old expiry date.
+
+Every patch is recorded, with the body of the response as it was, who made
+it, and why.  The record is kept even when the cache is cleared.
+ */
+use eyre::{eyre, Result};
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use super::ReportFilter;
+use crate::cli::PatchAction;
+
+/// The activation keys of the cached responses that match a key, and whether
+/// each is marked invalid.  The key is either an activation key, to match
+/// just that response, or a deactivation key (package and device), to match
+/// all of that device's responses for the package.
+pub async fn matching_responses(
+    pool: &SqlitePool,
+    key: &str,
+) -> Result<Vec<(String, bool)>> {
+    let q_str = r#"select activation_key, invalid from activation_responses
+        where activation_key = ?1 or deactivation_key = ?1 order by activation_key"#;
+    let rows = sqlx::query(q_str).bind(key).fetch_all(pool).await?;
+    Ok(rows.iter().map(|row| (row.get("activation_key"), row.get("invalid"))).collect())
+}
+
+/// Apply a patch to the responses with the given activation keys,
+/// recording each one in the audit trail.
+pub async fn patch_responses(
+    pool: &SqlitePool,
+    keys: &[String],
+    action: &PatchAction,
+    reason: &str,
+) -> Result<u64> {
+    if reason.trim().is_empty() {
+        return Err(eyre!("A reason is required to patch cached responses"));
+    }
+    let operator = ["USER", "USERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    let timestamp = Timestamp::now();
+    let p_str = match action {
+        PatchAction::Invalidate => {
+            "update activation_responses set invalid = 1 where activation_key = ?"
+        }
+        PatchAction::Restore => {
+            "update activation_responses set invalid = 0 where activation_key = ?"
+        }
+        PatchAction::Wipe => "delete from activation_responses where activation_key = ?",
+    };
+    let a_str = r#"
+        insert into response_patches
+            (timestamp, activation_key, action, reason, operator, body)
+            select ?, activation_key, ?, ?, ?, body from activation_responses
+            where activation_key = ?"#;
+    let mut count = 0;
+    let mut tx = pool.begin().await?;
+    for key in keys {
+        sqlx::query(a_str)
+            .bind(timestamp.to_db())
+            .bind(action.to_string())
+            .bind(reason)
+            .bind(&operator)
+            .bind(key)
+            .execute(&mut tx)
+            .await?;
+        count += sqlx::query(p_str).bind(key).execute(&mut tx).await?.rows_affected();
+    }
+    tx.commit().await?;
+    Ok(count)
+}
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(PATCHES_SCHEMA).execute(pool).await?;
+    Ok(())
+}
+
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        format!("Timestamp{time_suffix}"),
+        "Activation Key".to_string(),
+        "Action".to_string(),
+        "Reason".to_string(),
+        "Operator".to_string(),
+    ])?;
+    let q_str = "select * from response_patches order by timestamp, rowid";
+    let rows = sqlx::query(&filter.apply(q_str, "timestamp")).fetch_all(pool).await?;
+    for row in rows.iter() {
+        let timestamp = Timestamp::from_db(row.get("timestamp"));
+        let timestamp = if rfc3339 {
+            timestamp.format_rfc_3339(timezone)
+        } else {
+            timestamp.format_iso_8601(timezone)
+        };
+        writer.write_record([
+            timestamp,
+            row.get("activation_key"),
+            row.get("action"),
+            row.get("reason"),
+            row.get("operator"),
+        ])?;
+    }
+    Ok(())
+}
+
+const PATCHES_SCHEMA: &str = r#"
+    create table if not exists response_patches (
+        timestamp integer not null,
+        activation_key text not null,
+        action text not null,
+        reason text not null,
+        operator text not null,
+        body text not null
+    );"#;
@@ -0,0 +1,190 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+A pipeline for writing large reports.
+
+Rows are streamed from the database rather than fetched all at once, and
+each batch of them is formatted on a blocking thread while the next batches
+are fetched, so that querying, formatting, and writing overlap.  When the
+report is being run from a terminal, its progress is shown there (with the
+rate at which rows are processed and an estimate of the time remaining).
+Interrupting a report stops the pipeline and removes the partial report.
+ */
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use eyre::{eyre, Result, WrapErr};
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use sqlx::sqlite::SqliteRow;
+
+/// How many rows are formatted together.
+const BATCH_ROWS: usize = 1000;
+
+/// How many batches can be formatted at once.
+const FORMATTERS: usize = 4;
+
+/// How often progress is shown.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Write a CSV report to `path` from a stream of `total` rows, each of which
+/// is formatted as a record (or skipped, if `format` returns `None`).
+/// Returns how many records were written.
+pub async fn write_csv<F>(
+    path: &str,
+    headers: Vec<String>,
+    total: u64,
+    rows: BoxStream<'_, Result<SqliteRow, sqlx::Error>>,
+    format: F,
+) -> Result<u64>
+where
+    F: Fn(&SqliteRow) -> Option<Vec<String>> + Send + Sync + 'static,
+{
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record(&headers)?;
+    let (processed, written) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<(u64, Vec<Vec<String>>)>(FORMATTERS);
+    let counts = (processed.clone(), written.clone());
+    let writer_task = tokio::task::spawn_blocking(move || -> Result<()> {
+        while let Some((rows, records)) = rx.blocking_recv() {
+            for record in records.iter() {
+                writer.write_record(record)?;
+            }
+            counts.0.fetch_add(rows, Ordering::Relaxed);
+            counts.1.fetch_add(records.len() as u64, Ordering::Relaxed);
+        }
+        writer.flush()?;
+        Ok(())
+    });
+    let format = Arc::new(format);
+    let feed = async move {
+        let mut batches = rows
+            .chunks(BATCH_ROWS)
+            .map(|batch| {
+                let format = format.clone();
+                tokio::task::spawn_blocking(move || format_batch(batch, format.as_ref()))
+            })
+            .buffered(FORMATTERS);
+        while let Some(result) = batches.next().await {
+            let batch = result.wrap_err("Report formatting failed")??;
+            if tx.send(batch).await.is_err() {
+                // the writer has failed, and will report why
+                break;
+            }
+        }
+        Ok::<(), eyre::Report>(())
+    };
+    let progress = show_progress(total, processed.clone());
+    let outcome = tokio::select! {
+        result = feed => result,
+        _ = tokio::signal::ctrl_c() => Err(eyre!("Report was interrupted")),
+    };
+    let outcome = match writer_task.await {
+        Ok(result) => outcome.and(result),
+        Err(err) => Err(eyre!("Report writer failed: {}", err)),
+    };
+    if let Some((ticker, started)) = progress {
+        ticker.abort();
+        let rows = processed.load(Ordering::Relaxed);
+        let secs = started.elapsed().as_secs_f64();
+        eprintln!("\rProcessed {} row(s) in {:.1}s{:20}", rows, secs, "");
+    }
+    match outcome {
+        Ok(()) => Ok(written.load(Ordering::Relaxed)),
+        Err(err) => {
+            std::fs::remove_file(path).ok();
+            Err(err)
+        }
+    }
+}
+
+fn format_batch<F>(
+    batch: Vec<Result<SqliteRow, sqlx::Error>>,
+    format: &F,
+) -> Result<(u64, Vec<Vec<String>>)>
+where
+    F: Fn(&SqliteRow) -> Option<Vec<String>>,
+{
+    let mut records = Vec::with_capacity(batch.len());
+    for row in batch.iter() {
+        let row = row.as_ref().map_err(|err| eyre!("Can't fetch report row: {}", err))?;
+        records.extend(format(row));
+    }
+    Ok((batch.len() as u64, records))
+}
+
+/// If standard error is a terminal, show the progress there until the
+/// returned task is aborted.
+fn show_progress(
+    total: u64,
+    processed: Arc<AtomicU64>,
+) -> Option<(tokio::task::JoinHandle<()>, Instant)> {
+    if !std::io::stderr().is_terminal() {
+        return None;
+    }
+    let started = Instant::now();
+    let ticker = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let done = processed.load(Ordering::Relaxed);
+            eprint!("\r{}   ", progress_line(done, total, started.elapsed()));
+        }
+    });
+    Some((ticker, started))
+}
+
+fn progress_line(done: u64, total: u64, elapsed: Duration) -> String {
+    let rate = done as f64 / elapsed.as_secs_f64().max(0.001);
+    let eta = if rate >= 1.0 && total > done {
+        format_duration(((total - done) as f64 / rate) as u64)
+    } else {
+        "unknown".to_string()
+    };
+    format!("Processed {} of {} row(s) ({:.0} rows/sec, ETA {})", done, total, rate, eta)
+}
+
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_duration, progress_line};
+
+    #[test]
+    fn test_progress_line() {
+        assert_eq!(format_duration(5), "5s");
+        assert_eq!(format_duration(130), "2m10s");
+        assert_eq!(format_duration(3723), "1h02m03s");
+        let line = progress_line(50_000, 200_000, Duration::from_secs(10));
+        assert_eq!(line, "Processed 50000 of 200000 row(s) (5000 rows/sec, ETA 30s)");
+        let line = progress_line(0, 200_000, Duration::from_secs(1));
+        assert!(line.ends_with("ETA unknown)"), "{}", line);
+    }
+}
@@ -0,0 +1,101 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+An inventory of the operating systems that clients are running.
+
+Both FRL activations and log uploads report the client's OS name and
+version, so they can show which machines are still on builds that Adobe is
+about to stop supporting.  Each client is counted only under the platform
+it most recently reported for each app, so machines that have been
+upgraded don't linger under their old OS versions.
+ */
+use eyre::Result;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use super::ReportFilter;
+
+/// The number of clients running each combination of OS version and app
+/// version, together with when a client was last seen running it.
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "OS Name".to_string(),
+        "OS Version".to_string(),
+        "App ID".to_string(),
+        "App Version".to_string(),
+        "Source".to_string(),
+        "Clients".to_string(),
+        format!("Last Seen{time_suffix}"),
+    ])?;
+    let format = |ts: Timestamp| {
+        if rfc3339 {
+            ts.format_rfc_3339(timezone)
+        } else {
+            ts.format_iso_8601(timezone)
+        }
+    };
+    let q_str = filter.apply(PLATFORM_SUMMARY, "last_seen");
+    for row in sqlx::query(&q_str).fetch_all(pool).await?.iter() {
+        let clients: i64 = row.get("clients");
+        writer.write_record([
+            row.get("os_name"),
+            row.get("os_version"),
+            row.get("app_id"),
+            row.get("app_version"),
+            row.get("source"),
+            clients.to_string(),
+            format(Timestamp::from_db(row.get("last_seen"))),
+        ])?;
+    }
+    Ok(())
+}
+
+/// Clients are devices for FRL activations and users for log uploads, which
+/// don't identify the device.  The inner queries rely on SQLite taking the
+/// bare columns of an aggregate from the row that has the `max` value, which
+/// is the client's most recent report for the app.
+const PLATFORM_SUMMARY: &str = r#"
+    select
+        os_name, os_version, app_id, app_version, source,
+        count(*) as clients,
+        max(last_seen) as last_seen
+    from (
+        select os_name, os_version, app_id, app_version,
+            'FRL Activation' as source, max(timestamp) as last_seen
+        from activation_requests
+        where os_name != ''
+        group by device_id, app_id
+        union all
+        select os_name, os_version, app_id, app_version,
+            'Log Upload', max(session_end)
+        from log_sessions
+        where os_name != '' and user_id != ''
+        group by user_id, app_id
+    )
+    group by os_name, os_version, app_id, app_version, source
+    order by os_name, os_version, app_id, app_version, source"#;
@@ -0,0 +1,143 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Per-app and per-API-key overrides of how responses are cached.
+
+Some apps misbehave when they are given a replayed response (for example,
+Acrobat, whose packages have their own precedence), so the config can say
+that the responses to an app's requests (or to requests with an API key)
+are never replayed from the cache, or are only replayed while they are
+younger than a time-to-live.
+
+Responses are stored even when they won't be replayed, because a stored
+response is what marks its request as answered, so that it isn't forwarded
+again.  But responses that will never be replayed are kept out of the
+in-memory index of activation responses.
+ */
+use adlu_base::Timestamp;
+use adlu_parse::protocol::{FrlActivationRequestBody, NulLicenseRequestBody};
+
+use crate::proxy::{Request, RequestType, Response};
+use crate::settings::{CacheMode, CachePolicy};
+
+/// The policy that applies to a request, if any.
+pub fn policy_for<'a>(
+    policies: &'a [CachePolicy],
+    req: &Request,
+) -> Option<&'a CachePolicy> {
+    if policies.is_empty() {
+        return None;
+    }
+    let app_id = app_id(req);
+    let api_key = req.api_key.as_deref().unwrap_or_default();
+    policies.iter().find(|p| {
+        (p.app_id.is_empty() || matches!(&app_id, Some(id) if id == &p.app_id))
+            && (p.api_key.is_empty() || p.api_key == api_key)
+    })
+}
+
+/// Whether responses under a policy are ever replayed.
+pub fn replays(policy: Option<&CachePolicy>) -> bool {
+    !matches!(policy, Some(CachePolicy { policy: CacheMode::NoCache, .. }))
+}
+
+/// Whether a cached response can be replayed, under a policy, at `now`.
+pub fn can_replay(
+    policy: Option<&CachePolicy>,
+    resp: &Response,
+    now: &Timestamp,
+) -> bool {
+    match policy {
+        None => true,
+        Some(policy) => match policy.policy {
+            CacheMode::Cache => true,
+            CacheMode::NoCache => false,
+            CacheMode::Ttl => {
+                let age_millis = now.to_millis() - resp.timestamp.to_millis();
+                age_millis < policy.ttl_secs as i64 * 1000
+            }
+        },
+    }
+}
+
+/// The NGL app id of a request, if it has one.  Deactivations and
+/// log uploads don't say which app sent them.
+fn app_id(req: &Request) -> Option<String> {
+    let body = req.body.as_deref()?;
+    match req.request_type {
+        RequestType::FrlActivation => {
+            let parse = FrlActivationRequestBody::from_body(body).ok()?;
+            Some(parse.app_details.ngl_app_id)
+        }
+        RequestType::NulLicense => {
+            let parse = NulLicenseRequestBody::from_body(body).ok()?;
+            Some(parse.app_details.ngl_app_id)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use adlu_base::Timestamp;
+    use adlu_parse::protocol::FrlActivationRequestBody;
+
+    use crate::proxy::{RequestType, Response};
+    use crate::settings::{CacheMode, CachePolicy};
+    use crate::testing::frl::mock_cache_activation_request;
+
+    #[test]
+    fn test_cache_policies() {
+        let body = FrlActivationRequestBody::mock_from_device_id("policy-d1");
+        let req = mock_cache_activation_request(&body);
+        let app_id = body.app_details.ngl_app_id.clone();
+        let policy = |app_id: &str, api_key: &str, mode: CacheMode| CachePolicy {
+            app_id: app_id.to_string(),
+            api_key: api_key.to_string(),
+            policy: mode,
+            ttl_secs: 60,
+        };
+        let policies = vec![
+            policy(&app_id, "other-key", CacheMode::NoCache),
+            policy(&app_id, "", CacheMode::Ttl),
+            policy("", "", CacheMode::NoCache),
+        ];
+        let found = super::policy_for(&policies, &req).expect("No policy found");
+        assert_eq!(found.policy, CacheMode::Ttl);
+        assert!(super::replays(Some(found)));
+        let now = Timestamp::now();
+        let mut resp = Response {
+            timestamp: Timestamp::from_millis(now.to_millis() - 30_000),
+            request_type: RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: None,
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        assert!(super::can_replay(Some(found), &resp, &now));
+        resp.timestamp = Timestamp::from_millis(now.to_millis() - 90_000);
+        assert!(!super::can_replay(Some(found), &resp, &now));
+        assert!(super::can_replay(None, &resp, &now));
+        assert!(super::policy_for(&policies[..1], &req).is_none());
+    }
+}
@@ -0,0 +1,326 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Predicting what a package will do on the devices that are already licensed.
+
+A device can hold FRL licenses from several packages at once, and for each
+app it uses the one with the highest precedence.  Before a package is rolled
+out, a preview compares its precedence (and the apps it licenses) with the
+packages each device in the cache currently holds activations for, and
+predicts one outcome per device: the package is already active there, it
+will replace a lower-precedence package, it will be ignored in favor of a
+higher-precedence package, it will conflict with a different package of the
+same precedence, or it will be the device's first license for those apps.
+The precedence of a package the device holds is only known if its metadata
+has been imported; otherwise the outcome for that device is unknown.
+ */
+use std::collections::HashMap;
+
+use eyre::{eyre, Result, WrapErr};
+use serde::Serialize;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_parse::admin::Configuration;
+
+use super::packages::PackageInfo;
+
+/// What deploying a package is expected to do on one device.  The variants
+/// are in order of concern: a device with several outcomes gets the first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum Outcome {
+    Conflict,
+    Superseded,
+    UnknownPrecedence,
+    AlreadyActive,
+    Replaces,
+    NewActivation,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Conflict => "conflicts (same precedence)".fmt(f),
+            Outcome::Superseded => "superseded (higher precedence)".fmt(f),
+            Outcome::UnknownPrecedence => "unknown (precedence not imported)".fmt(f),
+            Outcome::AlreadyActive => "already active".fmt(f),
+            Outcome::Replaces => "replaces (lower precedence)".fmt(f),
+            Outcome::NewActivation => "new activation".fmt(f),
+        }
+    }
+}
+
+/// A package that a device holds an un-returned activation for.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveLicense {
+    pub device_id: String,
+    pub os_name: String,
+    pub npd_id: String,
+    pub package_name: Option<String>,
+    pub precedence: Option<i32>,
+    pub app_id: String,
+    pub timestamp: i64,
+}
+
+/// The predicted outcome on one device, and the packages it depends on.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Prediction {
+    pub device_id: String,
+    pub os_name: String,
+    pub outcome: Outcome,
+    pub other_packages: Vec<String>,
+}
+
+/// The predictions for one package.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Preview {
+    pub npd_id: String,
+    pub package_name: String,
+    pub precedence: i32,
+    pub predictions: Vec<Prediction>,
+}
+
+/// Preview each package found at a path against the activations in the cache,
+/// considering only the `limit` most recently active devices (if given).
+pub async fn preview(
+    pool: &SqlitePool,
+    path: &str,
+    limit: Option<usize>,
+) -> Result<Vec<Preview>> {
+    let config = Configuration::from_path(path)
+        .wrap_err(format!("Can't read package at: {}", path))?;
+    let packages = PackageInfo::from_configuration(&config, path);
+    if packages.is_empty() {
+        return Err(eyre!("No packages found at: {}", path));
+    }
+    let licenses = fetch_active_licenses(pool).await?;
+    let previews = packages
+        .iter()
+        .map(|package| Preview {
+            npd_id: package.npd_id.clone(),
+            package_name: package.name.clone(),
+            precedence: package.precedence,
+            predictions: predict(package, &licenses, limit),
+        })
+        .collect();
+    Ok(previews)
+}
+
+/// Predict the outcome of deploying a package on each device that holds
+/// licenses, most recently active devices first.
+pub fn predict(
+    package: &PackageInfo,
+    licenses: &[ActiveLicense],
+    limit: Option<usize>,
+) -> Vec<Prediction> {
+    let mut devices: Vec<(&str, Vec<&ActiveLicense>)> = Vec::new();
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for license in licenses.iter() {
+        let position =
+            *positions.entry(license.device_id.as_str()).or_insert_with(|| {
+                devices.push((license.device_id.as_str(), vec![]));
+                devices.len() - 1
+            });
+        devices[position].1.push(license);
+    }
+    let latest = |held: &[&ActiveLicense]| held.iter().map(|l| l.timestamp).max();
+    devices.sort_by_key(|(_, held)| std::cmp::Reverse(latest(held)));
+    devices.truncate(limit.unwrap_or(usize::MAX));
+    devices
+        .iter()
+        .map(|(device_id, held)| predict_device(package, device_id, held))
+        .collect()
+}
+
+fn predict_device(
+    package: &PackageInfo,
+    device_id: &str,
+    held: &[&ActiveLicense],
+) -> Prediction {
+    let mut outcomes: Vec<(Outcome, String)> = Vec::new();
+    for license in held.iter() {
+        if license.npd_id == package.npd_id {
+            outcomes.push((Outcome::AlreadyActive, String::new()));
+            continue;
+        }
+        // a package that doesn't list its apps is assumed to license all of them
+        if !package.app_ids.is_empty() && !package.app_ids.contains(&license.app_id) {
+            continue;
+        }
+        let outcome = match license.precedence {
+            None => Outcome::UnknownPrecedence,
+            Some(p) if p > package.precedence => Outcome::Superseded,
+            Some(p) if p == package.precedence => Outcome::Conflict,
+            Some(_) => Outcome::Replaces,
+        };
+        let name = license.package_name.clone().unwrap_or_else(|| license.npd_id.clone());
+        outcomes.push((outcome, name));
+    }
+    let outcome =
+        outcomes.iter().map(|(o, _)| *o).min().unwrap_or(Outcome::NewActivation);
+    let mut other_packages: Vec<String> = outcomes
+        .into_iter()
+        .filter(|(o, name)| *o == outcome && !name.is_empty())
+        .map(|(_, name)| name)
+        .collect();
+    other_packages.sort();
+    other_packages.dedup();
+    Prediction {
+        device_id: device_id.to_string(),
+        os_name: held.first().map(|l| l.os_name.clone()).unwrap_or_default(),
+        outcome,
+        other_packages,
+    }
+}
+
+/// The licenses held by every device: the apps activated for each package,
+/// where the latest activation hasn't been followed by a deactivation.
+async fn fetch_active_licenses(pool: &SqlitePool) -> Result<Vec<ActiveLicense>> {
+    let q_str = r#"
+        select req.device_id, req.os_name, req.package_id, req.app_id,
+            max(req.timestamp) as timestamp,
+            pkg.package_name as package_name, pkg.precedence as npd_precedence
+        from activation_requests req
+        left join packages pkg on pkg.npd_id = req.package_id
+        where not exists (select 1 from deactivation_requests d
+            where d.package_id = req.package_id and d.device_id = req.device_id
+            and d.timestamp >= req.timestamp)
+        group by req.device_id, req.package_id, req.app_id"#;
+    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    let licenses = rows
+        .iter()
+        .map(|row| {
+            let precedence: Option<i64> = row.get("npd_precedence");
+            ActiveLicense {
+                device_id: row.get("device_id"),
+                os_name: row.get("os_name"),
+                npd_id: row.get("package_id"),
+                package_name: row.get("package_name"),
+                precedence: precedence.map(|p| p as i32),
+                app_id: row.get("app_id"),
+                timestamp: row.get("timestamp"),
+            }
+        })
+        .collect();
+    Ok(licenses)
+}
+
+impl std::fmt::Display for Preview {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Package '{}' (precedence {}), npdId: {}",
+            self.package_name, self.precedence, self.npd_id
+        )?;
+        writeln!(f, "Devices considered: {}", self.predictions.len())?;
+        let mut counts: Vec<(Outcome, usize)> = Vec::new();
+        for prediction in self.predictions.iter() {
+            match counts.iter_mut().find(|(o, _)| *o == prediction.outcome) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((prediction.outcome, 1)),
+            }
+        }
+        counts.sort();
+        for (outcome, count) in counts.iter() {
+            writeln!(f, "    {:>8}  {}", count, outcome)?;
+        }
+        let concerns: Vec<&Prediction> = self
+            .predictions
+            .iter()
+            .filter(|p| p.outcome < Outcome::AlreadyActive)
+            .collect();
+        if !concerns.is_empty() {
+            writeln!(f, "Devices needing attention:")?;
+            for prediction in concerns {
+                writeln!(
+                    f,
+                    "    {} ({}): {} with {}",
+                    prediction.device_id,
+                    prediction.os_name,
+                    prediction.outcome,
+                    prediction.other_packages.join(", ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{predict, ActiveLicense, Outcome, PackageInfo};
+
+    fn license(
+        device: &str,
+        npd_id: &str,
+        precedence: Option<i32>,
+        ts: i64,
+    ) -> ActiveLicense {
+        ActiveLicense {
+            device_id: device.to_string(),
+            os_name: "MAC".to_string(),
+            npd_id: npd_id.to_string(),
+            package_name: None,
+            precedence,
+            app_id: "Photoshop1".to_string(),
+            timestamp: ts,
+        }
+    }
+
+    #[test]
+    fn test_predict() {
+        let package = PackageInfo {
+            npd_id: "new".to_string(),
+            name: "New Package".to_string(),
+            deployment_mode: "FRL_CONNECTED".to_string(),
+            precedence: 80,
+            app_ids: vec!["Photoshop1".to_string()],
+            org_id: String::new(),
+        };
+        let licenses = vec![
+            license("d1", "new", Some(80), 1),
+            license("d2", "all-apps", Some(90), 2),
+            license("d3", "other", Some(80), 3),
+            license("d3", "acrobat", Some(70), 3),
+            license("d4", "acrobat", Some(70), 4),
+            license("d5", "unimported", None, 5),
+        ];
+        let predictions = predict(&package, &licenses, None);
+        let outcomes: Vec<(&str, Outcome)> =
+            predictions.iter().map(|p| (p.device_id.as_str(), p.outcome)).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("d5", Outcome::UnknownPrecedence),
+                ("d4", Outcome::Replaces),
+                ("d3", Outcome::Conflict),
+                ("d2", Outcome::Superseded),
+                ("d1", Outcome::AlreadyActive),
+            ]
+        );
+        assert_eq!(predictions[2].other_packages, vec!["other".to_string()]);
+        let predictions = predict(&package, &licenses, Some(2));
+        assert_eq!(predictions.len(), 2);
+        let mut package = package;
+        package.app_ids = vec!["Illustrator1".to_string()];
+        let predictions = predict(&package, &licenses[1..2], None);
+        assert_eq!(predictions[0].outcome, Outcome::NewActivation);
+    }
+}
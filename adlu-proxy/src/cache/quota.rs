@@ -0,0 +1,224 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Enforcement of a maximum cache size, and purging of old cache entries.
+
+When the cache database grows beyond its configured size, we evict the oldest
+entries that aren't needed for forwarding: answered FRL requests (together with
+their responses), completed deactivations, FRL request outcomes, and NUL and log
+session data.
+Unanswered requests are never evicted.
+
+SQLite keeps the pages freed by an eviction in the database file and reuses them
+for new entries, so the file stops growing at (roughly) the configured size.
+We don't vacuum the database to shrink it, because vacuuming needs as much free
+disk space as the database itself.
+
+Purging evicts the same kinds of entries, but by age rather than by size.
+Retention is purging of a single datasource, with its own age limit.  Because
+unanswered requests are never evicted, neither purging nor retention can lose
+a request that's waiting to be forwarded.
+ */
+use eyre::Result;
+use log::{info, warn};
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use crate::cli::Datasource;
+
+/// How many entries to evict before re-measuring the database.
+const EVICTION_BATCH: usize = 25;
+
+#[derive(Debug)]
+enum Entry {
+    Activation(String),
+    Deactivation(String),
+    Outcome(String),
+    License(String),
+    Log(String),
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Entry::Activation(key) => write!(f, "FRL activation with key {}", key),
+            Entry::Deactivation(key) => write!(f, "FRL deactivation with key {}", key),
+            Entry::Outcome(id) => write!(f, "FRL request outcome {}", id),
+            Entry::License(id) => write!(f, "NUL license session {}", id),
+            Entry::Log(id) => write!(f, "log session {}", id),
+        }
+    }
+}
+
+impl Entry {
+    fn key(&self) -> &str {
+        match self {
+            Entry::Activation(key)
+            | Entry::Deactivation(key)
+            | Entry::Outcome(key)
+            | Entry::License(key)
+            | Entry::Log(key) => key,
+        }
+    }
+
+    fn delete_statements(&self) -> &'static [&'static str] {
+        match self {
+            Entry::Activation(_) => &[
+                "delete from activation_responses where activation_key = ?",
+                "delete from activation_requests where activation_key = ?",
+            ],
+            Entry::Deactivation(_) => {
+                &["delete from deactivation_responses where deactivation_key = ?"]
+            }
+            Entry::Outcome(_) => {
+                &["delete from request_outcomes where correlation_id = ?"]
+            }
+            Entry::License(_) => {
+                &["delete from license_sessions where session_id || '|' || user_key = ?"]
+            }
+            Entry::Log(_) => &[
+                "delete from log_events where session_id = ?",
+                "delete from log_sessions where session_id = ?",
+            ],
+        }
+    }
+}
+
+/// Evict the oldest evictable entries until the database is within `max_bytes`.
+pub async fn enforce(pool: &SqlitePool, max_bytes: u64) -> Result<()> {
+    let mut used = used_bytes(pool).await?;
+    while used > max_bytes {
+        info!("Cache size of {} bytes exceeds quota of {} bytes", used, max_bytes);
+        let entries = oldest_entries(pool, EVICTION_BATCH, None).await?;
+        if entries.is_empty() {
+            warn!("Cache is over quota but has nothing evictable");
+            break;
+        }
+        evict(pool, &entries).await?;
+        used = used_bytes(pool).await?;
+    }
+    Ok(())
+}
+
+/// Evict all evictable entries older than `cutoff`, returning how many there were.
+/// If a datasource is given, only its entries are evicted.
+pub async fn purge(
+    pool: &SqlitePool,
+    cutoff: &Timestamp,
+    source: Option<&Datasource>,
+) -> Result<usize> {
+    let mut count = 0;
+    loop {
+        let mut entries = oldest_entries(pool, EVICTION_BATCH, source).await?;
+        entries.retain(|(timestamp, _)| timestamp < cutoff);
+        if entries.is_empty() {
+            break;
+        }
+        evict(pool, &entries).await?;
+        count += entries.len();
+    }
+    Ok(count)
+}
+
+async fn evict(pool: &SqlitePool, entries: &[(Timestamp, Entry)]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for (timestamp, entry) in entries.iter() {
+        info!("Evicting {} from {}", entry, timestamp.format_iso_8601(false));
+        for d_str in entry.delete_statements() {
+            sqlx::query(d_str).bind(entry.key()).execute(&mut tx).await?;
+        }
+    }
+    sqlx::query(super::notes::ORPHAN_DELETE).execute(&mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The number of bytes in use by the database, not counting free pages.
+async fn used_bytes(pool: &SqlitePool) -> Result<u64> {
+    let page_size: i64 = sqlx::query("pragma page_size").fetch_one(pool).await?.get(0);
+    let page_count: i64 = sqlx::query("pragma page_count").fetch_one(pool).await?.get(0);
+    let free_count: i64 =
+        sqlx::query("pragma freelist_count").fetch_one(pool).await?.get(0);
+    Ok(((page_count - free_count) * page_size) as u64)
+}
+
+/// A query for evictable entries of one type, with the datasource they belong
+/// to and the constructor for those entries.
+type EntryQuery = (&'static str, Datasource, fn(String) -> Entry);
+
+/// The oldest evictable entries (of the given datasource, if there is one),
+/// in age order, up to `limit` of them.
+async fn oldest_entries(
+    pool: &SqlitePool,
+    limit: usize,
+    source: Option<&Datasource>,
+) -> Result<Vec<(Timestamp, Entry)>> {
+    let queries: [EntryQuery; 5] = [
+        (
+            r#"select req.activation_key as key, req.timestamp as timestamp
+                from activation_requests req inner join activation_responses resp
+                on req.activation_key = resp.activation_key
+                and resp.timestamp >= req.timestamp
+                order by req.timestamp limit ?"#,
+            Datasource::Frl,
+            Entry::Activation,
+        ),
+        (
+            r#"select deactivation_key as key, timestamp from deactivation_responses
+                order by timestamp limit ?"#,
+            Datasource::Frl,
+            Entry::Deactivation,
+        ),
+        (
+            r#"select correlation_id as key, timestamp from request_outcomes
+                order by timestamp limit ?"#,
+            Datasource::Frl,
+            Entry::Outcome,
+        ),
+        (
+            // a session that hasn't ended has a session_end of 0
+            r#"select session_id || '|' || user_key as key,
+                    max(session_start, session_end) as timestamp
+                from license_sessions order by timestamp limit ?"#,
+            Datasource::Nul,
+            Entry::License,
+        ),
+        (
+            r#"select session_id as key, final_entry as timestamp from log_sessions
+                order by final_entry limit ?"#,
+            Datasource::Log,
+            Entry::Log,
+        ),
+    ];
+    let mut result = vec![];
+    for (q_str, q_source, make_entry) in queries {
+        if matches!(source, Some(source) if *source != q_source) {
+            continue;
+        }
+        let rows = sqlx::query(q_str).bind(limit as i64).fetch_all(pool).await?;
+        for row in rows.iter() {
+            let timestamp = Timestamp::from_db(row.get("timestamp"));
+            result.push((timestamp, make_entry(row.get("key"))));
+        }
+    }
+    result.sort_by(|(t1, _), (t2, _)| t1.cmp(t2));
+    result.truncate(limit);
+    Ok(result)
+}
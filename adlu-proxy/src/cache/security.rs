@@ -0,0 +1,352 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+use eyre::Result;
+use log::debug;
+use sqlx::{
+    sqlite::{SqlitePool, SqliteRow},
+    Row,
+};
+
+use adlu_base::Timestamp;
+
+use crate::security::{InvalidKeyAttempt, ParseFailure, ValidationFailure};
+
+use super::schema_upgrade;
+use super::ReportFilter;
+
+pub async fn db_init(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(ATTEMPT_SCHEMA).execute(pool).await?;
+    sqlx::query(FAILURE_SCHEMA).execute(pool).await?;
+    sqlx::query(PARSE_FAILURE_SCHEMA).execute(pool).await?;
+    schema_upgrade(
+        "security",
+        ATTEMPT_SCHEMA_VERSION,
+        &SCHEMA_ALTERATIONS_BY_VERSION,
+        pool,
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn clear(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(CLEAR_ALL).execute(&mut tx).await?;
+    tx.commit().await?;
+    eprintln!("Invalid api key and request body cache has been cleared.");
+    Ok(())
+}
+
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record(report_headers(timezone))?;
+    let attempts = fetch_invalid_key_attempts(pool, filter).await?;
+    for attempt in attempts.iter() {
+        writer.write_record(report_record(attempt, timezone, rfc3339))?;
+    }
+    Ok(())
+}
+
+fn report_headers(timezone: bool) -> Vec<String> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    vec![
+        format!("Timestamp{time_suffix}"),
+        "Source Address".to_string(),
+        "Request Type".to_string(),
+        "Request ID".to_string(),
+        "API Key".to_string(),
+        "App ID".to_string(),
+        "Reason".to_string(),
+        "Rejected".to_string(),
+        "Correlation ID".to_string(),
+    ]
+}
+
+fn report_record(
+    attempt: &InvalidKeyAttempt,
+    timezone: bool,
+    rfc3339: bool,
+) -> Vec<String> {
+    let timestamp = if rfc3339 {
+        attempt.timestamp.format_rfc_3339(timezone)
+    } else {
+        attempt.timestamp.format_iso_8601(timezone)
+    };
+    vec![
+        timestamp,
+        attempt.source_addr.clone(),
+        attempt.request_type.clone(),
+        attempt.request_id.clone(),
+        attempt.api_key.clone(),
+        attempt.app_id.clone(),
+        attempt.reason.clone(),
+        attempt.rejected.to_string(),
+        attempt.correlation_id.clone(),
+    ]
+}
+
+pub async fn store_invalid_key_attempt(
+    pool: &SqlitePool,
+    attempt: &InvalidKeyAttempt,
+) -> Result<()> {
+    let field_list = r#"
+        (
+            timestamp, source_addr, request_type, request_id,
+            api_key, app_id, reason, rejected, correlation_id, dedupe_key
+        )"#;
+    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let dedupe_key = super::dedupe_key(pool, &attempt.correlation_id).await?;
+    let i_str =
+        format!("insert into invalid_api_keys {} values {}", field_list, value_list);
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(&i_str)
+        .bind(attempt.timestamp.to_db())
+        .bind(&attempt.source_addr)
+        .bind(&attempt.request_type)
+        .bind(&attempt.request_id)
+        .bind(&attempt.api_key)
+        .bind(&attempt.app_id)
+        .bind(&attempt.reason)
+        .bind(attempt.rejected)
+        .bind(&attempt.correlation_id)
+        .bind(&dedupe_key)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    debug!("Stored invalid api key attempt has rowid {}", result.last_insert_rowid());
+    Ok(())
+}
+
+pub async fn failure_report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "Request Type".to_string(),
+        "App ID".to_string(),
+        "App Version".to_string(),
+        "Failures".to_string(),
+        format!("First Failure{time_suffix}"),
+        format!("Last Failure{time_suffix}"),
+        "Last Problems".to_string(),
+    ])?;
+    let format = |ts: Timestamp| {
+        if rfc3339 {
+            ts.format_rfc_3339(timezone)
+        } else {
+            ts.format_iso_8601(timezone)
+        }
+    };
+    let q_str = "select * from validation_failures order by app_id, app_version";
+    let q_str = filter.apply(q_str, "first_failure");
+    for row in sqlx::query(&q_str).fetch_all(pool).await?.iter() {
+        let failures: i64 = row.get("failures");
+        writer.write_record([
+            row.get("request_type"),
+            row.get("app_id"),
+            row.get("app_version"),
+            failures.to_string(),
+            format(Timestamp::from_db(row.get("first_failure"))),
+            format(Timestamp::from_db(row.get("last_failure"))),
+            row.get("last_problems"),
+        ])?;
+    }
+    Ok(())
+}
+
+pub async fn store_validation_failure(
+    pool: &SqlitePool,
+    failure: &ValidationFailure,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(FAILURE_UPSERT)
+        .bind(&failure.request_type)
+        .bind(&failure.app_id)
+        .bind(&failure.app_version)
+        .bind(failure.timestamp.to_db())
+        .bind(failure.summary())
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    debug!(
+        "Counted validation failure for {} {} {}",
+        &failure.request_type, &failure.app_id, &failure.app_version
+    );
+    Ok(())
+}
+
+pub async fn payload_report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        format!("Timestamp{time_suffix}"),
+        "Request Type".to_string(),
+        "Correlation ID".to_string(),
+        "Recovered".to_string(),
+        "Error".to_string(),
+        "Body".to_string(),
+    ])?;
+    let q_str = "select * from parse_failures order by timestamp";
+    let q_str = filter.apply(q_str, "timestamp");
+    for row in sqlx::query(&q_str).fetch_all(pool).await?.iter() {
+        let timestamp = Timestamp::from_db(row.get("timestamp"));
+        let recovered: bool = row.get("recovered");
+        writer.write_record([
+            if rfc3339 {
+                timestamp.format_rfc_3339(timezone)
+            } else {
+                timestamp.format_iso_8601(timezone)
+            },
+            row.get("request_type"),
+            row.get("correlation_id"),
+            recovered.to_string(),
+            row.get("error"),
+            row.get("body"),
+        ])?;
+    }
+    Ok(())
+}
+
+pub async fn store_parse_failure(
+    pool: &SqlitePool,
+    failure: &ParseFailure,
+) -> Result<()> {
+    let i_str = r#"insert into parse_failures
+        (timestamp, request_type, correlation_id, error, body, recovered)
+        values (?, ?, ?, ?, ?, ?)"#;
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(i_str)
+        .bind(failure.timestamp.to_db())
+        .bind(&failure.request_type)
+        .bind(&failure.correlation_id)
+        .bind(&failure.error)
+        .bind(&failure.body)
+        .bind(failure.recovered)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    debug!("Stored unparsed request body has rowid {}", result.last_insert_rowid());
+    Ok(())
+}
+
+async fn fetch_invalid_key_attempts(
+    pool: &SqlitePool,
+    filter: &ReportFilter,
+) -> Result<Vec<InvalidKeyAttempt>> {
+    debug!("Fetching all invalid api key attempts");
+    let q_str = "select * from invalid_api_keys order by timestamp";
+    let rows = sqlx::query(&filter.apply(q_str, "timestamp")).fetch_all(pool).await?;
+    let result: Vec<InvalidKeyAttempt> = rows.iter().map(attempt_from_row).collect();
+    debug!("Fetched {} invalid api key attempts", result.len());
+    Ok(result)
+}
+
+fn attempt_from_row(row: &SqliteRow) -> InvalidKeyAttempt {
+    InvalidKeyAttempt {
+        timestamp: Timestamp::from_db(row.get("timestamp")),
+        source_addr: row.get("source_addr"),
+        request_type: row.get("request_type"),
+        request_id: row.get("request_id"),
+        api_key: row.get("api_key"),
+        app_id: row.get("app_id"),
+        reason: row.get("reason"),
+        rejected: row.get("rejected"),
+        correlation_id: row.get("correlation_id"),
+    }
+}
+
+const ATTEMPT_SCHEMA: &str = r#"
+    create table if not exists invalid_api_keys (
+        timestamp integer not null,
+        source_addr text not null,
+        request_type text not null,
+        request_id text not null,
+        api_key text not null,
+        app_id text not null,
+        reason text not null,
+        rejected integer not null
+    );"#;
+
+const FAILURE_SCHEMA: &str = r#"
+    create table if not exists validation_failures (
+        request_type text not null,
+        app_id text not null,
+        app_version text not null,
+        failures integer not null,
+        first_failure integer not null,
+        last_failure integer not null,
+        last_problems text not null,
+        unique(request_type, app_id, app_version)
+    );"#;
+
+const PARSE_FAILURE_SCHEMA: &str = r#"
+    create table if not exists parse_failures (
+        timestamp integer not null,
+        request_type text not null,
+        correlation_id text not null,
+        error text not null,
+        body text not null,
+        recovered integer not null
+    );"#;
+
+const FAILURE_UPSERT: &str = r#"
+    insert into validation_failures
+        (
+            request_type, app_id, app_version,
+            failures, first_failure, last_failure, last_problems
+        )
+        values (?1, ?2, ?3, 1, ?4, ?4, ?5)
+    on conflict (request_type, app_id, app_version) do update set
+        failures = failures + 1,
+        last_failure = excluded.last_failure,
+        last_problems = excluded.last_problems
+    "#;
+
+const ATTEMPT_SCHEMA_VERSION: usize = 3;
+
+const SCHEMA_ALTERATIONS_BY_VERSION: [&str; ATTEMPT_SCHEMA_VERSION] = [
+    "alter table invalid_api_keys add column correlation_id not null default ''",
+    "alter table invalid_api_keys add column dedupe_key not null default ''",
+    // attempts stored before correlation ids were assigned use their row id
+    r#"update invalid_api_keys set dedupe_key =
+        (select instance_id from proxy_instance) || '|' ||
+            coalesce(nullif(correlation_id, ''), rowid)"#,
+];
+
+const CLEAR_ALL: &str = r#"
+    delete from invalid_api_keys;
+    delete from validation_failures;
+    delete from parse_failures;
+    "#;
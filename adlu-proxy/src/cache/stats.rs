@@ -0,0 +1,249 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Summary statistics about the contents of the cache.
+
+These are meant for a quick look before and after a cleanup, so they are
+cheap to compute: row counts for every table, distinct devices, apps, and
+users for each datasource, the range of timestamps in each datasource,
+the size of the database, and the backlog of requests waiting to be
+forwarded to Adobe.  They also include the total bandwidth used by each
+type of request.
+ */
+use eyre::Result;
+use serde::Serialize;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use super::bandwidth::{self, Bandwidth};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub database_bytes: u64,
+    pub tables: Vec<TableStats>,
+    pub datasources: Vec<DatasourceStats>,
+    pub unanswered_activations: u64,
+    pub unanswered_deactivations: u64,
+    pub bandwidth: Vec<Bandwidth>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableStats {
+    pub name: String,
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatasourceStats {
+    pub name: String,
+    pub oldest: Option<String>,
+    pub newest: Option<String>,
+    pub devices: Option<u64>,
+    pub apps: u64,
+    pub users: u64,
+}
+
+/// The queries that summarize a datasource.  The timestamp query returns the
+/// oldest and newest timestamps; the others each return a count.  Timestamps
+/// are stored as epoch millis, with 0 for a missing timestamp.
+struct DatasourceQueries {
+    name: &'static str,
+    timestamps: &'static str,
+    devices: Option<&'static str>,
+    apps: &'static str,
+    users: &'static str,
+}
+
+const DATASOURCES: [DatasourceQueries; 3] = [
+    DatasourceQueries {
+        name: "FRL",
+        timestamps: r#"
+            select min(t), max(t) from (
+                select timestamp as t from activation_requests
+                union all select timestamp from activation_responses
+                union all select timestamp from deactivation_requests
+                union all select timestamp from deactivation_responses
+            )"#,
+        devices: Some("select count(distinct device_id) from activation_requests"),
+        apps: "select count(distinct app_id) from activation_requests",
+        users: "select count(distinct os_user_id) from activation_requests",
+    },
+    DatasourceQueries {
+        name: "NUL",
+        timestamps: "select min(session_start), max(session_end) from license_sessions",
+        devices: Some(
+            "select count(distinct device_name) from license_sessions where device_name != ''",
+        ),
+        apps: "select count(distinct app_id) from license_sessions",
+        users: "select count(distinct user_id) from license_sessions",
+    },
+    DatasourceQueries {
+        name: "Log",
+        timestamps: "select min(initial_entry), max(final_entry) from log_sessions",
+        devices: None,
+        apps: "select count(distinct app_id) from log_sessions where app_id != ''",
+        users: "select count(distinct user_id) from log_sessions where user_id != ''",
+    },
+];
+
+pub async fn collect(pool: &SqlitePool) -> Result<Stats> {
+    let page_size: i64 = sqlx::query("pragma page_size").fetch_one(pool).await?.get(0);
+    let page_count: i64 = sqlx::query("pragma page_count").fetch_one(pool).await?.get(0);
+    let mut tables = Vec::new();
+    let q_str = r#"select name from sqlite_master
+        where type = 'table' and name not like 'sqlite_%' order by name"#;
+    for row in sqlx::query(q_str).fetch_all(pool).await? {
+        let name: String = row.get(0);
+        let c_str = format!("select count(*) from \"{}\"", name);
+        let rows = count(pool, &c_str).await?;
+        tables.push(TableStats { name, rows });
+    }
+    let mut datasources = Vec::new();
+    for queries in DATASOURCES.iter() {
+        let row = sqlx::query(queries.timestamps).fetch_one(pool).await?;
+        let (oldest, newest): (Option<i64>, Option<i64>) = (row.get(0), row.get(1));
+        let format = |millis: Option<i64>| {
+            Timestamp::optional_from_db(millis.unwrap_or(0)).map(|ts| ts.to_string())
+        };
+        let devices = match queries.devices {
+            Some(q_str) => Some(count(pool, q_str).await?),
+            None => None,
+        };
+        datasources.push(DatasourceStats {
+            name: queries.name.to_string(),
+            oldest: format(oldest),
+            newest: format(newest),
+            devices,
+            apps: count(pool, queries.apps).await?,
+            users: count(pool, queries.users).await?,
+        });
+    }
+    let q_str = r#"select count(*) from activation_requests req where not exists
+        (select 1 from activation_responses resp
+            where resp.activation_key = req.activation_key
+            and resp.timestamp >= req.timestamp)"#;
+    let unanswered_activations = count(pool, q_str).await?;
+    let unanswered_deactivations =
+        count(pool, "select count(*) from deactivation_requests").await?;
+    Ok(Stats {
+        database_bytes: (page_size * page_count) as u64,
+        tables,
+        datasources,
+        unanswered_activations,
+        unanswered_deactivations,
+        bandwidth: bandwidth::totals(pool).await?,
+    })
+}
+
+async fn count(pool: &SqlitePool, q_str: &str) -> Result<u64> {
+    let count: i64 = sqlx::query(q_str).fetch_one(pool).await?.get(0);
+    Ok(count as u64)
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_dash =
+            |val: &Option<String>| val.clone().unwrap_or_else(|| "-".to_string());
+        writeln!(f, "Database size: {} bytes", self.database_bytes)?;
+        writeln!(
+            f,
+            "Unanswered requests: {} activation(s), {} deactivation(s)",
+            self.unanswered_activations, self.unanswered_deactivations
+        )?;
+        writeln!(f)?;
+        let (table, rows) = ("Table", "Rows");
+        writeln!(f, "{:<28} {:>10}", table, rows)?;
+        for table in self.tables.iter() {
+            writeln!(f, "{:<28} {:>10}", table.name, table.rows)?;
+        }
+        writeln!(f)?;
+        let headers = ["Data", "Devices", "Apps", "Users", "Oldest", "Newest"];
+        writeln!(
+            f,
+            "{:<6} {:>8} {:>6} {:>6}  {:<29} {}",
+            headers[0], headers[1], headers[2], headers[3], headers[4], headers[5]
+        )?;
+        for ds in self.datasources.iter() {
+            let devices = ds.devices.map_or_else(|| "-".to_string(), |n| n.to_string());
+            writeln!(
+                f,
+                "{:<6} {:>8} {:>6} {:>6}  {:<29} {}",
+                ds.name,
+                devices,
+                ds.apps,
+                ds.users,
+                or_dash(&ds.oldest),
+                or_dash(&ds.newest)
+            )?;
+        }
+        if !self.bandwidth.is_empty() {
+            writeln!(f)?;
+            let headers = ["Request Type", "Requests", "Request Bytes", "Response Bytes"];
+            writeln!(
+                f,
+                "{:<18} {:>10} {:>14} {:>14}",
+                headers[0], headers[1], headers[2], headers[3]
+            )?;
+            for bw in self.bandwidth.iter() {
+                writeln!(
+                    f,
+                    "{:<18} {:>10} {:>14} {:>14}",
+                    bw.request_type, bw.requests, bw.request_bytes, bw.response_bytes
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::collect;
+
+    #[tokio::test]
+    async fn test_collect_stats() {
+        let dir = std::env::temp_dir().join("adlu-proxy-stats-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        let i_str = r#"insert into deactivation_requests
+            (deactivation_key, api_key, request_id, package_id, device_id, os_user_id,
+                is_domain_user, is_vdi, is_virtual, timestamp)
+            values ('d-key', 'key', 'id', 'pkg', 'device', 'user', 0, 0, 0,
+                1664582400000)"#;
+        sqlx::query(i_str).execute(&pool).await.unwrap();
+        let stats = collect(&pool).await.unwrap();
+        assert!(stats.database_bytes > 0);
+        assert_eq!(stats.unanswered_activations, 0);
+        assert_eq!(stats.unanswered_deactivations, 1);
+        let table = stats.tables.iter().find(|t| t.name == "deactivation_requests");
+        assert_eq!(table.unwrap().rows, 1);
+        let frl = &stats.datasources[0];
+        assert_eq!(frl.oldest.as_deref(), Some("2022-10-01T00:00:00.000+0000"));
+        assert_eq!(frl.oldest, frl.newest);
+        assert!(stats.datasources[2].oldest.is_none());
+        assert!(stats.to_string().contains("deactivation_requests"));
+        pool.close().await;
+    }
+}
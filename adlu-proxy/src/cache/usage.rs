@@ -0,0 +1,348 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Estimates of app usage, from license checks and log sessions.
+
+License checks only show when an app was launched (and, for NUL, when it
+last checked its license), while log sessions show when the app ran but
+not how it was licensed.  This pass matches each launch with the log
+session that followed it, so each estimated session carries both.
+
+A launch matches a log session with the same NGL session ID: that is the
+same session of the app, so the estimate has `high` confidence.  Failing
+that, a NUL launch matches the first unmatched log session of the same app
+from the same address that started shortly after it, and the estimate has
+`medium` confidence.  A launch with no matching log session is estimated
+from its license checks alone, with `low` confidence.
+ */
+use std::collections::{HashMap, HashSet};
+
+use eyre::Result;
+use sqlx::{sqlite::SqlitePool, Row};
+
+use adlu_base::Timestamp;
+
+use super::ReportFilter;
+
+/// How long after a launch its log session can start.
+const MATCH_WINDOW_MILLIS: i64 = 10 * 60 * 1000;
+
+/// How long before a launch its log session can start, because device
+/// clocks and upload times aren't exact.
+const MATCH_SLACK_MILLIS: i64 = 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    High,
+    Medium,
+    Low,
+}
+
+impl std::fmt::Display for Confidence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Confidence::High => "high".fmt(f),
+            Confidence::Medium => "medium".fmt(f),
+            Confidence::Low => "low".fmt(f),
+        }
+    }
+}
+
+/// A launch seen in a license check.  FRL activations don't carry the
+/// address of the device, so for them the device is its ID.
+#[derive(Debug, Clone)]
+struct Launch {
+    license_type: &'static str,
+    session_id: String,
+    device: String,
+    app_id: String,
+    app_version: String,
+    user_id: String,
+    launched: i64,
+    last_check: i64,
+}
+
+#[derive(Debug, Clone)]
+struct LogSpan {
+    session_id: String,
+    source_addr: String,
+    app_id: String,
+    start: i64,
+    end: i64,
+}
+
+/// A launch and its estimated session.
+#[derive(Debug, Clone)]
+struct Usage {
+    launch: Launch,
+    log_session_id: String,
+    start: i64,
+    end: i64,
+    confidence: Confidence,
+}
+
+pub async fn report(
+    pool: &SqlitePool,
+    path: &str,
+    filter: &ReportFilter,
+    timezone: bool,
+    rfc3339: bool,
+) -> Result<()> {
+    let time_suffix = if timezone { "" } else { " (UTC)" };
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record([
+        "License Type".to_string(),
+        "Session ID".to_string(),
+        "Device".to_string(),
+        "App ID".to_string(),
+        "App Version".to_string(),
+        "User ID".to_string(),
+        format!("Launch{time_suffix}"),
+        format!("Session Start{time_suffix}"),
+        format!("Session End{time_suffix}"),
+        "Duration (Minutes)".to_string(),
+        "Log Session ID".to_string(),
+        "Confidence".to_string(),
+    ])?;
+    let format = |millis: i64| {
+        let ts = Timestamp::from_db(millis);
+        if rfc3339 {
+            ts.format_rfc_3339(timezone)
+        } else {
+            ts.format_iso_8601(timezone)
+        }
+    };
+    let launches = fetch_launches(pool, filter).await?;
+    let logs = fetch_log_spans(pool).await?;
+    for usage in correlate(launches, &logs) {
+        let minutes = (usage.end - usage.start) as f64 / 60_000.0;
+        writer.write_record([
+            usage.launch.license_type.to_string(),
+            usage.launch.session_id,
+            usage.launch.device,
+            usage.launch.app_id,
+            usage.launch.app_version,
+            usage.launch.user_id,
+            format(usage.launch.launched),
+            format(usage.start),
+            format(usage.end),
+            format!("{:.1}", minutes),
+            usage.log_session_id,
+            usage.confidence.to_string(),
+        ])?;
+    }
+    Ok(())
+}
+
+/// Match launches with log sessions, in order of launch.  Each log
+/// session is matched with at most one launch.
+fn correlate(mut launches: Vec<Launch>, logs: &[LogSpan]) -> Vec<Usage> {
+    launches.sort_by_key(|launch| launch.launched);
+    let by_id: HashMap<&str, &LogSpan> =
+        logs.iter().map(|log| (log.session_id.as_str(), log)).collect();
+    let mut matched: HashSet<&str> = HashSet::new();
+    let mut usages = Vec::with_capacity(launches.len());
+    // exact matches first, so a nearby launch can't claim another's session
+    let mut pending = Vec::new();
+    for launch in launches {
+        match by_id.get(launch.session_id.as_str()) {
+            Some(log) if !matched.contains(log.session_id.as_str()) => {
+                matched.insert(&log.session_id);
+                usages.push(estimate(launch, Some(log), Confidence::High));
+            }
+            _ => pending.push(launch),
+        }
+    }
+    for launch in pending {
+        let nearby = if launch.license_type == "NUL" && launch.device != "unknown" {
+            logs.iter()
+                .filter(|log| {
+                    log.source_addr == launch.device
+                        && log.app_id == launch.app_id
+                        && log.start >= launch.launched - MATCH_SLACK_MILLIS
+                        && log.start <= launch.launched + MATCH_WINDOW_MILLIS
+                        && !matched.contains(log.session_id.as_str())
+                })
+                .min_by_key(|log| (log.start - launch.launched).abs())
+        } else {
+            None
+        };
+        match nearby {
+            Some(log) => {
+                matched.insert(&log.session_id);
+                usages.push(estimate(launch, Some(log), Confidence::Medium));
+            }
+            None => usages.push(estimate(launch, None, Confidence::Low)),
+        }
+    }
+    usages.sort_by_key(|usage| usage.launch.launched);
+    usages
+}
+
+fn estimate(launch: Launch, log: Option<&LogSpan>, confidence: Confidence) -> Usage {
+    let (mut start, mut end) = (launch.launched, launch.last_check.max(launch.launched));
+    let mut log_session_id = String::new();
+    if let Some(log) = log {
+        start = start.min(log.start);
+        end = end.max(log.end);
+        log_session_id = log.session_id.clone();
+    }
+    Usage { launch, log_session_id, start, end, confidence }
+}
+
+async fn fetch_launches(pool: &SqlitePool, filter: &ReportFilter) -> Result<Vec<Launch>> {
+    let mut launches = vec![];
+    let q_str = filter.apply(NUL_LAUNCHES, "session_start");
+    for row in sqlx::query(&q_str).fetch_all(pool).await?.iter() {
+        launches.push(Launch {
+            license_type: "NUL",
+            session_id: row.get("session_id"),
+            device: row.get("source_addr"),
+            app_id: row.get("app_id"),
+            app_version: row.get("app_version"),
+            user_id: row.get("user_id"),
+            launched: row.get("session_start"),
+            last_check: row.get("session_end"),
+        });
+    }
+    let q_str = filter.apply(FRL_LAUNCHES, "timestamp");
+    for row in sqlx::query(&q_str).fetch_all(pool).await?.iter() {
+        let session_id: String = row.get("session_id");
+        let launched: i64 = row.get("timestamp");
+        launches.push(Launch {
+            license_type: "FRL",
+            session_id: base_session_id(&session_id).to_string(),
+            device: row.get("device_id"),
+            app_id: row.get("app_id"),
+            app_version: row.get("app_version"),
+            user_id: row.get("os_user_id"),
+            launched,
+            last_check: launched,
+        });
+    }
+    Ok(launches)
+}
+
+async fn fetch_log_spans(pool: &SqlitePool) -> Result<Vec<LogSpan>> {
+    let rows = sqlx::query(LOG_SPANS).fetch_all(pool).await?;
+    let spans = rows
+        .iter()
+        .map(|row| LogSpan {
+            session_id: row.get("session_id"),
+            source_addr: row.get("source_addr"),
+            app_id: row.get("app_id"),
+            start: row.get("session_start"),
+            end: row.get("session_end"),
+        })
+        .collect();
+    Ok(spans)
+}
+
+/// Session IDs in requests can have a suffix (such as `/SUBSEQUENT`)
+/// that isn't part of the app's session ID.
+fn base_session_id(session_id: &str) -> &str {
+    session_id.split('/').next().unwrap_or(session_id)
+}
+
+const NUL_LAUNCHES: &str = r#"
+    select session_id, source_addr, app_id, app_version, user_id,
+        session_start, session_end
+    from license_sessions
+    where session_start != 0
+    "#;
+
+const FRL_LAUNCHES: &str = r#"
+    select session_id, device_id, app_id, app_version, os_user_id, timestamp
+    from activation_requests
+    where timestamp != 0
+    "#;
+
+const LOG_SPANS: &str = r#"
+    select session_id, source_addr, app_id, session_start,
+        max(session_start, session_end) as session_end
+    from log_sessions
+    where session_start != 0
+    "#;
+
+#[cfg(test)]
+mod tests {
+    use super::{correlate, Confidence, Launch, LogSpan};
+
+    fn launch(license_type: &'static str, session_id: &str, launched: i64) -> Launch {
+        Launch {
+            license_type,
+            session_id: session_id.to_string(),
+            device: "10.0.0.5".to_string(),
+            app_id: "Photoshop1".to_string(),
+            app_version: "24.0".to_string(),
+            user_id: "user".to_string(),
+            launched,
+            last_check: launched + 1000,
+        }
+    }
+
+    fn log(session_id: &str, start: i64, end: i64) -> LogSpan {
+        LogSpan {
+            session_id: session_id.to_string(),
+            source_addr: "10.0.0.5".to_string(),
+            app_id: "Photoshop1".to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_correlate() {
+        let minute = 60_000;
+        let launches = vec![
+            launch("NUL", "nearby", 100 * minute),
+            launch("FRL", "exact", 10 * minute),
+            launch("NUL", "too-late", 300 * minute),
+            launch("FRL", "frl-nearby", 400 * minute),
+        ];
+        let logs = vec![
+            log("exact", 10 * minute + 5000, 70 * minute),
+            log("other", 102 * minute, 160 * minute),
+            log("late", 320 * minute, 330 * minute),
+            log("frl-other", 401 * minute, 420 * minute),
+        ];
+        let usages = correlate(launches, &logs);
+        let summary: Vec<(&str, &str, Confidence, i64)> = usages
+            .iter()
+            .map(|u| {
+                let minutes = (u.end - u.start) / minute;
+                (
+                    u.launch.session_id.as_str(),
+                    u.log_session_id.as_str(),
+                    u.confidence,
+                    minutes,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("exact", "exact", Confidence::High, 60),
+                ("nearby", "other", Confidence::Medium, 60),
+                ("too-late", "", Confidence::Low, 0),
+                ("frl-nearby", "", Confidence::Low, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,258 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Consistency checks for the cache database.
+
+The store functions keep the request and response tables consistent with each
+other: storing an FRL activation response removes any earlier deactivation of
+the same device and package, storing a deactivation response removes any
+earlier activations, and so on.  Each store happens in a transaction, but caches
+that were written by older proxies (or that were damaged by a crash or a disk
+problem) can still end up with orphaned or dangling rows.  This module finds
+those rows and, on request, removes them.
+
+Each check is a pair of statements: one that counts the anomalous rows, and
+(for those that can be repaired) one that repairs them.  Repairs are done in a
+single transaction, after which the indexes are rebuilt.
+ */
+use eyre::Result;
+use log::{info, warn};
+use sqlx::{sqlite::SqlitePool, Row};
+
+/// A consistency check: a description of the anomaly, a query that counts
+/// the rows which have it, and a statement that repairs them (if possible).
+struct Check {
+    description: &'static str,
+    count: &'static str,
+    repair: Option<&'static str>,
+}
+
+/// An anomaly found by a check.
+#[derive(Debug, Clone)]
+pub struct Anomaly {
+    pub description: String,
+    pub count: i64,
+    pub repairable: bool,
+}
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.count, self.description)?;
+        if !self.repairable {
+            write!(f, " (cannot be repaired automatically)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run all the checks, returning the anomalies found.  If `repair` is
+/// specified, the repairable anomalies are repaired and the indexes rebuilt.
+pub async fn verify(pool: &SqlitePool, repair: bool) -> Result<Vec<Anomaly>> {
+    let mut anomalies = integrity_problems(pool).await?;
+    let mut repairs = Vec::new();
+    for check in CHECKS.iter() {
+        let count: i64 = sqlx::query(check.count).fetch_one(pool).await?.get(0);
+        if count == 0 {
+            continue;
+        }
+        warn!("Cache has {} {}", count, check.description);
+        anomalies.push(Anomaly {
+            description: check.description.to_string(),
+            count,
+            repairable: check.repair.is_some(),
+        });
+        if let Some(r_str) = check.repair {
+            repairs.push(r_str);
+        }
+    }
+    if repair {
+        let mut tx = pool.begin().await?;
+        for r_str in repairs.iter() {
+            let result = sqlx::query(r_str).execute(&mut tx).await?;
+            info!("Repair affected {} row(s): {}", result.rows_affected(), r_str.trim());
+        }
+        tx.commit().await?;
+        sqlx::query("reindex").execute(pool).await?;
+        info!("Rebuilt cache indexes");
+    }
+    Ok(anomalies)
+}
+
+/// The problems (if any) reported by SQLite's own integrity check.
+async fn integrity_problems(pool: &SqlitePool) -> Result<Vec<Anomaly>> {
+    let rows = sqlx::query("pragma integrity_check").fetch_all(pool).await?;
+    let problems = rows
+        .iter()
+        .map(|row| row.get::<String, _>(0))
+        .filter(|msg| msg != "ok")
+        .map(|msg| {
+            warn!("Cache integrity check failed: {}", msg);
+            Anomaly {
+                description: format!("database integrity problem: {}", msg),
+                count: 1,
+                repairable: msg.contains("index"),
+            }
+        })
+        .collect();
+    Ok(problems)
+}
+
+const CHECKS: [Check; 8] = [
+    Check {
+        description: "FRL activation response(s) without a request",
+        count: r#"
+            select count(*) from activation_responses resp where not exists
+                (select 1 from activation_requests req
+                    where req.activation_key = resp.activation_key)"#,
+        repair: Some(
+            r#"
+            delete from activation_responses where not exists
+                (select 1 from activation_requests req
+                    where req.activation_key = activation_responses.activation_key)"#,
+        ),
+    },
+    Check {
+        description: "FRL activation response(s) with a different deactivation key than their request",
+        count: r#"
+            select count(*) from activation_responses resp
+                inner join activation_requests req on req.activation_key = resp.activation_key
+                where req.deactivation_key != resp.deactivation_key"#,
+        repair: Some(
+            r#"
+            update activation_responses set deactivation_key =
+                (select deactivation_key from activation_requests req
+                    where req.activation_key = activation_responses.activation_key)
+                where exists (select 1 from activation_requests req
+                    where req.activation_key = activation_responses.activation_key
+                    and req.deactivation_key != activation_responses.deactivation_key)"#,
+        ),
+    },
+    Check {
+        description: "FRL activation request(s) left over from before a later deactivation",
+        count: r#"
+            select count(*) from activation_requests req where exists
+                (select 1 from deactivation_responses d
+                    where d.deactivation_key = req.deactivation_key
+                    and d.timestamp >= req.timestamp)"#,
+        repair: Some(
+            r#"
+            delete from activation_requests where exists
+                (select 1 from deactivation_responses d
+                    where d.deactivation_key = activation_requests.deactivation_key
+                    and d.timestamp >= activation_requests.timestamp)"#,
+        ),
+    },
+    Check {
+        description: "FRL activation response(s) left over from before a later deactivation",
+        count: r#"
+            select count(*) from activation_responses resp where exists
+                (select 1 from deactivation_responses d
+                    where d.deactivation_key = resp.deactivation_key
+                    and d.timestamp >= resp.timestamp)"#,
+        repair: Some(
+            r#"
+            delete from activation_responses where exists
+                (select 1 from deactivation_responses d
+                    where d.deactivation_key = activation_responses.deactivation_key
+                    and d.timestamp >= activation_responses.timestamp)"#,
+        ),
+    },
+    Check {
+        description: "FRL deactivation request(s) left over from before a later activation",
+        count: r#"
+            select count(*) from deactivation_requests req where exists
+                (select 1 from activation_responses a
+                    where a.deactivation_key = req.deactivation_key
+                    and a.timestamp > req.timestamp)"#,
+        repair: Some(
+            r#"
+            delete from deactivation_requests where exists
+                (select 1 from activation_responses a
+                    where a.deactivation_key = deactivation_requests.deactivation_key
+                    and a.timestamp > deactivation_requests.timestamp)"#,
+        ),
+    },
+    Check {
+        description: "FRL deactivation response(s) left over from before a later activation",
+        count: r#"
+            select count(*) from deactivation_responses resp where exists
+                (select 1 from activation_responses a
+                    where a.deactivation_key = resp.deactivation_key
+                    and a.timestamp > resp.timestamp)"#,
+        repair: Some(
+            r#"
+            delete from deactivation_responses where exists
+                (select 1 from activation_responses a
+                    where a.deactivation_key = deactivation_responses.deactivation_key
+                    and a.timestamp > deactivation_responses.timestamp)"#,
+        ),
+    },
+    Check {
+        description: "NUL license session(s) that end before they start",
+        count: r#"
+            select count(*) from license_sessions
+                where session_end != 0 and session_end < session_start"#,
+        repair: None,
+    },
+    Check {
+        description: "log session(s) that end before they start",
+        count: r#"
+            select count(*) from log_sessions
+                where session_end != 0 and session_end < session_start"#,
+        repair: None,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use sqlx::Row;
+
+    #[tokio::test]
+    async fn test_verify_and_repair() {
+        let dir = std::env::temp_dir().join("adlu-proxy-verify-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.sqlite").to_string_lossy().to_string();
+        let pool = super::super::db_init(&path, "rwc", 1).await.unwrap();
+        assert!(verify(&pool, false).await.unwrap().is_empty());
+        let inserts = [
+            // an orphaned activation response
+            r#"insert into activation_responses (activation_key, deactivation_key, body, timestamp)
+                values ('orphan', 'd-orphan', '{}', 1664582400000)"#,
+            // a deactivation that was answered after the activation was stored
+            r#"insert into deactivation_responses (deactivation_key, body, timestamp)
+                values ('d-dangling', '{}', 1664668800000)"#,
+            r#"insert into activation_responses (activation_key, deactivation_key, body, timestamp)
+                values ('dangling', 'd-dangling', '{}', 1664582400000)"#,
+        ];
+        for i_str in inserts.iter() {
+            sqlx::query(i_str).execute(&pool).await.unwrap();
+        }
+        let anomalies = verify(&pool, true).await.unwrap();
+        let total: i64 = anomalies.iter().map(|a| a.count).sum();
+        assert_eq!(total, 3, "{:?}", anomalies);
+        assert!(anomalies.iter().all(|a| a.repairable));
+        assert!(verify(&pool, false).await.unwrap().is_empty());
+        let q_str = "select count(*) from deactivation_responses";
+        let count: i64 = sqlx::query(q_str).fetch_one(&pool).await.unwrap().get(0);
+        assert_eq!(count, 1);
+        pool.close().await;
+    }
+}
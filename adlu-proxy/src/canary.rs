@@ -0,0 +1,241 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Synthetic activation round-trips, for monitoring.
+
+A proxy can look healthy (it's up, its cache is fine) while activations are
+failing, because Adobe has become unreachable or is refusing its requests.
+The canary catches that by sending a test FRL activation through the same
+upstream path that client requests take, and checking that a valid activation
+comes back.  It runs on a schedule (see the `canary` settings), or on demand
+with a POST to the `/canary` endpoint; a GET returns the latest result, which
+is also part of the proxy's status.
+
+A canary only passes if its response came from upstream during its run: a
+response replayed from the cache (because Adobe couldn't be reached) fails.
+ */
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use adlu_base::Timestamp;
+use adlu_parse::protocol::{FrlActivationRequestBody, FrlActivationResponseBody};
+
+use crate::proxy::{send_request, Config, Request, RequestType, SendOutcome};
+use crate::settings::Canary;
+
+/// Responses dated more than this before a run started were replayed from the
+/// cache.  The `Date` header of a response only has whole seconds.
+const CLOCK_SLOP_MILLIS: i64 = 2000;
+
+/// The result of one canary run.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanaryResult {
+    pub passed: bool,
+    pub outcome: String,
+    pub started: String,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    last: Option<CanaryResult>,
+    runs: u64,
+    failures: u64,
+    consecutive_failures: u64,
+}
+
+/// The results of the canary runs so far.
+#[derive(Debug, Default)]
+pub struct CanaryMonitor {
+    history: Mutex<History>,
+}
+
+impl CanaryMonitor {
+    /// Send a canary activation upstream and record whether it passed.
+    pub async fn run(&self, conf: &Config) -> CanaryResult {
+        let req = canary_request(&conf.settings.canary);
+        let started = Timestamp::now();
+        let clock = Instant::now();
+        let outcome = send_request(conf, &req).await;
+        let elapsed_ms = clock.elapsed().as_millis() as u64;
+        let (passed, outcome) = judge(&outcome, &started);
+        if passed {
+            info!("Canary activation passed in {}ms", elapsed_ms);
+        } else {
+            warn!("Canary activation failed after {}ms: {}", elapsed_ms, outcome);
+        }
+        let result = CanaryResult {
+            passed,
+            outcome,
+            started: started.format_iso_8601(true),
+            elapsed_ms,
+        };
+        let mut history = self.history.lock().unwrap();
+        history.runs += 1;
+        if passed {
+            history.consecutive_failures = 0;
+        } else {
+            history.failures += 1;
+            history.consecutive_failures += 1;
+        }
+        history.last = Some(result.clone());
+        result
+    }
+
+    /// The canary's record, as reported in the proxy's status.
+    pub fn to_json(&self) -> Value {
+        let history = self.history.lock().unwrap();
+        json!({
+            "passed": history.last.as_ref().map(|last| last.passed),
+            "last": history.last,
+            "runs": history.runs,
+            "failures": history.failures,
+            "consecutiveFailures": history.consecutive_failures,
+        })
+    }
+}
+
+/// Start running the canary on its schedule, if it has one.
+/// Abort the returned task to stop it.
+pub fn spawn(conf: &Config) -> Option<JoinHandle<()>> {
+    let settings = &conf.settings.canary;
+    if !settings.enabled || settings.interval_mins == 0 {
+        return None;
+    }
+    info!("Running the canary activation every {} minute(s)", settings.interval_mins);
+    let period = Duration::from_secs(settings.interval_mins * 60);
+    let conf = conf.clone();
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            conf.canary.run(&conf).await;
+        }
+    }))
+}
+
+/// The activation request the canary sends.  Each one has its own request and
+/// session ids, so the runs can be told apart in the logs.
+pub fn canary_request(settings: &Canary) -> Request {
+    let mut body = FrlActivationRequestBody::valid_from_device_id(&settings.device_id);
+    if !settings.npd_id.is_empty() {
+        body.npd_id = settings.npd_id.clone();
+    }
+    let correlation_id = Request::new_correlation_id();
+    Request {
+        timestamp: Timestamp::now(),
+        request_type: RequestType::FrlActivation,
+        source_ip: None,
+        method: http::Method::POST,
+        host: None,
+        path: "/asnp/frl_connected/values/v2".to_string(),
+        query: None,
+        body: Some(body.to_body()),
+        content_type: Some("application/json".to_string()),
+        accept_type: Some("application/json".to_string()),
+        accept_language: None,
+        user_agent: Some(format!("adlu-proxy/{} (canary)", env!("CARGO_PKG_VERSION"))),
+        via: None,
+        api_key: Some(settings.api_key.clone()),
+        request_id: Some(format!("canary-{}", correlation_id)),
+        session_id: Some(format!("canary-{}", correlation_id)),
+        authorization: None,
+        correlation_id,
+    }
+}
+
+/// Whether a canary that started at `started` passed, and a description of
+/// what happened to it.
+fn judge(outcome: &SendOutcome, started: &Timestamp) -> (bool, String) {
+    match outcome {
+        SendOutcome::Success(resp) => {
+            if resp.timestamp.to_millis() < started.to_millis() - CLOCK_SLOP_MILLIS {
+                (false, "the response was replayed from the cache".to_string())
+            } else {
+                let body = resp.body.as_deref().unwrap_or_default();
+                match FrlActivationResponseBody::from_body(body) {
+                    Ok(_) => (true, "activated".to_string()),
+                    Err(err) => (false, format!("the response is invalid: {}", err)),
+                }
+            }
+        }
+        SendOutcome::Isolated => (false, "the proxy is isolated".to_string()),
+        SendOutcome::Throttled(secs) => {
+            (false, format!("Adobe is throttling requests for {}s", secs))
+        }
+        SendOutcome::Unreachable(err) => (false, format!("unreachable: {:#}", err)),
+        SendOutcome::ParseFailure(err) => (false, format!("unparseable: {:#}", err)),
+        SendOutcome::ErrorStatus(resp) => {
+            (false, format!("error status: {}", resp.status()))
+        }
+        SendOutcome::CacheFailure(err) => (false, format!("cache failure: {:#}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canary_request, judge};
+    use crate::proxy::{Response, SendOutcome};
+    use crate::settings::Canary;
+    use adlu_base::Timestamp;
+    use adlu_parse::protocol::{
+        FrlActivationRequestBody, FrlActivationResponseBody, RequestType,
+    };
+
+    #[test]
+    fn test_judge() {
+        let settings = Canary { npd_id: "test-npd".to_string(), ..Default::default() };
+        let req = canary_request(&settings);
+        let body =
+            FrlActivationRequestBody::from_body(req.body.as_ref().unwrap()).unwrap();
+        assert_eq!(body.npd_id, "test-npd");
+        assert_eq!(body.device_details.device_id, "adlu-proxy-canary");
+        let response = |timestamp: Timestamp, body: &str| Response {
+            timestamp,
+            request_type: RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: Some(body.to_string()),
+            content_type: Some("application/json".to_string()),
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        let started = Timestamp::now();
+        let valid = FrlActivationResponseBody::mock_from_device_id("canary").to_body();
+        let success = SendOutcome::Success(response(started.clone(), &valid));
+        assert_eq!(judge(&success, &started), (true, "activated".to_string()));
+        let (passed, outcome) =
+            judge(&SendOutcome::Success(response(started.clone(), "{}")), &started);
+        assert!(!passed && outcome.starts_with("the response is invalid"), "{}", outcome);
+        let stale = Timestamp::from_millis(started.to_millis() - 3_600_000);
+        let (passed, outcome) =
+            judge(&SendOutcome::Success(response(stale, "{}")), &started);
+        assert!(!passed && outcome.contains("cache"), "{}", outcome);
+        assert!(!judge(&SendOutcome::Throttled(30), &started).0);
+    }
+}
@@ -0,0 +1,219 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Recording and replay of the proxy's exchanges with Adobe ("cassettes").
+
+When the `[cassette]` mode is `record`, every request the proxy sends to
+Adobe, and the response it gets, is appended as a line of JSON to the
+cassette file.  When the mode is `replay`, requests aren't sent to Adobe at
+all: each is answered with the response recorded for it, and a request that
+was never recorded fails as if Adobe were unreachable.
+
+Requests are matched by a hash of their method, URL, and body, so a replayed
+request must be the same as the recorded one.  That makes cassettes suitable
+as deterministic fixtures for integration tests, and lets a site admin
+capture a problematic exchange to attach to a bug report.  Cassettes record
+no request headers (so no credentials), but they do hold request and response
+bodies, which identify devices and users.
+ */
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+
+use eyre::{eyre, Result, WrapErr};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use adlu_base::Timestamp;
+
+use crate::settings::{Cassette as CassetteSettings, CassetteMode};
+
+/// One recorded exchange with Adobe.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Interaction {
+    pub key: String,
+    pub recorded: String,
+    pub method: String,
+    pub url: String,
+    pub request_body: Option<String>,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// A cassette that's being recorded or replayed.
+#[derive(Debug)]
+pub struct Cassette {
+    mode: CassetteMode,
+    path: String,
+    interactions: Mutex<HashMap<String, Interaction>>,
+}
+
+impl Cassette {
+    /// The cassette configured by the settings, if any.  A cassette
+    /// that's replayed must exist; one that's recorded is appended to.
+    pub fn new(settings: &CassetteSettings) -> Result<Option<Self>> {
+        let interactions = match settings.mode {
+            CassetteMode::Off => return Ok(None),
+            CassetteMode::Record => HashMap::new(),
+            CassetteMode::Replay => load(&settings.path)?,
+        };
+        if let CassetteMode::Replay = settings.mode {
+            info!(
+                "Replaying {} recorded interaction(s) from cassette {}",
+                interactions.len(),
+                &settings.path
+            );
+        } else {
+            info!("Recording interactions with Adobe to cassette {}", &settings.path);
+        }
+        Ok(Some(Cassette {
+            mode: settings.mode.clone(),
+            path: settings.path.clone(),
+            interactions: Mutex::new(interactions),
+        }))
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.mode, CassetteMode::Replay)
+    }
+
+    /// The recorded response to a request.
+    pub fn replay(&self, request: &reqwest::Request) -> Result<reqwest::Response> {
+        let key = request_key(request);
+        let interactions = self.interactions.lock().unwrap();
+        let recorded = interactions.get(&key).ok_or_else(|| {
+            eyre!("No interaction for {} {} in cassette", request.method(), request.url())
+        })?;
+        debug!("Replaying the interaction recorded at {}", &recorded.recorded);
+        to_response(recorded)
+    }
+
+    /// Record an exchange, returning the response so it can be used as usual.
+    pub async fn record(
+        &self,
+        request: &RecordedRequest,
+        response: reqwest::Response,
+    ) -> Result<reqwest::Response> {
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, val)| {
+                Some((name.to_string(), val.to_str().ok()?.to_string()))
+            })
+            .collect();
+        let body = response.text().await.wrap_err("Failure to receive body")?;
+        let interaction = Interaction {
+            key: request.key.clone(),
+            recorded: Timestamp::now().format_rfc_3339(true),
+            method: request.method.clone(),
+            url: request.url.clone(),
+            request_body: request.body.clone(),
+            status,
+            headers,
+            body,
+        };
+        let mut line = serde_json::to_string(&interaction)?;
+        line.push('\n');
+        let result = to_response(&interaction);
+        let mut interactions = self.interactions.lock().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .wrap_err(format!("Can't open cassette {}", &self.path))?;
+        file.write_all(line.as_bytes())
+            .wrap_err(format!("Can't write cassette {}", &self.path))?;
+        interactions.insert(interaction.key.clone(), interaction);
+        result
+    }
+}
+
+/// What's recorded of a request, taken before the request is sent.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    key: String,
+    method: String,
+    url: String,
+    body: Option<String>,
+}
+
+impl From<&reqwest::Request> for RecordedRequest {
+    fn from(request: &reqwest::Request) -> Self {
+        RecordedRequest {
+            key: request_key(request),
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            body: request_body(request).map(|b| String::from_utf8_lossy(b).to_string()),
+        }
+    }
+}
+
+fn request_body(request: &reqwest::Request) -> Option<&[u8]> {
+    request.body().and_then(|body| body.as_bytes())
+}
+
+/// The key of a request: a hash of its method, URL, and body.
+pub fn request_key(request: &reqwest::Request) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(request.method().as_str());
+    hasher.update(b" ");
+    hasher.update(request.url().as_str());
+    hasher.update(b"\n");
+    hasher.update(request_body(request).unwrap_or_default());
+    format!("{:x}", hasher.finalize())
+}
+
+fn load(path: &str) -> Result<HashMap<String, Interaction>> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err(format!("Can't read cassette {}", path))?;
+    let mut result = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let interaction: Interaction = serde_json::from_str(line).wrap_err(format!(
+            "Line {} of cassette {} is not valid",
+            i + 1,
+            path
+        ))?;
+        // a later recording of the same request replaces an earlier one
+        result.insert(interaction.key.clone(), interaction);
+    }
+    Ok(result)
+}
+
+fn to_response(interaction: &Interaction) -> Result<reqwest::Response> {
+    let mut builder = http::Response::builder().status(interaction.status);
+    for (name, value) in interaction.headers.iter() {
+        // the body is recorded decoded, and its length may have changed
+        if !name.eq_ignore_ascii_case("content-encoding")
+            && !name.eq_ignore_ascii_case("content-length")
+        {
+            builder = builder.header(name, value);
+        }
+    }
+    let resp = builder
+        .body(interaction.body.clone())
+        .wrap_err("Can't build recorded response")?;
+    Ok(resp.into())
+}
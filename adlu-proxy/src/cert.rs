@@ -0,0 +1,139 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Generation of the key pair and certificate that the proxy serves SSL with.
+
+A self-signed certificate is written in the form the `[ssl]` settings call for:
+either a PKCS12 (aka PFX) file or separate PEM certificate and key files.  A
+certificate signing request (CSR) is written next to the PEM key, so the
+certificate that an organization's PKI issues for it can be used as the
+separate certificate file.  Keys are protected with the configured password,
+if there is one.
+
+Certificates are valid for the host names that clients use to reach the
+proxy, which (unless they are given explicitly) are guessed from the settings.
+ */
+use std::net::IpAddr;
+
+use eyre::{eyre, Result, WrapErr};
+
+use adlu_base::{CertificateData, CertificateRequest};
+
+use crate::cli::CertKind;
+use crate::proxy::proxy_id;
+use crate::settings::Settings;
+
+pub fn generate(
+    settings: &Settings,
+    kind: &CertKind,
+    hosts: &[String],
+    days: u32,
+) -> Result<()> {
+    let hosts = if hosts.is_empty() { default_hosts(settings) } else { hosts.to_vec() };
+    let ssl = &settings.ssl;
+    match kind {
+        CertKind::SelfSigned => {
+            let data = CertificateData::self_signed(&hosts, days)
+                .wrap_err("Can't generate a self-signed certificate")?;
+            if ssl.use_pfx {
+                write_new_file(&ssl.pfx_path, &data.to_pfx(&ssl.password, &proxy_id())?)?;
+                eprintln!("Wrote PKCS12 certificate and key to: {}", &ssl.pfx_path);
+            } else {
+                write_new_file(
+                    &ssl.key_path,
+                    &data.key_pem_with_password(&ssl.password)?,
+                )?;
+                write_new_file(&ssl.cert_path, &data.cert_pem())?;
+                eprintln!("Wrote key to: {}", &ssl.key_path);
+                eprintln!("Wrote certificate to: {}", &ssl.cert_path);
+            }
+            eprintln!(
+                "The certificate is valid for {} day(s) for: {}",
+                days,
+                hosts.join(", ")
+            );
+        }
+        CertKind::Csr => {
+            let request = CertificateRequest::new(&hosts)
+                .wrap_err("Can't generate a certificate signing request")?;
+            let csr_path = format!("{}.csr", &ssl.cert_path);
+            write_new_file(
+                &ssl.key_path,
+                &request.key_pem_with_password(&ssl.password)?,
+            )?;
+            write_new_file(&csr_path, &request.csr_pem())?;
+            eprintln!("Wrote key to: {}", &ssl.key_path);
+            eprintln!("Wrote signing request for {} to: {}", hosts.join(", "), &csr_path);
+            eprintln!(
+                "Save the signed certificate (in PEM format) to: {}",
+                &ssl.cert_path
+            );
+            if ssl.use_pfx {
+                eprintln!(
+                    "and reconfigure the proxy to use separate cert and key files."
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The names clients are likely to reach the proxy by: the address the
+/// proxy listens on (if it's specific) and the name of this machine.
+fn default_hosts(settings: &Settings) -> Vec<String> {
+    let mut candidates = vec![];
+    match settings.proxy.host.parse::<IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => {}
+        _ => candidates.push(settings.proxy.host.clone()),
+    }
+    if let Ok(hostname) = sys_info::hostname() {
+        candidates.push(hostname);
+    }
+    let mut result: Vec<String> = vec![];
+    for host in candidates {
+        let host = host.to_ascii_lowercase();
+        if !host.is_empty() && !result.contains(&host) {
+            result.push(host);
+        }
+    }
+    result
+}
+
+fn write_new_file(path: &str, content: &[u8]) -> Result<()> {
+    if std::fs::metadata(path).is_ok() {
+        return Err(eyre!("Cannot overwrite an existing file: {}", path));
+    }
+    std::fs::write(path, content).wrap_err(format!("Can't write file: {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{Settings, SettingsVal};
+
+    #[test]
+    fn test_default_hosts() {
+        let mut settings = SettingsVal::default_config();
+        settings.proxy.host = "10.0.0.5".to_string();
+        let hosts = super::default_hosts(&Settings::new(settings.clone()));
+        assert_eq!(hosts[0], "10.0.0.5");
+        settings.proxy.host = "0.0.0.0".to_string();
+        let hosts = super::default_hosts(&Settings::new(settings));
+        assert!(!hosts.contains(&"0.0.0.0".to_string()));
+    }
+}
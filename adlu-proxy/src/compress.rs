@@ -0,0 +1,164 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Compression of reports and exports.
+
+Reports and exports are first written uncompressed next to their destination,
+and then streamed through the compressor into it, so compressing even a very
+large report never needs more than a buffer's worth of memory.  Compressed
+exports are recognized by their extension when they are imported.
+ */
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use clap::ValueEnum;
+use eyre::{Result, WrapErr};
+
+#[derive(Debug, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// gzip (.gz)
+    Gzip,
+    /// Zstandard (.zst)
+    Zstd,
+}
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "application/gzip",
+            Compression::Zstd => "application/zstd",
+        }
+    }
+
+    /// The compression of a file, as given by its extension.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = std::path::Path::new(path).extension()?.to_str()?;
+        match extension.to_ascii_lowercase().as_str() {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The name of a compressed file: the given name, with the compression's
+    /// extension added if it doesn't already have it.
+    pub fn compressed_path(&self, path: &str) -> String {
+        if Compression::from_path(path).as_ref() == Some(self) {
+            path.to_string()
+        } else {
+            format!("{}.{}", path, self.extension())
+        }
+    }
+
+    /// Compress the file at `from` into a new file at `to`.
+    pub fn compress(&self, from: &str, to: &str) -> Result<()> {
+        let mut reader = BufReader::new(File::open(from)?);
+        let writer = BufWriter::new(
+            File::create(to).wrap_err(format!("Can't create compressed file: {}", to))?,
+        );
+        match self {
+            Compression::Gzip => {
+                let level = flate2::Compression::default();
+                let mut encoder = flate2::write::GzEncoder::new(writer, level);
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, 0)?;
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decompress the file at `from` into a new file at `to`.
+    pub fn decompress(&self, from: &str, to: &str) -> Result<()> {
+        let reader = BufReader::new(File::open(from)?);
+        let mut writer = BufWriter::new(File::create(to)?);
+        match self {
+            Compression::Gzip => {
+                let mut decoder = flate2::read::MultiGzDecoder::new(reader);
+                std::io::copy(&mut decoder, &mut writer)
+            }
+            Compression::Zstd => {
+                let mut decoder = zstd::Decoder::with_buffer(reader)?;
+                std::io::copy(&mut decoder, &mut writer)
+            }
+        }
+        .wrap_err(format!("Can't decompress {}", from))?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Write an output with `write` and then compress it to `path` (with the
+/// compression's extension added).  The uncompressed output is removed
+/// whether or not writing it succeeds.  Returns what `write` returned.
+pub async fn write_compressed<T, F, Fut>(
+    compression: &Compression,
+    path: &str,
+    write: F,
+) -> Result<T>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let out_path = compression.compressed_path(path);
+    let raw_path = format!("{}.partial", out_path);
+    let result = match write(raw_path.clone()).await {
+        Ok(val) => compression.compress(&raw_path, &out_path).map(|_| val),
+        Err(err) => Err(err),
+    };
+    std::fs::remove_file(&raw_path).ok();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+
+    #[test]
+    fn test_compression_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = dir.path().join("report.csv");
+        let content = "Header\n".to_string() + &"a,b,c\n".repeat(1000);
+        std::fs::write(&raw, &content).unwrap();
+        let raw = raw.to_str().unwrap();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compressed_path(raw);
+            assert_eq!(compressed, format!("{}.{}", raw, compression.extension()));
+            assert_eq!(compression.compressed_path(&compressed), compressed);
+            assert_eq!(Compression::from_path(&compressed), Some(compression.clone()));
+            compression.compress(raw, &compressed).unwrap();
+            let size = std::fs::metadata(&compressed).unwrap().len();
+            assert!(size < content.len() as u64 / 10, "{:?} is too big", compression);
+            let restored = format!("{}.restored", raw);
+            compression.decompress(&compressed, &restored).unwrap();
+            assert_eq!(std::fs::read_to_string(&restored).unwrap(), content);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Running the server in the background, with a PID file.
+
+Init scripts that are plain shell expect a server to detach itself and to
+record its process id.  With the `daemonize` proxy setting (or `serve
+--daemonize`), the proxy detaches on Unix in the traditional way: it forks,
+starts a new session, and forks again, and the grandchild (which can never
+reacquire a terminal) changes to the root directory, so it doesn't hold any
+mounted filesystem busy, and sets a known umask.  It redirects its standard
+input from `/dev/null` and its standard output and error to a file next to
+the log file (`/dev/null` if it doesn't log to a file), since the log file
+itself is renamed when it's rotated.  This happens before the async runtime
+starts any threads, because forking only copies the calling thread.  The
+relative paths in the config file are resolved against the directory the
+server was started in, so they mean the same thing in the background as in
+the foreground.
+
+With the `pid_file` proxy setting, the server writes its process id to that
+file and (on Unix) holds an exclusive lock on it while it runs, so a second
+server using the same PID file refuses to start.  A daemonizing server checks
+the lock before it detaches, so an init script sees the refusal.  The file is
+emptied but never removed when the server stops: a server that removed it
+could leave a second server locking the removed file while a third creates
+and locks a new one.
+ */
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+use eyre::{eyre, Result, WrapErr};
+use log::warn;
+
+#[cfg(unix)]
+use crate::settings::{LogDestination, Settings};
+
+/// A PID file, which holds this process's id for as long as it's alive,
+/// and is emptied when it's dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: String,
+    file: File,
+}
+
+impl PidFile {
+    /// Write this process's id to the file at `path` (if there is one),
+    /// unless another process holds the file.
+    pub fn acquire(path: &str) -> Result<Option<Self>> {
+        if path.is_empty() {
+            return Ok(None);
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .wrap_err(format!("Can't open PID file: {}", path))?;
+        lock(&file, path)?;
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(Some(PidFile { path: path.to_string(), file }))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // the lock is still held, so no other server is using the file
+        if let Err(err) = self.file.set_len(0) {
+            warn!("Can't empty PID file {}: {}", &self.path, err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lock(file: &File, path: &str) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor stays open for as long as `file` does
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let holder = std::fs::read_to_string(path).unwrap_or_default();
+    Err(eyre!("Another proxy (process {}) holds the PID file {}", holder.trim(), path))
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File, _path: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Detach from the terminal and continue in the background, returning the
+/// settings with their relative paths resolved.  This must be called before
+/// any threads are started.
+#[cfg(unix)]
+pub fn daemonize(settings: &Settings) -> Result<Settings> {
+    use std::os::unix::io::AsRawFd;
+    let start_dir =
+        std::env::current_dir().wrap_err("Can't find the current directory")?;
+    let mut resolved = settings.as_ref().clone();
+    resolved.resolve_paths(&start_dir);
+    let settings = Settings::new(resolved);
+    // refuse to start before detaching, so the refusal can be seen
+    drop(PidFile::acquire(&settings.proxy.pid_file)?);
+    let output = match settings.logging.destination {
+        LogDestination::File => format!("{}.out", settings.logging.file_path),
+        _ => "/dev/null".to_string(),
+    };
+    let output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&output)
+        .wrap_err(format!("Can't open {} for the server's output", output))?;
+    let input = File::open("/dev/null")?;
+    let root = std::ffi::CString::new("/")?;
+    // SAFETY: there's only one thread, so it's safe to fork, and the
+    // descriptors stay open until they have been duplicated
+    unsafe {
+        fork_and_exit_parent()?;
+        if libc::setsid() == -1 {
+            return Err(std::io::Error::last_os_error())
+                .wrap_err("Can't start a session");
+        }
+        fork_and_exit_parent()?;
+        if libc::chdir(root.as_ptr()) == -1 {
+            return Err(std::io::Error::last_os_error())
+                .wrap_err("Can't change to the root directory");
+        }
+        // the cache and logs hold license data, so others can't read them
+        libc::umask(0o027);
+        libc::dup2(input.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    Ok(settings)
+}
+
+#[cfg(unix)]
+unsafe fn fork_and_exit_parent() -> Result<()> {
+    match libc::fork() {
+        -1 => Err(std::io::Error::last_os_error()).wrap_err("Can't fork"),
+        0 => Ok(()),
+        _ => libc::_exit(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PidFile;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join("adlu-proxy-test.pid");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        assert!(PidFile::acquire("").unwrap().is_none());
+        let held = PidFile::acquire(path).unwrap().unwrap();
+        let pid = std::fs::read_to_string(path).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+        #[cfg(unix)]
+        {
+            let err = PidFile::acquire(path).unwrap_err().to_string();
+            assert!(err.starts_with("Another proxy"), "{}", err);
+        }
+        drop(held);
+        let pid = std::fs::read_to_string(path).unwrap();
+        assert!(pid.is_empty(), "PID file wasn't emptied");
+        let held = PidFile::acquire(path).unwrap().unwrap();
+        drop(held);
+    }
+}
@@ -0,0 +1,351 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Resolution of the upstream hosts the proxy sends requests to.
+
+In transparent mode, the DNS used by the proxy's host is the one that points
+Adobe's servers (such as `lcs-cops.adobe.io`) at the proxy, so the proxy
+can't use it to find the real servers.  The `[dns]` settings give the proxy
+its own resolver for its upstream client: static host entries, which are
+always used when present, and DNS servers that are asked directly (over UDP)
+for everything else.  Answers from the servers are cached for as long as
+their TTL allows (capped by the settings), so the proxy doesn't ask again
+for every request.
+
+The resolver only asks for address (A and AAAA) records, relying on the
+servers to be recursive and to follow any CNAME chain for it.
+ */
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use eyre::{eyre, Result, WrapErr};
+use hyper::client::connect::dns::Name;
+use log::debug;
+use tokio::net::UdpSocket;
+
+use crate::settings::Dns;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+type ResolveError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The resolver used by the upstream client, if one is configured.
+#[derive(Debug, Clone)]
+pub struct UpstreamResolver(Arc<Resolver>);
+
+#[derive(Debug)]
+struct Resolver {
+    servers: Vec<SocketAddr>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    max_ttl: Duration,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, Answer>>,
+}
+
+#[derive(Debug, Clone)]
+struct Answer {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl UpstreamResolver {
+    /// The resolver configured by the settings, if they configure one.
+    pub fn new(settings: &Dns) -> Result<Option<Self>> {
+        if settings.servers.is_empty() && settings.hosts.is_empty() {
+            return Ok(None);
+        }
+        let mut servers = vec![];
+        for server in settings.servers.iter() {
+            let addr = match server.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, 53),
+                Err(_) => server.parse::<SocketAddr>().map_err(|_| {
+                    eyre!("The DNS server '{}' is not an IP address (and port)", server)
+                })?,
+            };
+            servers.push(addr);
+        }
+        let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for entry in settings.hosts.iter() {
+            let ip: IpAddr = entry.address.parse().map_err(|_| {
+                eyre!(
+                    "The DNS address '{}' for host '{}' is not a numeric IP address",
+                    entry.address,
+                    entry.host
+                )
+            })?;
+            if entry.host.is_empty() {
+                return Err(eyre!("The DNS entry for '{}' has no host", entry.address));
+            }
+            hosts.entry(entry.host.to_ascii_lowercase()).or_default().push(ip);
+        }
+        Ok(Some(UpstreamResolver(Arc::new(Resolver {
+            servers,
+            hosts,
+            max_ttl: Duration::from_secs(settings.max_ttl_secs),
+            timeout: Duration::from_secs(settings.timeout_secs),
+            cache: Mutex::new(HashMap::new()),
+        }))))
+    }
+
+    /// The addresses of a host, from its static entry, the cache, or
+    /// (if neither has it) the DNS servers.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let resolver = &self.0;
+        let host = host.to_ascii_lowercase();
+        if let Some(addrs) = resolver.hosts.get(&host) {
+            return Ok(addrs.clone());
+        }
+        if resolver.servers.is_empty() {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await
+                .wrap_err(format!("Can't resolve host {}", host))?;
+            return Ok(addrs.map(|addr| addr.ip()).collect());
+        }
+        let cached = resolver.cache.lock().unwrap().get(&host).cloned();
+        if let Some(answer) = cached.filter(|answer| answer.expires > Instant::now()) {
+            return Ok(answer.addrs);
+        }
+        let (addrs, ttl) = resolver.query_servers(&host).await?;
+        debug!("Resolved {} to {:?} (TTL {}s)", &host, &addrs, ttl);
+        let ttl = Duration::from_secs(ttl as u64).min(resolver.max_ttl);
+        let answer = Answer { addrs: addrs.clone(), expires: Instant::now() + ttl };
+        resolver.cache.lock().unwrap().insert(host, answer);
+        Ok(addrs)
+    }
+}
+
+impl reqwest::dns::Resolve for UpstreamResolver {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup(name.as_str()).await?;
+            // the connector replaces the port with the one for the request
+            let addrs: reqwest::dns::Addrs =
+                Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok::<_, ResolveError>(addrs)
+        })
+    }
+}
+
+impl Resolver {
+    /// Ask each server in turn for the addresses of a host, preferring
+    /// IPv4 addresses, until one gives an answer.
+    async fn query_servers(&self, host: &str) -> Result<(Vec<IpAddr>, u32)> {
+        let mut last_err = eyre!("No DNS server has an address for {}", host);
+        for server in self.servers.iter() {
+            for qtype in [TYPE_A, TYPE_AAAA] {
+                match self.query(*server, host, qtype).await {
+                    Ok((addrs, ttl)) if !addrs.is_empty() => return Ok((addrs, ttl)),
+                    Ok(_) => {}
+                    Err(err) => {
+                        debug!("DNS query to {} failed: {}", server, err);
+                        last_err = err;
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn query(
+        &self,
+        server: SocketAddr,
+        host: &str,
+        qtype: u16,
+    ) -> Result<(Vec<IpAddr>, u32)> {
+        let id = query_id();
+        let packet = build_query(id, host, qtype)?;
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => ([0u8; 4], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.send_to(&packet, server).await?;
+        let receive = async {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                // ignore stray packets, which can't be the answer
+                if from == server && len >= 2 && buf[..2] == id.to_be_bytes() {
+                    return parse_response(&buf[..len], qtype);
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, receive)
+            .await
+            .map_err(|_| eyre!("The DNS server {} didn't answer in time", server))?
+    }
+}
+
+fn query_id() -> u16 {
+    static COUNTER: AtomicU16 = AtomicU16::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    ((nanos >> 10) as u16).wrapping_add(COUNTER.fetch_add(7919, Ordering::Relaxed))
+}
+
+/// A recursive query for the records of the given type for a host.
+fn build_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(host.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // flags: recursion desired; then one question and no other records
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(eyre!("Can't look up '{}': it's not a valid host name", host));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+/// The addresses of the given type in a response, with the smallest TTL
+/// among them.
+fn parse_response(packet: &[u8], qtype: u16) -> Result<(Vec<IpAddr>, u32)> {
+    let read_u16 = |pos: usize| -> Result<u16> {
+        match packet.get(pos..pos + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+            None => Err(eyre!("The DNS response is truncated")),
+        }
+    };
+    if packet.len() < 12 || packet[2] & 0x80 == 0 {
+        return Err(eyre!("The DNS response is not a response"));
+    }
+    match packet[3] & 0x0f {
+        0 => {}
+        3 => return Err(eyre!("The DNS server says there is no such host")),
+        rcode => return Err(eyre!("The DNS server failed the query (code {})", rcode)),
+    }
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+    let (mut addrs, mut min_ttl) = (vec![], u32::MAX);
+    for _ in 0..answers {
+        pos = skip_name(packet, pos)?;
+        let (rtype, class) = (read_u16(pos)?, read_u16(pos + 2)?);
+        let ttl = ((read_u16(pos + 4)? as u32) << 16) | read_u16(pos + 6)? as u32;
+        let len = read_u16(pos + 8)? as usize;
+        pos += 10;
+        let data = packet
+            .get(pos..pos + len)
+            .ok_or_else(|| eyre!("The DNS response is truncated"))?;
+        pos += len;
+        if rtype != qtype || class != CLASS_IN {
+            // such as the CNAME records leading to the addresses
+            continue;
+        }
+        let addr = match data.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(data).unwrap()),
+            16 => IpAddr::from(<[u8; 16]>::try_from(data).unwrap()),
+            _ => return Err(eyre!("The DNS response has a malformed address")),
+        };
+        addrs.push(addr);
+        min_ttl = min_ttl.min(ttl);
+    }
+    let ttl = if addrs.is_empty() { 0 } else { min_ttl };
+    Ok((addrs, ttl))
+}
+
+/// The position just after a (possibly compressed) name.
+fn skip_name(packet: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len =
+            *packet.get(pos).ok_or_else(|| eyre!("The DNS response is truncated"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::settings::HostAddress;
+
+    /// A DNS server that answers every A query with 10.1.2.3,
+    /// counting the queries it gets.
+    async fn mock_server(queries: Arc<AtomicUsize>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                queries.fetch_add(1, Ordering::SeqCst);
+                let mut reply = buf[..len].to_vec();
+                let is_a = reply[len - 4..len - 2] == TYPE_A.to_be_bytes();
+                reply[2..4].copy_from_slice(&[0x81, 0x80]);
+                reply[6..8].copy_from_slice(&[0, is_a as u8]);
+                if is_a {
+                    // a compressed name pointing at the question, then
+                    // the type, class, TTL (300), and address
+                    reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4]);
+                    reply.extend_from_slice(&[10, 1, 2, 3]);
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_resolver_hosts_and_cache() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let server = mock_server(queries.clone()).await;
+        let settings = Dns {
+            servers: vec![server.to_string()],
+            hosts: vec![HostAddress {
+                host: "Static.Example.Com".to_string(),
+                address: "10.9.9.9".to_string(),
+            }],
+            ..Default::default()
+        };
+        let resolver = UpstreamResolver::new(&settings).unwrap().expect("No resolver");
+        let addrs = resolver.lookup("static.example.com").await.unwrap();
+        assert_eq!(addrs, vec!["10.9.9.9".parse::<IpAddr>().unwrap()]);
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+        for _ in 0..2 {
+            let addrs = resolver.lookup("LCS-COPS.adobe.io").await.unwrap();
+            assert_eq!(addrs, vec!["10.1.2.3".parse::<IpAddr>().unwrap()]);
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert!(build_query(1, "bad..host", TYPE_A).is_err());
+        let bad =
+            Dns { servers: vec!["dns.example.com".to_string()], ..Default::default() };
+        assert!(UpstreamResolver::new(&bad).is_err());
+    }
+}
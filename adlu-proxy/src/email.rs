@@ -0,0 +1,313 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Mailing reports to the people who want them.
+
+Small sites often just want a report in their inbox every week.  A report
+that has been written to a file can be mailed, as an attachment, to the
+recipients in the `[reporting]` section of the config, through the SMTP
+server configured there.  The body of the message summarizes the report
+(what it covers, how many rows it has, and which proxy sent it), so that
+recipients can tell at a glance whether they need to open it.
+
+The conversation with the server is the minimal one: EHLO, an optional
+upgrade to TLS with STARTTLS, optional PLAIN authentication, and a single
+message to all the recipients.
+ */
+use std::time::Duration;
+
+use eyre::{eyre, Result, WrapErr};
+use log::{debug, info};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls;
+
+use adlu_base::{b64encode, Timestamp};
+
+use crate::cli::Datasource;
+use crate::proxy::proxy_id;
+use crate::settings::{Reporting, SmtpSecurity};
+
+/// How long to wait for the server at each step of the conversation.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The boundary between the parts of a message.  It can't appear in
+/// the (base64-encoded) attachment or in the summary.
+const BOUNDARY: &str = "adlu-proxy-report-boundary";
+
+/// Mail the report in the file at `path` to the configured recipients.
+pub async fn send_report(
+    conf: &Reporting,
+    source: &Datasource,
+    path: &str,
+) -> Result<()> {
+    if conf.smtp_host.is_empty() || conf.email_to.is_empty() {
+        return Err(eyre!("Emailing reports needs an SMTP host and recipients"));
+    }
+    let data = std::fs::read(path).wrap_err(format!("Can't read report: {}", path))?;
+    let message = compose(conf, source, path, &data);
+    let port: u16 = conf.smtp_port.parse().wrap_err("Invalid SMTP port")?;
+    deliver(conf, port, &message).await?;
+    info!("Mailed {} report to {}", source, conf.email_to.join(", "));
+    eprintln!("Mailed {} report to {}", source, conf.email_to.join(", "));
+    Ok(())
+}
+
+/// The message that carries a report: a summary, and the report attached.
+fn compose(conf: &Reporting, source: &Datasource, path: &str, data: &[u8]) -> String {
+    let name = std::path::Path::new(path)
+        .file_name()
+        .map_or_else(|| "report.csv".to_string(), |n| n.to_string_lossy().to_string());
+    let (content_type, rows) = if name.ends_with(".gz") {
+        ("application/gzip", None)
+    } else if name.ends_with(".zst") {
+        ("application/zstd", None)
+    } else {
+        // every line but the header is a row
+        let lines = data.iter().filter(|b| **b == b'\n').count();
+        ("text/csv", Some(lines.saturating_sub(1)))
+    };
+    let now = Timestamp::now();
+    let mut summary = format!(
+        "The attached {} report ({}) was generated by {} at {}.\r\n",
+        source,
+        &name,
+        proxy_id(),
+        now.format_iso_8601(true)
+    );
+    match rows {
+        Some(rows) => summary.push_str(&format!("It has {} row(s).\r\n", rows)),
+        None => summary.push_str(&format!("It is {} bytes compressed.\r\n", data.len())),
+    }
+    let attachment: Vec<String> = b64encode(data)
+        .as_bytes()
+        .chunks(76)
+        .map(|line| String::from_utf8_lossy(line).to_string())
+        .collect();
+    let date = chrono::Utc::now().to_rfc2822();
+    [
+        format!("From: {}", &conf.email_from),
+        format!("To: {}", conf.email_to.join(", ")),
+        format!("Subject: {} report from {}", source, proxy_id()),
+        format!("Date: {}", date),
+        "MIME-Version: 1.0".to_string(),
+        format!("Content-Type: multipart/mixed; boundary=\"{}\"", BOUNDARY),
+        String::new(),
+        format!("--{}", BOUNDARY),
+        "Content-Type: text/plain; charset=utf-8".to_string(),
+        String::new(),
+        summary,
+        format!("--{}", BOUNDARY),
+        format!("Content-Type: {}; name=\"{}\"", content_type, &name),
+        format!("Content-Disposition: attachment; filename=\"{}\"", &name),
+        "Content-Transfer-Encoding: base64".to_string(),
+        String::new(),
+        attachment.join("\r\n"),
+        format!("--{}--", BOUNDARY),
+        String::new(),
+    ]
+    .join("\r\n")
+}
+
+async fn deliver(conf: &Reporting, port: u16, message: &str) -> Result<()> {
+    let host = conf.smtp_host.as_str();
+    let tcp = tokio::time::timeout(SMTP_TIMEOUT, TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| eyre!("Timed out connecting to SMTP server {}:{}", host, port))?
+        .wrap_err(format!("Can't connect to SMTP server {}:{}", host, port))?;
+    let connector = || -> Result<tokio_native_tls::TlsConnector> {
+        Ok(native_tls::TlsConnector::new()?.into())
+    };
+    match conf.smtp_security {
+        SmtpSecurity::None => {
+            let mut stream = BufStream::new(tcp);
+            greet(&mut stream).await?;
+            converse(&mut stream, conf, message).await
+        }
+        SmtpSecurity::Tls => {
+            let tls =
+                connector()?.connect(host, tcp).await.wrap_err("TLS handshake failed")?;
+            let mut stream = BufStream::new(tls);
+            greet(&mut stream).await?;
+            converse(&mut stream, conf, message).await
+        }
+        SmtpSecurity::StartTls => {
+            let mut stream = BufStream::new(tcp);
+            greet(&mut stream).await?;
+            command(&mut stream, "STARTTLS", &[220]).await?;
+            let tcp = stream.into_inner();
+            let tls =
+                connector()?.connect(host, tcp).await.wrap_err("TLS handshake failed")?;
+            let mut stream = BufStream::new(tls);
+            command(&mut stream, &format!("EHLO {}", hostname()), &[250]).await?;
+            converse(&mut stream, conf, message).await
+        }
+    }
+}
+
+/// Wait for the server's greeting, and introduce ourselves.
+async fn greet<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+) -> Result<()> {
+    reply(stream, &[220]).await?;
+    command(stream, &format!("EHLO {}", hostname()), &[250]).await?;
+    Ok(())
+}
+
+/// Authenticate (if configured) and send the message.
+async fn converse<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    conf: &Reporting,
+    message: &str,
+) -> Result<()> {
+    if !conf.smtp_username.is_empty() {
+        let credentials = format!("\0{}\0{}", &conf.smtp_username, &conf.smtp_password);
+        let auth = format!("AUTH PLAIN {}", b64encode(credentials.as_bytes()));
+        command(stream, &auth, &[235]).await.wrap_err("SMTP authentication failed")?;
+    }
+    command(stream, &format!("MAIL FROM:<{}>", &conf.email_from), &[250]).await?;
+    for recipient in conf.email_to.iter() {
+        command(stream, &format!("RCPT TO:<{}>", recipient), &[250, 251]).await?;
+    }
+    command(stream, "DATA", &[354]).await?;
+    // lines starting with a dot have it doubled, so they don't end the data
+    let data = message.replace("\r\n.", "\r\n..");
+    stream.write_all(data.as_bytes()).await?;
+    command(stream, ".", &[250]).await.wrap_err("The SMTP server refused the report")?;
+    command(stream, "QUIT", &[221]).await.ok();
+    Ok(())
+}
+
+/// Send a command line and check the server's reply.
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    line: &str,
+    expected: &[u16],
+) -> Result<String> {
+    if line.starts_with("AUTH") {
+        debug!("SMTP > AUTH [OBSCURED]");
+    } else {
+        debug!("SMTP > {}", line);
+    }
+    stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    stream.flush().await?;
+    reply(stream, expected).await
+}
+
+/// Read a (possibly multi-line) reply, and check that its code is expected.
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    expected: &[u16],
+) -> Result<String> {
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        let read = tokio::time::timeout(SMTP_TIMEOUT, stream.read_line(&mut line))
+            .await
+            .map_err(|_| eyre!("Timed out waiting for the SMTP server"))??;
+        if read == 0 {
+            return Err(eyre!("The SMTP server closed the connection"));
+        }
+        debug!("SMTP < {}", line.trim_end());
+        text.push_str(&line);
+        // the last line of a reply has a space (not a dash) after the code
+        if line.len() < 4 || line.as_bytes()[3] != b'-' {
+            break;
+        }
+    }
+    let code: u16 = text.get(..3).and_then(|c| c.parse().ok()).unwrap_or_default();
+    if expected.contains(&code) {
+        Ok(text)
+    } else {
+        Err(eyre!("Unexpected SMTP reply: {}", text.trim_end()))
+    }
+}
+
+fn hostname() -> String {
+    sys_info::hostname().unwrap_or_else(|_| "localhost".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    use crate::cli::Datasource;
+    use crate::settings::{Reporting, SmtpSecurity};
+
+    #[tokio::test]
+    async fn test_send_report() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // a server that accepts anything, and returns the data it was sent
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = socket.into_split();
+            let mut reader = BufReader::new(reader);
+            writer.write_all(b"220 test ready\r\n").await.unwrap();
+            let (mut data, mut in_data) = (String::new(), false);
+            let mut line = String::new();
+            while reader.read_line(&mut line).await.unwrap() > 0 {
+                let reply: &[u8] = if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        data.push_str(&line);
+                        b""
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-test\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+                line.clear();
+            }
+            data
+        });
+        let path = std::env::temp_dir().join("adlu-proxy-email-test.csv");
+        std::fs::write(&path, "Name,Count\n.hidden,1\nother,2\n").unwrap();
+        let conf = Reporting {
+            smtp_host: "127.0.0.1".to_string(),
+            smtp_port: port.to_string(),
+            smtp_security: SmtpSecurity::None,
+            smtp_username: "user".to_string(),
+            smtp_password: "secret".to_string(),
+            email_from: "proxy@example.com".to_string(),
+            email_to: vec!["it@example.com".to_string()],
+            ..Default::default()
+        };
+        super::send_report(&conf, &Datasource::Usage, path.to_str().unwrap())
+            .await
+            .unwrap();
+        let data = server.await.unwrap();
+        assert!(data.contains("To: it@example.com"));
+        assert!(data.contains("It has 2 row(s)."));
+        assert!(data.contains("filename=\"adlu-proxy-email-test.csv\""));
+        std::fs::remove_file(&path).ok();
+    }
+}
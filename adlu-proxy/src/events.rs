@@ -0,0 +1,205 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+A live stream of the requests the proxy processes, for dashboards.
+
+Each request that the proxy handles is published as an event: its type, the
+app that sent it, a hash of the device it came from, and how it was handled.
+Subscribers to the `/events` endpoint receive these events as Server-Sent
+Events, starting with the most recent history (kept in a ring buffer) so that
+a dashboard that connects late doesn't start out empty.
+
+Device ids are hashed before they are published, so the stream can be shown
+to people who shouldn't see the ids themselves.
+ */
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use futures_util::Stream;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+
+use adlu_parse::protocol::{
+    FrlActivationRequestBody, FrlDeactivationQueryParams, NulLicenseRequestBody, Request,
+    RequestType,
+};
+
+/// An event describing how a request was handled.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub timestamp: String,
+    pub correlation_id: String,
+    pub request_type: String,
+    pub app_id: String,
+    pub app_version: String,
+    pub device_hash: String,
+    pub outcome: String,
+    /// Not part of the `/events` stream, but included when events are mirrored.
+    #[serde(skip)]
+    pub source_addr: String,
+}
+
+impl Event {
+    pub fn new(req: &Request, outcome: &str) -> Self {
+        let (app_id, app_version, device_id) = request_details(req);
+        Event {
+            timestamp: req.timestamp.format_rfc_3339(true),
+            correlation_id: req.correlation_id.clone(),
+            request_type: req.request_type.to_string(),
+            app_id,
+            app_version,
+            device_hash: if device_id.is_empty() {
+                device_id
+            } else {
+                device_hash(&device_id)
+            },
+            outcome: outcome.to_string(),
+            source_addr: req.source_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Publishes events to subscribers, remembering the most recent ones.
+#[derive(Debug)]
+pub struct EventHub {
+    sender: broadcast::Sender<Event>,
+    history: Mutex<VecDeque<Event>>,
+    capacity: usize,
+}
+
+impl EventHub {
+    /// A hub that remembers `capacity` events, and that lets subscribers fall
+    /// up to `backlog` events behind before they start to miss some.
+    pub fn new(capacity: usize, backlog: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(backlog).max(16));
+        EventHub {
+            sender,
+            history: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut history = self.history.lock().unwrap();
+        if self.capacity > 0 {
+            if history.len() == self.capacity {
+                history.pop_front();
+            }
+            history.push_back(event.clone());
+        }
+        // it's not an error for there to be no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// The recent history, and a receiver for the events that follow it.
+    /// These are taken together so no event is either missed or repeated.
+    pub fn subscribe(&self) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        let history = self.history.lock().unwrap();
+        (history.iter().cloned().collect(), self.sender.subscribe())
+    }
+}
+
+impl EventHub {
+    /// A stream of the recent history followed by live events.  A subscriber
+    /// that falls too far behind skips the events it missed.
+    pub fn stream(&self) -> impl Stream<Item = Event> {
+        let (history, receiver) = self.subscribe();
+        let live = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        futures_util::StreamExt::chain(futures_util::stream::iter(history), live)
+    }
+}
+
+/// A short, stable hash of a device id.
+fn device_hash(device_id: &str) -> String {
+    let digest = Sha256::digest(device_id.as_bytes());
+    hex::encode(&digest[..8])
+}
+
+/// The app id, app version, and device id of a request, where it has them.
+pub(crate) fn request_details(req: &Request) -> (String, String, String) {
+    let body = req.body.as_deref().unwrap_or_default();
+    match req.request_type {
+        RequestType::FrlActivation => match FrlActivationRequestBody::from_body(body) {
+            Ok(parse) => (
+                parse.app_details.ngl_app_id,
+                parse.app_details.ngl_app_version,
+                parse.device_details.device_id,
+            ),
+            Err(_) => Default::default(),
+        },
+        RequestType::FrlDeactivation => {
+            let query = req.query.as_deref().unwrap_or_default();
+            match FrlDeactivationQueryParams::from_query(query) {
+                Ok(parse) => (String::new(), String::new(), parse.device_id),
+                Err(_) => Default::default(),
+            }
+        }
+        RequestType::NulLicense => match NulLicenseRequestBody::from_body(body) {
+            Ok(parse) => (
+                parse.app_details.ngl_app_id,
+                parse.app_details.ngl_app_version,
+                parse.device_details.device_id,
+            ),
+            Err(_) => Default::default(),
+        },
+        RequestType::LogUpload | RequestType::Unknown => Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{device_hash, Event, EventHub};
+
+    fn event(outcome: &str) -> Event {
+        Event {
+            timestamp: "".to_string(),
+            correlation_id: "".to_string(),
+            request_type: "FRL Activation".to_string(),
+            app_id: "MockApp1".to_string(),
+            app_version: "10.1.3".to_string(),
+            device_hash: device_hash("device"),
+            outcome: outcome.to_string(),
+            source_addr: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_history_and_subscription() {
+        let hub = EventHub::new(2, 0);
+        hub.publish(event("first"));
+        hub.publish(event("second"));
+        hub.publish(event("third"));
+        let (history, mut receiver) = hub.subscribe();
+        let outcomes: Vec<&str> = history.iter().map(|e| e.outcome.as_str()).collect();
+        assert_eq!(outcomes, vec!["second", "third"]);
+        hub.publish(event("fourth"));
+        assert_eq!(receiver.try_recv().unwrap().outcome, "fourth");
+        assert_eq!(event("any").device_hash.len(), 16);
+    }
+}
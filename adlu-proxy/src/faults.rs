@@ -0,0 +1,223 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Injected upstream failures, for rehearsing outages in staging.
+
+Operators need to see what clients experience, what gets stored for later
+forwarding, and which alerts fire when Adobe can't be reached.  Rather than
+pulling cables, a staging proxy can be told to fail some percentage of the
+requests it sends upstream, in one of the ways that the integration tests
+use to mock Adobe failures: the server is unreachable, it returns an error
+status, it throttles the proxy, or its response can't be parsed.  Failures are
+spread evenly over the requests (rather than chosen at random) so that a
+rehearsal can be repeated exactly.
+
+Injection is started by the `faults` settings, or through the `/faults`
+endpoint: a POST (with `kind`, `percent`, and `secs` query parameters, all
+optional) starts it, a DELETE stops it, and a GET describes it.
+ */
+use std::sync::Mutex;
+
+use eyre::{eyre, Result, WrapErr};
+use log::info;
+use serde::Serialize;
+
+use adlu_base::Timestamp;
+
+pub use crate::settings::FaultKind;
+use crate::settings::Faults;
+
+impl std::fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaultKind::Unreachable => "unreachable".fmt(f),
+            FaultKind::ErrorStatus => "error-status".fmt(f),
+            FaultKind::Throttled => "throttled".fmt(f),
+            FaultKind::ParseFailure => "parse-failure".fmt(f),
+        }
+    }
+}
+
+impl TryFrom<&str> for FaultKind {
+    type Error = String;
+
+    fn try_from(s: &str) -> std::result::Result<Self, Self::Error> {
+        match s {
+            "unreachable" => Ok(FaultKind::Unreachable),
+            "error-status" => Ok(FaultKind::ErrorStatus),
+            "throttled" => Ok(FaultKind::Throttled),
+            "parse-failure" => Ok(FaultKind::ParseFailure),
+            _ => Err(format!("Not a fault kind: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Injection {
+    kind: FaultKind,
+    percent: u64,
+    until_millis: Option<i64>,
+    requests: u64,
+    failures: u64,
+}
+
+/// What is being injected, as reported by the `/faults` endpoint.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FaultStatus {
+    pub active: bool,
+    pub kind: Option<String>,
+    pub percent: Option<u64>,
+    pub remaining_secs: Option<u64>,
+    pub requests: u64,
+    pub failures: u64,
+}
+
+/// The faults (if any) being injected into upstream requests.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    injection: Mutex<Option<Injection>>,
+}
+
+impl FaultInjector {
+    pub fn new(settings: &Faults) -> Self {
+        let injector = FaultInjector::default();
+        if settings.enabled {
+            injector.start(settings.kind, settings.percent, settings.duration_secs);
+        }
+        injector
+    }
+
+    /// Fail `percent` of upstream requests in the given way, for `secs`
+    /// seconds (or until stopped, if that's 0).
+    pub fn start(&self, kind: FaultKind, percent: u8, secs: u64) {
+        let until_millis = match secs {
+            0 => None,
+            secs => Some(Timestamp::now().to_millis() + (secs as i64) * 1000),
+        };
+        let percent = (percent as u64).min(100);
+        info!("Injecting {} faults into {}% of upstream requests", kind, percent);
+        let injection =
+            Injection { kind, percent, until_millis, requests: 0, failures: 0 };
+        *self.injection.lock().unwrap() = Some(injection);
+    }
+
+    /// Stop injecting faults, returning whether any were being injected.
+    pub fn stop(&self) -> bool {
+        let stopped = self.injection.lock().unwrap().take().is_some();
+        if stopped {
+            info!("Stopped injecting faults into upstream requests");
+        }
+        stopped
+    }
+
+    /// The fault (if any) to inject into the next upstream request.
+    pub fn next_fault(&self) -> Option<FaultKind> {
+        let mut guard = self.injection.lock().unwrap();
+        let injection = guard.as_mut()?;
+        let now = Timestamp::now().to_millis();
+        if matches!(injection.until_millis, Some(until) if until <= now) {
+            info!("Fault injection has run its course");
+            *guard = None;
+            return None;
+        }
+        injection.requests += 1;
+        // the n-th request fails if fewer than n * percent / 100 have failed
+        if injection.requests * injection.percent / 100 > injection.failures {
+            injection.failures += 1;
+            Some(injection.kind)
+        } else {
+            None
+        }
+    }
+
+    pub fn status(&self) -> FaultStatus {
+        let guard = self.injection.lock().unwrap();
+        match guard.as_ref() {
+            None => FaultStatus::default(),
+            Some(injection) => FaultStatus {
+                active: true,
+                kind: Some(injection.kind.to_string()),
+                percent: Some(injection.percent),
+                remaining_secs: injection.until_millis.map(|until| {
+                    ((until - Timestamp::now().to_millis()).max(0) as u64).div_ceil(1000)
+                }),
+                requests: injection.requests,
+                failures: injection.failures,
+            },
+        }
+    }
+}
+
+/// What an upstream request gets when it fails in the given way.  An
+/// unreachable server is a network error; the others are responses.
+pub fn response(kind: FaultKind, req: &reqwest::Request) -> Result<reqwest::Response> {
+    let (status, body, echoed): (u16, &str, &[&str]) = match kind {
+        FaultKind::Unreachable => {
+            return Err(eyre!("NetworkError - server not reachable (injected fault)"))
+        }
+        FaultKind::ErrorStatus => (
+            400,
+            r#"{"error": "Error response requested"}"#,
+            &["X-Request-Id", "X-Request-Name"],
+        ),
+        FaultKind::Throttled => {
+            (429, r#"{"error": "Too many requests"}"#, &["X-Request-Id"])
+        }
+        FaultKind::ParseFailure => {
+            (200, r#"{"invalid key": "invalid body"}"#, &["X-Request-Id"])
+        }
+    };
+    let mut builder = http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json;encoding=utf-8");
+    if let FaultKind::Throttled = kind {
+        builder = builder.header("Retry-After", "30");
+    }
+    for header_name in echoed {
+        if let Some(val) = req.headers().get(*header_name) {
+            builder = builder.header(*header_name, val);
+        }
+    }
+    let resp = builder.body(body.as_bytes()).wrap_err("Can't build fault response")?;
+    Ok(resp.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultInjector, FaultKind};
+
+    #[test]
+    fn test_fault_injector() {
+        let injector = FaultInjector::default();
+        assert_eq!(injector.next_fault(), None);
+        injector.start(FaultKind::ErrorStatus, 25, 0);
+        let faults: Vec<Option<FaultKind>> =
+            (0..8).map(|_| injector.next_fault()).collect();
+        assert_eq!(faults.iter().filter(|f| f.is_some()).count(), 2);
+        assert_eq!(faults[3], Some(FaultKind::ErrorStatus));
+        let status = injector.status();
+        assert!(status.active);
+        assert_eq!((status.requests, status.failures), (8, 2));
+        assert!(injector.stop());
+        assert!(!injector.status().active);
+        assert_eq!(injector.next_fault(), None);
+        assert_eq!(FaultKind::try_from("parse-failure"), Ok(FaultKind::ParseFailure));
+    }
+}
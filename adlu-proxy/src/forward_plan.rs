@@ -0,0 +1,315 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+What forwarding the stored requests would do, without doing it.
+
+Before replaying a large backlog of stored requests, admins want to see what
+would be sent and where.  A forward plan lists the un-answered requests in
+the order they would be forwarded, grouped by their type and the upstream
+endpoint they would go to, and points out the requests that are obviously
+stale:
+
+- an activation that's followed by a later request for the same license
+  (that is, the same package and device or VDI user), which makes it moot;
+- a deactivation that's followed by a later deactivation of the same license;
+- a request that's older than the staleness limit, which Adobe may no
+  longer honor; and
+- a request whose body or query can't be parsed, which Adobe will reject.
+
+Stale requests are still forwarded (so their outcomes are recorded), but
+admins who don't want that can clear them before forwarding.
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+
+use eyre::{eyre, Result};
+
+use adlu_base::Timestamp;
+use adlu_parse::protocol::{
+    FrlActivationRequestBody, FrlDeactivationQueryParams, Request, RequestType,
+};
+
+use crate::cache::Cache;
+use crate::proxy::{self, Config};
+use crate::settings::{ProxyMode, Settings};
+
+/// A stored request, as it would be forwarded.
+#[derive(Debug, Clone)]
+pub struct PlannedRequest {
+    pub kind: String,
+    pub destination: String,
+    pub timestamp: Timestamp,
+    pub package_id: String,
+    pub device_id: String,
+    pub correlation_id: String,
+    /// Why the request is stale, if it is.
+    pub stale: Option<String>,
+}
+
+/// The stored requests that forwarding would send, in the order it
+/// would send them.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardPlan {
+    pub requests: Vec<PlannedRequest>,
+    /// The reason nothing would be sent, if the proxy wouldn't forward.
+    pub blocked: Option<String>,
+}
+
+impl ForwardPlan {
+    /// Plan the forwarding of requests (which must be in the order they were
+    /// received), marking those received more than `stale_days` before `now`
+    /// as stale.
+    pub fn new(
+        conf: &Config,
+        reqs: &[Request],
+        stale_days: u32,
+        now: &Timestamp,
+    ) -> Self {
+        let cutoff = now.to_millis() - stale_days as i64 * 24 * 3600 * 1000;
+        let mut requests: Vec<PlannedRequest> = Vec::with_capacity(reqs.len());
+        // the latest planned request for each license, by its index in the plan
+        let mut latest: HashMap<String, usize> = HashMap::new();
+        for req in reqs.iter() {
+            let destination = proxy::upstream_server(conf, req)
+                .map_or_else(|_| "(none)".to_string(), |s| format!("{}/{}", s, req.path));
+            let mut planned = PlannedRequest {
+                kind: kind_of(req).to_string(),
+                destination,
+                timestamp: req.timestamp.clone(),
+                package_id: String::new(),
+                device_id: String::new(),
+                correlation_id: req.correlation_id.clone(),
+                stale: None,
+            };
+            let license = match license_of(req) {
+                Ok((package_id, device_id, license)) => {
+                    planned.package_id = package_id;
+                    planned.device_id = device_id;
+                    Some(license)
+                }
+                Err(err) => {
+                    planned.stale = Some(format!("can't be parsed: {}", err));
+                    None
+                }
+            };
+            if let Some(license) = license {
+                let is_deactivation = planned.kind == DEACTIVATION;
+                if let Some(prior) = latest.get(&license).map(|&i| &mut requests[i]) {
+                    // a deactivation still matters if an activation follows it
+                    let moot = prior.kind != DEACTIVATION || is_deactivation;
+                    if moot && prior.stale.is_none() {
+                        let when = planned.timestamp.format_iso_8601(false);
+                        prior.stale =
+                            Some(format!("superseded by a request at {}", when));
+                    }
+                }
+                latest.insert(license, requests.len());
+            }
+            if planned.stale.is_none() && planned.timestamp.to_millis() < cutoff {
+                planned.stale = Some(format!("more than {} day(s) old", stale_days));
+            }
+            requests.push(planned);
+        }
+        let blocked = match conf.settings.proxy.mode {
+            ProxyMode::Isolated => Some("the proxy is in isolated mode".to_string()),
+            ProxyMode::Simulate => Some("the proxy is in simulate mode".to_string()),
+            _ => None,
+        };
+        ForwardPlan { requests, blocked }
+    }
+
+    pub fn stale_count(&self) -> usize {
+        self.requests.iter().filter(|r| r.stale.is_some()).count()
+    }
+
+    /// Write the planned requests as CSV.
+    pub fn write_csv(&self, path: &str) -> Result<()> {
+        let mut writer = csv::WriterBuilder::new().from_path(path)?;
+        writer.write_record([
+            "Request Type",
+            "Timestamp (UTC)",
+            "Destination",
+            "Package ID",
+            "Device ID",
+            "Correlation ID",
+            "Stale",
+        ])?;
+        for req in self.requests.iter() {
+            writer.write_record([
+                req.kind.clone(),
+                req.timestamp.format_iso_8601(false),
+                req.destination.clone(),
+                req.package_id.clone(),
+                req.device_id.clone(),
+                req.correlation_id.clone(),
+                req.stale.clone().unwrap_or_default(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Display for ForwardPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.requests.is_empty() {
+            return writeln!(f, "No requests to forward.");
+        }
+        writeln!(f, "Would forward {} request(s):", self.requests.len())?;
+        // (count, stale count, oldest, newest) for each type and destination
+        let mut groups: BTreeMap<(&str, &str), (usize, usize, i64, i64)> =
+            BTreeMap::new();
+        for req in self.requests.iter() {
+            let millis = req.timestamp.to_millis();
+            let group = groups
+                .entry((req.kind.as_str(), req.destination.as_str()))
+                .or_insert((0, 0, millis, millis));
+            group.0 += 1;
+            group.1 += req.stale.is_some() as usize;
+            group.2 = group.2.min(millis);
+            group.3 = group.3.max(millis);
+        }
+        for ((kind, destination), (count, stale, oldest, newest)) in groups {
+            let oldest = Timestamp::from_millis(oldest).format_iso_8601(false);
+            let newest = Timestamp::from_millis(newest).format_iso_8601(false);
+            writeln!(f, "    {} {}(s) to {}", count, kind, destination)?;
+            writeln!(
+                f,
+                "        received {} to {} (UTC), {} stale",
+                oldest, newest, stale
+            )?;
+        }
+        let stale: Vec<&PlannedRequest> =
+            self.requests.iter().filter(|r| r.stale.is_some()).collect();
+        if !stale.is_empty() {
+            writeln!(f, "Stale request(s):")?;
+            for req in stale {
+                writeln!(
+                    f,
+                    "    {} {} at {} (correlation id {}): {}",
+                    req.kind,
+                    if req.device_id.is_empty() {
+                        "request"
+                    } else {
+                        req.device_id.as_str()
+                    },
+                    req.timestamp.format_iso_8601(false),
+                    req.correlation_id,
+                    req.stale.as_deref().unwrap_or_default()
+                )?;
+            }
+        }
+        if let Some(reason) = &self.blocked {
+            writeln!(f, "Nothing would actually be sent, because {}.", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Show what forwarding the stored requests would do, and optionally
+/// save the list of requests to a CSV file.
+pub async fn dry_run(
+    settings: &Settings,
+    cache: &Cache,
+    stale_days: u32,
+    to_path: Option<&str>,
+) -> Result<()> {
+    let conf = Config::new(settings.clone(), cache.clone())?;
+    let reqs = cache.fetch_unanswered_requests().await?;
+    let plan = ForwardPlan::new(&conf, &reqs, stale_days, &Timestamp::now());
+    eprint!("{}", plan);
+    if let Some(path) = to_path {
+        plan.write_csv(path)?;
+        eprintln!("Saved the list of request(s) to {}", path);
+    }
+    Ok(())
+}
+
+const ACTIVATION: &str = "activation";
+const DEACTIVATION: &str = "deactivation";
+
+fn kind_of(req: &Request) -> &'static str {
+    match req.request_type {
+        RequestType::FrlActivation => ACTIVATION,
+        RequestType::FrlDeactivation => DEACTIVATION,
+        RequestType::NulLicense => "license",
+        RequestType::LogUpload => "log upload",
+        RequestType::Unknown => "unknown request",
+    }
+}
+
+/// The package, device, and license key of an FRL request.  The license key
+/// identifies the license that the request acquires or returns.
+fn license_of(req: &Request) -> Result<(String, String, String)> {
+    match req.request_type {
+        RequestType::FrlActivation => {
+            let body = req.body.as_deref().ok_or_else(|| eyre!("no body"))?;
+            let parse = FrlActivationRequestBody::from_body(body)?;
+            let license = parse.deactivation_id();
+            Ok((parse.npd_id, parse.device_details.device_id, license))
+        }
+        RequestType::FrlDeactivation => {
+            let query = req.query.as_deref().ok_or_else(|| eyre!("no query"))?;
+            let parse = FrlDeactivationQueryParams::from_query(query)?;
+            let license = parse.deactivation_id();
+            Ok((parse.npd_id, parse.device_id, license))
+        }
+        _ => Err(eyre!("not an FRL request")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use adlu_base::Timestamp;
+
+    use crate::settings::ProxyMode;
+    use crate::testing::*;
+
+    use super::ForwardPlan;
+
+    #[tokio::test]
+    async fn test_forward_plan() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let day = 24 * 3600 * 1000;
+        let now = Timestamp::now();
+        let at = |days_ago: i64, mut req: adlu_parse::protocol::Request| {
+            req.timestamp = Timestamp::from_millis(now.to_millis() - days_ago * day);
+            req
+        };
+        let act = adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id;
+        let deact = adlu_parse::protocol::FrlDeactivationQueryParams::mock_from_device_id;
+        let reqs = vec![
+            at(40, frl::mock_cache_activation_request(&act("plan-d1"))),
+            at(3, frl::mock_cache_activation_request(&act("plan-d2"))),
+            at(2, frl::mock_cache_deactivation_request(&deact("plan-d2"))),
+            at(1, frl::mock_cache_deactivation_request(&deact("plan-d3"))),
+        ];
+        let plan = ForwardPlan::new(&conf, &reqs, 30, &now);
+        assert_eq!(plan.requests.len(), 4);
+        assert!(plan.requests[0].stale.as_ref().unwrap().contains("30 day(s)"));
+        assert!(plan.requests[1].stale.as_ref().unwrap().contains("superseded"));
+        assert!(plan.requests[2].stale.is_none());
+        assert!(plan.requests[3].stale.is_none());
+        assert!(plan.requests[1].destination.ends_with(&reqs[1].path));
+        let listing = plan.to_string();
+        assert!(listing.contains("Would forward 4 request(s)"), "{}", listing);
+        assert!(listing.contains("2 activation(s) to"), "{}", listing);
+        release_test_config(conf).await;
+    }
+}
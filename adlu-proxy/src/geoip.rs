@@ -0,0 +1,376 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Optional lookup of where clients are, based on their address.
+
+When a MaxMind database (e.g., GeoLite2-City) is configured, each client address
+is looked up in it for a country and city.  Independently, each address is
+matched against the configured subnets of each campus.  The labels found are
+stored with the client's request, so reports can show where traffic came from.
+
+MaxMind databases are read directly: the format is a binary search tree over the
+bits of the address, whose leaves point into a section of self-describing data.
+See <https://maxmind.github.io/MaxMind-DB/> for the specification.
+ */
+use std::net::IpAddr;
+
+use eyre::{eyre, Result, WrapErr};
+use ipnet::IpNet;
+use log::error;
+use serde_json::{Map, Number, Value};
+
+use crate::settings::Settings;
+
+/// The labels found for a client address.  Labels that weren't found are empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub country: String,
+    pub city: String,
+    pub campus: String,
+}
+
+#[derive(Debug, Default)]
+pub struct GeoIp {
+    db: Option<MaxMindDb>,
+    campuses: Vec<(String, IpNet)>,
+}
+
+impl GeoIp {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let geoip = &settings.geoip;
+        let db = if geoip.db_path.is_empty() {
+            None
+        } else {
+            let db = MaxMindDb::from_path(&geoip.db_path)
+                .wrap_err(format!("Can't read GeoIP database: {}", &geoip.db_path))?;
+            Some(db)
+        };
+        let mut campuses = vec![];
+        for campus in geoip.campuses.iter() {
+            for subnet in campus.subnets.iter() {
+                let net: IpNet = subnet.parse().wrap_err(format!(
+                    "Invalid subnet for campus {}: {}",
+                    &campus.name, subnet
+                ))?;
+                campuses.push((campus.name.clone(), net));
+            }
+        }
+        Ok(GeoIp { db, campuses })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.db.is_some() || !self.campuses.is_empty()
+    }
+
+    /// Find the labels for an address, if there are any.
+    pub fn locate(&self, ip: IpAddr) -> Option<Location> {
+        let mut location = Location::default();
+        if let Some(db) = self.db.as_ref() {
+            match db.lookup(ip) {
+                Ok(Some(record)) => {
+                    location.country = place_name(&record["country"]);
+                    location.city = place_name(&record["city"]);
+                }
+                Ok(None) => {}
+                Err(err) => error!("GeoIP lookup of {} failed: {}", ip, err),
+            }
+        }
+        // IPv4 clients of a dual-stack listener show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        if let Some((name, _)) = self.campuses.iter().find(|(_, net)| net.contains(&ip)) {
+            location.campus = name.clone();
+        }
+        if location == Location::default() {
+            None
+        } else {
+            Some(location)
+        }
+    }
+}
+
+/// The English name of a place record, or its ISO code if it has no name.
+fn place_name(place: &Value) -> String {
+    place["names"]["en"]
+        .as_str()
+        .or_else(|| place["iso_code"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The marker that precedes the metadata at the end of a MaxMind database.
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// The size of the zero-filled separator between the tree and the data.
+const DATA_SEPARATOR_SIZE: usize = 16;
+
+#[derive(Debug)]
+struct MaxMindDb {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    data_start: usize,
+}
+
+impl MaxMindDb {
+    fn from_path(path: &str) -> Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let marker = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| eyre!("Not a MaxMind database"))?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = decode(&bytes[metadata_start..], 0)?;
+        let field = |name: &str| {
+            metadata[name].as_u64().ok_or_else(|| eyre!("Metadata has no {}", name))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(eyre!("Unsupported record size: {}", record_size));
+        }
+        let data_start = node_count * record_size / 4 + DATA_SEPARATOR_SIZE;
+        if data_start > marker {
+            return Err(eyre!("Search tree is larger than the database"));
+        }
+        Ok(MaxMindDb { bytes, node_count, record_size, ip_version, data_start })
+    }
+
+    /// The data record for an address, if the database has one.
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>> {
+        let (bits, bit_count): (u128, usize) = match ip {
+            IpAddr::V4(v4) if self.ip_version == 6 => (u32::from(v4) as u128, 128),
+            IpAddr::V4(v4) => ((u32::from(v4) as u128) << 96, 32),
+            IpAddr::V6(v6) if self.ip_version == 6 => (u128::from(v6), 128),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => ((u32::from(v4) as u128) << 96, 32),
+                None => return Ok(None),
+            },
+        };
+        let mut node = 0;
+        for i in 0..bit_count {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits >> (127 - i)) & 1 == 1;
+            node = self.record(node, bit)?;
+        }
+        if node <= self.node_count {
+            return Ok(None);
+        }
+        let offset = node - self.node_count - DATA_SEPARATOR_SIZE;
+        let data = &self.bytes[self.data_start..];
+        let (value, _) = decode(data, offset)?;
+        Ok(Some(value))
+    }
+
+    /// The left (`false`) or right (`true`) record of a node.
+    fn record(&self, node: usize, right: bool) -> Result<usize> {
+        let node_size = self.record_size / 4;
+        let start = node * node_size;
+        let bytes = self
+            .bytes
+            .get(start..start + node_size)
+            .ok_or_else(|| eyre!("Search tree node {} is out of range", node))?;
+        let be = |b: &[u8]| b.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        let value = match (self.record_size, right) {
+            (24, false) => be(&bytes[0..3]),
+            (24, true) => be(&bytes[3..6]),
+            (28, false) => ((bytes[3] as usize & 0xF0) << 20) | be(&bytes[0..3]),
+            (28, true) => ((bytes[3] as usize & 0x0F) << 24) | be(&bytes[4..7]),
+            (_, false) => be(&bytes[0..4]),
+            (_, true) => be(&bytes[4..8]),
+        };
+        Ok(value)
+    }
+}
+
+fn slice(data: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+    data.get(at..at + len).ok_or_else(|| eyre!("Data is truncated"))
+}
+
+/// Decode the value at an offset in a data section, returning it and the
+/// offset just past it.
+fn decode(data: &[u8], offset: usize) -> Result<(Value, usize)> {
+    let byte = |at: usize| -> Result<usize> {
+        data.get(at).map(|b| *b as usize).ok_or_else(|| eyre!("Data is truncated"))
+    };
+    let be = |b: &[u8]| b.iter().fold(0u128, |acc, &b| (acc << 8) | b as u128);
+    let control = byte(offset)?;
+    let mut pos = offset + 1;
+    let mut kind = control >> 5;
+    if kind == 1 {
+        // pointers have their own size encoding, and point at a value
+        // whose decoding doesn't affect where this one ends
+        let size = (control >> 3) & 0x3;
+        let low = control & 0x7;
+        let raw = be(slice(data, pos, size + 1)?) as usize;
+        let target = match size {
+            0 => (low << 8) | raw,
+            1 => ((low << 16) | raw) + 2048,
+            2 => ((low << 24) | raw) + 526336,
+            _ => raw,
+        };
+        let (value, _) = decode(data, target)?;
+        return Ok((value, pos + size + 1));
+    }
+    if kind == 0 {
+        kind = 7 + byte(pos)?;
+        pos += 1;
+    }
+    let mut size = control & 0x1f;
+    if size >= 29 {
+        let extra = size - 28;
+        let raw = be(slice(data, pos, extra)?) as usize;
+        size = match extra {
+            1 => 29 + raw,
+            2 => 285 + raw,
+            _ => 65821 + raw,
+        };
+        pos += extra;
+    }
+    let value = match kind {
+        2 => {
+            let s = std::str::from_utf8(slice(data, pos, size)?)?;
+            pos += size;
+            Value::String(s.to_string())
+        }
+        3 | 15 => {
+            let raw = slice(data, pos, size)?;
+            pos += size;
+            let val = match raw.len() {
+                4 => f32::from_be_bytes(raw.try_into()?) as f64,
+                8 => f64::from_be_bytes(raw.try_into()?),
+                n => return Err(eyre!("Invalid floating point size: {}", n)),
+            };
+            Number::from_f64(val).map_or(Value::Null, Value::Number)
+        }
+        4 => {
+            let raw = slice(data, pos, size)?;
+            pos += size;
+            Value::String(hex::encode(raw))
+        }
+        5 | 6 | 9 | 10 => {
+            let raw = be(slice(data, pos, size)?);
+            pos += size;
+            u64::try_from(raw)
+                .map_or_else(|_| Value::String(raw.to_string()), Value::from)
+        }
+        8 => {
+            let raw = be(slice(data, pos, size)?) as u32;
+            pos += size;
+            // shorter encodings are zero-extended, so only full ones can be negative
+            Value::from(if size == 4 { raw as i32 as i64 } else { raw as i64 })
+        }
+        7 => {
+            let mut map = Map::new();
+            for _ in 0..size {
+                let (key, next) = decode(data, pos)?;
+                let (val, next) = decode(data, next)?;
+                pos = next;
+                let key = key.as_str().ok_or_else(|| eyre!("Map key is not a string"))?;
+                map.insert(key.to_string(), val);
+            }
+            Value::Object(map)
+        }
+        11 => {
+            let mut array = vec![];
+            for _ in 0..size {
+                let (val, next) = decode(data, pos)?;
+                pos = next;
+                array.push(val);
+            }
+            Value::Array(array)
+        }
+        14 => Value::Bool(size != 0),
+        kind => return Err(eyre!("Unsupported data type: {}", kind)),
+    };
+    Ok((value, pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, MaxMindDb, METADATA_MARKER};
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut result = vec![0x40 | s.len() as u8];
+        result.extend_from_slice(s.as_bytes());
+        result
+    }
+
+    /// A database with one node, whose left half (0.0.0.0/1) is in Berkeley.
+    fn mock_db() -> Vec<u8> {
+        let mut bytes = vec![0, 0, 17, 0, 0, 1];
+        bytes.extend([0u8; 16]);
+        bytes.push(0xE2);
+        bytes.extend(string("country"));
+        bytes.push(0xE2);
+        bytes.extend(string("iso_code"));
+        bytes.extend(string("US"));
+        bytes.extend(string("names"));
+        bytes.push(0xE1);
+        bytes.extend(string("en"));
+        bytes.extend(string("United States"));
+        bytes.extend(string("city"));
+        bytes.push(0xE1);
+        bytes.extend(string("names"));
+        bytes.push(0xE1);
+        bytes.extend(string("en"));
+        bytes.extend(string("Berkeley"));
+        bytes.extend(METADATA_MARKER);
+        bytes.push(0xE3);
+        bytes.extend(string("node_count"));
+        bytes.extend([0xC1, 1]);
+        bytes.extend(string("record_size"));
+        bytes.extend([0xA1, 24]);
+        bytes.extend(string("ip_version"));
+        bytes.extend([0xA1, 4]);
+        bytes
+    }
+
+    #[test]
+    fn test_lookup() {
+        let db = MaxMindDb::from_bytes(mock_db()).expect("Can't read database");
+        let record = db.lookup("10.1.2.3".parse().unwrap()).unwrap().unwrap();
+        assert_eq!(super::place_name(&record["country"]), "United States");
+        assert_eq!(super::place_name(&record["city"]), "Berkeley");
+        assert!(db.lookup("192.168.1.1".parse().unwrap()).unwrap().is_none());
+        assert!(db.lookup("::ffff:10.1.2.3".parse().unwrap()).unwrap().is_some());
+        assert!(db.lookup("2001:db8::1".parse().unwrap()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_pointer() {
+        // a map whose value is a pointer back to the string at offset 0
+        let mut data = string("campus");
+        data.push(0xE1);
+        data.extend(string("name"));
+        data.extend([0x20, 0]);
+        let (value, end) = decode(&data, 7).unwrap();
+        assert_eq!(value["name"], "campus");
+        assert_eq!(end, data.len());
+    }
+}
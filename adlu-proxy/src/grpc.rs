@@ -0,0 +1,306 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+An optional gRPC API for machine-to-machine administration of the proxy.
+
+When the proxy is built with the `grpc` feature (which requires the protobuf
+compiler, `protoc`), and the `[grpc]` settings have a bind address, the
+server also serves the `adlu.proxy.v1.ProxyAdmin` service defined in
+`proto/adlu/proxy/v1/admin.proto`.  It offers the proxy's status, cache
+activity and reports, and triggers for forwarding and purging.  Callers are
+authorized like those of the proxy's other admin endpoints (see
+[`crate::auth`]), so the API is unavailable until there are gRPC tokens or
+admin credentials.
+
+The protocol definition is versioned by its package: compatible changes
+(such as new fields) are made in place, and incompatible ones in a new
+package alongside the old one.
+ */
+use eyre::Result;
+#[cfg(not(feature = "grpc"))]
+use log::warn;
+use tokio::task::JoinHandle;
+
+use crate::proxy::Config;
+
+#[cfg(feature = "grpc")]
+pub mod pb {
+    tonic::include_proto!("adlu.proxy.v1");
+}
+
+#[cfg(feature = "grpc")]
+pub use service::AdminService;
+
+/// Start serving the gRPC API, if it's configured.
+#[cfg(feature = "grpc")]
+pub fn spawn(conf: &Config) -> Result<Option<JoinHandle<()>>> {
+    service::spawn(conf)
+}
+
+/// Start serving the gRPC API, if it's configured.  This proxy was
+/// built without it, so it can only warn that it's unavailable.
+#[cfg(not(feature = "grpc"))]
+pub fn spawn(conf: &Config) -> Result<Option<JoinHandle<()>>> {
+    if !conf.settings.grpc.bind_address.is_empty() {
+        warn!("This proxy was built without gRPC support, so the API is not served");
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use clap::ValueEnum;
+    use eyre::{eyre, Result, WrapErr};
+    use log::{error, info, warn};
+    use tokio::task::JoinHandle;
+    use tonic::{Request, Response, Status};
+
+    use adlu_base::Timestamp;
+
+    use super::pb;
+    use super::pb::proxy_admin_server::{ProxyAdmin, ProxyAdminServer};
+    use crate::auth::Credentials;
+    use crate::cache::{ActivityBin, LockHeld, ReportFilter, PURGE_LOCK};
+    use crate::cli::Datasource;
+    use crate::proxy::{self, Config};
+    use crate::settings::{AdminEndpoint, Settings};
+
+    pub fn spawn(conf: &Config) -> Result<Option<JoinHandle<()>>> {
+        let settings = &conf.settings.grpc;
+        if settings.bind_address.is_empty() {
+            return Ok(None);
+        }
+        let addr = std::net::SocketAddr::from_str(&settings.bind_address)
+            .wrap_err(format!("Invalid gRPC bind address: {}", &settings.bind_address))?;
+        if settings.tokens.iter().all(String::is_empty)
+            && !conf.settings.admin.has_credentials()
+        {
+            warn!("No gRPC credentials are configured, so every call will be refused");
+        }
+        let settings = conf.settings.clone();
+        let service = ProxyAdminServer::with_interceptor(
+            AdminService::new(conf.clone()),
+            move |req: Request<()>| check_credentials(&settings, req),
+        );
+        info!("Serving the gRPC API on {}", addr);
+        let server = tonic::transport::Server::builder().add_service(service).serve(addr);
+        Ok(Some(tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("The gRPC server failed: {}", err);
+            }
+        })))
+    }
+
+    fn check_credentials(
+        settings: &Settings,
+        req: Request<()>,
+    ) -> Result<Request<()>, Status> {
+        let authorization =
+            req.metadata().get("authorization").and_then(|val| val.to_str().ok());
+        let credentials = Credentials::new(authorization, None);
+        if credentials.authorize(settings, AdminEndpoint::Grpc) {
+            Ok(req)
+        } else {
+            Err(Status::unauthenticated(
+                "Valid credentials are required for the gRPC API",
+            ))
+        }
+    }
+
+    /// The implementation of the `ProxyAdmin` service.
+    pub struct AdminService {
+        conf: Config,
+    }
+
+    impl AdminService {
+        pub fn new(conf: Config) -> Self {
+            AdminService { conf }
+        }
+    }
+
+    /// Reports are generated into uniquely-named temporary files.
+    static REPORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    fn internal(err: eyre::Report) -> Status {
+        if let Some(held) = err.downcast_ref::<LockHeld>() {
+            Status::aborted(held.to_string())
+        } else {
+            Status::internal(format!("{:#}", err))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl ProxyAdmin for AdminService {
+        async fn get_status(
+            &self,
+            _request: Request<pb::GetStatusRequest>,
+        ) -> Result<Response<pb::GetStatusResponse>, Status> {
+            info!("gRPC status request received");
+            let status = proxy::status_json(&self.conf).await;
+            let modes: HashMap<String, String> = status["modes"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(service, mode)| {
+                    (service.clone(), mode.as_str().unwrap_or_default().to_string())
+                })
+                .collect();
+            Ok(Response::new(pb::GetStatusResponse {
+                status: status["status"].as_str().unwrap_or_default().to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                modes,
+                json: status.to_string(),
+            }))
+        }
+
+        async fn get_activity(
+            &self,
+            request: Request<pb::GetActivityRequest>,
+        ) -> Result<Response<pb::GetActivityResponse>, Status> {
+            let request = request.into_inner();
+            let bin = if request.bin.is_empty() { "day" } else { &request.bin };
+            let bin = ActivityBin::try_from(bin).map_err(Status::invalid_argument)?;
+            // the defaults are the same as for the /activity endpoint
+            let until = match request.until {
+                0 => Timestamp::from_millis(Timestamp::now().to_millis() + 1),
+                millis => Timestamp::from_millis(millis),
+            };
+            let bins = if let ActivityBin::Hour = bin { 48 } else { 30 };
+            let since = match request.since {
+                0 => Timestamp::from_millis(until.to_millis() - bins * bin.millis()),
+                millis => Timestamp::from_millis(millis),
+            };
+            let counts =
+                self.conf.cache.activity(bin, &since, &until).await.map_err(internal)?;
+            let bins = counts
+                .into_iter()
+                .map(|count| pb::ActivityCount {
+                    start: count.start,
+                    activations: count.activations,
+                    license_sessions: count.license_sessions,
+                    log_sessions: count.log_sessions,
+                })
+                .collect();
+            Ok(Response::new(pb::GetActivityResponse { bins }))
+        }
+
+        async fn get_report(
+            &self,
+            request: Request<pb::GetReportRequest>,
+        ) -> Result<Response<pb::GetReportResponse>, Status> {
+            let request = request.into_inner();
+            let source = Datasource::from_str(&request.data, true)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            let filter = if request.filter.is_empty() {
+                ReportFilter::default()
+            } else {
+                ReportFilter::parse(&request.filter)
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?
+            };
+            info!("gRPC request for a {} report", source);
+            let count = REPORT_COUNT.fetch_add(1, Ordering::SeqCst);
+            let path = std::env::temp_dir().join(format!(
+                "adlu-proxy-grpc-report-{}-{}.csv",
+                std::process::id(),
+                count
+            ));
+            let path_str = path.to_string_lossy().to_string();
+            let (empty, timezone, rfc3339) =
+                (request.empty, request.timezone, request.rfc3339);
+            let result = self
+                .conf
+                .cache
+                .filtered_report(&source, &path_str, &filter, empty, timezone, rfc3339)
+                .await
+                .and_then(|_| std::fs::read(&path).map_err(|e| eyre!(e)));
+            std::fs::remove_file(&path).ok();
+            let csv = result.map_err(internal)?;
+            Ok(Response::new(pb::GetReportResponse { csv }))
+        }
+
+        async fn forward(
+            &self,
+            _request: Request<pb::ForwardRequest>,
+        ) -> Result<Response<pb::ForwardResponse>, Status> {
+            info!("gRPC request to forward stored requests");
+            proxy::forward_stored_requests(&self.conf.settings, &self.conf.cache)
+                .await
+                .map_err(internal)?;
+            Ok(Response::new(pb::ForwardResponse {}))
+        }
+
+        async fn purge(
+            &self,
+            request: Request<pb::PurgeRequest>,
+        ) -> Result<Response<pb::PurgeResponse>, Status> {
+            let max_age_days = request.into_inner().max_age_days;
+            info!("gRPC request to purge entries over {} days old", max_age_days);
+            let cache = &self.conf.cache;
+            let lock = cache.lock(PURGE_LOCK).await.map_err(internal)?;
+            let result = cache.purge(max_age_days).await;
+            lock.release().await;
+            let purged = result.map_err(internal)? as u64;
+            Ok(Response::new(pb::PurgeResponse { purged }))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::settings::ProxyMode;
+        use crate::testing::{config_with, get_test_config, release_test_config};
+
+        #[tokio::test]
+        async fn test_grpc_service() {
+            let conf = get_test_config(&ProxyMode::Connected).await;
+            let service = AdminService::new(conf.clone());
+            let status = service
+                .get_status(Request::new(pb::GetStatusRequest {}))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+            assert!(status.json.contains("statusCode"));
+            let request = pb::GetReportRequest {
+                data: "nosuchdata".to_string(),
+                ..Default::default()
+            };
+            let err = service.get_report(Request::new(request)).await.unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+            let request =
+                pb::GetReportRequest { data: "frl".to_string(), ..Default::default() };
+            let report = service.get_report(Request::new(request)).await.unwrap();
+            assert!(report.into_inner().csv.starts_with(b"Request Type"));
+            let conf = config_with(&conf, |settings| {
+                settings.grpc.tokens = vec!["grpc-token".to_string()];
+            });
+            let mut request = Request::new(());
+            assert!(check_credentials(&conf.settings, Request::new(())).is_err());
+            request
+                .metadata_mut()
+                .insert("authorization", "Bearer grpc-token".parse().unwrap());
+            assert!(check_credentials(&conf.settings, request).is_ok());
+            release_test_config(conf).await;
+        }
+    }
+}
@@ -0,0 +1,115 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Pass-through of the IMS token requests that NGL makes during named-user sign-in.
+
+When a user signs in to a named-user licensed app, NGL exchanges its device
+credentials for a device token, and that for an access token, at Adobe's IMS
+server.  Sending those requests through the proxy lets sites with a single
+egress point support named-user workflows.  Because both the requests and the
+responses carry credentials, they are never cached or recorded to a cassette,
+and only their method, path, and status are logged.  Since the proxy then
+handles user credentials, this pass-through is off unless it's enabled.
+ */
+use eyre::{Result, WrapErr};
+
+use adlu_parse::protocol::Request;
+
+use crate::settings::Ims;
+
+/// Whether a request path is for one of the configured token endpoints.
+pub fn is_token_path(settings: &Ims, path: &str) -> bool {
+    settings.enabled
+        && settings
+            .path_prefixes
+            .iter()
+            .any(|prefix| !prefix.is_empty() && path.starts_with(prefix.as_str()))
+}
+
+/// The request to send to the IMS server for a token request.
+pub fn upstream_request(
+    client: &reqwest::Client,
+    server: &str,
+    req: &Request,
+) -> Result<reqwest::Request> {
+    let endpoint = if let Some(query) = &req.query {
+        format!("{}{}?{}", server.trim_end_matches('/'), &req.path, query)
+    } else {
+        format!("{}{}", server.trim_end_matches('/'), &req.path)
+    };
+    let mut builder = client.request(req.method.clone(), &endpoint);
+    if let Some(content_type) = &req.content_type {
+        builder = builder.header("Content-Type", content_type)
+    }
+    if let Some(accept_type) = &req.accept_type {
+        builder = builder.header("Accept", accept_type)
+    }
+    if let Some(accept_language) = &req.accept_language {
+        builder = builder.header("Accept-Language", accept_language);
+    }
+    if let Some(user_agent) = &req.user_agent {
+        builder = builder.header("User-Agent", user_agent);
+    }
+    if let Some(api_key) = &req.api_key {
+        builder = builder.header("X-Api-Key", api_key);
+    }
+    if let Some(request_id) = &req.request_id {
+        builder = builder.header("X-Request-Id", request_id);
+    }
+    if let Some(authorization) = &req.authorization {
+        builder = builder.header("Authorization", authorization);
+    }
+    if let Some(body) = &req.body {
+        builder = builder.body(body.clone())
+    }
+    builder.build().wrap_err("Error creating IMS token request")
+}
+
+#[cfg(test)]
+mod tests {
+    use adlu_parse::protocol::Request;
+
+    use super::{is_token_path, upstream_request};
+    use crate::settings::Ims;
+
+    #[tokio::test]
+    async fn test_token_request() {
+        let mut settings = Ims::default();
+        assert!(!is_token_path(&settings, "/ims/token/v3"));
+        settings.enabled = true;
+        assert!(is_token_path(&settings, "/ims/token/v3"));
+        assert!(!is_token_path(&settings, "/asnp/frl_connected/values/v2"));
+        let filter = Request::unknown_filter(32_000);
+        let req = warp::test::request()
+            .method("POST")
+            .path("/ims/token/v3?client_id=ngl_test")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Authorization", "Bearer secret")
+            .body("grant_type=device&device_token=secret")
+            .filter(&filter)
+            .await
+            .expect("Token request was rejected");
+        let client = reqwest::Client::new();
+        let request = upstream_request(&client, &settings.remote_host, &req).unwrap();
+        let url = "https://ims-na1.adobelogin.com/ims/token/v3?client_id=ngl_test";
+        assert_eq!(request.url().as_str(), url);
+        assert_eq!(request.headers()["Authorization"], "Bearer secret");
+        assert!(request.body().is_some());
+    }
+}
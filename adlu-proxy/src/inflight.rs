@@ -0,0 +1,137 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+De-duplication of activations that are already on their way to Adobe.
+
+A client that times out waiting for an activation will often retry it while
+the first attempt is still waiting for Adobe, and without de-duplication the
+proxy would send both upstream.  Instead, the first request for an activation
+key becomes the leader: it is sent upstream as usual, and identical requests
+that arrive before it's answered follow it, waiting for it to finish and then
+replying with the response it cached.  Only if the leader fails (or goes away)
+does a follower send its own request.
+ */
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+/// The activations that are being sent upstream, by activation key.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    leaders: Mutex<HashMap<String, watch::Receiver<Option<bool>>>>,
+    followers: AtomicU64,
+}
+
+/// How a request takes part in the sending of its activation key.
+pub enum Role<'a> {
+    Leader(Leader<'a>),
+    Follower(Follower),
+}
+
+/// The request that is sending an activation upstream.  Followers are
+/// released when it finishes (or is dropped without finishing).
+pub struct Leader<'a> {
+    key: String,
+    in_flight: &'a InFlight,
+    sender: watch::Sender<Option<bool>>,
+}
+
+/// A request that waits for its leader instead of sending its activation.
+pub struct Follower {
+    receiver: watch::Receiver<Option<bool>>,
+}
+
+impl InFlight {
+    /// Lead the sending of the given activation key, or follow the
+    /// request that is already sending it.
+    pub fn join(&self, key: &str) -> Role<'_> {
+        let mut leaders = self.leaders.lock().unwrap();
+        if let Some(receiver) = leaders.get(key) {
+            self.followers.fetch_add(1, Ordering::Relaxed);
+            return Role::Follower(Follower { receiver: receiver.clone() });
+        }
+        let (sender, receiver) = watch::channel(None);
+        leaders.insert(key.to_string(), receiver);
+        Role::Leader(Leader { key: key.to_string(), in_flight: self, sender })
+    }
+
+    /// The number of activations in flight, and the number of requests
+    /// that have waited for one rather than being sent upstream.
+    pub fn to_json(&self) -> Value {
+        let in_flight = self.leaders.lock().unwrap().len();
+        json!({"inFlight": in_flight, "coalesced": self.followers.load(Ordering::Relaxed)})
+    }
+}
+
+impl Leader<'_> {
+    /// Release the followers, telling them whether a response was cached.
+    pub fn finish(self, cached: bool) {
+        self.sender.send_replace(Some(cached));
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.in_flight.leaders.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Follower {
+    /// Wait for the leader, returning whether it cached a response.
+    pub async fn wait(mut self) -> bool {
+        loop {
+            if let Some(cached) = *self.receiver.borrow() {
+                return cached;
+            }
+            if self.receiver.changed().await.is_err() {
+                // the leader went away without finishing
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InFlight, Role};
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        let in_flight = InFlight::default();
+        let Role::Leader(leader) = in_flight.join("a1") else { panic!("Not the leader") };
+        let Role::Follower(follower) = in_flight.join("a1") else {
+            panic!("Not a follower")
+        };
+        assert!(matches!(in_flight.join("a2"), Role::Leader(_)));
+        let waiting = tokio::spawn(follower.wait());
+        leader.finish(true);
+        assert!(waiting.await.unwrap());
+        assert!(matches!(in_flight.join("a1"), Role::Leader(_)));
+        let Role::Leader(leader) = in_flight.join("a3") else { panic!("Not the leader") };
+        let Role::Follower(follower) = in_flight.join("a3") else {
+            panic!("Not a follower")
+        };
+        drop(leader);
+        assert!(!follower.wait().await);
+        assert_eq!(in_flight.to_json()["coalesced"], 2);
+    }
+}
@@ -0,0 +1,102 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+A small HTML page served at `/` to browsers, so that someone who pastes the
+proxy's URL into one sees what it is rather than an error.
+
+Only requests that accept HTML get the page; license requests from Adobe
+applications never ask for it, so they are handled exactly as before.
+ */
+use serde_json::Value;
+
+use crate::proxy::proxy_id;
+use crate::settings::Settings;
+
+/// Whether a request's `Accept` header asks for an HTML page.
+pub fn wants_html(accept: Option<&str>) -> bool {
+    matches!(accept, Some(accept) if accept.to_ascii_lowercase().contains("text/html"))
+}
+
+/// The landing page, given the proxy's status (see [`crate::proxy::status_json`]).
+pub fn landing_page(settings: &Settings, status: &Value) -> String {
+    let status = status["status"].as_str().unwrap_or_default();
+    let contact = &settings.landing.admin_contact;
+    let contact = if contact.is_empty() {
+        "".to_string()
+    } else {
+        format!("    <p>For help, contact {}.</p>\n", escape(contact))
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>Adobe Licensing Proxy</title>
+</head>
+<body>
+    <h1>Adobe Licensing Proxy</h1>
+    <p>This server answers licensing requests from Adobe desktop applications.
+    It has nothing else to show in a browser.</p>
+    <p>Version: {}</p>
+    <p>Status: {} (<a href="/status">details</a>)</p>
+{}</body>
+</html>
+"#,
+        escape(&proxy_id()),
+        escape(status),
+        contact
+    )
+}
+
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::settings::{Settings, SettingsVal};
+
+    #[test]
+    fn test_landing_page() {
+        assert!(super::wants_html(Some("text/html,application/xhtml+xml,*/*;q=0.8")));
+        assert!(!super::wants_html(Some("application/json")));
+        assert!(!super::wants_html(None));
+        let mut settings = SettingsVal::default_config();
+        let status = json!({"status": "adlu-proxy running in Connected mode"});
+        let page = super::landing_page(&Settings::new(settings.clone()), &status);
+        assert!(page.contains("<p>Status: adlu-proxy running in Connected mode"));
+        assert!(!page.contains("For help"));
+        settings.landing.admin_contact = "IT <help@example.com>".to_string();
+        let page = super::landing_page(&Settings::new(settings), &status);
+        assert!(page.contains("For help, contact IT &lt;help@example.com&gt;."));
+    }
+}
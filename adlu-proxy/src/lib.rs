@@ -16,19 +16,55 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
-use eyre::{Result, WrapErr};
+use eyre::{eyre, Result, WrapErr};
 use log::{debug, info};
 
-use cli::{Command, ProxyArgs};
+use cache::ReportFilter;
+use cli::{Command, Datasource, ProxyArgs};
 use settings::Settings;
 
+pub mod auth;
 pub mod cache;
+pub mod canary;
+pub mod cassette;
+pub mod cert;
 pub mod cli;
+pub mod compress;
+pub mod daemon;
+pub mod dns;
+pub mod email;
+pub mod events;
+pub mod faults;
+pub mod forward_plan;
+pub mod geoip;
+pub mod grpc;
+pub mod ims;
+pub mod inflight;
+pub mod landing;
+pub mod listener;
 pub mod logging;
+pub mod mirror;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod privileges;
 pub mod proxy;
+pub mod proxy_protocol;
+pub mod relay;
+pub mod reporting;
+pub mod reset;
+pub mod schedule;
+pub mod security;
 pub mod settings;
+pub mod simulate;
+pub mod survey;
+pub mod syslog;
+pub mod template;
 #[cfg(test)]
 pub mod testing;
+pub mod throttle;
+pub mod trace;
+pub mod transfer;
+pub mod unknown;
 
 pub async fn run(
     settings: Settings,
@@ -38,49 +74,279 @@ pub async fn run(
     logging::init(&settings.logging)?;
     info!("{} invoked with command: {:?}", proxy::proxy_id(), args.cmd);
     debug!("Loaded config: {:?}", &settings);
-    let cache = cache::connect(&settings.proxy.db_path).await?;
+    let cache = cache::connect(&settings.proxy).await?;
+    if matches!(args.cmd, Command::Serve { .. } | Command::Import { .. }) {
+        // only requests coming into the cache need tagging with their orgs
+        cache.store_org_mappings(&settings.frl.orgs).await?;
+    }
     let result = match args.cmd {
         Command::Configure { .. } => settings::update_config_file(Some(&settings), &args),
-        Command::Serve { .. } => {
-            if settings.proxy.ssl {
+        // main runs the mock server without a config file, so it never gets here
+        #[cfg(feature = "mock")]
+        Command::MockServer { .. } => {
+            Err(eyre!("The mock server can't run in the proxy"))
+        }
+        Command::Serve { .. } if settings.proxy.daemonize && !cfg!(unix) => {
+            Err(eyre!("The proxy can only daemonize on Unix systems"))
+        }
+        Command::Serve { .. } => match daemon::PidFile::acquire(&settings.proxy.pid_file)
+        {
+            // the PID file is held until the server stops
+            Ok(_pid_file) if settings.proxy.ssl => {
                 proxy::serve_incoming_https_requests(&settings, &cache, stop_signal).await
-            } else {
+            }
+            Ok(_pid_file) => {
                 proxy::serve_incoming_http_requests(&settings, &cache, stop_signal).await
             }
+            Err(err) => Err(err),
+        },
+        Command::PatchResponse { ref key, ref action, ref reason, yes } => {
+            let count = cache
+                .patch_responses(key, action, reason, yes)
+                .await
+                .wrap_err(format!("Failed to patch responses for {}", key))?;
+            eprintln!("Patched ({}) {} cached activation response(s)", action, count);
+            Ok(())
+        }
+        Command::Preview { ref package, devices, json } => cache
+            .preview(package, devices, json)
+            .await
+            .wrap_err(format!("Failed to preview package {}", package)),
+        Command::Survey { check: Some(ref path), .. } => survey::check_report_file(path),
+        Command::Survey { ref to_path, json, .. } => {
+            survey::survey(&settings, &cache, to_path.as_deref(), json)
+                .await
+                .wrap_err("Failed to survey the site")
+        }
+        Command::Forward { dry_run: true, stale_days, to_path } => {
+            forward_plan::dry_run(&settings, &cache, stale_days, to_path.as_deref()).await
         }
-        Command::Forward => proxy::forward_stored_requests(&settings, &cache).await,
-        Command::Clear { yes } => {
+        Command::Forward { .. } => {
+            proxy::forward_stored_requests(&settings, &cache).await
+        }
+        Command::Reset { ref data, dry_run, yes, ref archive_dir } => {
+            reset::reset(&settings, &cache, data, archive_dir, dry_run, yes)
+                .await
+                .wrap_err("Failed to reset the proxy")
+        }
+        // main handles these without starting the proxy, so they never get here
+        Command::PrintConfig { .. } | Command::Completions { .. } => {
+            Err(eyre!("Printing the config or completions can't run in the proxy"))
+        }
+        Command::Cert { ref kind, ref hosts, days } => {
+            cert::generate(&settings, kind, hosts, days)
+        }
+        Command::Clear { yes, data: None, before: None } => {
             cache.clear(yes).await.wrap_err("Failed to clear cache")
         }
-        Command::Import { data: source, from_path: import_path } => cache
-            .import(&source, &import_path)
+        Command::Clear { yes, ref data, ref before } => {
+            let count = cache
+                .clear_selected(data.as_ref(), before.as_ref(), yes)
+                .await
+                .wrap_err("Failed to clear cache")?;
+            eprintln!("Cleared {} entries from the cache.", count);
+            Ok(())
+        }
+        Command::Stats { json } => {
+            cache.stats(json).await.wrap_err("Failed to collect cache statistics")
+        }
+        Command::Verify { repair } => {
+            cache.verify(repair).await.wrap_err("Failed to verify cache")
+        }
+        Command::Annotate { ref key, ref note } => {
+            let count = cache
+                .annotate(key, note)
+                .await
+                .wrap_err(format!("Failed to annotate {}", key))?;
+            eprintln!("Attached note to {} row(s) with key {}", count, key);
+            Ok(())
+        }
+        Command::Import { data: source, from_url: Some(url), token, .. } => {
+            transfer::import_from_url(&settings, &cache, &source, &url, token)
+                .await
+                .wrap_err(format!("Failed to import {} from {}", &source, &url))
+        }
+        Command::Import { data: source, from_path, legacy, skip_damaged, .. } => {
+            let import_path = from_path.unwrap_or_default();
+            let result = if legacy {
+                cache.import_legacy(&source, &import_path).await
+            } else {
+                cache.import_skipping_damaged(&source, &import_path, skip_damaged).await
+            };
+            result.wrap_err(format!("Failed to import {} from {}", &source, &import_path))
+        }
+        Command::Export { data: source, to_url: Some(url), token, .. } => {
+            transfer::export_to_url(&settings, &cache, &source, &url, token)
+                .await
+                .wrap_err(format!("Failed to export {} to {}", &source, &url))
+        }
+        Command::Export { data: source, template: Some(name), to_path, org, .. } => {
+            if !matches!(source, Datasource::Frl) {
+                return Err(eyre!("Export templates only apply to FRL licenses"));
+            }
+            let export_path = to_path.unwrap_or_default();
+            template::export(&settings, &cache, &name, &export_path, org.as_deref())
+                .await
+                .wrap_err(format!("Failed to export licenses to {}", &export_path))
+        }
+        Command::Export { data: source, archive: true, to_path, org, .. } => {
+            let export_path = to_path.unwrap_or_default();
+            cache
+                .export_archive(&source, &export_path, org.as_deref())
+                .await
+                .wrap_err(format!("Failed to export {} to {}", &source, &export_path))
+        }
+        Command::Export {
+            data: source,
+            to_path,
+            chunk_mb,
+            compress: compression,
+            org,
+            ..
+        } => {
+            let export_path = to_path.unwrap_or_default();
+            let org = org.as_deref();
+            let result = match compression {
+                None => cache.export_for_org(&source, &export_path, chunk_mb, org).await,
+                Some(compression) => {
+                    let path = compression.compressed_path(&export_path);
+                    let (cache, source) = (&cache, &source);
+                    if std::fs::metadata(&path).is_ok() {
+                        Err(eyre!("Cannot export to an existing file: {}", path))
+                    } else {
+                        compress::write_compressed(
+                            &compression,
+                            &path,
+                            |path| async move {
+                                cache.export_for_org(source, &path, None, org).await
+                            },
+                        )
+                        .await
+                    }
+                }
+            };
+            result.wrap_err(format!("Failed to export {} to {}", &source, &export_path))
+        }
+        Command::Split { chunk_mb, ref path } => {
+            cache::split_into_chunks(path, path, chunk_mb)
+        }
+        Command::Join { ref path } => cache::join_chunks(path),
+        Command::Pack { ref path } => cache::pack_archive(path).await,
+        Command::Unpack { ref path } => cache::unpack_archive(path).await,
+        Command::Report {
+            data: source,
+            empty,
+            timezone,
+            rfc3339,
+            filter,
+            org,
+            since_last,
+            to: Some(url),
+            compress: compression,
+            ..
+        } => {
+            let filter = report_filter(&source, filter.as_deref(), org.as_deref())?;
+            reporting::report_to_sink(
+                &settings,
+                &cache,
+                &source,
+                &url,
+                &filter,
+                empty,
+                timezone,
+                rfc3339,
+                since_last.then_some(url.as_str()),
+                compression.as_ref(),
+            )
             .await
-            .wrap_err(format!("Failed to import {} from {}", &source, &import_path)),
-        Command::Export { data: source, to_path: export_path } => cache
-            .export(&source, &export_path)
+            .wrap_err(format!("Failed to report {} to {}", &source, &url))
+        }
+        Command::Report {
+            data: source,
+            empty,
+            timezone,
+            rfc3339,
+            filter,
+            org,
+            since_last,
+            to_path,
+            compress: compression,
+            email: true,
+            ..
+        } => {
+            let filter = report_filter(&source, filter.as_deref(), org.as_deref())?;
+            reporting::report_to_email(
+                &settings,
+                &cache,
+                &source,
+                to_path.as_deref(),
+                &filter,
+                empty,
+                timezone,
+                rfc3339,
+                since_last.then_some("email"),
+                compression.as_ref(),
+            )
             .await
-            .wrap_err(format!("Failed to export {} to {}", &source, &export_path)),
+            .wrap_err(format!("Failed to email {} report", &source))
+        }
         Command::Report {
             data: source,
             empty,
             timezone,
             rfc3339,
-            to_path: report_path,
-        } => cache
-            .report(&source, &report_path, empty, timezone, rfc3339)
+            filter,
+            org,
+            since_last,
+            to_path,
+            compress: compression,
+            ..
+        } => {
+            let filter = report_filter(&source, filter.as_deref(), org.as_deref())?;
+            let report_path = to_path.unwrap_or_default();
+            let since_last = since_last.then_some(report_path.as_str());
+            reporting::report_to_file(
+                &cache,
+                &source,
+                &report_path,
+                &filter,
+                empty,
+                timezone,
+                rfc3339,
+                since_last,
+                compression.as_ref(),
+            )
             .await
-            .wrap_err(format!("Failed to report {} to {}", &source, &report_path)),
+            .wrap_err(format!("Failed to report {} to {}", &source, &report_path))
+        }
     };
     cache.close().await;
     result
 }
 
+/// The filter for a report, narrowed to an org if there is one.
+fn report_filter(
+    source: &Datasource,
+    filter: Option<&str>,
+    org: Option<&str>,
+) -> Result<ReportFilter> {
+    let filter = filter.map(ReportFilter::parse).transpose()?.unwrap_or_default();
+    match org {
+        None => Ok(filter),
+        Some(org) if matches!(source, Datasource::Frl | Datasource::Packages) => {
+            Ok(filter.for_org(org))
+        }
+        Some(_) => Err(eyre!("Only FRL and package reports can be limited to an org")),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::proxy;
-    use super::settings::ProxyMode;
+    use super::settings::{ProxyMode, Settings};
     use super::testing::*;
-    use crate::cli::Datasource;
+    use super::{proxy, reporting, template};
+    use crate::cache::ReportFilter;
+    use crate::cli::{Datasource, PatchAction};
 
     async fn send_frl_activation(
         conf: &proxy::Config,
@@ -228,6 +494,32 @@ mod tests {
             .expect("Report failed");
         let content = std::fs::read_to_string(&path).expect("Can't read report");
         assert!(content.contains("MockApp1"));
+        assert!(content.contains("forwarded-success"));
+    }
+
+    #[tokio::test]
+    async fn test_version_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let filter = proxy::nul_license_route(conf.clone());
+        let agent = "NGL Client/1.30.0.1 (MAC/12.4.0) [2022-06-28T17:08:01.895-0700]";
+        let mut builder = warp::test::request();
+        builder = named_user::mock_license_request(&MockOutcome::Success, "nv1", builder);
+        let response = builder.header("User-Agent", agent).reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let path = tempdir.join("version-report1.csv");
+        conf.cache
+            .report(&Datasource::Versions, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        // the body's version is preferred to the user agent's
+        let line = content
+            .lines()
+            .find(|l| l.starts_with("1.23.0.5,NUL License,"))
+            .expect("No NUL versions in report");
+        assert!(line.ends_with(agent), "No user agent: {}", line);
+        release_test_config(conf).await;
     }
 
     #[tokio::test]
@@ -238,6 +530,1780 @@ mod tests {
         release_test_config(conf).await;
     }
 
+    #[tokio::test]
+    async fn test_log_upload_relay() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| settings.log.relay_uploads = true);
+        assert!(conf.relay.is_enabled());
+        let worker = crate::relay::spawn(&conf).expect("Relay didn't start");
+        let result = send_log_upload(&conf, &MockOutcome::Success, "rq1").await;
+        assert_eq!(result, 200);
+        for _ in 0..100 {
+            if conf.relay.depth() == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert_eq!(conf.relay.depth(), 0, "Relayed upload was never sent");
+        worker.abort();
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_incremental_log_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.log.upload_tokens = vec!["script-token".to_string()]
+        });
+        let filter = proxy::routes(conf.clone());
+        let path = tempdir.join("incremental-report1.csv");
+        let path = path.to_str().unwrap();
+        let mut reports = vec![];
+        for session_id in ["inc1", "inc2", ""] {
+            if !session_id.is_empty() {
+                let body =
+                    adlu_parse::protocol::LogSession::mock_from_session_id(session_id)
+                        .to_body();
+                let response = warp::test::request()
+                    .method("POST")
+                    .path("/ulecs/v1")
+                    .header("Authorization", "Bearer script-token")
+                    .body(&body)
+                    .reply(&filter)
+                    .await;
+                assert_eq!(response.status().as_u16(), 200);
+            }
+            // sessions stored in the same millisecond as a report are left for the next
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            reporting::report_to_file(
+                &conf.cache,
+                &Datasource::Log,
+                path,
+                &Default::default(),
+                true,
+                false,
+                false,
+                Some("nightly"),
+                None,
+            )
+            .await
+            .expect("Report failed");
+            reports.push(std::fs::read_to_string(path).expect("Can't read report"));
+        }
+        assert!(reports[0].contains("inc1"));
+        assert!(reports[1].contains("inc2") && !reports[1].contains("inc1"));
+        assert_eq!(reports[2].lines().count(), 1);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_landing_page() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let filter = proxy::routes(conf.clone());
+        let response = warp::test::request()
+            .path("/")
+            .header("Accept", "text/html,*/*;q=0.8")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+        let page = String::from_utf8_lossy(response.body());
+        assert!(page.contains(r#"<a href="/status">"#), "Wrong page: {}", page);
+        // requests that don't ask for HTML are routed as before
+        let response = warp::test::request().path("/").reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 404);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_script_log_upload() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.log.upload_tokens = vec!["script-token".to_string()]
+        });
+        let filter = proxy::routes(conf.clone());
+        let body =
+            adlu_parse::protocol::LogSession::mock_from_session_id("su1").to_body();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/ulecs/v1")
+            .header("Authorization", "Bearer script-token")
+            .body(&body)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/ulecs/v1")
+            .header("Authorization", "Bearer wrong-token")
+            .body(&body)
+            .reply(&filter)
+            .await;
+        assert_ne!(response.status().as_u16(), 200);
+        let path = tempdir.join("script-upload-report1.csv");
+        conf.cache
+            .report(&Datasource::Log, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = content.lines().find(|l| l.contains(",su1,")).expect("No session");
+        assert!(line.ends_with(",script-uploaded"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_log_event_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.log.upload_tokens = vec!["event-token".to_string()]
+        });
+        let filter = proxy::routes(conf.clone());
+        let mut body =
+            adlu_parse::protocol::LogSession::mock_from_session_id("le1").to_body();
+        for millis in ["100", "200"] {
+            body.push_str(&format!(
+                "SessionID=le1 Timestamp=2022-08-12T10:50:22:{}-0700 \
+                Component=ngl-lib_HttpRequest \
+                Description=\"PostLogFile: Failed to post logs with status 401\"\n",
+                millis
+            ));
+        }
+        for _ in 0..2 {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/ulecs/v1")
+                .header("Authorization", "Bearer event-token")
+                .body(&body)
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status().as_u16(), 200);
+        }
+        let path = tempdir.join("log-event-report1.csv");
+        conf.cache
+            .report(&Datasource::Errors, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = content
+            .lines()
+            .find(|l| l.starts_with("MockApp1,10.1.3,WARN,401,PostLogFile,"))
+            .expect("No log event summary");
+        // the repeated upload doesn't count the same lines twice
+        assert!(line.contains(",PostLogFile,2,1,"), "Wrong counts: {}", line);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_correlation_id_header() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let filter = proxy::upload_route(conf.clone());
+        let builder = log::mock_log_upload_request(
+            &MockOutcome::Success,
+            "ci1",
+            warp::test::request(),
+        );
+        let response = builder.reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let id = response.headers().get(proxy::CORRELATION_ID_HEADER);
+        assert!(!id.expect("No correlation id").is_empty());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_log_upload_synthesis() {
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        let result = send_log_upload(&conf, &MockOutcome::Isolated, "ls1").await;
+        assert_eq!(result, 200);
+        let conf =
+            config_with(&conf, |settings| settings.log.synthesize_isolated = false);
+        let result = send_log_upload(&conf, &MockOutcome::Isolated, "ls1").await;
+        assert_eq!(result, 502);
+        let conf = conf.clone_with_mode(&ProxyMode::Connected);
+        let result = send_log_upload(&conf, &MockOutcome::Unreachable, "ls1").await;
+        assert_eq!(result, 200);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_service_modes() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.proxy.log_mode = "isolated".to_string();
+            settings.log.synthesize_isolated = false;
+        });
+        // log uploads are isolated, so they aren't sent even though Adobe is up
+        let result = send_log_upload(&conf, &MockOutcome::Success, "sm1").await;
+        assert_eq!(result, 502);
+        let result = send_nul_license(&conf, &MockOutcome::Success, "sm1").await;
+        assert_eq!(result, 200);
+        let body = proxy::status(conf.clone()).await.into_body();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["modes"]["log"], "isolated");
+        assert_eq!(status["modes"]["nul"], "connected");
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_status_authorization() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.events.tokens = vec!["dash-token".to_string()];
+        });
+        let filter = proxy::status_route(conf.clone());
+        let get = |token: &str| {
+            warp::test::request()
+                .path("/status")
+                .header("Authorization", format!("Bearer {token}"))
+                .reply(&filter)
+        };
+        assert_eq!(get("wrong-token").await.status().as_u16(), 401);
+        let response = get("dash-token").await;
+        assert_eq!(response.status().as_u16(), 200);
+        let status: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(status["status"].as_str().unwrap().contains("Connected"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_patch_responses() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let result = send_frl_activation(&conf, &MockOutcome::Success, "pr1").await;
+        assert_eq!(result, 200);
+        let conf = conf.clone_with_mode(&ProxyMode::Isolated);
+        let key =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("pr1")
+                .deactivation_id();
+        let patch = |action: PatchAction| {
+            let conf = conf.clone();
+            let key = key.clone();
+            async move {
+                conf.cache.patch_responses(&key, &action, "license extended", true).await
+            }
+        };
+        assert_eq!(patch(PatchAction::Invalidate).await.unwrap(), 1);
+        let result = send_frl_activation(&conf, &MockOutcome::Isolated, "pr1").await;
+        assert_eq!(result, 502);
+        assert_eq!(patch(PatchAction::Restore).await.unwrap(), 1);
+        let result = send_frl_activation(&conf, &MockOutcome::Isolated, "pr1").await;
+        assert_eq!(result, 200);
+        assert_eq!(patch(PatchAction::Wipe).await.unwrap(), 1);
+        let result = send_frl_activation(&conf, &MockOutcome::Isolated, "pr1").await;
+        assert_eq!(result, 502);
+        assert!(patch(PatchAction::Wipe).await.is_err());
+        let path = tempdir.join("patches-report1.csv");
+        conf.cache
+            .report(&Datasource::Patches, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        // the audit trail survives clearing the cache, so look at this run's patches
+        let lines: Vec<&str> = content.lines().filter(|l| l.contains("pr1")).collect();
+        assert!(lines.len() >= 3, "Wrong patches report: {}", content);
+        let lines = &lines[lines.len() - 3..];
+        assert!(lines[0].contains(",invalidate,license extended,"), "{}", lines[0]);
+        assert!(lines[2].contains(",wipe,license extended,"), "{}", lines[2]);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_cache_effectiveness() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let result = send_frl_activation(&conf, &MockOutcome::Success, "ce1").await;
+        assert_eq!(result, 200);
+        let conf = conf.clone_with_mode(&ProxyMode::Isolated);
+        let result = send_frl_activation(&conf, &MockOutcome::Isolated, "ce1").await;
+        assert_eq!(result, 200);
+        let body = proxy::status(conf.clone()).await.into_body();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // other tests share the cache, so there may be more than ours
+        let today = &status["cacheEffectiveness"]["today"]["FRL Activation"];
+        let count = |name: &str| today[name].as_u64().unwrap_or_default();
+        assert!(count("cacheServed") >= 1, "Wrong status: {}", status);
+        assert!(count("upstreamServed") >= 1, "Wrong status: {}", status);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_unknown_request_routing() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.unknown.routing = crate::settings::UnknownRouting::Reject
+        });
+        let filter = proxy::unknown_route(conf.clone());
+        for _ in 0..2 {
+            let response = warp::test::request()
+                .method("GET")
+                .path("https://lcs-cops.adobe.io/asnp/v9/unknown")
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status().as_u16(), 404);
+            let code = response.headers().get(proxy::ERROR_CODE_HEADER);
+            assert_eq!(code.expect("No error code"), "not-found");
+        }
+        let body = proxy::status(conf.clone()).await.into_body();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["unknownPaths"]["/asnp/v9/unknown"], 2);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_non_adobe_request() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let filter = proxy::unknown_route(conf.clone());
+        let response = warp::test::request()
+            .method("GET")
+            .path("https://www.example.com/index.html")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status().as_u16(), 404);
+        let code = response.headers().get(proxy::ERROR_CODE_HEADER);
+        assert_eq!(code.expect("No error code"), "not-found");
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_traced_activation() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let path = tempdir.join("trace1.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let traced = config_with(&conf, |settings| {
+            settings.trace.device_ids = vec!["tr1".to_string()];
+            settings.trace.path = path.to_str().unwrap().to_string();
+        });
+        let filter = proxy::routes(traced);
+        let mut builder = warp::test::request();
+        builder = frl::mock_activation_request(&MockOutcome::Success, "tr1", builder);
+        let response = builder.reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let content = std::fs::read_to_string(&path).expect("No trace file");
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("tr1"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_cassette_record_replay() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let path = tempdir.join("cassette1.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let recorder = config_with(&conf, |settings| {
+            settings.cassette.path = path.to_str().unwrap().to_string();
+            settings.cassette.mode = crate::settings::CassetteMode::Record;
+        });
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("cas1");
+        let mut req = frl::mock_cache_activation_request(&body);
+        frl::mock_forwardable_activation(&mut req, &MockOutcome::Success);
+        let recorded = proxy::send_to_adobe(&req, &recorder).await.unwrap();
+        assert_eq!(recorded.status().as_u16(), 200);
+        let recorded = recorded.text().await.unwrap();
+        // replay doesn't go to the (mock) server, so it can't fail
+        let player = config_with(&recorder, |settings| {
+            settings.cassette.mode = crate::settings::CassetteMode::Replay;
+        });
+        let replayed = proxy::send_to_adobe(&req, &player).await.unwrap();
+        assert_eq!(replayed.status().as_u16(), 200);
+        assert_eq!(replayed.text().await.unwrap(), recorded);
+        let other =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("cas2");
+        let other = frl::mock_cache_activation_request(&other);
+        assert!(proxy::send_to_adobe(&other, &player).await.is_err());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_upstream_throttling() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let filter = proxy::nul_license_route(conf.clone());
+        let builder = named_user::mock_license_request(
+            &MockOutcome::Throttled,
+            "th1",
+            warp::test::request(),
+        );
+        let response = builder.reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 429);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "30");
+        // while throttled, requests aren't forwarded
+        let builder = named_user::mock_license_request(
+            &MockOutcome::Success,
+            "th2",
+            warp::test::request(),
+        );
+        let response = builder.reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 503);
+        let retry_after = response.headers().get("Retry-After").unwrap();
+        let secs: u64 = retry_after.to_str().unwrap().parse().unwrap();
+        assert!(secs > 0 && secs <= 30);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_error_code_reply() {
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        let conf =
+            config_with(&conf, |settings| settings.log.synthesize_isolated = false);
+        let filter = proxy::upload_route(conf.clone());
+        let builder = log::mock_log_upload_request(
+            &MockOutcome::Isolated,
+            "ec1",
+            warp::test::request(),
+        );
+        let response = builder.reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 502);
+        let code = response.headers().get(proxy::ERROR_CODE_HEADER);
+        assert_eq!(code.expect("No error code"), "isolated-store");
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["errorCode"], "isolated-store");
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_request_body_validation() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        let filter = proxy::nul_license_route(conf.clone());
+        let body =
+            adlu_parse::protocol::NulLicenseRequestBody::mock_from_device_id("bv1");
+        let mut val = serde_json::to_value(body).unwrap();
+        val["deviceDetails"].as_object_mut().unwrap().remove("osUserId");
+        val["deviceDetails"]["deviceName"] = serde_json::json!(17);
+        for _ in 0..2 {
+            let builder = named_user::mock_license_request(
+                &MockOutcome::Isolated,
+                "bv1",
+                warp::test::request(),
+            );
+            let response = builder.body(val.to_string()).reply(&filter).await;
+            assert_eq!(response.status().as_u16(), 400);
+            let code = response.headers().get(proxy::ERROR_CODE_HEADER);
+            assert_eq!(code.expect("No error code"), "invalid-request");
+            let body: serde_json::Value =
+                serde_json::from_slice(response.body()).unwrap();
+            let fields: Vec<&str> = body["invalidFields"]
+                .as_array()
+                .expect("No invalid fields")
+                .iter()
+                .map(|f| f["field"].as_str().unwrap())
+                .collect();
+            assert_eq!(
+                fields,
+                vec!["deviceDetails.deviceName", "deviceDetails.osUserId"]
+            );
+        }
+        let path = tempdir.join("body-report1.csv");
+        conf.cache
+            .report(&Datasource::Bodies, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = content.lines().nth(1).expect("No failures in report");
+        assert!(line.starts_with("NUL License,MockApp1,10.1.3,2,"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_api_key_validation() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.security.validate_api_keys = true;
+            settings.security.reject_invalid_api_keys = true;
+            settings.security.allowed_app_ids = vec!["Photoshop1".to_string()];
+        });
+        let result = send_log_upload(&conf, &MockOutcome::Success, "ak1").await;
+        assert_eq!(result, 403);
+        let conf = config_with(&conf, |settings| {
+            settings.security.reject_invalid_api_keys = false;
+        });
+        let result = send_log_upload(&conf, &MockOutcome::Success, "ak2").await;
+        assert_eq!(result, 200);
+        let conf = config_with(&conf, |settings| {
+            settings.security.reject_invalid_api_keys = true;
+            settings.security.allowed_app_ids.push("ngl_mock1".to_string());
+        });
+        let result = send_log_upload(&conf, &MockOutcome::Success, "ak3").await;
+        assert_eq!(result, 200);
+        let path = tempdir.join("key-report1.csv");
+        conf.cache
+            .report(&Datasource::Keys, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        assert!(content.contains("ngl_mock1"));
+        assert!(content.contains("not for a known app"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_cache_quota() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let path = tempdir.join("quota-cache.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let db_settings = crate::settings::Proxy {
+            db_path: path.to_str().unwrap().to_string(),
+            db_max_size_kb: 1,
+            ..Default::default()
+        };
+        let cache = crate::cache::connect(&db_settings).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("q1");
+        let req = frl::mock_cache_activation_request(&body);
+        cache.store_request(&req).await;
+        let quota_conf =
+            proxy::Config::new(conf.settings.clone(), cache.clone()).unwrap();
+        for session_id in ["q1", "q2", "q3"] {
+            let result =
+                send_log_upload(&quota_conf, &MockOutcome::Success, session_id).await;
+            assert_eq!(result, 200);
+        }
+        let report_path = tempdir.join("quota-report1.csv");
+        cache
+            .report(&Datasource::Log, report_path.to_str().unwrap(), true, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&report_path).expect("Can't read report");
+        assert_eq!(content.lines().count(), 1, "Log sessions were not evicted");
+        let unanswered = cache.fetch_unanswered_requests().await.unwrap();
+        assert_eq!(unanswered.len(), 1, "Unanswered request was evicted");
+        cache.close().await;
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let path = tempdir.join("retention-cache.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let db_settings = crate::settings::Proxy {
+            db_path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let cache = crate::cache::connect(&db_settings).await.unwrap();
+        let long_ago = adlu_base::Timestamp::from_millis(
+            adlu_base::Timestamp::now().to_millis() - 400 * 24 * 3600 * 1000,
+        );
+        let old_request = |device_id: &str| {
+            let body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            let mut req = frl::mock_cache_activation_request(&body);
+            req.timestamp = long_ago.clone();
+            req
+        };
+        let (pending, answered) = (old_request("ret1"), old_request("ret2"));
+        cache.store_request(&pending).await;
+        cache.store_request(&answered).await;
+        let resp = proxy::Response {
+            timestamp: long_ago.clone(),
+            request_type: proxy::RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: Some("old-asnp".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        cache.store_response(&answered, &resp).await;
+        let retention_conf =
+            proxy::Config::new(conf.settings.clone(), cache.clone()).unwrap();
+        let result =
+            send_log_upload(&retention_conf, &MockOutcome::Success, "ret1").await;
+        assert_eq!(result, 200);
+        let retention = crate::settings::Retention {
+            frl: "90d".to_string(),
+            log: "forever".to_string(),
+            ..Default::default()
+        };
+        let counts = cache.apply_retention(&retention).await.unwrap();
+        assert_eq!(counts, vec![(Datasource::Frl, 1)]);
+        let unanswered = cache.fetch_unanswered_requests().await.unwrap();
+        assert_eq!(unanswered.len(), 1, "Pending request was evicted");
+        assert!(cache.fetch_response(&answered).await.is_none());
+        let retention =
+            crate::settings::Retention { log: "30d".to_string(), ..Default::default() };
+        let counts = cache.apply_retention(&retention).await.unwrap();
+        assert_eq!(counts, vec![(Datasource::Log, 0)], "Recent log session was evicted");
+        let bad =
+            crate::settings::Retention { nul: "soon".to_string(), ..Default::default() };
+        assert!(bad.policies().is_err());
+        cache.close().await;
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_clear_selected() {
+        let tempdir = get_test_directory().await;
+        let path = tempdir.join("clear-cache.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let db_settings = crate::settings::Proxy {
+            db_path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let cache = crate::cache::connect(&db_settings).await.unwrap();
+        let now = adlu_base::Timestamp::now();
+        let long_ago =
+            adlu_base::Timestamp::from_millis(now.to_millis() - 400 * 24 * 3600 * 1000);
+        let answered_request = |device_id: &str, timestamp: &adlu_base::Timestamp| {
+            let body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            let mut req = frl::mock_cache_activation_request(&body);
+            req.timestamp = timestamp.clone();
+            let resp = proxy::Response {
+                timestamp: timestamp.clone(),
+                request_type: proxy::RequestType::FrlActivation,
+                status: http::StatusCode::OK,
+                body: Some(format!("asnp-{}", device_id)),
+                content_type: None,
+                server: None,
+                via: None,
+                request_id: None,
+                session_id: None,
+                headers: vec![],
+            };
+            (req, resp)
+        };
+        let (old, old_resp) = answered_request("clear1", &long_ago);
+        let (new, new_resp) = answered_request("clear2", &now);
+        for (req, resp) in [(&old, &old_resp), (&new, &new_resp)] {
+            cache.store_request(req).await;
+            cache.store_response(req, resp).await;
+        }
+        let year_ago =
+            adlu_base::Timestamp::from_millis(now.to_millis() - 365 * 24 * 3600 * 1000);
+        let count = cache.clear_selected(Some(&Datasource::Log), None, true).await;
+        assert_eq!(count.unwrap(), 0, "Clearing log data cleared activations");
+        let count = cache.clear_selected(Some(&Datasource::Frl), Some(&year_ago), true);
+        assert_eq!(count.await.unwrap(), 1);
+        assert!(cache.fetch_response(&old).await.is_none());
+        assert!(cache.fetch_response(&new).await.is_some());
+        let result = cache.clear_selected(Some(&Datasource::Keys), None, true).await;
+        assert!(result.is_err(), "Cleared a datasource that can't be cleared");
+        cache.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        let path = tempdir.join("reset-cache.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let db_settings = crate::settings::Proxy {
+            db_path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let cache = crate::cache::connect(&db_settings).await.unwrap();
+        for device_id in ["rst1", "rst2"] {
+            let body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        }
+        let devices = cache.activated_devices().await.unwrap();
+        assert_eq!(devices.len(), 2);
+        let (npd_id, device_id) = &devices[1];
+        let deactivation =
+            cache.deactivation_for_device(npd_id, device_id).await.unwrap().unwrap();
+        cache.store_request(&deactivation).await;
+        let devices = cache.activated_devices().await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].1, "rst1");
+        let archive = tempdir.join("reset-archive");
+        let _ = std::fs::remove_dir_all(&archive);
+        let archive_dir = archive.to_str().unwrap();
+        let sources = [Datasource::Frl, Datasource::Log];
+        crate::reset::reset(&conf.settings, &cache, &sources, archive_dir, true, true)
+            .await
+            .unwrap();
+        assert!(!archive.exists(), "Dry run archived reports");
+        assert_eq!(cache.activated_devices().await.unwrap().len(), 1);
+        crate::reset::reset(&conf.settings, &cache, &sources, archive_dir, false, true)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_dir(&archive).unwrap().count(), 2);
+        assert!(cache.activated_devices().await.unwrap().is_empty());
+        // isolated deactivations are stored for forwarding later
+        let unanswered = cache.fetch_unanswered_requests().await.unwrap();
+        assert_eq!(unanswered.len(), 4, "Wrong unanswered: {:?}", unanswered);
+        cache.close().await;
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_frl_refresh_cache() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let make_response = |req: &proxy::Request, asnp_id: &str| proxy::Response {
+            timestamp: req.timestamp.clone(),
+            request_type: proxy::RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: Some(asnp_id.to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        let mut body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("rc1");
+        let initial = frl::mock_cache_activation_request(&body);
+        body.app_details.current_asnp_id = "initial-asnp".to_string();
+        let refresh = frl::mock_cache_activation_request(&body);
+        conf.cache.store_request(&initial).await;
+        conf.cache
+            .store_response(&initial, &make_response(&initial, "initial-asnp"))
+            .await;
+        // an unanswered refresh falls back to the initial profile
+        conf.cache.store_request(&refresh).await;
+        let resp = conf.cache.fetch_response(&refresh).await.unwrap();
+        assert_eq!(resp.body.unwrap(), "initial-asnp");
+        // an answered refresh doesn't replace the initial profile
+        conf.cache.store_request(&refresh).await;
+        conf.cache
+            .store_response(&refresh, &make_response(&refresh, "refresh-asnp"))
+            .await;
+        let resp = conf.cache.fetch_response(&refresh).await.unwrap();
+        assert_eq!(resp.body.unwrap(), "refresh-asnp");
+        let resp = conf.cache.fetch_response(&initial).await.unwrap();
+        assert_eq!(resp.body.unwrap(), "initial-asnp");
+        let path = tempdir.join("frl-report1.csv");
+        conf.cache
+            .report(&Datasource::Frl, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let refresh_line =
+            content.lines().find(|l| l.contains("rc1") && l.starts_with("Refresh"));
+        let fields: Vec<&str> =
+            refresh_line.expect("No refresh in report").split(',').collect();
+        assert_eq!(fields[fields.len() - 3], "2");
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_filtered_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        for device_id in ["flt1", "flt2"] {
+            let body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        }
+        let path = tempdir.join("filtered-report1.csv");
+        let path = path.to_str().unwrap();
+        let filter = ReportFilter::parse("device_id=flt1 && start>2020-01-01").unwrap();
+        conf.cache
+            .filtered_report(&Datasource::Frl, path, &filter, false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(path).expect("Can't read report");
+        assert!(content.contains("flt1"));
+        assert!(!content.contains("flt2"));
+        let filter = ReportFilter::parse("no_such_column=1").unwrap();
+        assert!(conf
+            .cache
+            .filtered_report(&Datasource::Frl, path, &filter, false, false, false)
+            .await
+            .is_err());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_header_pass_through() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("hp1");
+        let req = frl::mock_cache_activation_request(&body);
+        let upstream = || {
+            let resp = http::Response::builder()
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", "no-store")
+                .header("X-Adobe-Diagnostic", "edge-7")
+                .header("Set-Cookie", "session=secret")
+                .header("Connection", "keep-alive")
+                .body("mock-asnp")
+                .unwrap();
+            reqwest::Response::from(resp)
+        };
+        let configured = vec!["cache-control".to_string()];
+        let resp =
+            proxy::Response::from_network(&req, upstream(), &configured).await.unwrap();
+        assert_eq!(
+            resp.headers,
+            vec![("cache-control".to_string(), "no-store".to_string())]
+        );
+        let configured = vec!["*".to_string()];
+        let resp =
+            proxy::Response::from_network(&req, upstream(), &configured).await.unwrap();
+        let names: Vec<&str> =
+            resp.headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["cache-control", "x-adobe-diagnostic"]);
+        // cached responses keep the headers they were received with
+        conf.cache.store_request(&req).await;
+        conf.cache.store_response(&req, &resp).await;
+        let cached = conf.cache.fetch_response(&req).await.unwrap();
+        assert_eq!(cached.headers, resp.headers);
+        let reply: warp::reply::Response = cached.into();
+        assert_eq!(reply.headers()["x-adobe-diagnostic"], "edge-7");
+        assert!(reply.headers().get("set-cookie").is_none());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_vdi_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let vdi_body = |device_id: &str, os_user_id: &str| {
+            let mut body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            body.npd_id = "vdi-package".to_string();
+            body.device_details.os_user_id = os_user_id.to_string();
+            body.device_details.enable_vdi_marker_exists = true;
+            body.device_details.is_virtual_environment = true;
+            body
+        };
+        // two users on one pool machine, and a roaming user who is the same seat
+        for (device_id, os_user_id) in [("v1", "u1"), ("v1", "u2"), ("v2", "u1")] {
+            let req =
+                frl::mock_cache_activation_request(&vdi_body(device_id, os_user_id));
+            conf.cache.store_request(&req).await;
+        }
+        let mut physical =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("p1");
+        physical.npd_id = "vdi-package".to_string();
+        conf.cache.store_request(&frl::mock_cache_activation_request(&physical)).await;
+        let path = tempdir.join("vdi-report1.csv");
+        conf.cache
+            .report(&Datasource::Vdi, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = content.lines().find(|l| l.starts_with("vdi-package"));
+        assert_eq!(line.expect("No package in report"), "vdi-package,2,1,2,1");
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_inventory_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.frl.inventory_tokens = vec!["decoder-token".to_string()]
+        });
+        let filter = proxy::inventory_route(conf.clone());
+        let inventory =
+            adlu_parse::protocol::InventoryReport::mock_from_hostname("inv1", "inv-pkg");
+        let post = || warp::test::request().method("POST").path("/inventory/v1");
+        let response = post().json(&inventory).reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 401);
+        let auth = ("Authorization", "Bearer decoder-token");
+        let response =
+            post().header(auth.0, auth.1).json(&inventory).reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let response = post()
+            .header(auth.0, auth.1)
+            .body("{\"hostname\": \"inv2\"}")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status().as_u16(), 400);
+        let mut body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("inv-d1");
+        body.npd_id = "inv-pkg".to_string();
+        conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        let path = tempdir.join("inventory-report1.csv");
+        conf.cache
+            .report(&Datasource::Inventory, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        assert_eq!(content.lines().count(), 2);
+        let line = content.lines().nth(1).unwrap();
+        assert!(line.starts_with("inv1,"));
+        assert!(line.ends_with(",1,1"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_platform_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        // an upgraded machine only counts under its newer OS version
+        for (device_id, os_version) in
+            [("os1", "10.15.7"), ("os2", "10.15.7"), ("os2", "12.4.0"), ("os3", "12.4.0")]
+        {
+            let mut body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            body.app_details.ngl_app_id = "PlatformApp1".to_string();
+            body.device_details.os_version = os_version.to_string();
+            conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let path = tempdir.join("platform-report1.csv");
+        let path = path.to_str().unwrap();
+        let filter = ReportFilter::parse("app_id=PlatformApp1").unwrap();
+        conf.cache
+            .filtered_report(&Datasource::Platforms, path, &filter, false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(path).expect("Can't read report");
+        let lines: Vec<&str> = content.lines().skip(1).collect();
+        assert_eq!(lines.len(), 2);
+        let rest = "PlatformApp1,10.1.3,FRL Activation";
+        assert!(lines[0].starts_with(&format!("MAC,10.15.7,{rest},1,")));
+        assert!(lines[1].starts_with(&format!("MAC,12.4.0,{rest},2,")));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_activity_histogram() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.events.enabled = true;
+            settings.events.tokens = vec!["dash-token".to_string()];
+        });
+        for device_id in ["ah1", "ah2"] {
+            let body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        }
+        let filter = proxy::activity_route(conf.clone());
+        let get = |path: &str| warp::test::request().path(path).reply(&filter);
+        assert_eq!(get("/activity?bin=hour").await.status().as_u16(), 401);
+        let response = get("/activity?bin=week&token=dash-token").await;
+        assert_eq!(response.status().as_u16(), 400);
+        let response = get("/activity?bin=hour&token=dash-token").await;
+        assert_eq!(response.status().as_u16(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let last = body["bins"].as_array().unwrap().last().expect("No bins").clone();
+        let hour = adlu_base::Timestamp::now().format_rfc_3339(true)[..13].to_string();
+        assert_eq!(last["start"], format!("{}:00:00Z", hour));
+        assert!(last["activations"].as_i64().unwrap() >= 2);
+        let path = tempdir.join("activity-report1.csv");
+        conf.cache
+            .report(&Datasource::Activity, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let today = &hour[..10];
+        assert!(content.lines().any(|l| l.starts_with(today)), "No activity today");
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_report() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let result = send_nul_license(&conf, &MockOutcome::Success, "bw2").await;
+        assert_eq!(result, 200);
+        let result = send_log_upload(&conf, &MockOutcome::Success, "bw2").await;
+        assert_eq!(result, 200);
+        let path = tempdir.join("bandwidth-report1.csv");
+        conf.cache
+            .report(&Datasource::Bandwidth, path.to_str().unwrap(), false, false, false)
+            .await
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        // log upload responses have no body
+        for (request_type, responds) in [("NUL License", true), ("Log Upload", false)] {
+            let line = content.lines().find(|line| line.contains(request_type));
+            let fields: Vec<&str> =
+                line.expect("No bandwidth counted").split(',').collect();
+            assert!(fields[3].parse::<u64>().unwrap() > 0, "{}", content);
+            assert_eq!(fields[4].parse::<u64>().unwrap() > 0, responds, "{}", content);
+        }
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_annotate() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        let conf = config_with(&conf, |settings| {
+            settings.events.enabled = true;
+            settings.events.tokens = vec!["dash-token".to_string()];
+        });
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("an1");
+        let req = frl::mock_cache_activation_request(&body);
+        conf.cache.store_request(&req).await;
+        assert!(conf.cache.annotate("no-such-key", "ticket 1234").await.is_err());
+        let count = conf.cache.annotate(&req.correlation_id, "ticket 1234").await;
+        assert_eq!(count.unwrap(), 1);
+        conf.cache.annotate(&req.correlation_id, "device was reimaged").await.unwrap();
+        let path = tempdir.join("annotate-report1.csv");
+        conf.cache
+            .report(&Datasource::Frl, path.to_str().unwrap(), false, false, false)
+            .await
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let line = content.lines().find(|line| line.contains(&req.correlation_id));
+        assert!(line
+            .expect("No activation reported")
+            .ends_with(",ticket 1234; device was reimaged"));
+        let filter = proxy::notes_route(conf.clone());
+        let get = |path: String| warp::test::request().path(&path).reply(&filter);
+        assert_eq!(get("/notes".to_string()).await.status().as_u16(), 401);
+        let response =
+            get(format!("/notes?key={}&token=dash-token", req.correlation_id)).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let notes = body["notes"].as_array().unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[1]["note"], "device was reimaged");
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_admin_credentials() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.admin.users = vec![crate::settings::AdminUser {
+                username: "ops".to_string(),
+                password: "ops-secret".to_string(),
+                role: crate::settings::AdminRole::Operator,
+            }];
+            settings.admin.tokens = vec![crate::settings::AdminToken {
+                token: "root-token".to_string(),
+                role: crate::settings::AdminRole::Admin,
+            }];
+        });
+        // transfers are enabled by admin credentials, but need the admin role
+        let filter = proxy::transfer_export_route(conf.clone());
+        let get = |auth: String| {
+            warp::test::request()
+                .path("/cache/v1/frl")
+                .header("Authorization", auth)
+                .reply(&filter)
+        };
+        let basic = format!("Basic {}", adlu_base::b64encode(b"ops:ops-secret"));
+        let response = get(basic).await;
+        assert_eq!(response.status().as_u16(), 401);
+        assert!(response.headers()["www-authenticate"]
+            .to_str()
+            .unwrap()
+            .starts_with("Basic"));
+        let response = get("Bearer root-token".to_string()).await;
+        assert_eq!(response.status().as_u16(), 200);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_uninstall_hook() {
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        let conf = config_with(&conf, |settings| {
+            settings.frl.uninstall_tokens = vec!["deploy-token".to_string()]
+        });
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("un1");
+        conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        let filter = proxy::uninstall_route(conf.clone());
+        let notice = serde_json::json!({"deviceId": "un1", "npdId": &body.npd_id});
+        let send = |token: &str, notice: &serde_json::Value| {
+            warp::test::request()
+                .method("POST")
+                .path("/uninstall/v1")
+                .header("Authorization", format!("Bearer {}", token))
+                .json(notice)
+                .reply(&filter)
+        };
+        assert_eq!(send("wrong-token", &notice).await.status().as_u16(), 401);
+        let unknown = serde_json::json!({"deviceId": "un2", "npdId": &body.npd_id});
+        assert_eq!(send("deploy-token", &unknown).await.status().as_u16(), 404);
+        assert_eq!(send("deploy-token", &notice).await.status().as_u16(), 202);
+        let unanswered = conf.cache.fetch_unanswered_requests().await.unwrap();
+        assert!(unanswered.iter().any(|req| {
+            matches!(req.request_type, proxy::RequestType::FrlDeactivation)
+                && req.query.as_deref().unwrap_or_default().contains("deviceId=un1")
+        }));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_routes() {
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        let conf = config_with(&conf, |settings| {
+            settings.transfer.tokens = vec!["diode-token".to_string()]
+        });
+        let export = proxy::transfer_export_route(conf.clone());
+        let get = |path: &str, token: &str| {
+            warp::test::request()
+                .path(path)
+                .header("Authorization", format!("Bearer {}", token))
+                .reply(&export)
+        };
+        assert_eq!(get("/cache/v1/frl", "wrong-token").await.status().as_u16(), 401);
+        assert_eq!(get("/cache/v1/nonesuch", "diode-token").await.status().as_u16(), 404);
+        let response = get("/cache/v1/frl", "diode-token").await;
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["Content-Type"], "application/vnd.sqlite3");
+        assert!(response.body().starts_with(b"SQLite format 3"));
+        let import = proxy::transfer_import_route(conf.clone());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/cache/v1/frl")
+            .header("Authorization", "Bearer diode-token")
+            .body("not an export")
+            .reply(&import)
+            .await;
+        assert_eq!(response.status().as_u16(), 500);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_package_metadata() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let package_path =
+            "../rsrc/packages/mac/online-default-allapps/ngl-preconditioning-data.json";
+        conf.cache
+            .import(&Datasource::Packages, package_path)
+            .await
+            .expect("Package import failed");
+        let mut body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("pkg-d1");
+        body.npd_id = "NzBjZmVlYWItNzc2Ni00ZTNiLTk4NjQtNjczYjc5ZDM2ZGRk".to_string();
+        conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        let path = tempdir.join("frl-report2.csv");
+        conf.cache
+            .report(&Datasource::Frl, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = content.lines().find(|l| l.contains("pkg-d1")).expect("No activation");
+        let fields: Vec<&str> = line.split(',').collect();
+        assert_eq!(fields[3], "online-default-allapps");
+        assert_eq!(fields[4], "FRL_CONNECTED");
+        assert_eq!(fields[5], "90");
+        let path = tempdir.join("packages-report1.csv");
+        conf.cache
+            .report(&Datasource::Packages, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        assert!(content.contains(",online-default-allapps,FRL_CONNECTED,90,"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_directory_users() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let users_path = tempdir.join("directory-users.txt");
+        std::fs::write(&users_path, "# exported from AD\nEXAMPLE\\Dir-User\n\n").unwrap();
+        conf.cache
+            .import(&Datasource::Users, users_path.to_str().unwrap())
+            .await
+            .expect("User import failed");
+        let mut body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("usr-d1");
+        body.device_details.os_user_id = crate::cache::os_user_id("dir-user");
+        conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        let path = tempdir.join("frl-report-users.csv");
+        conf.cache
+            .report(&Datasource::Frl, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = content.lines().find(|l| l.contains("usr-d1")).expect("No activation");
+        assert!(line.contains(r",EXAMPLE\Dir-User,"), "No username: {}", line);
+        let path = tempdir.join("users-report1.csv");
+        conf.cache
+            .report(&Datasource::Users, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        assert_eq!(content.lines().count(), 5, "Wrong users report: {}", content);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_client_locations() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let mut settings = conf.settings.as_ref().clone();
+        settings.geoip.campuses = vec![super::settings::Campus {
+            name: "North".to_string(),
+            subnets: vec!["10.20.0.0/16".to_string()],
+        }];
+        let geoip = super::geoip::GeoIp::new(&Settings::new(settings)).unwrap();
+        assert!(geoip.locate("192.168.1.1".parse().unwrap()).is_none());
+        let location =
+            geoip.locate("::ffff:10.20.3.4".parse().unwrap()).expect("No location");
+        assert_eq!(location.campus, "North");
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("geo-d1");
+        let req = frl::mock_cache_activation_request(&body);
+        conf.cache.store_request(&req).await;
+        conf.cache.store_location(&req, &location).await;
+        let path = tempdir.join("frl-report3.csv");
+        conf.cache
+            .report(&Datasource::Frl, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = content.lines().find(|l| l.contains("geo-d1")).expect("No activation");
+        assert!(line.contains(",,,North,"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let conf = get_test_config(&ProxyMode::Simulate).await;
+        let conf = config_with(&conf, |settings| {
+            settings.events.enabled = true;
+            settings.events.tokens = vec!["event-token".to_string()];
+        });
+        let filter = proxy::events_route(conf.clone());
+        let response = warp::test::request().path("/events").reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 401);
+        let response =
+            warp::test::request().path("/events?token=wrong").reply(&filter).await;
+        assert_eq!(response.status().as_u16(), 401);
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("ev1");
+        let req = frl::mock_cache_activation_request(&body);
+        proxy::send_request(&conf, &req).await;
+        let (history, _) = conf.events.subscribe();
+        let event = history.last().expect("No event published");
+        assert_eq!(event.correlation_id, req.correlation_id);
+        assert_eq!(event.outcome, "simulated");
+        assert_eq!(event.device_hash.len(), 16);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_package_quota() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.frl.quotas = vec![crate::settings::PackageQuota {
+                npd_id: "quota-pkg".to_string(),
+                name: "Quota Test".to_string(),
+                max_activations: 1,
+                ..Default::default()
+            }];
+        });
+        let request = |device_id: &str| {
+            let mut body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            body.npd_id = "quota-pkg".to_string();
+            frl::mock_cache_activation_request(&body)
+        };
+        assert!(proxy::check_package_quota(&conf, &request("quota1")).await.is_none());
+        conf.cache.store_request(&request("quota1")).await;
+        // the device holding the activation can refresh it, but no others can activate
+        assert!(proxy::check_package_quota(&conf, &request("quota1")).await.is_none());
+        let reason = proxy::check_package_quota(&conf, &request("quota2")).await;
+        assert!(reason.expect("Quota not enforced").contains("Quota Test"));
+        // other packages are unaffected
+        let other =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("quota2");
+        let other = frl::mock_cache_activation_request(&other);
+        assert!(proxy::check_package_quota(&conf, &other).await.is_none());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_org_tagging() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf = config_with(&conf, |settings| {
+            settings.frl.orgs = vec![crate::settings::OrgMapping {
+                org_id: "org-acme".to_string(),
+                name: "Acme".to_string(),
+                npd_ids: vec!["acme-pkg1".to_string(), "acme-pkg2".to_string()],
+            }];
+            settings.frl.quotas = vec![crate::settings::PackageQuota {
+                org_id: "org-acme".to_string(),
+                max_activations: 1,
+                ..Default::default()
+            }];
+        });
+        conf.cache.store_org_mappings(&conf.settings.frl.orgs).await.unwrap();
+        let request = |device_id: &str, npd_id: &str| {
+            let mut body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            body.npd_id = npd_id.to_string();
+            frl::mock_cache_activation_request(&body)
+        };
+        conf.cache.store_request(&request("org-d1", "acme-pkg1")).await;
+        // the org's quota covers all its packages
+        let reason =
+            proxy::check_package_quota(&conf, &request("org-d2", "acme-pkg2")).await;
+        assert!(reason.expect("Quota not enforced").contains("org-acme"));
+        let other = request("org-d2", "other-pkg");
+        assert!(proxy::check_package_quota(&conf, &other).await.is_none());
+        conf.cache.store_request(&other).await;
+        let path = tempdir.join("frl-org-report.csv");
+        let filter =
+            super::report_filter(&Datasource::Frl, None, Some("org-acme")).unwrap();
+        conf.cache
+            .filtered_report(
+                &Datasource::Frl,
+                path.to_str().unwrap(),
+                &filter,
+                false,
+                false,
+                false,
+            )
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        assert!(content.contains(",org-acme,org-d1,"), "{}", content);
+        assert!(!content.contains("org-d2"), "{}", content);
+        assert!(super::report_filter(&Datasource::Log, None, Some("org-acme")).is_err());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_simulate_mode() {
+        let conf = get_test_config(&ProxyMode::Simulate).await;
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("sim1");
+        let req = frl::mock_cache_activation_request(&body);
+        let resp = match proxy::send_request(&conf, &req).await {
+            proxy::SendOutcome::Success(resp) => resp,
+            _ => panic!("Simulated activation failed"),
+        };
+        assert!(resp.body.unwrap().contains(crate::simulate::SIMULATED_MARKER));
+        // other tests share the cache, so look only for this request
+        let unanswered = conf.cache.fetch_unanswered_requests().await.unwrap();
+        assert!(
+            !unanswered.iter().any(|r| r.body == req.body),
+            "Simulated request was cached"
+        );
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_import() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let make_response = |req: &proxy::Request| proxy::Response {
+            timestamp: req.timestamp.clone(),
+            request_type: req.request_type.clone(),
+            status: http::StatusCode::OK,
+            body: Some("{}".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        // make an export with one answered activation
+        let path = tempdir.join("import-source.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap().to_string();
+        let db_settings =
+            crate::settings::Proxy { db_path: path.clone(), ..Default::default() };
+        let source = crate::cache::connect(&db_settings).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("im1");
+        let activation = frl::mock_cache_activation_request(&body);
+        source.store_request(&activation).await;
+        source.store_response(&activation, &make_response(&activation)).await;
+        source.close().await;
+        conf.cache.import(&Datasource::Frl, &path).await.expect("Import failed");
+        assert!(conf.cache.fetch_response(&activation).await.is_some());
+        // a later deactivation must not be undone by importing the export again
+        let params =
+            adlu_parse::protocol::FrlDeactivationQueryParams::mock_from_device_id("im1");
+        let deactivation = frl::mock_cache_deactivation_request(&params);
+        conf.cache.store_request(&deactivation).await;
+        conf.cache.store_response(&deactivation, &make_response(&deactivation)).await;
+        assert!(conf.cache.fetch_response(&activation).await.is_none());
+        conf.cache.import(&Datasource::Frl, &path).await.expect("Import failed");
+        assert!(conf.cache.fetch_response(&activation).await.is_none());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_archive_import() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        // make an export with one answered activation, and pack it
+        let path = tempdir.join("archive-source.sqlite");
+        let archive = tempdir.join("archive-source.sqlite.tar.zst");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&archive);
+        let path = path.to_str().unwrap().to_string();
+        let archive = archive.to_str().unwrap().to_string();
+        let db_settings =
+            crate::settings::Proxy { db_path: path.clone(), ..Default::default() };
+        let source = crate::cache::connect(&db_settings).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("ar1");
+        let activation = frl::mock_cache_activation_request(&body);
+        let response = proxy::Response {
+            timestamp: activation.timestamp.clone(),
+            request_type: activation.request_type.clone(),
+            status: http::StatusCode::OK,
+            body: Some("{\"archived\": true}".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        source.store_request(&activation).await;
+        source.store_response(&activation, &response).await;
+        source.close().await;
+        crate::cache::pack_archive(&path).await.expect("Pack failed");
+        // an archive that was cut short is refused
+        let data = std::fs::read(&archive).unwrap();
+        let short = tempdir.join("archive-short.tar.zst");
+        std::fs::write(&short, &data[..data.len() / 2]).unwrap();
+        let short = short.to_str().unwrap();
+        assert!(conf.cache.import(&Datasource::Frl, short).await.is_err());
+        // but a whole one is imported, payloads and all
+        conf.cache.import(&Datasource::Frl, &archive).await.expect("Import failed");
+        let imported = conf.cache.fetch_response(&activation).await.expect("No response");
+        assert_eq!(imported.body, response.body);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_template_export() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        for device_id in ["tpl1", "tpl2"] {
+            let mut body =
+                adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id(
+                    device_id,
+                );
+            body.npd_id = "tpl-package".to_string();
+            conf.cache.store_request(&frl::mock_cache_activation_request(&body)).await;
+        }
+        // a returned license isn't held any more
+        let mut params =
+            adlu_parse::protocol::FrlDeactivationQueryParams::mock_from_device_id("tpl2");
+        params.npd_id = "tpl-package".to_string();
+        conf.cache.store_request(&frl::mock_cache_deactivation_request(&params)).await;
+        let path = tempdir.join("template-export1.csv");
+        let path = path.to_str().unwrap();
+        template::export(&conf.settings, &conf.cache, "adobe-return", path, None)
+            .await
+            .expect("Export failed");
+        let content = std::fs::read_to_string(path).expect("Can't read export");
+        assert!(content.starts_with("Org ID,Package ID,Package Name,Device ID,"));
+        let lines: Vec<&str> =
+            content.lines().filter(|l| l.contains("tpl-package")).collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(",tpl1,"));
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_import_conflict() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let make_response = |req: &proxy::Request| proxy::Response {
+            timestamp: req.timestamp.clone(),
+            request_type: req.request_type.clone(),
+            status: http::StatusCode::OK,
+            body: Some("{}".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        // make an export with an answered activation...
+        let path = tempdir.join("conflict-source.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap().to_string();
+        let db_settings =
+            crate::settings::Proxy { db_path: path.clone(), ..Default::default() };
+        let source = crate::cache::connect(&db_settings).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("cf1");
+        let activation = frl::mock_cache_activation_request(&body);
+        source.store_request(&activation).await;
+        source.store_response(&activation, &make_response(&activation)).await;
+        source.close().await;
+        // ...that is older than a deactivation of the same device in the cache
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let params =
+            adlu_parse::protocol::FrlDeactivationQueryParams::mock_from_device_id("cf1");
+        let deactivation = frl::mock_cache_deactivation_request(&params);
+        conf.cache.store_request(&deactivation).await;
+        conf.cache.store_response(&deactivation, &make_response(&deactivation)).await;
+        conf.cache.import(&Datasource::Frl, &path).await.expect("Import failed");
+        assert!(conf.cache.fetch_response(&activation).await.is_none());
+        assert!(conf.cache.fetch_response(&deactivation).await.is_some());
+        let report = tempdir.join("conflict-report.csv");
+        let report = report.to_str().unwrap();
+        conf.cache
+            .report(&Datasource::Conflicts, report, false, false, false)
+            .await
+            .unwrap();
+        let content = std::fs::read_to_string(report).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2, "Wrong conflicts report: {}", content);
+        assert!(lines[1].contains(",Activation,"), "Wrong conflict: {}", lines[1]);
+        assert!(lines[1].ends_with("Kept Cached"), "Wrong resolution: {}", lines[1]);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_legacy_import() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let path = tempdir.join("legacy-cache.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap().to_string();
+        // make a legacy cache with one answered activation
+        let legacy = sqlx::SqlitePool::connect(&format!("sqlite:{}?mode=rwc", &path))
+            .await
+            .unwrap();
+        let schema = r#"
+            create table activation_request_log (
+                activation_key text not null unique, deactivation_key text not null,
+                api_key text not null, request_id text not null,
+                session_id text not null, device_date text not null,
+                package_id text not null, asnp_id text not null,
+                device_id text not null, os_user_id text not null,
+                is_vdi boolean not null, is_domain_user boolean not null,
+                is_virtual boolean not null, os_name text not null,
+                os_version text not null, app_id text not null,
+                app_version text not null, ngl_version text not null,
+                timestamp string not null);
+            create table activation_response_cache (
+                activation_key text not null unique, deactivation_key text not null,
+                body text not null, timestamp string not null);
+            create table deactivation_request_log (
+                deactivation_key text not null unique, api_key text not null,
+                request_id text not null, package_id text not null,
+                device_id text not null, os_user_id text not null,
+                is_domain_user boolean not null, is_vdi boolean not null,
+                is_virtual boolean not null, timestamp string not null);
+            create table deactivation_response_cache (
+                deactivation_key text not null unique,
+                body text not null, timestamp string not null);"#;
+        sqlx::query(schema).execute(&legacy).await.unwrap();
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("leg1");
+        let (app, device) = (&body.app_details, &body.device_details);
+        let timestamp = "2022-06-28T17:08:01.895-0700";
+        let i_str = r#"
+            insert into activation_request_log values
+            ('ak1', 'dk1', 'ngl_mock1', 'legacy1', 'session1', ?, ?, ?, ?, ?,
+             ?, ?, ?, ?, ?, ?, ?, ?, ?)"#;
+        sqlx::query(i_str)
+            .bind(&device.current_date)
+            .bind(&body.npd_id)
+            .bind(&body.asnp_template_id)
+            .bind(&device.device_id)
+            .bind(&device.os_user_id)
+            .bind(device.enable_vdi_marker_exists)
+            .bind(device.is_os_user_account_in_domain)
+            .bind(device.is_virtual_environment)
+            .bind(&device.os_name)
+            .bind(&device.os_version)
+            .bind(&app.ngl_app_id)
+            .bind(&app.ngl_app_version)
+            .bind(&app.ngl_lib_version)
+            .bind(timestamp)
+            .execute(&legacy)
+            .await
+            .unwrap();
+        let i_str =
+            "insert into activation_response_cache values ('ak1', 'dk1', '{}', ?)";
+        sqlx::query(i_str).bind(timestamp).execute(&legacy).await.unwrap();
+        legacy.close().await;
+        conf.cache
+            .import_legacy(&Datasource::Frl, &path)
+            .await
+            .expect("Legacy import failed");
+        let activation = frl::mock_cache_activation_request(&body);
+        assert!(conf.cache.fetch_response(&activation).await.is_some());
+        let report = tempdir.join("legacy-import-report.csv");
+        conf.cache
+            .report(&Datasource::Frl, report.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&report).expect("Can't read report");
+        assert!(content.contains("2022-06-29T00:08:01.895"), "Wrong timestamp");
+        // a non-legacy database is refused
+        assert!(conf
+            .cache
+            .import_legacy(&Datasource::Frl, report.to_str().unwrap())
+            .await
+            .is_err());
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf =
+            config_with(&conf, |settings| settings.frl.stale_while_revalidate = true);
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("swr1");
+        let req = frl::mock_cache_activation_request(&body);
+        let stale = proxy::Response {
+            timestamp: req.timestamp.clone(),
+            request_type: proxy::RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: Some("stale".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        conf.cache.store_request(&req).await;
+        conf.cache.store_response(&req, &stale).await;
+        let mut req = frl::mock_cache_activation_request(&body);
+        frl::mock_forwardable_activation(&mut req, &MockOutcome::Success);
+        let reply = proxy::process_adobe_request(req.clone(), conf.clone()).await;
+        assert_eq!(reply.status().as_u16(), 200);
+        let reply_body = warp::hyper::body::to_bytes(reply.into_body()).await.unwrap();
+        assert_eq!(reply_body, "stale");
+        // the background forward replaces the stale response
+        let mut refreshed = false;
+        for _ in 0..50 {
+            let resp = conf.cache.fetch_response(&req).await.unwrap();
+            if resp.body.unwrap() != "stale" {
+                refreshed = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(refreshed, "Cached response was not revalidated");
+        // the client got the cached response, and only that is recorded
+        let (history, _) = conf.events.subscribe();
+        let outcomes: Vec<&str> = history
+            .iter()
+            .filter(|event| event.correlation_id == req.correlation_id)
+            .map(|event| event.outcome.as_str())
+            .collect();
+        assert_eq!(outcomes, vec!["cache-hit"]);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_latency_budget() {
+        let conf = get_test_config(&ProxyMode::Connected).await;
+        let conf =
+            config_with(&conf, |settings| settings.upstream.activation_budget_ms = 200);
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("lb1");
+        let mut req = frl::mock_cache_activation_request(&body);
+        // Adobe answers, but not within the budget
+        frl::mock_forwardable_activation(&mut req, &MockOutcome::Slow);
+        let cached = proxy::Response {
+            timestamp: req.timestamp.clone(),
+            request_type: proxy::RequestType::FrlActivation,
+            status: http::StatusCode::OK,
+            body: Some("cached".to_string()),
+            content_type: None,
+            server: None,
+            via: None,
+            request_id: None,
+            session_id: None,
+            headers: vec![],
+        };
+        conf.cache.store_request(&req).await;
+        conf.cache.store_response(&req, &cached).await;
+        let start = std::time::Instant::now();
+        let reply = proxy::process_adobe_request(req.clone(), conf.clone()).await;
+        assert!(start.elapsed() < SLOW_RESPONSE_DELAY);
+        assert_eq!(reply.status().as_u16(), 200);
+        let reply_body = warp::hyper::body::to_bytes(reply.into_body()).await.unwrap();
+        assert_eq!(reply_body, "cached");
+        // Adobe's late answer refreshes the cache, but isn't recorded
+        let mut refreshed = false;
+        for _ in 0..50 {
+            let resp = conf.cache.fetch_response(&req).await.unwrap();
+            if resp.body.unwrap() != "cached" {
+                refreshed = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(refreshed, "Late answer was not cached");
+        let (history, _) = conf.events.subscribe();
+        let outcomes: Vec<&str> = history
+            .iter()
+            .filter(|event| event.correlation_id == req.correlation_id)
+            .map(|event| event.outcome.as_str())
+            .collect();
+        assert_eq!(outcomes, vec!["cache-hit"]);
+        release_test_config(conf).await;
+    }
+
+    #[tokio::test]
+    async fn test_lenient_parsing() {
+        let tempdir = get_test_directory().await;
+        let conf = get_test_config(&ProxyMode::Isolated).await;
+        // don't count the drifted bodies as validation failures
+        let conf = config_with(&conf, |settings| {
+            settings.security.validate_request_bodies = false;
+        });
+        let body =
+            adlu_parse::protocol::FrlActivationRequestBody::mock_from_device_id("lp1");
+        let mut val = serde_json::to_value(&body).unwrap();
+        let details = val["deviceDetails"].as_object_mut().unwrap();
+        let device_id = details.remove("deviceId").unwrap();
+        details.insert("deviceID".to_string(), device_id);
+        details.insert("isVirtualEnvironment".to_string(), serde_json::json!("false"));
+        let mut strict = frl::mock_cache_activation_request(&body);
+        strict.body = Some(val.to_string());
+        proxy::process_adobe_request(strict.clone(), conf.clone()).await;
+        let conf =
+            config_with(&conf, |settings| settings.security.lenient_parsing = true);
+        let mut lenient = strict.clone();
+        lenient.correlation_id = proxy::Request::new_correlation_id();
+        proxy::process_adobe_request(lenient.clone(), conf.clone()).await;
+        let path = tempdir.join("payload-report1.csv");
+        conf.cache
+            .report(&Datasource::Payloads, path.to_str().unwrap(), false, false, false)
+            .await
+            .expect("Report failed");
+        let content = std::fs::read_to_string(&path).expect("Can't read report");
+        let line = |id: &str| {
+            content.lines().find(|l| l.contains(id)).expect("No failure in report")
+        };
+        assert!(line(&strict.correlation_id).contains(",false,"));
+        assert!(line(&lenient.correlation_id).contains(",true,"));
+        assert!(line(&lenient.correlation_id).contains("deviceID"));
+        release_test_config(conf).await;
+    }
+
     #[tokio::test]
     async fn test_log_upload_report() {
         let tempdir = get_test_directory().await;
@@ -0,0 +1,464 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+The accept loop for the proxy's servers, which protects the proxy from slow
+and wedged clients and counts the connections it serves.
+
+There is a limit on how many connections can be open at once: when it's
+reached, new connections wait in the listen backlog until others close.
+A client has a limited time to send each request header (and to finish its
+TLS handshake), and a connection that neither reads nor writes anything for
+the idle timeout is closed, so a client that stops partway through a request
+body can't hold its socket forever.
+ */
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use eyre::{eyre, Result, WrapErr};
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::Body;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::Sleep;
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+use adlu_base::CertificateData;
+use adlu_parse::protocol::ProxiedPeer;
+
+use crate::proxy_protocol::read_header;
+use crate::settings::Proxy;
+
+/// The limits on the connections a server accepts.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// The most connections that can be open at once (0 for no limit).
+    pub max_connections: usize,
+    /// How long a client has to send a request header or finish its handshake.
+    pub header_timeout: Duration,
+    /// How long a connection can go without reading or writing anything.
+    pub idle_timeout: Duration,
+}
+
+impl From<&Proxy> for ConnectionLimits {
+    fn from(proxy: &Proxy) -> Self {
+        ConnectionLimits {
+            max_connections: proxy.max_connections,
+            header_timeout: Duration::from_secs(proxy.header_timeout_secs.max(1)),
+            idle_timeout: Duration::from_secs(proxy.idle_timeout_secs.max(1)),
+        }
+    }
+}
+
+/// Counts of the connections the proxy's servers have handled.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    active: AtomicUsize,
+    accepted: AtomicU64,
+    limited: AtomicU64,
+    timed_out: AtomicU64,
+    tls_handshakes: AtomicU64,
+    tls_failures: AtomicU64,
+    tls_handshake_micros: AtomicU64,
+}
+
+impl ConnectionStats {
+    /// The number of connections open right now.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// The counts, as reported by the status endpoint.
+    pub fn to_json(&self) -> Value {
+        let handshakes = self.tls_handshakes.load(Ordering::Relaxed);
+        let micros = self.tls_handshake_micros.load(Ordering::Relaxed);
+        let average_millis = micros.checked_div(handshakes).unwrap_or(0) / 1000;
+        json!({
+            "active": self.active(),
+            "accepted": self.accepted.load(Ordering::Relaxed),
+            "limited": self.limited.load(Ordering::Relaxed),
+            "timedOut": self.timed_out.load(Ordering::Relaxed),
+            "tlsHandshakes": handshakes,
+            "tlsFailures": self.tls_failures.load(Ordering::Relaxed),
+            "tlsHandshakeAverageMillis": average_millis,
+        })
+    }
+}
+
+/// Counts a connection as active for as long as it's open.
+struct ActiveConnection(Arc<ConnectionStats>);
+
+impl ActiveConnection {
+    fn new(stats: &Arc<ConnectionStats>) -> Self {
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        stats.accepted.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(stats.clone())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A TLS acceptor that presents the proxy's certificate.
+pub fn tls_acceptor(cert_data: &CertificateData) -> Result<TlsAcceptor> {
+    let pfx = cert_data.to_pfx("", "adlu-proxy")?;
+    let identity = native_tls::Identity::from_pkcs12(&pfx, "")
+        .wrap_err("Can't use the SSL certificate")?;
+    let acceptor =
+        native_tls::TlsAcceptor::new(identity).wrap_err("Can't create TLS acceptor")?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+/// Serve the connections made to the listener until the stop signal.
+/// If `proxy_header` is set, each connection must start with a PROXY header.
+/// If there is a TLS acceptor, connections are decrypted (after their header).
+pub async fn serve<S>(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    proxy_header: bool,
+    limits: ConnectionLimits,
+    stats: Arc<ConnectionStats>,
+    service: S,
+    stop_signal: impl Future<Output = ()>,
+) where
+    S: Service<
+            hyper::Request<Body>,
+            Response = hyper::Response<Body>,
+            Error = Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let slots = Arc::new(Semaphore::new(match limits.max_connections {
+        0 => Semaphore::MAX_PERMITS,
+        n => n,
+    }));
+    tokio::pin!(stop_signal);
+    loop {
+        if slots.available_permits() == 0 {
+            stats.limited.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Reached the limit of {} open connections; new ones must wait",
+                limits.max_connections
+            );
+        }
+        let slot = tokio::select! {
+            _ = &mut stop_signal => break,
+            slot = slots.clone().acquire_owned() => slot.expect("Connection slots closed"),
+        };
+        let (stream, remote) = tokio::select! {
+            _ = &mut stop_signal => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("Failed to accept a connection: {}", err);
+                    continue;
+                }
+            },
+        };
+        let active = ActiveConnection::new(&stats);
+        let (tls, limits, service) = (tls.clone(), limits.clone(), service.clone());
+        tokio::spawn(async move {
+            let stats = &active.0;
+            let result = serve_connection(
+                stream,
+                remote,
+                tls,
+                proxy_header,
+                &limits,
+                stats,
+                service,
+            )
+            .await;
+            if let Err(err) = result {
+                if is_timeout(&err) {
+                    stats.timed_out.fetch_add(1, Ordering::Relaxed);
+                }
+                debug!("Dropped connection from {}: {:#}", remote, err);
+            }
+            drop(slot);
+        });
+    }
+}
+
+async fn serve_connection<S>(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    proxy_header: bool,
+    limits: &ConnectionLimits,
+    stats: &ConnectionStats,
+    service: S,
+) -> Result<()>
+where
+    S: Service<
+            hyper::Request<Body>,
+            Response = hyper::Response<Body>,
+            Error = Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let peer = if proxy_header {
+        tokio::time::timeout(limits.header_timeout, read_header(&mut stream))
+            .await
+            .map_err(|_| timed_out("waiting for a PROXY header"))??
+    } else {
+        Some(remote)
+    };
+    let service = hyper::service::service_fn(move |mut req: hyper::Request<Body>| {
+        if let Some(peer) = peer {
+            req.extensions_mut().insert(ProxiedPeer(peer));
+        }
+        service.clone().call(req)
+    });
+    let stream = IdleTimeout::new(stream, limits.idle_timeout);
+    let mut http = Http::new();
+    http.http1_header_read_timeout(limits.header_timeout);
+    match tls {
+        Some(tls) => {
+            let start = Instant::now();
+            let stream =
+                match tokio::time::timeout(limits.header_timeout, tls.accept(stream))
+                    .await
+                {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        stats.tls_failures.fetch_add(1, Ordering::Relaxed);
+                        return Err(eyre!(err).wrap_err("TLS handshake failed"));
+                    }
+                    Err(_) => {
+                        stats.tls_failures.fetch_add(1, Ordering::Relaxed);
+                        return Err(timed_out("during TLS handshake"));
+                    }
+                };
+            let micros = start.elapsed().as_micros() as u64;
+            stats.tls_handshakes.fetch_add(1, Ordering::Relaxed);
+            stats.tls_handshake_micros.fetch_add(micros, Ordering::Relaxed);
+            http.serve_connection(stream, service).await?;
+        }
+        None => http.serve_connection(stream, service).await?,
+    }
+    Ok(())
+}
+
+fn timed_out(during: &str) -> eyre::Report {
+    eyre!(io::Error::new(io::ErrorKind::TimedOut, format!("Timed out {}", during)))
+}
+
+fn is_timeout(err: &eyre::Report) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<io::Error>() {
+            err.kind() == io::ErrorKind::TimedOut
+        } else if let Some(err) = cause.downcast_ref::<hyper::Error>() {
+            // hyper doesn't flag its header read timeout as a timeout
+            err.is_timeout() || err.to_string().contains("timeout")
+        } else {
+            false
+        }
+    })
+}
+
+/// A stream that fails with a timeout if it goes too long
+/// without reading or writing anything.
+struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(inner: S, timeout: Duration) -> Self {
+        let deadline = Box::pin(tokio::time::sleep(timeout));
+        IdleTimeout { inner, timeout, deadline }
+    }
+
+    fn poll_progress<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(result) => {
+                let next = tokio::time::Instant::now() + self.timeout;
+                self.deadline.as_mut().reset(next);
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Connection was idle too long",
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.poll_progress(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.poll_progress(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.poll_progress(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use warp::Filter;
+
+    use super::{serve, ConnectionLimits, ConnectionStats};
+
+    fn limits(max_connections: usize) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections,
+            header_timeout: Duration::from_millis(200),
+            idle_timeout: Duration::from_millis(500),
+        }
+    }
+
+    fn peer_filter(
+    ) -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+        adlu_parse::protocol::peer_addr().map(|peer: Option<SocketAddr>| {
+            peer.map(|p| p.to_string()).unwrap_or_default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_header() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(ConnectionStats::default());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            None,
+            true,
+            limits(0),
+            stats.clone(),
+            warp::service(peer_filter()),
+            async {
+                stopped.await.ok();
+            },
+        ));
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"PROXY TCP4 203.0.113.9 127.0.0.1 4321 80\r\n\
+                GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("203.0.113.9:4321"), "{}", response);
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert_eq!(stats.to_json()["accepted"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_limits() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(ConnectionStats::default());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            None,
+            false,
+            limits(1),
+            stats.clone(),
+            warp::service(peer_filter()),
+            async {
+                stopped.await.ok();
+            },
+        ));
+        // a client that never finishes its header holds the only slot...
+        let mut wedged = tokio::net::TcpStream::connect(addr).await.unwrap();
+        wedged.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(stats.active(), 1);
+        // ...until it times out, so the next client gets served
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with(&stream.local_addr().unwrap().to_string()));
+        let mut rest = Vec::new();
+        assert!(wedged.read_to_end(&mut rest).await.map_or(true, |n| n == 0));
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        let counts = stats.to_json();
+        assert_eq!(counts["accepted"], 2);
+        assert_eq!(counts["timedOut"], 1);
+        assert!(counts["limited"].as_u64().unwrap() >= 1);
+    }
+}
@@ -16,17 +16,19 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use chrono::{DateTime, Local, LocalResult, TimeZone};
 use eyre::{eyre, Result, WrapErr};
-use log::LevelFilter;
+use log::{Level, LevelFilter};
 use log4rs::{
     append::{
         console::{ConsoleAppender, Target},
         file::FileAppender,
         rolling_file::{
             policy::compound::{
-                roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger,
-                trigger::Trigger, CompoundPolicy,
+                roll::fixed_window::FixedWindowRoller, roll::Roll,
+                trigger::size::SizeTrigger, trigger::Trigger, CompoundPolicy,
             },
             LogFile, RollingFileAppender,
         },
@@ -36,12 +38,18 @@ use log4rs::{
 };
 
 use crate::settings::{LogDestination, LogLevel, LogRotationType, Logging};
+use crate::syslog::SyslogAppender;
+
+/// Whether critical events are also sent to the OS-native log.
+static PLATFORM_LOG: AtomicBool = AtomicBool::new(false);
 
 pub fn init(logging: &Logging) -> Result<()> {
     let pattern = "{d([%Y-%m-%d][%H:%M:%S])}[{P:5}][{t}][{l}] {m}{n}";
     let encoder = PatternEncoder::new(pattern);
     let filter = log_level(&logging.level);
-    let appender = if let LogDestination::Console = &logging.destination {
+    let appender = if let LogDestination::Syslog = &logging.destination {
+        Appender::builder().build("logger", Box::new(SyslogAppender::new(logging)?))
+    } else if let LogDestination::Console = &logging.destination {
         Appender::builder().build(
             "logger",
             Box::new(
@@ -62,19 +70,24 @@ pub fn init(logging: &Logging) -> Result<()> {
             ),
         )
     } else {
-        let window_size = logging.rotate_count;
-        let pattern = roll_pattern(&logging.file_path);
-        let fixed_window_roller = FixedWindowRoller::builder()
-            .build(&pattern, window_size)
-            .map_err(|err| eyre!("Can't build log rotation config: {:?}", err))?;
-        let compound_policy = if let LogRotationType::Sized = logging.rotate_type {
-            let size_limit = 1024 * logging.rotate_size_kb;
-            let size_trigger = SizeTrigger::new(size_limit);
-            CompoundPolicy::new(Box::new(size_trigger), Box::new(fixed_window_roller))
-        } else {
-            let daily_trigger = DailyTrigger::new();
-            CompoundPolicy::new(Box::new(daily_trigger), Box::new(fixed_window_roller))
-        };
+        let roller = AgedRoller::new(logging)?;
+        roller.remove_expired();
+        // rotating at startup is done before the log is opened, so the
+        // rotated file has only what was logged by earlier runs
+        let path = std::path::Path::new(&logging.file_path);
+        if logging.rotate_on_startup && std::fs::metadata(path).map_or(0, |m| m.len()) > 0
+        {
+            roller
+                .roll(path)
+                .map_err(|err| eyre!("Can't rotate log at startup: {:?}", err))?;
+        }
+        let trigger: Box<dyn Trigger> =
+            if let LogRotationType::Sized = logging.rotate_type {
+                Box::new(SizeTrigger::new(1024 * logging.rotate_size_kb))
+            } else {
+                Box::new(DailyTrigger::new())
+            };
+        let compound_policy = CompoundPolicy::new(trigger, Box::new(roller));
         Appender::builder().build(
             "logger",
             Box::new(
@@ -90,9 +103,74 @@ pub fn init(logging: &Logging) -> Result<()> {
         .build(Root::builder().appender("logger").build(filter))
         .wrap_err("Can't create root logging configuration")?;
     log4rs::init_config(config).wrap_err("Can't initialize logging")?;
+    if logging.platform_log {
+        if cfg!(any(windows, target_os = "macos")) {
+            PLATFORM_LOG.store(true, Ordering::Relaxed);
+        } else {
+            log::warn!("Platform logging is only available on Windows and Mac");
+        }
+    }
+    Ok(())
+}
+
+/// Log a critical event: startup and shutdown, upstream outages, and
+/// certificate problems.  These are logged as usual and, if platform
+/// logging is enabled, are also sent to the OS-native log (the Windows
+/// Event Log or the Mac unified log), which is what site admins watch.
+pub fn critical_event(level: Level, message: &str) {
+    log::log!(level, "{}", message);
+    if PLATFORM_LOG.load(Ordering::Relaxed) {
+        // the platform tools can be slow, so don't make the caller wait
+        let (level, message) = (level, message.to_string());
+        std::thread::spawn(move || {
+            if let Err(err) = platform_log(level, &message) {
+                log::warn!("Can't write to the platform log: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(windows)]
+fn platform_log(level: Level, message: &str) -> Result<()> {
+    let (kind, id) = match level {
+        Level::Error => ("ERROR", "1"),
+        Level::Warn => ("WARNING", "2"),
+        _ => ("INFORMATION", "3"),
+    };
+    let status = std::process::Command::new("eventcreate")
+        .args(["/L", "APPLICATION", "/SO", env!("CARGO_PKG_NAME"), "/T", kind, "/ID", id])
+        .args(["/D", message])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(eyre!("eventcreate failed: {}", status));
+    }
     Ok(())
 }
 
+#[cfg(target_os = "macos")]
+fn platform_log(level: Level, message: &str) -> Result<()> {
+    // on Mac, messages sent with `logger` go to the unified log
+    let priority = match level {
+        Level::Error => "user.err",
+        Level::Warn => "user.warning",
+        _ => "user.notice",
+    };
+    let status = std::process::Command::new("/usr/bin/logger")
+        .args(["-p", priority, "-t", env!("CARGO_PKG_NAME"), message])
+        .status()?;
+    if !status.success() {
+        return Err(eyre!("logger failed: {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn platform_log(_level: Level, _message: &str) -> Result<()> {
+    Err(eyre!("there is no platform log on this OS"))
+}
+
 fn log_level(level: &LogLevel) -> LevelFilter {
     match level {
         LogLevel::Off => LevelFilter::Off,
@@ -104,8 +182,64 @@ fn log_level(level: &LogLevel) -> LevelFilter {
     }
 }
 
-fn roll_pattern(log_name: &str) -> String {
-    format!("{}.{{}}.gz", log_name)
+/// Rotated logs are gzipped if their names end in `.gz`.
+fn roll_pattern(log_name: &str, compress: bool) -> String {
+    if compress {
+        format!("{}.{{}}.gz", log_name)
+    } else {
+        format!("{}.{{}}", log_name)
+    }
+}
+
+/// A roller which rotates logs through a fixed window of files and then
+/// removes the rotated files that are too old.
+#[derive(Debug)]
+struct AgedRoller {
+    roller: FixedWindowRoller,
+    pattern: String,
+    count: u32,
+    max_age: Option<std::time::Duration>,
+}
+
+impl AgedRoller {
+    fn new(logging: &Logging) -> Result<Self> {
+        let pattern = roll_pattern(&logging.file_path, logging.rotate_compress);
+        let count = logging.rotate_count;
+        let roller = FixedWindowRoller::builder()
+            .build(&pattern, count)
+            .map_err(|err| eyre!("Can't build log rotation config: {:?}", err))?;
+        let max_age = match logging.rotate_max_age_days {
+            0 => None,
+            days => Some(std::time::Duration::from_secs(u64::from(days) * 24 * 3600)),
+        };
+        Ok(Self { roller, pattern, count, max_age })
+    }
+
+    /// Remove the rotated files that were last written before the maximum
+    /// age.  Files that can't be examined or removed are left alone.
+    fn remove_expired(&self) {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        for index in 0..self.count {
+            let path = self.pattern.replace("{}", &index.to_string());
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified());
+            if let Ok(age) = modified.map(|m| m.elapsed().unwrap_or_default()) {
+                if age > max_age {
+                    std::fs::remove_file(&path).ok();
+                }
+            }
+        }
+    }
+}
+
+impl Roll for AgedRoller {
+    fn roll(&self, file: &std::path::Path) -> anyhow::Result<()> {
+        self.roller.roll(file)?;
+        self.remove_expired();
+        Ok(())
+    }
 }
 
 /// A trigger which rolls the log on a daily basis.
@@ -146,3 +280,41 @@ fn tomorrow_midnight() -> DateTime<Local> {
         _ => panic!("There is no midnight tomorrow!"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aged_roller() {
+        let dir = std::env::temp_dir().join("adlu-proxy-log-rotation-test");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("proxy-log.log");
+        let logging = Logging {
+            file_path: log_path.to_str().unwrap().to_string(),
+            rotate_type: LogRotationType::Daily,
+            rotate_count: 2,
+            rotate_max_age_days: 1,
+            rotate_compress: false,
+            ..Default::default()
+        };
+        let roller = AgedRoller::new(&logging).unwrap();
+        std::fs::write(&log_path, "first run\n").unwrap();
+        roller.roll(&log_path).unwrap();
+        assert!(!log_path.exists());
+        // with background rotation, the rotated file appears a bit later
+        let rotated = dir.join("proxy-log.log.0");
+        for _ in 0..100 {
+            if rotated.exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap(), "first run\n");
+        // recently rotated files are younger than the maximum age
+        roller.remove_expired();
+        assert!(rotated.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
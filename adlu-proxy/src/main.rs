@@ -22,11 +22,89 @@ use adlu_base::get_first_interrupt;
 use adlu_proxy::cli::{Command, ProxyArgs};
 use adlu_proxy::settings;
 
-#[tokio::main]
-async fn main() {
-    let mut args: ProxyArgs = ProxyArgs::parse();
+fn main() {
+    let args: ProxyArgs = ProxyArgs::parse();
+    // forking has to happen before the runtime starts any threads
+    let mut daemon_settings = None;
+    #[cfg(unix)]
+    if let Command::Serve { .. } = &args.cmd {
+        if let Ok(settings) = settings::load_config_file(&args) {
+            if settings.proxy.daemonize {
+                match adlu_proxy::daemon::daemonize(&settings) {
+                    Ok(settings) => daemon_settings = Some(settings),
+                    Err(err) => {
+                        eprintln!("Can't run in the background: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+    tokio::runtime::Runtime::new()
+        .expect("Can't start the async runtime")
+        .block_on(async_main(args, daemon_settings))
+}
+
+async fn async_main(mut args: ProxyArgs, daemon_settings: Option<settings::Settings>) {
+    // the mock server runs without a config file
+    #[cfg(feature = "mock")]
+    if let Command::MockServer { host, port } = &args.cmd {
+        let bind_addr = format!("{}:{}", host, port);
+        let stop_signal = get_first_interrupt();
+        if let Err(err) =
+            adlu_proxy::mock::serve_mock_requests(&bind_addr, stop_signal).await
+        {
+            eprintln!("Mock server failure: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    // completions don't need a config file either
+    if let Command::Completions { shell } = &args.cmd {
+        adlu_proxy::cli::write_completions(*shell, &mut std::io::stdout());
+        return;
+    }
+    // checking a config file mustn't change it, so we don't load it
+    if let Command::Configure { check: true, .. } = &args.cmd {
+        match settings::check_config_file(&args) {
+            Ok(problems) if problems.is_empty() => {
+                println!("Config file '{}' has no problems", &args.config_file);
+            }
+            Ok(problems) => {
+                problems.iter().for_each(|problem| println!("{}", problem));
+                std::process::exit(1);
+            }
+            Err(err) => {
+                println!("Can't read config file '{}': {}", &args.config_file, err);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    // printing the config mustn't have side effects, so we don't run the proxy
+    if let Command::PrintConfig { format, schema } = &args.cmd {
+        let result = if *schema {
+            settings::config_schema()
+        } else {
+            settings::load_config_file(&args)
+                .and_then(|settings| settings::print_config(&settings, format))
+        };
+        match result {
+            Ok(text) => println!("{}", text.trim_end()),
+            Err(err) => {
+                eprintln!("Can't print config: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // a daemon loaded its config before it left the directory it started in
+    let loaded = match daemon_settings {
+        Some(settings) => Ok(settings),
+        None => settings::load_config_file(&args),
+    };
     // if we have a valid config, proceed, else update the config
-    if let Ok(settings) = settings::load_config_file(&args) {
+    if let Ok(settings) = loaded {
         let stop_signal = get_first_interrupt();
         if let Err(err) = adlu_proxy::run(settings, args, stop_signal).await {
             eprintln!("Proxy failure: {}", err);
@@ -35,7 +113,11 @@ async fn main() {
     } else {
         if !matches!(args.cmd, Command::Configure { .. }) {
             eprintln!("The proxy cannot run without a valid configuration file.");
-            args.cmd = Command::Configure { repair: false };
+            args.cmd = Command::Configure {
+                repair: false,
+                check: false,
+                flags: Default::default(),
+            };
         }
         let settings = settings::load_config_file(&args);
         eprintln!("Please answer the questions to update your configuration file...");
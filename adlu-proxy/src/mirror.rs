@@ -0,0 +1,226 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Mirroring of request summaries to an analytics endpoint (such as a SIEM).
+
+The mirror subscribes to the same events that feed the `/events` stream, so
+it sees a summary of every request without being on the path that serves it.
+Summaries are sent in batches from a task of their own, and the channel that
+feeds that task is bounded: if the destination is slow or unreachable, the
+mirror falls behind and drops summaries (logging how many) rather than ever
+making a client wait.
+ */
+use std::time::Duration;
+
+use eyre::{eyre, Result, WrapErr};
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{error::RecvError, error::TryRecvError, Receiver};
+use tokio::task::JoinHandle;
+
+use crate::events::{Event, EventHub};
+use crate::settings::Settings;
+
+/// What gets mirrored for each request: its event plus where it came from.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirrorRecord {
+    #[serde(flatten)]
+    pub event: Event,
+    pub source_addr: String,
+}
+
+impl From<Event> for MirrorRecord {
+    fn from(event: Event) -> Self {
+        let source_addr = event.source_addr.clone();
+        MirrorRecord { event, source_addr }
+    }
+}
+
+#[derive(Debug)]
+enum Sink {
+    Http { client: reqwest::Client, url: String, token: String },
+    File { path: String },
+}
+
+impl Sink {
+    fn new(settings: &Settings) -> Result<Option<Self>> {
+        let mirror = &settings.mirror;
+        let destination = mirror.destination.trim();
+        if destination.is_empty() {
+            return Ok(None);
+        }
+        if destination.starts_with("http://") || destination.starts_with("https://") {
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(mirror.timeout_secs.max(1)))
+                .build()
+                .wrap_err("Can't create mirror client")?;
+            Ok(Some(Sink::Http {
+                client,
+                url: destination.to_string(),
+                token: mirror.token.clone(),
+            }))
+        } else {
+            Ok(Some(Sink::File { path: destination.to_string() }))
+        }
+    }
+
+    fn describe(&self) -> &str {
+        match self {
+            Sink::Http { url, .. } => url,
+            Sink::File { path } => path,
+        }
+    }
+
+    async fn send(&self, batch: &[MirrorRecord]) -> Result<()> {
+        match self {
+            Sink::Http { client, url, token } => {
+                let body = serde_json::to_string(batch)?;
+                let mut builder = client
+                    .post(url)
+                    .header("Content-Type", "application/json")
+                    .body(body);
+                if !token.is_empty() {
+                    builder = builder.bearer_auth(token);
+                }
+                let resp = builder.send().await?;
+                if resp.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(eyre!("Mirror endpoint returned status {}", resp.status()))
+                }
+            }
+            Sink::File { path } => {
+                let mut lines = String::new();
+                for record in batch {
+                    lines.push_str(&serde_json::to_string(record)?);
+                    lines.push('\n');
+                }
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(lines.as_bytes()).await?;
+                Ok(file.flush().await?)
+            }
+        }
+    }
+}
+
+/// Start mirroring the hub's events, if a mirror destination is configured.
+/// Abort the returned task to stop mirroring.
+pub fn spawn(settings: &Settings, events: &EventHub) -> Result<Option<JoinHandle<()>>> {
+    let sink = match Sink::new(settings)? {
+        Some(sink) => sink,
+        None => return Ok(None),
+    };
+    info!("Mirroring request summaries to: {}", sink.describe());
+    // only events that happen from now on are mirrored, not the history
+    let (_, receiver) = events.subscribe();
+    let batch_size = settings.mirror.batch_size.max(1);
+    Ok(Some(tokio::spawn(run(sink, receiver, batch_size))))
+}
+
+async fn run(sink: Sink, mut receiver: Receiver<Event>, batch_size: usize) {
+    while let Some(batch) = next_batch(&mut receiver, batch_size).await {
+        if let Err(err) = sink.send(&batch).await {
+            error!("Dropped {} mirror record(s): {:?}", batch.len(), err);
+        }
+    }
+}
+
+/// Wait for at least one event, then take whatever else is already waiting
+/// (up to the batch size).  Returns `None` once the hub is gone.
+async fn next_batch(
+    receiver: &mut Receiver<Event>,
+    batch_size: usize,
+) -> Option<Vec<MirrorRecord>> {
+    let mut batch = Vec::new();
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                batch.push(MirrorRecord::from(event));
+                break;
+            }
+            Err(RecvError::Lagged(count)) => {
+                warn!("Mirror fell behind: skipped {} record(s)", count)
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+    while batch.len() < batch_size {
+        match receiver.try_recv() {
+            Ok(event) => batch.push(MirrorRecord::from(event)),
+            Err(TryRecvError::Lagged(count)) => {
+                warn!("Mirror fell behind: skipped {} record(s)", count)
+            }
+            Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_batch, Sink};
+    use crate::events::{Event, EventHub};
+
+    fn event(correlation_id: &str) -> Event {
+        Event {
+            timestamp: "2022-09-01T00:00:00Z".to_string(),
+            correlation_id: correlation_id.to_string(),
+            request_type: "FRL Activation".to_string(),
+            app_id: "Photoshop1".to_string(),
+            app_version: "23.0".to_string(),
+            device_hash: "abcdef".to_string(),
+            outcome: "from adobe".to_string(),
+            source_addr: "10.0.0.1".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_to_file() {
+        let hub = EventHub::new(0, 2);
+        let (_, mut receiver) = hub.subscribe();
+        // overfill the channel (which holds 16), so the first event is dropped
+        for id in 'a'..='q' {
+            hub.publish(event(&id.to_string()));
+        }
+        let batch = next_batch(&mut receiver, 10).await.unwrap();
+        assert_eq!(batch.len(), 10);
+        assert_eq!(batch[0].event.correlation_id, "b");
+        let batch = next_batch(&mut receiver, 10).await.unwrap();
+        assert_eq!(batch.len(), 6);
+        let path = std::env::temp_dir().join("adlu-proxy-test-mirror.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let sink = Sink::File { path: path.to_str().unwrap().to_string() };
+        sink.send(&batch).await.unwrap();
+        sink.send(&batch[..1]).await.unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 7);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["correlationId"], "l");
+        assert_eq!(first["sourceAddr"], "10.0.0.1");
+        assert_eq!(first["appId"], "Photoshop1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+A mock Adobe licensing server, for use in integration testing.
+
+The mock server accepts the same FRL activation, FRL deactivation, NUL license,
+and log upload requests as the Adobe servers, and answers them with canned
+(but well-formed) responses.  Point a proxy's `frl.remote_host` and `log.remote_host`
+settings at a running mock server to test a deployment without contacting Adobe.
+
+This module is only available when the `mock` feature is enabled.
+ */
+use eyre::{Result, WrapErr};
+use log::{error, info};
+use warp::{Filter, Rejection, Reply};
+
+use adlu_parse::protocol::{
+    FrlActivationRequestBody, FrlActivationResponseBody, FrlDeactivationResponseBody,
+    NulLicenseRequestBody, NulLicenseResponseBody, Request,
+};
+
+/// Serve mock Adobe responses on the given address until `stop_signal` fires.
+pub async fn serve_mock_requests(
+    bind_addr: &str,
+    stop_signal: impl std::future::Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let bind_addr: std::net::SocketAddr =
+        bind_addr.parse().wrap_err("Invalid mock server host/port")?;
+    let (addr, server) =
+        warp::serve(routes()).bind_with_graceful_shutdown(bind_addr, stop_signal);
+    info!("Mock Adobe server serving HTTP requests on {:?}...", addr);
+    eprintln!("Mock Adobe server listening on {:?}", addr);
+    match tokio::task::spawn(server).await {
+        Ok(_) => info!("Mock server terminated normally"),
+        Err(err) => error!("Mock server terminated abnormally: {:?}", err),
+    }
+    Ok(())
+}
+
+/// The routes served by the mock server.
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let activation = Request::frl_activation_boxed_filter(50_000).map(|req: Request| {
+        frl_activation_response(body_of(&req), req.request_id.as_deref())
+    });
+    let deactivation = Request::frl_deactivation_boxed_filter(50_000)
+        .map(|req: Request| frl_deactivation_response(req.request_id.as_deref()));
+    let license = Request::nul_license_boxed_filter(50_000).map(|req: Request| {
+        nul_license_response(body_of(&req), req.request_id.as_deref())
+    });
+    let upload =
+        Request::log_upload_boxed_filter(1_500_000).map(|_| log_upload_response());
+    activation.or(deactivation).or(license).or(upload).with(warp::log("mock::summary"))
+}
+
+fn body_of(req: &Request) -> &[u8] {
+    req.body.as_ref().map(|b| b.as_bytes()).unwrap_or_default()
+}
+
+/// A canned response to an FRL activation request with the given body.
+pub fn frl_activation_response(
+    body: &[u8],
+    request_id: Option<&str>,
+) -> http::Response<String> {
+    match serde_json::from_slice::<FrlActivationRequestBody>(body) {
+        Ok(data) => {
+            let device_id = data.device_details.device_id.as_str();
+            let body = FrlActivationResponseBody::mock_from_device_id(device_id);
+            json_response(http::StatusCode::OK, request_id, body.to_body())
+        }
+        Err(err) => bad_request_response(request_id, &err.to_string()),
+    }
+}
+
+/// A canned response to an FRL deactivation request.
+pub fn frl_deactivation_response(request_id: Option<&str>) -> http::Response<String> {
+    let body = FrlDeactivationResponseBody::mock_from_device_id("");
+    json_response(http::StatusCode::OK, request_id, body.to_body())
+}
+
+/// A canned response to a NUL license request with the given body.
+pub fn nul_license_response(
+    body: &[u8],
+    request_id: Option<&str>,
+) -> http::Response<String> {
+    match serde_json::from_slice::<NulLicenseRequestBody>(body) {
+        Ok(data) => {
+            let device_id = data.device_details.device_id.as_str();
+            let body = NulLicenseResponseBody::mock_from_device_id(device_id);
+            json_response(http::StatusCode::OK, request_id, body.to_body())
+        }
+        Err(err) => bad_request_response(request_id, &err.to_string()),
+    }
+}
+
+/// A canned response to a log upload.
+pub fn log_upload_response() -> http::Response<String> {
+    http::Response::builder().status(200).body(String::new()).unwrap()
+}
+
+fn bad_request_response(
+    request_id: Option<&str>,
+    message: &str,
+) -> http::Response<String> {
+    let body = serde_json::json!({"statusCode": 400, "message": message});
+    json_response(http::StatusCode::BAD_REQUEST, request_id, body.to_string())
+}
+
+fn json_response(
+    status: http::StatusCode,
+    request_id: Option<&str>,
+    body: String,
+) -> http::Response<String> {
+    let mut builder = http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json;encoding=utf-8");
+    if let Some(request_id) = request_id {
+        builder = builder.header("X-Request-Id", request_id);
+    }
+    builder.body(body).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use adlu_parse::protocol::FrlActivationRequestBody;
+
+    #[tokio::test]
+    async fn test_mock_activation() {
+        let body = FrlActivationRequestBody::mock_from_device_id("mock1");
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/asnp/frl_connected/values/v2")
+            .header("X-Api-Key", "ngl_mock1")
+            .header("X-Request-Id", "Req-Id-mock1")
+            .json(&body)
+            .reply(&super::routes())
+            .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.headers().get("X-Request-Id").unwrap(), "Req-Id-mock1");
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/asnp/frl_connected/values/v2")
+            .header("X-Api-Key", "ngl_mock1")
+            .header("X-Request-Id", "Req-Id-mock2")
+            .body("not json")
+            .reply(&super::routes())
+            .await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+}
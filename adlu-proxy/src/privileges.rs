@@ -0,0 +1,214 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Dropping root privileges once the server's listener is bound.
+
+Binding to a privileged port (below 1024, such as 443) requires root on
+most Unix systems.  Rather than run as root for its whole life, the proxy
+can be started as root with the `run_as_user` (and, optionally,
+`run_as_group`) proxy settings, and it will switch to that user and group
+as soon as its listener is bound.  The user's other groups are dropped, and
+the switch can't be undone, so a compromised proxy can't regain root.
+
+Alternatively, on Linux, the proxy can run as an ordinary user from the
+start if it's given just the capability to bind privileged ports, either
+with `setcap 'cap_net_bind_service=+ep' /path/to/adlu-proxy` or, under
+systemd, with `AmbientCapabilities=CAP_NET_BIND_SERVICE` in its unit.
+
+Either way, the user must be able to write the directory of the cache
+database, where SQLite keeps its write-ahead log, and the log directory.  The
+cache is opened before the switch, so just before it the database and its WAL
+files are given to the user, and right after it they are checked, so a
+misconfiguration is reported when the server starts rather than when the cache
+next opens a connection.  The server's background tasks (including the gRPC
+API, which must therefore use an unprivileged port) only start after the
+switch, so anything they create belongs to the user.  The PID file is opened
+before the switch, and stays open, so the server can empty it when it stops.
+ */
+use eyre::{eyre, Result};
+#[cfg(unix)]
+use log::{info, warn};
+
+use crate::settings::Proxy;
+
+/// Check that the configured user and group exist (and that this
+/// platform can switch to them).
+pub fn validate(settings: &Proxy) -> Result<()> {
+    if settings.run_as_user.is_empty() {
+        if settings.run_as_group.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("The run_as_group setting requires a run_as_user setting"))
+        }
+    } else if cfg!(unix) {
+        #[cfg(unix)]
+        target_ids(settings)?;
+        Ok(())
+    } else {
+        Err(eyre!("The run_as_user setting is only supported on Unix systems"))
+    }
+}
+
+/// Switch to the configured user and group, if any.  This must be
+/// called after the server's listener is bound.
+#[cfg(unix)]
+pub fn drop_privileges(settings: &Proxy) -> Result<()> {
+    validate(settings)?;
+    // SAFETY: these calls have no preconditions
+    let euid = unsafe { libc::geteuid() };
+    if settings.run_as_user.is_empty() {
+        if euid == 0 {
+            warn!("The proxy is running as root (see the proxy run_as_user setting)");
+        }
+        return Ok(());
+    }
+    let user = &settings.run_as_user;
+    let (uid, gid) = target_ids(settings)?;
+    if euid != 0 {
+        return if euid == uid {
+            Ok(())
+        } else {
+            Err(eyre!(
+                "Only root can switch to user '{}' (running as uid {})",
+                user,
+                euid
+            ))
+        };
+    }
+    let db_files = cache_files(&settings.db_path);
+    for path in db_files.iter() {
+        let c_path = std::ffi::CString::new(path.as_str())?;
+        // SAFETY: the path is a valid C string
+        if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(os_error(&format!(
+                "Can't give user '{}' the file {}",
+                user, path
+            )));
+        }
+    }
+    // the groups must be set while we are still root
+    // SAFETY: the group list is a single valid gid
+    if unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err(os_error("Can't drop supplementary groups"));
+    }
+    // SAFETY: these calls have no preconditions
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(os_error(&format!("Can't switch to group {}", gid)));
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(os_error(&format!("Can't switch to user '{}'", user)));
+    }
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(eyre!("Root privileges could not be dropped"));
+    }
+    info!("Switched to user '{}' (uid {}, gid {})", user, uid, gid);
+    for path in db_files.iter() {
+        if let Err(err) = std::fs::OpenOptions::new().read(true).write(true).open(path) {
+            return Err(eyre!(
+                "User '{}' can't write the cache file '{}': {}",
+                user,
+                path,
+                err
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The files of the cache database that exist: the database itself and,
+/// since it's in WAL mode, its write-ahead log and shared-memory index.
+#[cfg(unix)]
+fn cache_files(db_path: &str) -> Vec<String> {
+    [db_path.to_string(), format!("{}-wal", db_path), format!("{}-shm", db_path)]
+        .into_iter()
+        .filter(|path| std::path::Path::new(path).exists())
+        .collect()
+}
+
+/// Privileges can't be dropped on this platform, so there's nothing to do
+/// ([`validate`] rejects any configuration that asks for it).
+#[cfg(not(unix))]
+pub fn drop_privileges(settings: &Proxy) -> Result<()> {
+    validate(settings)
+}
+
+/// The uid and gid to switch to.  Users and groups can be given by name or id;
+/// without a group, it's the user's primary group.
+#[cfg(unix)]
+fn target_ids(settings: &Proxy) -> Result<(libc::uid_t, libc::gid_t)> {
+    use std::ffi::CString;
+
+    let user = &settings.run_as_user;
+    let c_user = CString::new(user.as_str())?;
+    // SAFETY: the name is a valid C string, and the returned entry is only
+    // read before any other lookup can reuse it
+    let pw = unsafe {
+        match user.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid(uid),
+            Err(_) => libc::getpwnam(c_user.as_ptr()),
+        }
+    };
+    if pw.is_null() {
+        return Err(eyre!("There is no user '{}' to run as", user));
+    }
+    let (uid, user_gid) = unsafe { ((*pw).pw_uid, (*pw).pw_gid) };
+    let group = &settings.run_as_group;
+    if group.is_empty() {
+        return Ok((uid, user_gid));
+    }
+    let c_group = CString::new(group.as_str())?;
+    // SAFETY: as above
+    let gr = unsafe {
+        match group.parse::<libc::gid_t>() {
+            Ok(gid) => libc::getgrgid(gid),
+            Err(_) => libc::getgrnam(c_group.as_ptr()),
+        }
+    };
+    if gr.is_null() {
+        return Err(eyre!("There is no group '{}' to run as", group));
+    }
+    Ok((uid, unsafe { (*gr).gr_gid }))
+}
+
+#[cfg(unix)]
+fn os_error(message: &str) -> eyre::Report {
+    eyre!("{}: {}", message, std::io::Error::last_os_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_run_as() {
+        let mut settings = Proxy::default();
+        assert!(validate(&settings).is_ok());
+        settings.run_as_group = "nobody".to_string();
+        assert!(validate(&settings).is_err());
+        settings.run_as_user = "no-such-proxy-user".to_string();
+        settings.run_as_group = "".to_string();
+        assert!(validate(&settings).is_err());
+        if cfg!(unix) {
+            // every Unix system has a root user and group 0
+            settings.run_as_user = "root".to_string();
+            settings.run_as_group = "0".to_string();
+            assert!(validate(&settings).is_ok());
+        }
+    }
+}
@@ -21,42 +21,149 @@ released.  That license is reproduced here in the LICENSE-MIT file.
 Provides the top-level proxy framework, both insecure and secure.  This includes a status endpoint
 that can be used to ensure the proxy is up and find out which services it is providing.
  */
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use eyre::{eyre, Context, Report, Result};
-use log::{debug, error, info, warn};
+use log::{debug, error, info, warn, Level};
 use serde_json::{json, Value};
 use warp::{Filter, Rejection, Reply};
 
 use adlu_base::{load_pem_files, load_pfx_file, CertificateData, Timestamp};
+use adlu_parse::protocol::{
+    FieldProblem, FrlActivationRequestBody, InventoryReport, LogUploadResponse,
+};
 pub use adlu_parse::protocol::{Request, RequestType};
 
-use crate::cache::Cache;
-use crate::settings::{ProxyMode, Settings};
+use crate::cache::{ActivityBin, Cache, FORWARD_LOCK};
+use crate::canary::CanaryMonitor;
+use crate::cassette::{Cassette, RecordedRequest};
+use crate::dns::UpstreamResolver;
+use crate::events::{Event, EventHub};
+use crate::faults::{FaultInjector, FaultKind};
+use crate::geoip::GeoIp;
+use crate::inflight::{InFlight, Role};
+use crate::listener::{ConnectionLimits, ConnectionStats};
+use crate::logging::critical_event;
+use crate::relay::RelayQueue;
+use crate::security::{ApiKeyValidator, ParseFailure, ValidationFailure};
+use crate::settings::{AdminEndpoint, ProxyMode, Settings, SettingsVal, UnknownRouting};
+use crate::throttle::Throttle;
+use crate::trace::Tracer;
+use crate::unknown::UnknownPaths;
+use crate::{
+    auth, canary, grpc, ims, landing, listener, mirror, privileges, relay, schedule,
+    simulate, transfer, unknown,
+};
 
 pub async fn serve_incoming_https_requests(
     settings: &Settings,
     cache: &Cache,
     stop_signal: impl std::future::Future<Output = ()> + Send + 'static,
 ) -> Result<()> {
-    let conf = Config::new(settings.clone(), cache.clone())?;
+    let mut conf = Config::new(settings.clone(), cache.clone())?;
+    openssl_probe::init_ssl_cert_env_vars();
+    let cert_data = conf.cert_data().map_err(|err| {
+        critical_event(
+            Level::Error,
+            &format!("Can't load the SSL certificate: {:#}", err),
+        );
+        err
+    })?;
+    let not_after = cert_data.not_after()?;
+    if check_cert_expiry(settings, &not_after) < 0 && settings.ssl.refuse_expired {
+        return Err(eyre!(
+            "The SSL certificate has expired (use --force to serve anyway)"
+        ));
+    }
+    conf.cert_expiry = Some(not_after.clone());
     let routes = routes(conf.clone());
     let bind_addr = conf.bind_addr()?;
-    openssl_probe::init_ssl_cert_env_vars();
-    let cert_data = conf.cert_data()?;
-    let server =
-        warp::serve(routes).tls().cert(cert_data.cert_pem()).key(cert_data.key_pem());
-    let (addr, server) = server.bind_with_graceful_shutdown(bind_addr, stop_signal);
-    info!(
-        "adlu-proxy v{} serving HTTPS requests on {:?}...",
-        env!("CARGO_PKG_VERSION"),
-        addr
+    let listener = bind_listener(bind_addr).await?;
+    // the background tasks run as the user the server switches to
+    privileges::drop_privileges(&settings.proxy)?;
+    let monitor = tokio::spawn(monitor_cert_expiry(settings.clone(), not_after));
+    let jobs = schedule::spawn_jobs(settings, cache)?;
+    let mirror = mirror::spawn(settings, &conf.events)?;
+    let relay = relay::spawn(&conf);
+    let canary = canary::spawn(&conf);
+    let grpc = grpc::spawn(&conf)?;
+    let addr = listener.local_addr()?;
+    let tls = listener::tls_acceptor(&cert_data)?;
+    let server = listener::serve(
+        listener,
+        Some(tls),
+        settings.proxy.proxy_protocol,
+        ConnectionLimits::from(&settings.proxy),
+        conf.connections.clone(),
+        warp::service(routes),
+        stop_signal,
+    );
+    critical_event(
+        Level::Info,
+        &format!(
+            "adlu-proxy v{} serving HTTPS requests on {:?}...",
+            env!("CARGO_PKG_VERSION"),
+            addr
+        ),
     );
     match tokio::task::spawn(server).await {
-        Ok(_) => info!("HTTPS server terminated normally"),
-        Err(err) => error!("HTTPS server terminated abnormally: {:?}", err),
+        Ok(_) => critical_event(Level::Info, "HTTPS server terminated normally"),
+        Err(err) => critical_event(
+            Level::Error,
+            &format!("HTTPS server terminated abnormally: {:?}", err),
+        ),
     }
+    monitor.abort();
+    jobs.iter().for_each(|job| job.abort());
+    mirror.iter().for_each(|task| task.abort());
+    relay.iter().for_each(|task| task.abort());
+    canary.iter().for_each(|task| task.abort());
+    grpc.iter().for_each(|task| task.abort());
     Ok(())
 }
 
+/// How often a running server re-checks its certificate's expiration.
+const CERT_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(6 * 3600);
+
+const MILLIS_PER_DAY: i64 = 24 * 3600 * 1000;
+
+/// Whole days until the certificate expires (negative once it has expired).
+fn cert_days_remaining(not_after: &Timestamp) -> i64 {
+    (not_after.to_millis() - Timestamp::now().to_millis()).div_euclid(MILLIS_PER_DAY)
+}
+
+/// Log a warning if the certificate has expired or will expire soon.
+/// Returns the number of days remaining.
+fn check_cert_expiry(settings: &Settings, not_after: &Timestamp) -> i64 {
+    let days = cert_days_remaining(not_after);
+    let expiry = not_after.format_iso_8601(false);
+    if days < 0 {
+        critical_event(
+            Level::Error,
+            &format!("The SSL certificate expired at {}", expiry),
+        );
+    } else if days < settings.ssl.expiry_warning_days as i64 {
+        critical_event(
+            Level::Warn,
+            &format!("The SSL certificate expires in {} day(s), at {}", days, expiry),
+        );
+    } else {
+        debug!("The SSL certificate expires in {} day(s), at {}", days, expiry);
+    }
+    days
+}
+
+async fn monitor_cert_expiry(settings: Settings, not_after: Timestamp) {
+    let mut interval = tokio::time::interval(CERT_CHECK_INTERVAL);
+    // the first tick is immediate, and we checked at startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        check_cert_expiry(&settings, &not_after);
+    }
+}
+
 pub async fn serve_incoming_http_requests(
     settings: &Settings,
     cache: &Cache,
@@ -65,38 +172,88 @@ pub async fn serve_incoming_http_requests(
     let conf = Config::new(settings.clone(), cache.clone())?;
     let routes = routes(conf.clone());
     let bind_addr = conf.bind_addr()?;
-    let (addr, server) =
-        warp::serve(routes).bind_with_graceful_shutdown(bind_addr, stop_signal);
-    info!(
-        "adlu-proxy v{} serving HTTP requests on {:?}...",
-        env!("CARGO_PKG_VERSION"),
-        addr
+    let listener = bind_listener(bind_addr).await?;
+    // the background tasks run as the user the server switches to
+    privileges::drop_privileges(&settings.proxy)?;
+    let jobs = schedule::spawn_jobs(settings, cache)?;
+    let mirror = mirror::spawn(settings, &conf.events)?;
+    let relay = relay::spawn(&conf);
+    let canary = canary::spawn(&conf);
+    let grpc = grpc::spawn(&conf)?;
+    let addr = listener.local_addr()?;
+    let server = listener::serve(
+        listener,
+        None,
+        settings.proxy.proxy_protocol,
+        ConnectionLimits::from(&settings.proxy),
+        conf.connections.clone(),
+        warp::service(routes),
+        stop_signal,
+    );
+    critical_event(
+        Level::Info,
+        &format!(
+            "adlu-proxy v{} serving HTTP requests on {:?}...",
+            env!("CARGO_PKG_VERSION"),
+            addr
+        ),
     );
     match tokio::task::spawn(server).await {
-        Ok(_) => info!("HTTP server terminated normally"),
-        Err(err) => error!("HTTP server terminated abnormally: {:?}", err),
+        Ok(_) => critical_event(Level::Info, "HTTP server terminated normally"),
+        Err(err) => critical_event(
+            Level::Error,
+            &format!("HTTP server terminated abnormally: {:?}", err),
+        ),
     }
+    jobs.iter().for_each(|job| job.abort());
+    mirror.iter().for_each(|task| task.abort());
+    relay.iter().for_each(|task| task.abort());
+    canary.iter().for_each(|task| task.abort());
+    grpc.iter().for_each(|task| task.abort());
     Ok(())
 }
 
+/// Bind the server's listener.
+async fn bind_listener(
+    bind_addr: std::net::SocketAddr,
+) -> Result<tokio::net::TcpListener> {
+    tokio::net::TcpListener::bind(bind_addr)
+        .await
+        .wrap_err(format!("Can't listen on {}", bind_addr))
+}
+
 pub async fn forward_stored_requests(settings: &Settings, cache: &Cache) -> Result<()> {
+    if let ProxyMode::Simulate = settings.proxy.mode {
+        return Err(eyre!("Stored requests can't be forwarded in simulate mode"));
+    }
     let conf = Config::new(settings.clone(), cache.clone())?;
+    let lock = cache.lock(FORWARD_LOCK).await.wrap_err("Can't forward requests")?;
     let reqs = conf.cache.fetch_unanswered_requests().await?;
     if reqs.is_empty() {
         info!("No requests to forward.");
         eprintln!("No requests to forward.");
+        lock.release().await;
         return Ok(());
     }
     let count = reqs.len();
     eprintln!("Found {} request(s) to forward", count);
     let (mut successes, mut failures) = (0u64, 0u64);
     for req in reqs.iter() {
+        if let Some(secs) = conf.throttle.remaining_secs() {
+            eprintln!("Adobe is throttling requests: waiting {} second(s)...", secs);
+            tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
+        }
         if forward_stored_request(&conf, req).await {
             successes += 1
         } else {
             failures += 1
         }
     }
+    let resealed = cache.reseal_export().await;
+    lock.release().await;
+    if resealed? {
+        eprintln!("Updated the checksums of the export with its responses.");
+    }
     eprintln!(
         "Forwarding produced {} success(es) and {} failure(s).",
         successes, failures
@@ -111,40 +268,111 @@ pub struct Config {
     pub client: reqwest::Client,
     pub frl_server: String,
     pub log_server: String,
+    pub unknown_server: String,
+    pub ims_server: String,
+    pub api_keys: Arc<ApiKeyValidator>,
+    pub cert_expiry: Option<Timestamp>,
+    pub events: Arc<EventHub>,
+    pub throttle: Arc<Throttle>,
+    pub faults: Arc<FaultInjector>,
+    pub in_flight: Arc<InFlight>,
+    pub geoip: Arc<GeoIp>,
+    pub relay: Arc<RelayQueue>,
+    pub connections: Arc<ConnectionStats>,
+    pub unknown_paths: Arc<UnknownPaths>,
+    pub cassette: Option<Arc<Cassette>>,
+    pub tracer: Option<Arc<Tracer>>,
+    pub canary: Arc<CanaryMonitor>,
 }
 
-impl Config {
-    pub fn new(settings: Settings, cache: Cache) -> Result<Self> {
-        let mut builder = reqwest::Client::builder();
-        builder = builder.timeout(std::time::Duration::new(59, 0));
-        if settings.upstream.use_proxy {
-            let proxy_host = format!(
-                "{}://{}:{}",
-                settings.upstream.proxy_protocol,
-                settings.upstream.proxy_host,
-                settings.upstream.proxy_port
+/// The client for requests to Adobe and other upstream services, which goes
+/// through the configured upstream proxy and DNS.
+pub fn upstream_client(settings: &Settings) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    builder = builder.timeout(std::time::Duration::new(59, 0));
+    if settings.upstream.use_proxy {
+        let proxy_host = format!(
+            "{}://{}:{}",
+            settings.upstream.proxy_protocol,
+            settings.upstream.proxy_host,
+            settings.upstream.proxy_port
+        );
+        let mut proxy =
+            reqwest::Proxy::https(proxy_host).wrap_err("Invalid proxy configuration")?;
+        if settings.upstream.use_basic_auth {
+            proxy = proxy.basic_auth(
+                &settings.upstream.proxy_username,
+                &settings.upstream.proxy_password,
             );
-            let mut proxy = reqwest::Proxy::https(proxy_host)
-                .wrap_err("Invalid proxy configuration")?;
-            if settings.upstream.use_basic_auth {
-                proxy = proxy.basic_auth(
-                    &settings.upstream.proxy_username,
-                    &settings.upstream.proxy_password,
-                );
-            }
-            builder = builder.proxy(proxy)
         }
-        let client = builder.build().wrap_err("Can't create proxy client")?;
+        builder = builder.proxy(proxy)
+    }
+    if let Some(resolver) =
+        UpstreamResolver::new(&settings.dns).wrap_err("Invalid DNS configuration")?
+    {
+        builder = builder.dns_resolver(Arc::new(resolver));
+    }
+    if let UnknownRouting::Host = settings.unknown.routing {
+        let addresses = unknown::host_addresses(&settings.unknown)
+            .wrap_err("Invalid unknown request configuration")?;
+        for (host, addr) in addresses {
+            builder = builder.resolve(&host, addr);
+        }
+    }
+    builder.build().wrap_err("Can't create proxy client")
+}
+
+impl Config {
+    pub fn new(settings: Settings, cache: Cache) -> Result<Self> {
+        let client = upstream_client(&settings)?;
         let frl_server: http::Uri =
             settings.frl.remote_host.parse().wrap_err("Invalid FRL endpoint")?;
         let log_server: http::Uri =
             settings.log.remote_host.parse().wrap_err("Invalid log endpoint")?;
+        let unknown_server = match settings.unknown.upstream_host.as_str() {
+            "" => frl_server.clone(),
+            host => host.parse().wrap_err("Invalid unknown request endpoint")?,
+        };
+        let ims_server: http::Uri =
+            settings.ims.remote_host.parse().wrap_err("Invalid IMS endpoint")?;
+        let api_keys = Arc::new(
+            ApiKeyValidator::new(&settings).wrap_err("Invalid api key configuration")?,
+        );
+        let events = Arc::new(EventHub::new(
+            settings.events.history_size,
+            settings.mirror.queue_size,
+        ));
+        let geoip =
+            Arc::new(GeoIp::new(&settings).wrap_err("Invalid GeoIP configuration")?);
+        let relay = Arc::new(RelayQueue::new(&settings.log));
+        let faults = Arc::new(FaultInjector::new(&settings.faults));
+        let cassette = Cassette::new(&settings.cassette)
+            .wrap_err("Invalid cassette configuration")?
+            .map(Arc::new);
+        let tracer = Tracer::new(&settings.trace)
+            .wrap_err("Invalid trace configuration")?
+            .map(Arc::new);
         Ok(Config {
             settings,
             cache,
             client,
             frl_server: frl_server.to_string(),
             log_server: log_server.to_string(),
+            unknown_server: unknown_server.to_string(),
+            ims_server: ims_server.to_string(),
+            api_keys,
+            cert_expiry: None,
+            events,
+            throttle: Default::default(),
+            faults,
+            in_flight: Default::default(),
+            geoip,
+            relay,
+            connections: Default::default(),
+            unknown_paths: Default::default(),
+            cassette,
+            tracer,
+            canary: Default::default(),
         })
     }
 
@@ -175,9 +403,9 @@ impl Config {
     }
 }
 
-fn load_cert_data(settings: &Settings) -> Result<CertificateData> {
+pub fn load_cert_data(settings: &SettingsVal) -> Result<CertificateData> {
     if settings.ssl.use_pfx {
-        load_pfx_file(&settings.ssl.cert_path, &settings.ssl.password)
+        load_pfx_file(&settings.ssl.pfx_path, &settings.ssl.password)
             .wrap_err("Failed to load PKCS12 data:")
     } else {
         let key_pass = match settings.ssl.password.as_str() {
@@ -200,6 +428,40 @@ pub struct Response {
     pub via: Option<String>,
     pub request_id: Option<String>,
     pub session_id: Option<String>,
+    /// Other headers from Adobe that are passed through to clients.
+    pub headers: Vec<(String, String)>,
+}
+
+/// Response headers that are never passed through from Adobe, because they
+/// describe the connection rather than the response, because the proxy sets
+/// them itself, or because they aren't safe to replay from the cache.
+const STRIPPED_HEADERS: [&str; 18] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+    "content-encoding",
+    "content-type",
+    "date",
+    "server",
+    "via",
+    "x-request-id",
+    "x-session-id",
+    "set-cookie",
+];
+
+/// Whether a response header from Adobe is passed through to clients, given
+/// the configured header names (where `*` means all safe headers).
+pub fn passes_through(name: &str, configured: &[String]) -> bool {
+    let name = name.to_ascii_lowercase();
+    !STRIPPED_HEADERS.contains(&name.as_str())
+        && configured.iter().any(|h| h == "*" || h.eq_ignore_ascii_case(&name))
 }
 
 impl From<Response> for warp::reply::Response {
@@ -219,6 +481,9 @@ impl From<Response> for warp::reply::Response {
         if let Some(session_id) = resp.session_id {
             builder = builder.header("X-Session-Id", session_id);
         }
+        for (name, value) in resp.headers {
+            builder = builder.header(name, value);
+        }
         if let Some(content) = resp.body {
             if let Some(content_type) = resp.content_type {
                 builder = builder.header("Content-Type", content_type);
@@ -237,9 +502,13 @@ impl Reply for Response {
 }
 
 impl Response {
-    pub async fn from_network(req: &Request, resp: reqwest::Response) -> Result<Self> {
+    pub async fn from_network(
+        req: &Request,
+        resp: reqwest::Response,
+        pass_through: &[String],
+    ) -> Result<Self> {
         let timestamp = if let Some(val) = resp.headers().get("Date") {
-            val.to_str().map(Timestamp::from_db).unwrap_or_default()
+            val.to_str().map(Timestamp::from_db_string).unwrap_or_default()
         } else {
             Timestamp::now()
         };
@@ -270,6 +539,14 @@ impl Response {
         } else {
             None
         };
+        let headers = resp
+            .headers()
+            .iter()
+            .filter(|(name, _)| passes_through(name.as_str(), pass_through))
+            .filter_map(|(name, val)| {
+                Some((name.to_string(), val.to_str().ok()?.to_string()))
+            })
+            .collect();
         let content = resp.text().await.wrap_err("Failure to receive body")?;
         let body = if content.is_empty() { None } else { Some(content) };
         Ok(Self {
@@ -282,6 +559,7 @@ impl Response {
             via,
             request_id,
             session_id,
+            headers,
         })
     }
 }
@@ -290,10 +568,22 @@ pub fn routes(
     conf: Config,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     status_route(conf.clone())
+        .or(landing_route(conf.clone()))
+        .or(events_route(conf.clone()))
+        .or(activity_route(conf.clone()))
+        .or(notes_route(conf.clone()))
+        .or(faults_route(conf.clone()))
+        .or(canary_route(conf.clone()))
         .or(frl_activate_route(conf.clone()))
         .or(frl_deactivate_route(conf.clone()))
         .or(nul_license_route(conf.clone()))
+        .or(script_upload_route(conf.clone()))
         .or(upload_route(conf.clone()))
+        .or(inventory_route(conf.clone()))
+        .or(uninstall_route(conf.clone()))
+        .or(transfer_export_route(conf.clone()))
+        .or(transfer_import_route(conf.clone()))
+        .or(ims_token_route(conf.clone()))
         .or(unknown_route(conf))
         .with(warp::log("route::summary"))
 }
@@ -304,14 +594,164 @@ pub fn with_conf(
     warp::any().map(move || conf.clone())
 }
 
+/// Pass requests only if an endpoint is enabled, so requests for a disabled
+/// endpoint fall through to the other routes.
+fn enabled(is_enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if is_enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Reply to a request that [`auth::authorize`] rejected, so it doesn't fall
+/// through to the other routes.  Other rejections are passed on.
+async fn recover_unauthorized(
+    err: Rejection,
+) -> Result<warp::reply::Response, Rejection> {
+    match err.find::<auth::Unauthorized>() {
+        Some(unauthorized) => Ok(unauthorized_reply(unauthorized)),
+        None => Err(err),
+    }
+}
+
+/// Monitoring and operators check the proxy's status here, with any
+/// credentials that can read the dashboards.
 pub fn status_route(
     conf: Config,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::get()
         .and(warp::path("status"))
         .and(warp::path::end())
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Status))
         .and(with_conf(conf))
         .then(status)
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Browsers that visit the proxy get a page describing it.  Requests that
+/// don't accept HTML are left for the other routes.
+pub fn landing_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_conf(conf))
+        .and_then(|accept: Option<String>, conf: Config| async move {
+            if conf.settings.landing.enabled && landing::wants_html(accept.as_deref()) {
+                Ok(landing(conf).await)
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+}
+
+/// Dashboards subscribe to the live event stream here.  Browsers can't
+/// set headers on an event source, so the token can also be given as a
+/// `token` query parameter.
+pub fn events_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(enabled(conf.settings.events.enabled))
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Events))
+        .and(with_conf(conf))
+        .map(events)
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Dashboards fetch binned counts of activity here, authorized with the
+/// same tokens as the event stream.  The query gives the size of the bins
+/// (`bin=hour` or `bin=day`, the default) and, optionally, the range they
+/// cover (`since` and `until`, in any format the cache understands).
+pub fn activity_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("activity"))
+        .and(warp::path::end())
+        .and(enabled(conf.settings.events.enabled))
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Activity))
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and(with_conf(conf))
+        .then(
+            |query: std::collections::HashMap<String, String>, conf: Config| async move {
+                activity(&query, &conf).await
+            },
+        )
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Dashboards fetch the notes admins have attached to cached rows here,
+/// authorized with the same tokens as the event stream.  The query can give
+/// a `key` (as for the annotate command) to fetch only the notes on its rows.
+pub fn notes_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("notes"))
+        .and(warp::path::end())
+        .and(enabled(conf.settings.events.enabled))
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Notes))
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and(with_conf(conf))
+        .then(
+            |query: std::collections::HashMap<String, String>, conf: Config| async move {
+                notes(&query, &conf).await
+            },
+        )
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Staging operators start (POST), stop (DELETE), and check (GET) the
+/// injection of upstream faults here, with admin credentials whose role is at
+/// least the one the endpoint requires.  The endpoint only exists if the
+/// settings allow it.
+pub fn faults_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("faults")
+        .and(warp::path::end())
+        .and(enabled(conf.settings.faults.admin_api))
+        .and(warp::method())
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Faults))
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and(with_conf(conf))
+        .map(
+            |method: http::Method,
+             query: std::collections::HashMap<String, String>,
+             conf: Config| faults(&method, &query, &conf),
+        )
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Monitoring runs the canary with a POST and reads its latest result with a GET.
+pub fn canary_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("canary")
+        .and(warp::path::end())
+        .and(enabled(conf.settings.canary.enabled))
+        .and(warp::method())
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Canary))
+        .and(with_conf(conf))
+        .then(|method: http::Method, conf: Config| async move {
+            canary(&method, &conf).await
+        })
+        .recover(recover_unauthorized)
+        .unify()
 }
 
 pub fn frl_activate_route(
@@ -346,6 +786,133 @@ pub fn upload_route(
         .then(process_adobe_request)
 }
 
+/// Custom scripts upload logs to the same endpoint as Adobe apps, but they
+/// authorize themselves with one of the configured bearer tokens.  The token
+/// is checked before the body is read, so uploads from Adobe apps fall
+/// through to the usual upload route.
+pub fn script_upload_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let tokens = Arc::new(conf.settings.log.upload_tokens.clone());
+    warp::header::optional::<String>("Authorization")
+        .and_then(move |auth: Option<String>| {
+            let tokens = tokens.clone();
+            async move {
+                match auth.as_deref().and_then(|a| a.strip_prefix("Bearer ")) {
+                    Some(token) if tokens.iter().any(|t| !t.is_empty() && t == token) => {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .untuple_one()
+        .and(Request::script_log_upload_boxed_filter(1_500_000))
+        .and(with_conf(conf))
+        .then(script_upload)
+}
+
+/// Decoders post the inventory of the machine they run on to this endpoint.
+/// It's only enabled when there are tokens to authorize them.
+pub fn inventory_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("inventory" / "v1"))
+        .and(enabled(
+            !conf.settings.frl.inventory_tokens.is_empty()
+                || conf.settings.admin.has_credentials(),
+        ))
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Inventory))
+        .and(adlu_parse::protocol::peer_addr())
+        .and(warp::body::content_length_limit(1_000_000))
+        .and(warp::body::bytes())
+        .and(with_conf(conf))
+        .then(inventory)
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Deployment tools call this hook when they uninstall an app, so that the
+/// device's license for the app's FRL package is returned.  The hook is only
+/// enabled when there are tokens to authorize its callers.
+pub fn uninstall_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("uninstall" / "v1"))
+        .and(enabled(
+            !conf.settings.frl.uninstall_tokens.is_empty()
+                || conf.settings.admin.has_credentials(),
+        ))
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Uninstall))
+        .and(warp::body::content_length_limit(10_000))
+        .and(warp::body::bytes())
+        .and(with_conf(conf))
+        .then(uninstall)
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Another proxy fetches an export of this proxy's cache here.  Transfers
+/// are only enabled when there are tokens to authorize them.
+pub fn transfer_export_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("cache" / "v1" / String))
+        .and(enabled(transfer_enabled(&conf)))
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Transfer))
+        .and(with_conf(conf))
+        .then(|source: String, conf: Config| async move {
+            transfer_export(&source, conf).await
+        })
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// Another proxy posts an export to be imported into this proxy's cache here.
+/// Transfers are only enabled when there are tokens to authorize them.
+pub fn transfer_import_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("cache" / "v1" / String))
+        .and(enabled(transfer_enabled(&conf)))
+        .and(auth::authorize(conf.clone(), AdminEndpoint::Transfer))
+        .and(warp::body::stream())
+        .and(with_conf(conf))
+        .then(|source: String, body, conf: Config| async move {
+            transfer_import(&source, body, conf).await
+        })
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+/// The IMS token requests made during named-user sign-in are passed
+/// through to the IMS server (see [`crate::ims`]).
+pub fn ims_token_route(
+    conf: Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(with_conf(conf))
+        .and_then(|path: warp::path::FullPath, conf: Config| async move {
+            if ims::is_token_path(&conf.settings.ims, path.as_str()) {
+                Ok(conf)
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .and(Request::unknown_boxed_filter(100_000))
+        .then(|conf: Config, req: Request| async move {
+            let reply = ims_token(&req, &conf).await;
+            match &conf.tracer {
+                Some(tracer) => tracer.trace_outline(&req, reply).await,
+                None => reply,
+            }
+        })
+}
+
 pub fn unknown_route(
     conf: Config,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
@@ -353,17 +920,16 @@ pub fn unknown_route(
     to_adobe_host()
         .and(Request::unknown_boxed_filter(100_000))
         .and(with_conf(conf))
-        .then(process_adobe_request)
+        .then(process_unknown_request)
         .recover(|err: Rejection| async move {
             if err.is_not_found() {
-                info!("Rejecting unknown request to non-Adobe endpoint");
-                let reply = json!({"status": "Not Found", "statusCode": 404});
-                Ok(proxy_reply(http::StatusCode::NOT_FOUND, &reply))
+                let message = "Requests to non-Adobe endpoints are not proxied";
+                let status = http::StatusCode::NOT_FOUND;
+                Ok(error_reply(ErrorCode::NotFound, status, message))
             } else {
-                warn!("Unknown request rejected for unknown reason: {:?}", err);
                 let message = format!("Request rejected: {:?}", err);
-                let reply = json!({"status": message, "statusCode": 500});
-                Ok(proxy_reply(http::StatusCode::INTERNAL_SERVER_ERROR, &reply))
+                let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+                Ok(error_reply(ErrorCode::ParseFailure, status, &message))
             }
         })
 }
@@ -381,28 +947,701 @@ fn to_adobe_host() -> impl Filter<Extract = (), Error = Rejection> + Clone {
         .untuple_one()
 }
 
+/// Unknown requests are counted by path, and then either rejected
+/// or handled like any other request (see [`crate::unknown`]).  Host
+/// routing only reaches the hosts that have configured addresses.
+async fn process_unknown_request(req: Request, conf: Config) -> warp::reply::Response {
+    conf.unknown_paths.count(&req.path);
+    match conf.settings.unknown.routing {
+        UnknownRouting::Reject => {
+            let message =
+                format!("Requests to unrecognized endpoint {} are not proxied", req.path);
+            return error_reply(
+                ErrorCode::NotFound,
+                http::StatusCode::NOT_FOUND,
+                &message,
+            );
+        }
+        UnknownRouting::Host => {
+            let host = req.host.as_deref().unwrap_or_default();
+            if !unknown::allows_host(&conf.settings.unknown, host) {
+                let message = format!("Requests to host '{}' are not proxied", host);
+                return error_reply(
+                    ErrorCode::AclDenied,
+                    http::StatusCode::FORBIDDEN,
+                    &message,
+                );
+            }
+        }
+        UnknownRouting::Upstream => {}
+    }
+    process_adobe_request(req, conf).await
+}
+
+/// Token requests and their responses carry credentials, so they are
+/// neither cached nor recorded, and only their outline is logged.
+async fn ims_token(req: &Request, conf: &Config) -> warp::reply::Response {
+    info!(
+        "Received IMS token request {} {} [{}]",
+        req.method, req.path, req.correlation_id
+    );
+    let mode = conf.settings.proxy.mode_for(&req.request_type);
+    if matches!(mode, ProxyMode::Isolated | ProxyMode::Simulate) {
+        let message = "Proxy is operating offline: IMS token requests can't be forwarded";
+        let status = http::StatusCode::BAD_GATEWAY;
+        return error_reply(ErrorCode::UpstreamUnreachable, status, message);
+    }
+    let request = match ims::upstream_request(&conf.client, &conf.ims_server, req) {
+        Ok(request) => request,
+        Err(err) => return unreachable_reply(err),
+    };
+    let response = match execute_request(conf, request).await {
+        Ok(response) => response,
+        Err(err) => {
+            error!("Can't send IMS token request [{}]: {}", req.correlation_id, err);
+            return unreachable_reply(err);
+        }
+    };
+    info!(
+        "Received IMS token response status {} [{}]",
+        response.status(),
+        req.correlation_id
+    );
+    let pass_through = &conf.settings.proxy.pass_through_headers;
+    match Response::from_network(req, response, pass_through).await {
+        Ok(resp) => resp.into_response(),
+        Err(err) => {
+            error!("Can't receive IMS token response [{}]: {}", req.correlation_id, err);
+            adobe_error_reply(err)
+        }
+    }
+}
+
 pub async fn status(conf: Config) -> warp::reply::Response {
+    let body = status_json(&conf).await;
+    let status = body["status"].as_str().unwrap_or_default();
+    info!("Status request received, issuing status: {}", status);
+    proxy_reply(http::StatusCode::OK, &body)
+}
+
+/// The proxy's status, as reported by the status endpoint.
+pub async fn status_json(conf: &Config) -> Value {
     let status = format!("{} running in {:?} mode", proxy_id(), conf.settings.proxy.mode);
-    info!("Status request received, issuing status: {}", &status);
-    let body = json!({"statusCode": 200, "status": &status});
+    let mut body = json!({"statusCode": 200, "status": &status});
+    let modes: serde_json::Map<String, Value> = conf
+        .settings
+        .proxy
+        .service_modes()
+        .into_iter()
+        .map(|(service, mode)| (service.to_string(), json!(mode)))
+        .collect();
+    body["modes"] = modes.into();
+    if let Some(not_after) = &conf.cert_expiry {
+        body["certDaysRemaining"] = json!(cert_days_remaining(not_after));
+    }
+    if conf.relay.is_enabled() {
+        body["logRelayQueue"] = json!(conf.relay.depth());
+    }
+    if conf.settings.canary.enabled {
+        body["canary"] = conf.canary.to_json();
+    }
+    body["connections"] = conf.connections.to_json();
+    body["activations"] = conf.in_flight.to_json();
+    body["unknownPaths"] = conf.unknown_paths.to_json();
+    match conf.cache.effectiveness().await {
+        Ok(effectiveness) => body["cacheEffectiveness"] = effectiveness,
+        Err(err) => error!("Can't count cache effectiveness for status: {}", err),
+    }
+    body
+}
+
+async fn landing(conf: Config) -> warp::reply::Response {
+    info!("Landing page requested");
+    let body = landing::landing_page(&conf.settings, &status_json(&conf).await);
+    let reply = warp::reply::html(body);
+    warp::reply::with_header(reply, "Via", proxy_via()).into_response()
+}
+
+fn events(conf: Config) -> warp::reply::Response {
+    info!("Event stream subscriber connected");
+    let stream = futures_util::StreamExt::map(conf.events.stream(), |event| {
+        warp::sse::Event::default().event("request").json_data(event)
+    });
+    warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response()
+}
+
+async fn activity(
+    query: &std::collections::HashMap<String, String>,
+    conf: &Config,
+) -> warp::reply::Response {
+    let bad_request = |message: &str| {
+        error_reply(ErrorCode::InvalidRequest, http::StatusCode::BAD_REQUEST, message)
+    };
+    let bin = match ActivityBin::try_from(query.get("bin").map_or("day", String::as_str))
+    {
+        Ok(bin) => bin,
+        Err(message) => return bad_request(&message),
+    };
+    let time = |name: &str| query.get(name).map(|s| s.parse::<Timestamp>()).transpose();
+    let (since, until) = match (time("since"), time("until")) {
+        (Ok(since), Ok(until)) => (since, until),
+        _ => return bad_request("The since and until parameters must be dates"),
+    };
+    // by default, show the last two days by hour or the last month by day
+    // (the range excludes `until`, so include activity from this millisecond)
+    let until =
+        until.unwrap_or_else(|| Timestamp::from_millis(Timestamp::now().to_millis() + 1));
+    let bins = if let ActivityBin::Hour = bin { 48 } else { 30 };
+    let since = since.unwrap_or_else(|| {
+        Timestamp::from_millis(until.to_millis() - bins * bin.millis())
+    });
+    match conf.cache.activity(bin, &since, &until).await {
+        Ok(counts) => {
+            let body = json!({
+                "statusCode": 200,
+                "since": since.format_rfc_3339(true),
+                "until": until.format_rfc_3339(true),
+                "bins": counts,
+            });
+            proxy_reply(http::StatusCode::OK, &body)
+        }
+        Err(err) => {
+            let message = format!("Could not count activity: {}", err);
+            let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+            error_reply(ErrorCode::CacheFailure, status, &message)
+        }
+    }
+}
+
+async fn notes(
+    query: &std::collections::HashMap<String, String>,
+    conf: &Config,
+) -> warp::reply::Response {
+    match conf.cache.notes(query.get("key").map(String::as_str)).await {
+        Ok(notes) => {
+            let body = json!({ "statusCode": 200, "notes": notes });
+            proxy_reply(http::StatusCode::OK, &body)
+        }
+        Err(err) => {
+            let message = format!("Could not fetch notes: {}", err);
+            let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+            error_reply(ErrorCode::CacheFailure, status, &message)
+        }
+    }
+}
+
+fn faults(
+    method: &http::Method,
+    query: &std::collections::HashMap<String, String>,
+    conf: &Config,
+) -> warp::reply::Response {
+    let bad_request = |message: &str| {
+        error_reply(ErrorCode::InvalidRequest, http::StatusCode::BAD_REQUEST, message)
+    };
+    match *method {
+        http::Method::GET => {}
+        http::Method::DELETE => {
+            conf.faults.stop();
+        }
+        http::Method::POST => {
+            let defaults = &conf.settings.faults;
+            let kind = match query.get("kind").map(|k| FaultKind::try_from(k.as_str())) {
+                None => defaults.kind,
+                Some(Ok(kind)) => kind,
+                Some(Err(message)) => return bad_request(&message),
+            };
+            let number =
+                |name: &str| query.get(name).map(|n| n.parse::<u64>()).transpose();
+            let (percent, secs) = match (number("percent"), number("secs")) {
+                (Ok(percent), Ok(secs)) if percent.unwrap_or_default() <= 100 => (
+                    percent.map_or(defaults.percent, |p| p as u8),
+                    secs.unwrap_or(defaults.duration_secs),
+                ),
+                _ => {
+                    return bad_request("The percent must be 0 to 100, and secs a number")
+                }
+            };
+            conf.faults.start(kind, percent, secs);
+        }
+        _ => {
+            let status = http::StatusCode::METHOD_NOT_ALLOWED;
+            return error_reply(
+                ErrorCode::InvalidRequest,
+                status,
+                "Use GET, POST, or DELETE",
+            );
+        }
+    }
+    let body = json!({ "statusCode": 200, "faults": conf.faults.status() });
     proxy_reply(http::StatusCode::OK, &body)
 }
 
-pub async fn process_adobe_request(req: Request, conf: Config) -> warp::reply::Response {
-    info!("Received {}", req);
-    debug!("Received {} request: {:?}", &req.request_type, &req);
-    if !matches!(conf.settings.proxy.mode, ProxyMode::Isolated) {
+async fn canary(method: &http::Method, conf: &Config) -> warp::reply::Response {
+    let body = match *method {
+        http::Method::GET => {
+            json!({ "statusCode": 200, "canary": conf.canary.to_json() })
+        }
+        http::Method::POST => {
+            let result = conf.canary.run(conf).await;
+            let canary = conf.canary.to_json();
+            json!({ "statusCode": 200, "result": result, "canary": canary })
+        }
+        _ => {
+            let status = http::StatusCode::METHOD_NOT_ALLOWED;
+            return error_reply(ErrorCode::InvalidRequest, status, "Use GET or POST");
+        }
+    };
+    proxy_reply(http::StatusCode::OK, &body)
+}
+
+pub async fn inventory(
+    addr: Option<std::net::SocketAddr>,
+    body: bytes::Bytes,
+    conf: Config,
+) -> warp::reply::Response {
+    let source_addr = addr.map_or_else(|| "unknown".to_string(), |a| a.ip().to_string());
+    let body = String::from_utf8_lossy(&body);
+    let report = match InventoryReport::from_body(&body) {
+        Ok(report) => report,
+        Err(err) => {
+            let message = format!("{:#}", err);
+            let status = http::StatusCode::BAD_REQUEST;
+            return error_reply(ErrorCode::InvalidRequest, status, &message);
+        }
+    };
+    info!(
+        "Received inventory of {} package(s) from {} ({})",
+        report.packages.len(),
+        &report.hostname,
+        &source_addr
+    );
+    if let Err(err) = conf.cache.store_inventory(&report, &source_addr).await {
+        let message = format!("Could not store inventory: {}", err);
+        let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+        return error_reply(ErrorCode::CacheFailure, status, &message);
+    }
+    let body = json!({"statusCode": 200, "packages": report.packages.len()});
+    proxy_reply(http::StatusCode::OK, &body)
+}
+
+/// Transfers are only enabled when there are credentials to authorize them.
+fn transfer_enabled(conf: &Config) -> bool {
+    !conf.settings.transfer.tokens.is_empty() || conf.settings.admin.has_credentials()
+}
+
+async fn transfer_export(source: &str, conf: Config) -> warp::reply::Response {
+    let source = match transfer::datasource(source) {
+        Ok(source) => source,
+        Err(err) => {
+            let status = http::StatusCode::NOT_FOUND;
+            return error_reply(ErrorCode::InvalidRequest, status, &err.to_string());
+        }
+    };
+    let path = transfer::temp_path("export");
+    if let Err(err) = conf.cache.export(&source, path.to_str().unwrap(), None).await {
+        std::fs::remove_file(&path).ok();
+        let message = format!("Could not export {}: {}", source, err);
+        let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+        return error_reply(ErrorCode::CacheFailure, status, &message);
+    }
+    info!("Sending an export of {} to another proxy", source);
+    let body = hyper::Body::wrap_stream(transfer::file_stream(path));
+    let mut response = warp::reply::Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert("Content-Type", transfer::EXPORT_CONTENT_TYPE.parse().unwrap());
+    headers.insert("Via", proxy_via().parse().unwrap());
+    response
+}
+
+async fn transfer_import(
+    source: &str,
+    body: impl futures_util::Stream<Item = Result<impl bytes::Buf, warp::Error>>,
+    conf: Config,
+) -> warp::reply::Response {
+    let source = match transfer::datasource(source) {
+        Ok(source) => source,
+        Err(err) => {
+            let status = http::StatusCode::NOT_FOUND;
+            return error_reply(ErrorCode::InvalidRequest, status, &err.to_string());
+        }
+    };
+    let path = transfer::temp_path("import");
+    if let Err(err) = transfer::write_stream(&path, body).await {
+        std::fs::remove_file(&path).ok();
+        let message = format!("Could not receive export: {:#}", err);
+        let status = http::StatusCode::BAD_REQUEST;
+        return error_reply(ErrorCode::InvalidRequest, status, &message);
+    }
+    info!("Importing an export of {} from another proxy", source);
+    let result = conf.cache.import(&source, path.to_str().unwrap()).await;
+    std::fs::remove_file(&path).ok();
+    if let Err(err) = result {
+        let message = format!("Could not import {}: {}", source, err);
+        let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+        return error_reply(ErrorCode::CacheFailure, status, &message);
+    }
+    let body = json!({"statusCode": 200, "imported": source.to_string()});
+    proxy_reply(http::StatusCode::OK, &body)
+}
+
+/// Return a device's license for a package by deactivating it.  The body
+/// gives the `deviceId` and `npdId`; the rest of the deactivation comes from
+/// the device's cached activation of the package.  Isolated proxies store the
+/// deactivation so it can be forwarded later, like any other request.
+async fn uninstall(body: bytes::Bytes, conf: Config) -> warp::reply::Response {
+    let notice: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let (device_id, npd_id) =
+        match (notice["deviceId"].as_str(), notice["npdId"].as_str()) {
+            (Some(device_id), Some(npd_id))
+                if !device_id.is_empty() && !npd_id.is_empty() =>
+            {
+                (device_id, npd_id)
+            }
+            _ => {
+                let message = "The body must be JSON with a deviceId and an npdId";
+                let status = http::StatusCode::BAD_REQUEST;
+                return error_reply(ErrorCode::InvalidRequest, status, message);
+            }
+        };
+    let req = match conf.cache.deactivation_for_device(npd_id, device_id).await {
+        Ok(Some(req)) => req,
+        Ok(None) => {
+            let message = format!(
+                "Device {} has no known activation of package {}",
+                device_id, npd_id
+            );
+            let status = http::StatusCode::NOT_FOUND;
+            return error_reply(ErrorCode::InvalidRequest, status, &message);
+        }
+        Err(err) => {
+            let message = format!("Could not look up activation: {}", err);
+            let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+            return error_reply(ErrorCode::CacheFailure, status, &message);
+        }
+    };
+    info!(
+        "Uninstall hook for package {} on device {}: issuing {}",
+        npd_id, device_id, req
+    );
+    if let ProxyMode::Isolated = conf.settings.proxy.mode_for(&req.request_type) {
         conf.cache.store_request(&req).await;
+        conf.events.publish(Event::new(&req, "stored"));
+        let body = json!({
+            "statusCode": 202,
+            "status": "Deactivation stored for forwarding",
+            "requestId": &req.request_id,
+        });
+        let reply = proxy_reply(http::StatusCode::ACCEPTED, &body);
+        return traced(&conf, &req, reply).await;
+    }
+    process_adobe_request(req, conf).await
+}
+
+/// Script uploads are stored (marked as such) but never forwarded,
+/// since Adobe would not accept their authorization.
+pub async fn script_upload(req: Request, conf: Config) -> warp::reply::Response {
+    info!("Received {} from an upload script", req);
+    let mut reply = match conf.cache.store_script_upload(&req).await {
+        Ok(count) => {
+            info!("Stored {} log session(s) from {}", count, req);
+            let body = json!({"statusCode": 200, "sessions": count});
+            proxy_reply(http::StatusCode::OK, &body)
+        }
+        Err(err) => {
+            let message = format!("Could not store upload: {}", err);
+            let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+            error_reply(ErrorCode::CacheFailure, status, &message)
+        }
+    };
+    if let Ok(val) = http::HeaderValue::from_str(&req.correlation_id) {
+        reply.headers_mut().insert(CORRELATION_ID_HEADER, val);
     }
-    match send_request(&conf, &req).await {
+    traced(&conf, &req, reply).await
+}
+
+/// Pass the reply to a request through the tracer, if there is one.
+async fn traced(
+    conf: &Config,
+    req: &Request,
+    reply: warp::reply::Response,
+) -> warp::reply::Response {
+    match &conf.tracer {
+        Some(tracer) => tracer.trace(req, reply).await,
+        None => reply,
+    }
+}
+
+pub async fn process_adobe_request(
+    mut req: Request,
+    conf: Config,
+) -> warp::reply::Response {
+    let lenient = conf.settings.security.lenient_parsing;
+    if let Some(failure) = ParseFailure::check(&mut req, lenient) {
+        if failure.recovered {
+            warn!("Rewrote unparseable body of {}: {}", req, &failure.error);
+        } else {
+            warn!("Can't parse body of {}: {}", req, &failure.error);
+        }
+        conf.cache.store_parse_failure(&failure).await;
+    }
+    let mut reply = reply_to_adobe_request(&req, &conf).await;
+    // replies from the proxy have their whole body, so its size is known
+    let response_bytes = hyper::body::HttpBody::size_hint(reply.body()).exact();
+    conf.cache.store_bandwidth(&req, response_bytes.unwrap_or_default()).await;
+    if let Ok(val) = http::HeaderValue::from_str(&req.correlation_id) {
+        reply.headers_mut().insert(CORRELATION_ID_HEADER, val);
+    }
+    traced(&conf, &req, reply).await
+}
+
+/// The response header that carries the proxy's correlation id for a request.
+pub const CORRELATION_ID_HEADER: &str = "X-Proxy-Correlation-Id";
+
+async fn reply_to_adobe_request(req: &Request, conf: &Config) -> warp::reply::Response {
+    info!("Received {}", req);
+    debug!("Received {} request: {:?}", &req.request_type, req);
+    if conf.settings.security.validate_request_bodies {
+        if let Some(failure) = ValidationFailure::check(req) {
+            warn!("Invalid request body in {}: {}", req, failure.summary());
+            conf.cache.store_validation_failure(&failure).await;
+            conf.events.publish(Event::new(req, ErrorCode::InvalidRequest.as_str()));
+            return invalid_request_reply(&failure.problems);
+        }
+    }
+    if let Some(attempt) = conf.api_keys.validate(req) {
+        warn!("Invalid api key in {}: {}", req, &attempt.reason);
+        conf.cache.store_invalid_key_attempt(&attempt).await;
+        if conf.api_keys.rejects_invalid() {
+            conf.events.publish(Event::new(req, "invalid-api-key"));
+            return invalid_api_key_reply(&attempt.reason);
+        }
+    }
+    if let Some(reason) = check_package_quota(conf, req).await {
+        warn!("Refusing {}: {}", req, &reason);
+        conf.events.publish(Event::new(req, ErrorCode::QuotaExceeded.as_str()));
+        return quota_exceeded_reply(conf, &reason);
+    }
+    let mode = conf.settings.proxy.mode_for(&req.request_type);
+    if !matches!(mode, ProxyMode::Isolated | ProxyMode::Simulate) {
+        conf.cache.store_request(req).await;
+        if let Some(location) = req.source_ip.and_then(|ip| conf.geoip.locate(ip)) {
+            conf.cache.store_location(req, &location).await;
+        }
+    }
+    if let Some(resp) = stale_response(conf, req).await {
+        return resp.into_response();
+    }
+    if matches!(req.request_type, RequestType::LogUpload)
+        && conf.relay.enqueue(&mode, req)
+    {
+        info!("Queued {} for relay to Adobe", req);
+        conf.cache.count_reply(req, &RequestOutcome::IsolatedStored).await;
+        return LogUploadResponse::new().into_response();
+    }
+    let leader = match join_in_flight(conf, req, &mode) {
+        Some(Role::Follower(follower)) => {
+            info!("Waiting for an identical request already in flight: {}", req);
+            if let Some(resp) = follow_in_flight(conf, req, follower.wait().await).await {
+                return resp.into_response();
+            }
+            None
+        }
+        Some(Role::Leader(leader)) => Some(leader),
+        None => None,
+    };
+    let outcome = send_within_budget(conf, req).await;
+    if let Some(leader) = leader {
+        leader.finish(matches!(outcome, SendOutcome::Success(_)));
+    }
+    match outcome {
         SendOutcome::Success(resp) => resp.into_response(),
         SendOutcome::Isolated => proxy_offline_reply(),
+        SendOutcome::Throttled(secs) => throttled_reply(secs),
         SendOutcome::Unreachable(err) => unreachable_reply(err),
         SendOutcome::ParseFailure(err) => adobe_error_reply(err),
-        SendOutcome::ErrorStatus(response) => adobe_bad_status_reply(response).await,
+        SendOutcome::ErrorStatus(response) => {
+            adobe_bad_status_reply(response, conf.throttle.remaining_secs()).await
+        }
+        SendOutcome::CacheFailure(err) => cache_failure_reply(err),
+    }
+}
+
+/// Activations that will be sent upstream take part in de-duplication
+/// (see [`crate::inflight`]), by their activation key.
+fn join_in_flight<'a>(
+    conf: &'a Config,
+    req: &Request,
+    mode: &ProxyMode,
+) -> Option<Role<'a>> {
+    if !matches!(req.request_type, RequestType::FrlActivation)
+        || matches!(mode, ProxyMode::Isolated | ProxyMode::Simulate)
+    {
+        return None;
+    }
+    let body = FrlActivationRequestBody::from_body(req.body.as_deref()?).ok()?;
+    Some(conf.in_flight.join(&body.activation_id()))
+}
+
+/// The response cached by the leader that a request followed, if it cached
+/// one.  If not, the request will be sent upstream on its own.
+async fn follow_in_flight(
+    conf: &Config,
+    req: &Request,
+    cached: bool,
+) -> Option<Response> {
+    if !cached {
+        info!("The identical request in flight failed; sending {}", req);
+        return None;
+    }
+    match conf.cache.try_fetch_response(req).await {
+        Ok(Some(resp)) => {
+            info!("Using the response to the identical request in flight for {}", req);
+            record_reply(conf, req, &RequestOutcome::CacheHit).await;
+            Some(resp)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            error!(
+                "Can't fetch the response to the identical request in flight: {}",
+                err
+            );
+            None
+        }
     }
 }
 
+/// If an FRL activation would put its package over quota, the reason to refuse
+/// it.  Devices that already hold an activation for the package (for example,
+/// because they are refreshing it) are never refused, so a quota only stops
+/// new devices.  A package with its own quota is not also subject to the quota
+/// of its org.  If the cache can't be read, the activation is allowed.
+pub async fn check_package_quota(conf: &Config, req: &Request) -> Option<String> {
+    let quotas = &conf.settings.frl.quotas;
+    if quotas.is_empty() || !matches!(req.request_type, RequestType::FrlActivation) {
+        return None;
+    }
+    let parse = FrlActivationRequestBody::from_body(req.body.as_deref()?).ok()?;
+    let device_id = &parse.device_details.device_id;
+    let (quota, usage) = match quotas.iter().find(|q| q.npd_id == parse.npd_id) {
+        Some(quota) => (quota, conf.cache.package_usage(&parse.npd_id, device_id).await),
+        None if quotas.iter().any(|q| !q.org_id.is_empty()) => {
+            let org_id = match conf.cache.package_org(&parse.npd_id).await {
+                Ok(org_id) if !org_id.is_empty() => org_id,
+                Ok(_) => return None,
+                Err(err) => {
+                    error!("Can't find the org of package {}: {}", &parse.npd_id, err);
+                    return None;
+                }
+            };
+            let quota =
+                quotas.iter().find(|q| q.npd_id.is_empty() && q.org_id == org_id)?;
+            (quota, conf.cache.org_usage(&org_id, device_id).await)
+        }
+        None => return None,
+    };
+    let kind = if quota.npd_id.is_empty() { "org" } else { "package" };
+    match usage {
+        Ok((_, true)) => None,
+        Ok((count, false)) if count < quota.max_activations => {
+            info!(
+                "The {} {} will have {} of {} activation(s) in use",
+                kind,
+                quota.label(),
+                count + 1,
+                quota.max_activations
+            );
+            None
+        }
+        Ok((count, false)) => Some(format!(
+            "{} {} has {} of {} activation(s) in use",
+            kind,
+            quota.label(),
+            count,
+            quota.max_activations
+        )),
+        Err(err) => {
+            error!("Can't check the quota of {} {}: {}", kind, quota.label(), err);
+            None
+        }
+    }
+}
+
+/// When stale-while-revalidate is on, a connected proxy answers FRL activations
+/// from the cache right away, and forwards them in the background to refresh
+/// the cached copy.
+async fn stale_response(conf: &Config, req: &Request) -> Option<Response> {
+    if !conf.settings.frl.stale_while_revalidate
+        || !matches!(
+            conf.settings.proxy.mode_for(&req.request_type),
+            ProxyMode::Connected
+        )
+        || !matches!(req.request_type, RequestType::FrlActivation)
+    {
+        return None;
+    }
+    let resp = conf.cache.fetch_response(req).await?;
+    info!("Using cached response for {} while revalidating", req);
+    record_reply(conf, req, &RequestOutcome::CacheHit).await;
+    // the client already has its answer, so the revalidation isn't recorded
+    let (conf, req) = (conf.clone(), req.clone());
+    tokio::spawn(async move {
+        if let (_, RequestOutcome::ForwardedSuccess) = send_unrecorded(&conf, &req).await
+        {
+            info!("Revalidated cached response for {}", &req);
+        }
+    });
+    Some(resp)
+}
+
+/// Send a request upstream, but if there's a latency budget for its type
+/// and Adobe hasn't answered within it, answer from the cache if possible.
+/// The request keeps going in the background, so Adobe's answer (if it
+/// comes) still refreshes the cache, but only the answer from the cache is
+/// recorded, since that's what the client got.
+async fn send_within_budget(conf: &Config, req: &Request) -> SendOutcome {
+    let mode = conf.settings.proxy.mode_for(&req.request_type);
+    if let ProxyMode::Simulate = mode {
+        return send_request(conf, req).await;
+    }
+    let budget = match conf.settings.upstream.latency_budget(&req.request_type) {
+        Some(budget) if matches!(mode, ProxyMode::Connected | ProxyMode::Transparent) => {
+            budget
+        }
+        _ => {
+            let (outcome, recorded) = send_unrecorded(conf, req).await;
+            record_reply(conf, req, &recorded).await;
+            return outcome;
+        }
+    };
+    let (bg_conf, bg_req) = (conf.clone(), req.clone());
+    let mut upstream =
+        tokio::spawn(async move { send_unrecorded(&bg_conf, &bg_req).await });
+    let joined = match tokio::time::timeout(budget, &mut upstream).await {
+        Ok(joined) => joined,
+        Err(_) => match conf.cache.try_fetch_response(req).await {
+            Ok(Some(resp)) if use_cached_response(conf, req) => {
+                warn!(
+                    "Adobe didn't answer {} within {}ms; using cached response",
+                    req,
+                    budget.as_millis()
+                );
+                record_reply(conf, req, &RequestOutcome::CacheHit).await;
+                return SendOutcome::Success(resp);
+            }
+            Ok(_) => upstream.await,
+            Err(err) => {
+                error!("Can't fetch cached response for {}: {}", req, err);
+                upstream.await
+            }
+        },
+    };
+    let (outcome, recorded) = joined.unwrap_or_else(|err| {
+        (SendOutcome::Unreachable(eyre!(err)), RequestOutcome::UpstreamError)
+    });
+    record_reply(conf, req, &recorded).await;
+    outcome
+}
+
 pub async fn forward_stored_request(conf: &Config, req: &Request) -> bool {
     matches!(send_request(conf, req).await, SendOutcome::Success(_))
 }
@@ -410,23 +1649,103 @@ pub async fn forward_stored_request(conf: &Config, req: &Request) -> bool {
 pub enum SendOutcome {
     Success(Response),
     Isolated,
+    Throttled(u64),
     Unreachable(Report),
     ParseFailure(Report),
     ErrorStatus(reqwest::Response),
+    CacheFailure(Report),
+}
+
+/// How a request was handled, as recorded in the cache for reporting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestOutcome {
+    ForwardedSuccess,
+    CacheHit,
+    IsolatedStored,
+    UpstreamThrottled,
+    UpstreamError,
+}
+
+impl From<&SendOutcome> for RequestOutcome {
+    fn from(outcome: &SendOutcome) -> Self {
+        match outcome {
+            SendOutcome::Success(_) => RequestOutcome::ForwardedSuccess,
+            SendOutcome::Isolated => RequestOutcome::IsolatedStored,
+            SendOutcome::Throttled(_) => RequestOutcome::UpstreamThrottled,
+            SendOutcome::Unreachable(_)
+            | SendOutcome::ParseFailure(_)
+            | SendOutcome::ErrorStatus(_)
+            | SendOutcome::CacheFailure(_) => RequestOutcome::UpstreamError,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestOutcome::ForwardedSuccess => "forwarded-success".fmt(f),
+            RequestOutcome::CacheHit => "cache-hit".fmt(f),
+            RequestOutcome::IsolatedStored => "isolated-stored".fmt(f),
+            RequestOutcome::UpstreamThrottled => "upstream-throttled".fmt(f),
+            RequestOutcome::UpstreamError => "upstream-error".fmt(f),
+        }
+    }
 }
 
 pub async fn send_request(conf: &Config, req: &Request) -> SendOutcome {
-    let outcome = if let ProxyMode::Isolated = conf.settings.proxy.mode {
+    let mode = conf.settings.proxy.mode_for(&req.request_type);
+    if let ProxyMode::Simulate = mode {
+        // simulated responses are neither cached nor recorded
+        info!("Simulating response to {}", req);
+        conf.events.publish(Event::new(req, "simulated"));
+        return match simulate::response(req) {
+            Ok(resp) => SendOutcome::Success(resp),
+            Err(err) => {
+                warn!("Can't simulate response to {}: {}", req, err);
+                SendOutcome::Isolated
+            }
+        };
+    }
+    let (outcome, recorded) = send_unrecorded(conf, req).await;
+    record_outcome(conf, req, &recorded).await;
+    outcome
+}
+
+/// Record how a request was handled, in the cache and on the event stream.
+async fn record_outcome(conf: &Config, req: &Request, outcome: &RequestOutcome) {
+    conf.cache.store_outcome(req, outcome).await;
+    conf.events.publish(Event::new(req, &outcome.to_string()));
+}
+
+/// Record how a client's request was answered, which (unlike the outcome of
+/// a request sent by the proxy itself, or forwarded later) is also counted
+/// toward the cache's effectiveness.
+async fn record_reply(conf: &Config, req: &Request, outcome: &RequestOutcome) {
+    record_outcome(conf, req, outcome).await;
+    conf.cache.count_reply(req, outcome).await;
+}
+
+/// Send a request upstream (unless the proxy is isolated or throttled),
+/// falling back to the cache if that fails, without recording how it went.
+/// Returns the outcome and how it should be recorded.
+async fn send_unrecorded(conf: &Config, req: &Request) -> (SendOutcome, RequestOutcome) {
+    let mode = conf.settings.proxy.mode_for(&req.request_type);
+    let outcome = if let ProxyMode::Isolated = mode {
         info!("Isolated - not forwarding {}", req);
         SendOutcome::Isolated
+    } else if let Some(secs) = conf.throttle.remaining_secs() {
+        info!("Adobe is throttling requests - not forwarding {} for {}s", req, secs);
+        SendOutcome::Throttled(secs)
     } else {
         info!("Sending {} to Adobe endpoint", req);
         match send_to_adobe(req, conf).await {
             Ok(response) => {
+                upstream_reachable(true, None);
                 let status = response.status();
                 if status.is_success() {
                     info!("Received valid response status for {}: {}", req, status);
-                    match Response::from_network(req, response).await {
+                    let pass_through = &conf.settings.proxy.pass_through_headers;
+                    match Response::from_network(req, response, pass_through).await {
                         Ok(resp) => {
                             debug!("Response for {}: {:?}", req, resp);
                             // cache the response
@@ -440,6 +1759,10 @@ pub async fn send_request(conf: &Config, req: &Request) -> SendOutcome {
                     }
                 } else {
                     info!("Received failure status for {}: {}", req, status);
+                    let upstream = &conf.settings.upstream;
+                    if let Some(secs) = conf.throttle.note_response(&response, upstream) {
+                        warn!("Adobe is throttling requests: holding off for {}s", secs);
+                    }
                     debug!("Response for {}: {:?}", req, response);
                     // return the safe bits of the response
                     SendOutcome::ErrorStatus(response)
@@ -447,25 +1770,72 @@ pub async fn send_request(conf: &Config, req: &Request) -> SendOutcome {
             }
             Err(err) => {
                 info!("Network failure sending {}", req);
+                upstream_reachable(false, Some(&err));
                 SendOutcome::Unreachable(err)
             }
         }
     };
-    if let SendOutcome::Success(resp) = outcome {
+    let mut recorded = RequestOutcome::from(&outcome);
+    let outcome = if let SendOutcome::Success(resp) = outcome {
         SendOutcome::Success(resp)
-    } else if let Some(resp) = conf.cache.fetch_response(req).await {
-        info!("Using previously cached response for {}", req);
-        SendOutcome::Success(resp)
-    } else {
+    } else if !use_cached_response(conf, req) {
         outcome
+    } else {
+        match conf.cache.try_fetch_response(req).await {
+            Ok(Some(resp)) => {
+                info!("Using previously cached response for {}", req);
+                recorded = RequestOutcome::CacheHit;
+                SendOutcome::Success(resp)
+            }
+            Ok(None) => outcome,
+            Err(err) => SendOutcome::CacheFailure(err),
+        }
+    };
+    (outcome, recorded)
+}
+
+/// Whether the last attempt to reach Adobe failed.
+static UPSTREAM_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Record whether Adobe was reachable, so that the start and end
+/// of an outage are logged as critical events (but only once each).
+fn upstream_reachable(reachable: bool, err: Option<&Report>) {
+    let was_down = UPSTREAM_DOWN.swap(!reachable, Ordering::Relaxed);
+    if was_down && reachable {
+        critical_event(Level::Info, "Adobe licensing servers are reachable again");
+    } else if !was_down && !reachable {
+        let reason = err.map(|e| format!(": {:#}", e)).unwrap_or_default();
+        critical_event(
+            Level::Warn,
+            &format!("Adobe licensing servers are unreachable{}", reason),
+        );
+    }
+}
+
+fn use_cached_response(conf: &Config, req: &Request) -> bool {
+    match req.request_type {
+        // the cache synthesizes log upload responses, which is configurable
+        RequestType::LogUpload => {
+            let mode = conf.settings.proxy.mode_for(&req.request_type);
+            conf.settings.log.synthesize_response(&mode)
+        }
+        _ => true,
+    }
+}
+
+/// The upstream server that a request is sent to.
+pub fn upstream_server(conf: &Config, req: &Request) -> Result<String> {
+    match req.request_type {
+        RequestType::LogUpload => Ok(conf.log_server.clone()),
+        RequestType::Unknown => {
+            unknown::server_for(&conf.settings.unknown, &conf.unknown_server, req)
+        }
+        _ => Ok(conf.frl_server.clone()),
     }
 }
 
 pub async fn send_to_adobe(req: &Request, conf: &Config) -> Result<reqwest::Response> {
-    let server = match req.request_type {
-        RequestType::LogUpload => conf.log_server.as_str(),
-        _ => conf.frl_server.as_str(),
-    };
+    let server = upstream_server(conf, req)?;
     let endpoint = if let Some(query) = &req.query {
         format!("{}/{}?{}", server, &req.path, query)
     } else {
@@ -500,6 +1870,25 @@ pub async fn send_to_adobe(req: &Request, conf: &Config) -> Result<reqwest::Resp
         builder = builder.body(body.clone())
     }
     let request = builder.build().wrap_err("Error creating network request")?;
+    match &conf.cassette {
+        Some(cassette) if cassette.is_replaying() => cassette.replay(&request),
+        Some(cassette) => {
+            let recorded = RecordedRequest::from(&request);
+            let response = execute_request(conf, request).await?;
+            cassette.record(&recorded, response).await
+        }
+        None => execute_request(conf, request).await,
+    }
+}
+
+async fn execute_request(
+    conf: &Config,
+    request: reqwest::Request,
+) -> Result<reqwest::Response> {
+    if let Some(kind) = conf.faults.next_fault() {
+        warn!("Injecting {} fault into {} {}", kind, request.method(), request.url());
+        return crate::faults::response(kind, &request);
+    }
     if cfg!(test) {
         mock_adobe_server(conf, request).await.wrap_err("Error mocking network request")
     } else {
@@ -528,28 +1917,175 @@ fn proxy_reply(status: http::StatusCode, body: &Value) -> warp::reply::Response
     .into_response()
 }
 
+/// Why the proxy (rather than Adobe) failed a request.  The code is
+/// logged, and is sent to the client both in the JSON body of the reply and in
+/// the [`ERROR_CODE_HEADER`], so that support scripts can branch on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    IsolatedStore,
+    UpstreamUnreachable,
+    UpstreamErrorStatus,
+    ParseFailure,
+    CacheFailure,
+    AclDenied,
+    NotFound,
+    InvalidRequest,
+    Unauthorized,
+    UpstreamThrottled,
+    QuotaExceeded,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::IsolatedStore => "isolated-store",
+            ErrorCode::UpstreamUnreachable => "upstream-unreachable",
+            ErrorCode::UpstreamErrorStatus => "upstream-error-status",
+            ErrorCode::ParseFailure => "parse-failure",
+            ErrorCode::CacheFailure => "cache-failure",
+            ErrorCode::AclDenied => "acl-denied",
+            ErrorCode::NotFound => "not-found",
+            ErrorCode::InvalidRequest => "invalid-request",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::UpstreamThrottled => "upstream-throttled",
+            ErrorCode::QuotaExceeded => "quota-exceeded",
+        }
+    }
+
+    fn log_level(&self) -> log::Level {
+        match self {
+            // storing requests is what an isolated proxy is supposed to do
+            ErrorCode::IsolatedStore => log::Level::Debug,
+            ErrorCode::AclDenied
+            | ErrorCode::NotFound
+            | ErrorCode::UpstreamErrorStatus
+            | ErrorCode::InvalidRequest
+            | ErrorCode::Unauthorized
+            | ErrorCode::UpstreamThrottled
+            | ErrorCode::QuotaExceeded => log::Level::Warn,
+            _ => log::Level::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// The response header that carries the proxy's error code for a failed request.
+pub const ERROR_CODE_HEADER: &str = "X-Proxy-Error-Code";
+
+fn error_reply(
+    code: ErrorCode,
+    status: http::StatusCode,
+    message: &str,
+) -> warp::reply::Response {
+    error_reply_with_body(code, status, message, json!({}))
+}
+
+/// An error reply whose JSON body has fields beyond the standard ones.
+fn error_reply_with_body(
+    code: ErrorCode,
+    status: http::StatusCode,
+    message: &str,
+    mut body: Value,
+) -> warp::reply::Response {
+    log::log!(code.log_level(), "Replying with {} ({}): {}", status, code, message);
+    body["statusCode"] = json!(status.as_u16());
+    body["errorCode"] = json!(code.as_str());
+    body["message"] = json!(message);
+    let mut reply = proxy_reply(status, &body);
+    let code_val = http::HeaderValue::from_static(code.as_str());
+    reply.headers_mut().insert(ERROR_CODE_HEADER, code_val);
+    reply
+}
+
+/// An error reply to a caller who isn't authorized (see [`crate::auth`]).
+fn unauthorized_reply(unauthorized: &auth::Unauthorized) -> warp::reply::Response {
+    let status = http::StatusCode::UNAUTHORIZED;
+    let message = format!(
+        "Valid credentials are required for the {} endpoint",
+        unauthorized.endpoint
+    );
+    let mut reply = error_reply(ErrorCode::Unauthorized, status, &message);
+    if let Some(challenge) = unauthorized.challenge {
+        let value = http::HeaderValue::from_static(challenge);
+        reply.headers_mut().insert("WWW-Authenticate", value);
+    }
+    reply
+}
+
 fn proxy_offline_reply() -> warp::reply::Response {
     let message = "Proxy is operating offline: request stored for later replay";
-    debug!("{}", message);
-    let body = json!({"statusCode": 502, "message": message});
-    proxy_reply(http::StatusCode::BAD_GATEWAY, &body)
+    error_reply(ErrorCode::IsolatedStore, http::StatusCode::BAD_GATEWAY, message)
+}
+
+fn invalid_api_key_reply(reason: &str) -> warp::reply::Response {
+    let message = format!("Invalid api key: {}", reason);
+    error_reply(ErrorCode::AclDenied, http::StatusCode::FORBIDDEN, &message)
+}
+
+fn invalid_request_reply(problems: &[FieldProblem]) -> warp::reply::Response {
+    let message =
+        format!("Invalid request: {} field(s) missing or invalid", problems.len());
+    let body = json!({ "invalidFields": problems });
+    let status = http::StatusCode::BAD_REQUEST;
+    error_reply_with_body(ErrorCode::InvalidRequest, status, &message, body)
 }
 
 fn unreachable_reply(err: Report) -> warp::reply::Response {
     let message = format!("Could not reach Adobe: {}", err);
-    error!("{}", &message);
-    let body = json!({"statusCode": 502, "message": message});
-    proxy_reply(http::StatusCode::BAD_GATEWAY, &body)
+    error_reply(ErrorCode::UpstreamUnreachable, http::StatusCode::BAD_GATEWAY, &message)
+}
+
+fn quota_exceeded_reply(conf: &Config, reason: &str) -> warp::reply::Response {
+    let frl = &conf.settings.frl;
+    let status = http::StatusCode::from_u16(frl.quota_status)
+        .unwrap_or(http::StatusCode::FORBIDDEN);
+    let message = format!("{} ({})", frl.quota_message, reason);
+    error_reply(ErrorCode::QuotaExceeded, status, &message)
+}
+
+/// While Adobe is throttling us, clients are asked to wait for as long as we are.
+fn throttled_reply(secs: u64) -> warp::reply::Response {
+    let message = format!("Adobe is throttling requests: retry after {} second(s)", secs);
+    let status = http::StatusCode::SERVICE_UNAVAILABLE;
+    let mut reply = error_reply(ErrorCode::UpstreamThrottled, status, &message);
+    reply.headers_mut().insert("Retry-After", http::HeaderValue::from(secs));
+    reply
 }
 
-async fn adobe_bad_status_reply(resp: reqwest::Response) -> warp::reply::Response {
-    let mut builder = http::Response::builder().status(resp.status());
+fn cache_failure_reply(err: Report) -> warp::reply::Response {
+    let message = format!("Could not read cached response: {}", err);
+    let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+    error_reply(ErrorCode::CacheFailure, status, &message)
+}
+
+/// Pass through an Adobe error response.  If Adobe is throttling us but
+/// didn't say for how long, the client is told how long we're holding off.
+async fn adobe_bad_status_reply(
+    resp: reqwest::Response,
+    retry_after: Option<u64>,
+) -> warp::reply::Response {
+    let code = ErrorCode::UpstreamErrorStatus;
+    log::log!(code.log_level(), "Passing through {} ({})", resp.status(), code);
+    // Adobe's body is passed through as is, so only the header has the code
+    let mut builder = http::Response::builder()
+        .status(resp.status())
+        .header(ERROR_CODE_HEADER, code.as_str());
     if let Some(request_id) = resp.headers().get("X-Request-Id") {
         builder = builder.header("X-Request-Id", request_id)
     }
     if let Some(content_type) = resp.headers().get("Content-Type") {
         builder = builder.header("Content-Type", content_type)
     }
+    if let Some(retry_after) = resp.headers().get("Retry-After") {
+        builder = builder.header("Retry-After", retry_after)
+    } else if let Some(secs) = retry_after {
+        builder = builder.header("Retry-After", secs)
+    }
     if let Some(via) = resp.headers().get("Via") {
         builder = builder.header(
             "Via",
@@ -567,9 +2103,8 @@ async fn adobe_bad_status_reply(resp: reqwest::Response) -> warp::reply::Respons
 
 fn adobe_error_reply(err: Report) -> warp::reply::Response {
     let message = format!("Invalid Adobe response: {}", err);
-    error!("{}", &message);
-    let body = json!({"statusCode": 500, "message": message});
-    proxy_reply(http::StatusCode::INTERNAL_SERVER_ERROR, &body)
+    let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+    error_reply(ErrorCode::ParseFailure, status, &message)
 }
 
 pub fn proxy_id() -> String {
@@ -0,0 +1,145 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Support for the PROXY protocol (versions 1 and 2), which load balancers such
+as HAProxy use in TCP mode to tell the servers behind them who their clients are.
+
+When the protocol is enabled, every connection must start with a PROXY header.
+(The protocol requires this, so that a client which connects directly can't
+claim to be someone else.)  The client address in the header is attached to
+each request read from the connection, so it is recorded as the request's
+source address.  On an HTTPS listener, the header comes before the TLS
+handshake.  (The proxy's accept loop, which reads the header, is in the
+`listener` module.)
+ */
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use eyre::{eyre, Result, WrapErr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The first 12 bytes of every version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a version 1 header can be, including its CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read the PROXY header at the start of a connection, returning the client
+/// address it reports.  Headers for connections that the load balancer makes
+/// on its own behalf (such as health checks) don't report an address.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>> {
+    // both versions of header are at least as long as the version 2 signature
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let mut addresses = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(fixed[0], fixed[1], &addresses)
+    } else if prefix.starts_with(b"PROXY ") {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(eyre!("PROXY header is too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(eyre!("Connection did not start with a PROXY header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).wrap_err("PROXY header is not text")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr =
+                source.parse().wrap_err("Invalid source address in PROXY header")?;
+            let port: u16 =
+                port.parse().wrap_err("Invalid source port in PROXY header")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(eyre!("Malformed PROXY header: {}", line)),
+    }
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(eyre!("Unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // a LOCAL connection, made by the load balancer itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(eyre!("Unsupported PROXY protocol command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[0..4].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[0..16].try_into()?;
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        // unspecified and unix socket addresses don't identify a client
+        0 | 3 => Ok(None),
+        _ => Err(eyre!("Malformed PROXY header address")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_header, V2_SIGNATURE};
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut v1 = &b"PROXY TCP4 192.0.2.1 192.0.2.2 5555 443\r\nGET"[..];
+        let addr = read_header(&mut v1).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:5555".parse().unwrap()));
+        assert_eq!(v1, b"GET");
+        let mut v1 = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut v1).await.unwrap(), None);
+        let mut v1 = &b"PROXY TCP6 2001:db8::1 2001:db8::2 6666 443\r\n"[..];
+        let addr = read_header(&mut v1).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:6666".parse().unwrap()));
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 12, 198, 51, 100, 7, 192, 0, 2, 2, 0x1f, 0x90, 1, 187]);
+        v2.extend(b"GET");
+        let mut v2 = &v2[..];
+        let addr = read_header(&mut v2).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.7:8080".parse().unwrap()));
+        assert_eq!(v2, b"GET");
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).await.unwrap(), None);
+        let mut plain = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        assert!(read_header(&mut plain).await.is_err());
+    }
+}
@@ -0,0 +1,196 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Background relaying of log uploads to Adobe.
+
+When a lot of clients come online at once, forwarding all of their log uploads
+while they wait can saturate a slow WAN link.  With relaying enabled, a log
+upload is stored and acknowledged right away, and then queued for a worker
+task that forwards queued uploads to Adobe with limited concurrency and
+bandwidth.  The queue is bounded: when it's full, uploads are forwarded
+while the client waits, as they would be without relaying.
+
+Uploads still in the queue when the server stops are not forwarded, but
+their sessions have already been stored in the cache.
+ */
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+use crate::proxy::{send_request, Config, Request, SendOutcome};
+use crate::settings::{Log, ProxyMode};
+
+/// The queue of log uploads waiting to be relayed.
+#[derive(Debug)]
+pub struct RelayQueue {
+    sender: Option<Sender<Request>>,
+    receiver: Mutex<Option<Receiver<Request>>>,
+    depth: AtomicUsize,
+}
+
+impl RelayQueue {
+    pub fn new(settings: &Log) -> Self {
+        if !settings.relay_uploads {
+            return RelayQueue {
+                sender: None,
+                receiver: Mutex::new(None),
+                depth: AtomicUsize::new(0),
+            };
+        }
+        let (sender, receiver) = mpsc::channel(settings.relay_queue_size.max(1));
+        RelayQueue {
+            sender: Some(sender),
+            receiver: Mutex::new(Some(receiver)),
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// The number of uploads that have been queued but not yet relayed.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Queue an upload for relaying, returning whether it was queued.
+    /// Only connected and transparent proxies relay uploads, and they
+    /// only relay them once a worker has been started to do it.
+    pub fn enqueue(&self, mode: &ProxyMode, req: &Request) -> bool {
+        if !matches!(mode, ProxyMode::Connected | ProxyMode::Transparent) {
+            return false;
+        }
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return false,
+        };
+        if self.receiver.lock().unwrap().is_some() {
+            return false;
+        }
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        match sender.try_send(req.clone()) {
+            Ok(()) => true,
+            Err(err) => {
+                self.depth.fetch_sub(1, Ordering::Relaxed);
+                if let TrySendError::Full(_) = err {
+                    warn!("Log relay queue is full: forwarding {} directly", req);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Start relaying queued log uploads, if relaying is enabled.
+/// Abort the returned task to stop relaying.
+pub fn spawn(conf: &Config) -> Option<JoinHandle<()>> {
+    let receiver = conf.relay.receiver.lock().unwrap().take()?;
+    let log = &conf.settings.log;
+    info!(
+        "Relaying log uploads with {} connection(s) and {}",
+        log.relay_concurrency.max(1),
+        match log.relay_max_kbps {
+            0 => "no bandwidth limit".to_string(),
+            kbps => format!("a limit of {} KB/s", kbps),
+        }
+    );
+    Some(tokio::spawn(run(conf.clone(), receiver)))
+}
+
+async fn run(conf: Config, mut receiver: Receiver<Request>) {
+    let permits = Arc::new(Semaphore::new(conf.settings.log.relay_concurrency.max(1)));
+    let pacer = Arc::new(Pacer::new(conf.settings.log.relay_max_kbps));
+    while let Some(req) = receiver.recv().await {
+        let permit = match permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        let (conf, pacer) = (conf.clone(), pacer.clone());
+        tokio::spawn(async move {
+            if let Some(secs) = conf.throttle.remaining_secs() {
+                debug!(
+                    "Adobe is throttling requests: waiting {}s to relay {}",
+                    secs, req
+                );
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+            }
+            let size = req.body.as_ref().map_or(0, |body| body.len());
+            tokio::time::sleep(pacer.delay_for(size, Instant::now())).await;
+            match send_request(&conf, &req).await {
+                SendOutcome::Success(_) => info!("Relayed {} to Adobe", req),
+                _ => warn!("Failed to relay {} to Adobe", req),
+            }
+            conf.relay.depth.fetch_sub(1, Ordering::Relaxed);
+            drop(permit);
+        });
+    }
+}
+
+/// Spaces out uploads so that, taken together, they stay under a bandwidth
+/// limit.  Each upload reserves the time its bytes take at the limit, starting
+/// when the uploads before it have finished their reservations.
+#[derive(Debug)]
+struct Pacer {
+    bytes_per_sec: u64,
+    next_free: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    fn new(max_kbps: u64) -> Self {
+        Pacer { bytes_per_sec: max_kbps * 1024, next_free: Mutex::new(None) }
+    }
+
+    /// How long (after `now`) to wait before sending an upload of the given size.
+    fn delay_for(&self, size: usize, now: Instant) -> Duration {
+        if self.bytes_per_sec == 0 {
+            return Duration::ZERO;
+        }
+        let mut next_free = self.next_free.lock().unwrap();
+        let start = next_free.map_or(now, |next| next.max(now));
+        let millis = (size as u64 * 1000).div_ceil(self.bytes_per_sec);
+        *next_free = Some(start + Duration::from_millis(millis));
+        start - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pacer;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pacer() {
+        let now = Instant::now();
+        let unlimited = Pacer::new(0);
+        assert_eq!(unlimited.delay_for(1_000_000, now), Duration::ZERO);
+        // at 1 KB/s, 2 KB takes 2 seconds and half a KB takes half a second
+        let pacer = Pacer::new(1);
+        assert_eq!(pacer.delay_for(2048, now), Duration::ZERO);
+        assert_eq!(pacer.delay_for(512, now), Duration::from_secs(2));
+        assert_eq!(pacer.delay_for(0, now), Duration::from_millis(2500));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(pacer.delay_for(1024, later), Duration::ZERO);
+    }
+}
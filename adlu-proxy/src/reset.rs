@@ -0,0 +1,181 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+The end-of-term reset, which closes out everything the proxy has recorded.
+
+A reset archives final reports, forwards any requests that are still
+waiting for an answer, returns every license that's still activated (by
+issuing a deactivation for each device's latest activation), and then
+purges data as the retention policy says.  A dry run reports what each
+step would do without doing any of it.
+ */
+use std::path::Path;
+
+use chrono::Local;
+use clap::ValueEnum;
+use dialoguer::Confirm;
+use eyre::{Result, WrapErr};
+use log::info;
+
+use crate::cache::{Cache, ReportFilter, FORWARD_LOCK, PURGE_LOCK};
+use crate::cli::Datasource;
+use crate::proxy;
+use crate::reporting;
+use crate::settings::Settings;
+
+pub async fn reset(
+    settings: &Settings,
+    cache: &Cache,
+    sources: &[Datasource],
+    archive_dir: &str,
+    dry_run: bool,
+    yes: bool,
+) -> Result<()> {
+    archive_reports(cache, sources, archive_dir, dry_run).await?;
+    if dry_run {
+        let count = cache.fetch_unanswered_requests().await?.len();
+        eprintln!("Would forward {} un-answered request(s)", count);
+    } else {
+        proxy::forward_stored_requests(settings, cache).await?;
+    }
+    if !deactivate_devices(settings, cache, dry_run, yes).await? {
+        eprintln!("Reset stopped: nothing was deactivated or purged.");
+        return Ok(());
+    }
+    purge_data(settings, cache, dry_run).await?;
+    if !dry_run {
+        info!("Reset complete, with reports archived in {}", archive_dir);
+        eprintln!("Reset complete.");
+    }
+    Ok(())
+}
+
+async fn archive_reports(
+    cache: &Cache,
+    sources: &[Datasource],
+    archive_dir: &str,
+    dry_run: bool,
+) -> Result<()> {
+    if !dry_run {
+        std::fs::create_dir_all(archive_dir)
+            .wrap_err(format!("Can't create archive directory {}", archive_dir))?;
+    }
+    let date = Local::now().format("%Y%m%d");
+    for source in sources {
+        let name = source.to_possible_value().expect("No name for report");
+        let path =
+            Path::new(archive_dir).join(format!("{}-{}.csv", name.get_name(), date));
+        let path = path.to_string_lossy();
+        if dry_run {
+            eprintln!("Would archive the {} report to {}", source, path);
+        } else {
+            let filter = ReportFilter::default();
+            reporting::report_to_file(
+                cache, source, &path, &filter, false, false, false, None, None,
+            )
+            .await
+            .wrap_err(format!("Failed to archive {} to {}", source, path))?;
+            eprintln!("Archived the {} report to {}", source, path);
+        }
+    }
+    Ok(())
+}
+
+/// Deactivate every activated device, returning whether the reset should go on.
+async fn deactivate_devices(
+    settings: &Settings,
+    cache: &Cache,
+    dry_run: bool,
+    yes: bool,
+) -> Result<bool> {
+    let devices = cache.activated_devices().await?;
+    if devices.is_empty() {
+        eprintln!("There are no activated devices to deactivate.");
+        return Ok(true);
+    }
+    if dry_run {
+        eprintln!("Would deactivate {} activated device(s):", devices.len());
+        for (npd_id, device_id) in devices.iter() {
+            eprintln!("    device {} for package {}", device_id, npd_id);
+        }
+        return Ok(true);
+    }
+    let confirm = yes
+        || Confirm::new()
+            .with_prompt(format!(
+                "Really deactivate {} device(s)? This operation cannot be undone.",
+                devices.len()
+            ))
+            .default(false)
+            .show_default(true)
+            .interact()?;
+    if !confirm {
+        return Ok(false);
+    }
+    let conf = proxy::Config::new(settings.clone(), cache.clone())?;
+    let lock = cache.lock(FORWARD_LOCK).await.wrap_err("Can't deactivate devices")?;
+    let (mut successes, mut failures) = (0u64, 0u64);
+    for (npd_id, device_id) in devices.iter() {
+        let req = match cache.deactivation_for_device(npd_id, device_id).await? {
+            Some(req) => req,
+            None => continue,
+        };
+        if let Some(secs) = conf.throttle.remaining_secs() {
+            eprintln!("Adobe is throttling requests: waiting {} second(s)...", secs);
+            tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
+        }
+        info!("Reset: issuing {} for device {} of package {}", req, device_id, npd_id);
+        cache.store_request(&req).await;
+        if proxy::forward_stored_request(&conf, &req).await {
+            successes += 1
+        } else {
+            failures += 1
+        }
+    }
+    lock.release().await;
+    eprintln!(
+        "Deactivation produced {} success(es) and {} failure(s).",
+        successes, failures
+    );
+    if failures > 0 {
+        eprintln!("Failed deactivations are stored, so a later forward will retry them.");
+    }
+    Ok(true)
+}
+
+async fn purge_data(settings: &Settings, cache: &Cache, dry_run: bool) -> Result<()> {
+    let policies = settings.retention.policies()?;
+    if policies.is_empty() {
+        eprintln!("There is no retention policy, so nothing will be purged.");
+        return Ok(());
+    }
+    if dry_run {
+        for (source, days) in policies {
+            eprintln!("Would purge {} over {} day(s) old", source, days);
+        }
+        return Ok(());
+    }
+    let lock = cache.lock(PURGE_LOCK).await.wrap_err("Can't purge data")?;
+    let result = cache.apply_retention(&settings.retention).await;
+    lock.release().await;
+    for (source, count) in result? {
+        eprintln!("Purged {} entries from {}", count, source);
+    }
+    Ok(())
+}
@@ -0,0 +1,429 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Runs the jobs in the `[schedule]` section of the config while the proxy is serving.
+
+Each job has a cron-style schedule with the usual five fields (minute, hour,
+day of month, month, day of week), each of which can be `*`, a number, a range
+(`1-5`), a list (`1,15`), or a range or `*` with a step (`0-30/10`).  Months and
+days of the week can also be given by their three-letter names, and the shorthands
+`@hourly`, `@daily`, `@weekly`, `@monthly`, and `@yearly` are accepted.  Schedules
+are in local time.
+
+Report destinations can contain `{date}` and `{time}`, which are replaced with
+the local date (`YYYY-MM-DD`) and time (`HHMMSS`) of the run, so that each run
+produces a separate file.  The outcome of each run is logged.
+
+If the `[retention]` section limits how long any datasource is kept, a job
+that applies the retention policies is scheduled along with the others.
+
+Forward, purge, and retention jobs take the same cache locks as the commands
+that do the same work, so a job that finds another process (such as a
+`forward` run from cron) already doing its work skips that run.
+ */
+use std::str::FromStr;
+
+use chrono::{
+    DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike,
+};
+use clap::ValueEnum;
+use eyre::{eyre, Report, Result, WrapErr};
+use log::{error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::cache::{Cache, LockHeld, ReportFilter, PURGE_LOCK};
+use crate::cli::Datasource;
+use crate::proxy;
+use crate::reporting;
+use crate::settings::{Job, JobAction, Settings};
+
+/// Start a background task for each scheduled job.  The caller should abort
+/// the returned tasks when the server stops.
+pub fn spawn_jobs(settings: &Settings, cache: &Cache) -> Result<Vec<JoinHandle<()>>> {
+    let mut tasks = vec![];
+    for (i, job) in settings.schedule.jobs.iter().enumerate() {
+        let name = if job.name.is_empty() {
+            format!("{:?} job {}", job.action, i + 1).to_ascii_lowercase()
+        } else {
+            job.name.clone()
+        };
+        let schedule = CronSchedule::from_str(&job.cron)
+            .wrap_err(format!("Invalid schedule for job '{}'", &name))?;
+        check_job(job).wrap_err(format!("Invalid settings for job '{}'", &name))?;
+        info!("Scheduling job '{}' with schedule '{}'", &name, &job.cron);
+        let (settings, cache, job) = (settings.clone(), cache.clone(), job.clone());
+        tasks.push(tokio::spawn(run_job(settings, cache, name, job, schedule)));
+    }
+    let retention = &settings.retention;
+    if !retention.policies()?.is_empty() {
+        let schedule = CronSchedule::from_str(&retention.cron)
+            .wrap_err("Invalid schedule for retention")?;
+        info!("Scheduling retention with schedule '{}'", &retention.cron);
+        let job = Job {
+            name: "retention".to_string(),
+            cron: retention.cron.clone(),
+            action: JobAction::Retain,
+            ..Default::default()
+        };
+        let (settings, cache) = (settings.clone(), cache.clone());
+        tasks.push(tokio::spawn(run_job(
+            settings,
+            cache,
+            job.name.clone(),
+            job,
+            schedule,
+        )));
+    }
+    Ok(tasks)
+}
+
+fn check_job(job: &Job) -> Result<()> {
+    if let JobAction::Report = job.action {
+        let source = Datasource::from_str(&job.data, true).map_err(|e| eyre!(e))?;
+        if job.to.is_empty() {
+            return Err(eyre!("A report job needs a destination"));
+        }
+        if job.email && job.to.contains("://") {
+            return Err(eyre!(
+                "A report job can only email a report it writes to a file"
+            ));
+        }
+        if job.since_last && !matches!(source, Datasource::Log) {
+            return Err(eyre!("Only log reports can be incremental"));
+        }
+    }
+    Ok(())
+}
+
+async fn run_job(
+    settings: Settings,
+    cache: Cache,
+    name: String,
+    job: Job,
+    schedule: CronSchedule,
+) {
+    loop {
+        let now = Local::now();
+        let next = match schedule.next_run(&now) {
+            Some(next) => next,
+            None => {
+                warn!("Job '{}' will never run: its schedule has no future times", &name);
+                return;
+            }
+        };
+        info!("Job '{}' will next run at {}", &name, next.to_rfc3339());
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+        info!("Running job '{}'", &name);
+        match run_once(&settings, &cache, &job).await {
+            Ok(outcome) => info!("Job '{}' succeeded: {}", &name, outcome),
+            Err(err) if err.downcast_ref::<LockHeld>().is_some() => {
+                // another process is doing this work, so there's nothing to do
+                info!("Job '{}' skipped: {:#}", &name, err)
+            }
+            Err(err) => error!("Job '{}' failed: {:?}", &name, err),
+        }
+    }
+}
+
+/// Run a job, returning a description of what it did.
+async fn run_once(settings: &Settings, cache: &Cache, job: &Job) -> Result<String> {
+    match job.action {
+        JobAction::Report => {
+            let source = Datasource::from_str(&job.data, true).map_err(|e| eyre!(e))?;
+            let to = expand_destination(&job.to, &Local::now());
+            let (timezone, rfc3339) = (job.timezone, job.rfc3339);
+            let since_last = job.since_last.then_some(job.to.as_str());
+            let filter = if job.filter.is_empty() {
+                ReportFilter::default()
+            } else {
+                ReportFilter::parse(&job.filter)?
+            };
+            if to.contains("://") {
+                reporting::report_to_sink(
+                    settings, cache, &source, &to, &filter, false, timezone, rfc3339,
+                    since_last, None,
+                )
+                .await?;
+            } else if job.email {
+                reporting::report_to_email(
+                    settings,
+                    cache,
+                    &source,
+                    Some(&to),
+                    &filter,
+                    false,
+                    timezone,
+                    rfc3339,
+                    since_last,
+                    None,
+                )
+                .await?;
+                return Ok(format!("reported {} to {} and emailed it", source, to));
+            } else {
+                reporting::report_to_file(
+                    cache, &source, &to, &filter, false, timezone, rfc3339, since_last,
+                    None,
+                )
+                .await?;
+            }
+            Ok(format!("reported {} to {}", source, to))
+        }
+        JobAction::Forward => {
+            proxy::forward_stored_requests(settings, cache).await?;
+            Ok("forwarded stored requests".to_string())
+        }
+        JobAction::Purge => {
+            let lock = cache.lock(PURGE_LOCK).await?;
+            let result = cache.purge(job.max_age_days).await;
+            lock.release().await;
+            let count = result?;
+            Ok(format!("purged {} entries over {} days old", count, job.max_age_days))
+        }
+        JobAction::Retain => {
+            let lock = cache.lock(PURGE_LOCK).await?;
+            let result = cache.apply_retention(&settings.retention).await;
+            lock.release().await;
+            let counts = result?;
+            let counts: Vec<String> = counts
+                .iter()
+                .map(|(source, count)| format!("{} from {}", count, source))
+                .collect();
+            Ok(format!("evicted {}", counts.join(", ")))
+        }
+    }
+}
+
+fn expand_destination(to: &str, when: &DateTime<Local>) -> String {
+    to.replace("{date}", &when.format("%Y-%m-%d").to_string())
+        .replace("{time}", &when.format("%H%M%S").to_string())
+}
+
+/// A parsed cron schedule.  Each field is a bit set of the values it allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTH_NAMES: [&str; 12] =
+    ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl FromStr for CronSchedule {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let expanded = match s.trim().to_ascii_lowercase().as_str() {
+            "@yearly" | "@annually" => "0 0 1 1 *".to_string(),
+            "@monthly" => "0 0 1 * *".to_string(),
+            "@weekly" => "0 0 * * 0".to_string(),
+            "@daily" | "@midnight" => "0 0 * * *".to_string(),
+            "@hourly" => "0 * * * *".to_string(),
+            other => other.to_string(),
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(eyre!("'{}' does not have five fields", s));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7, &WEEKDAY_NAMES)?;
+        // both 0 and 7 mean Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &MONTH_NAMES)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let value = |s: &str| -> Result<u32> {
+        let val = match names.iter().position(|name| *name == s) {
+            // names start at the minimum value
+            Some(index) => index as u32 + min,
+            None => s.parse().map_err(|_| eyre!("'{}' is not a valid value", s))?,
+        };
+        if val < min || val > max {
+            Err(eyre!("{} is not between {} and {}", val, min, max))
+        } else {
+            Ok(val)
+        }
+    };
+    let mut result = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 =
+                    step.parse().map_err(|_| eyre!("'{}' is not a valid step", step))?;
+                if step == 0 {
+                    return Err(eyre!("A step can't be zero"));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // a single value with a step runs to the end of the range
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(eyre!("'{}' is not a valid range", range));
+        }
+        for val in (start..=end).step_by(step as usize) {
+            result |= 1 << val;
+        }
+    }
+    Ok(result)
+}
+
+fn allows(set: u64, val: u32) -> bool {
+    set & (1 << val) != 0
+}
+
+impl CronSchedule {
+    /// The first time strictly after `after` (a local time) that the
+    /// schedule allows, or `None` if there's none in the next few years.
+    pub fn next_after(&self, after: &NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t = after.date().and_hms_opt(after.hour(), after.minute(), 0)?
+            + Duration::minutes(1);
+        let limit = *after + Duration::days(5 * 366);
+        while t < limit {
+            if !allows(self.months, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    month => (t.year(), month + 1),
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.allows_day(&t.date()) {
+                t = (t.date() + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+            } else if !allows(self.hours, t.hour()) {
+                t = t.date().and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1);
+            } else if !allows(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    /// The next time after `after` that the schedule allows, skipping
+    /// local times that don't exist because of daylight saving changes.
+    pub fn next_run(&self, after: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut naive = after.naive_local();
+        loop {
+            naive = self.next_after(&naive)?;
+            if let Some(when) = Local.from_local_datetime(&naive).earliest() {
+                if when > *after {
+                    return Some(when);
+                }
+            }
+        }
+    }
+
+    /// As in standard cron, if both the day of the month and the day of the week
+    /// are restricted, a day that matches either one is allowed.
+    fn allows_day(&self, date: &NaiveDate) -> bool {
+        let day = allows(self.days, date.day());
+        let weekday = allows(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    use super::{expand_destination, CronSchedule};
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        assert_eq!(
+            CronSchedule::from_str("@daily").unwrap(),
+            CronSchedule::from_str("0 0 * * *").unwrap()
+        );
+        assert_eq!(
+            CronSchedule::from_str("0 6 * jan-mar mon-fri").unwrap(),
+            CronSchedule::from_str("0 6 * 1-3 1-5").unwrap()
+        );
+        assert_eq!(
+            CronSchedule::from_str("0 0 * * 7").unwrap(),
+            CronSchedule::from_str("0 0 * * 0,7").unwrap()
+        );
+        assert!(CronSchedule::from_str("0 0 * *").is_err());
+        assert!(CronSchedule::from_str("60 0 * * *").is_err());
+        assert!(CronSchedule::from_str("*/0 0 * * *").is_err());
+        assert!(CronSchedule::from_str("0 5-2 * * *").is_err());
+        assert!(CronSchedule::from_str("0 0 * foo *").is_err());
+    }
+
+    #[test]
+    fn test_next_after() {
+        let daily = CronSchedule::from_str("30 2 * * *").unwrap();
+        let start = at(2022, 12, 31, 2, 30);
+        assert_eq!(daily.next_after(&start), Some(at(2023, 1, 1, 2, 30)));
+        assert_eq!(daily.next_after(&at(2023, 1, 1, 1, 0)), Some(at(2023, 1, 1, 2, 30)));
+        let quarter_hour = CronSchedule::from_str("*/15 * * * *").unwrap();
+        assert_eq!(quarter_hour.next_after(&start), Some(at(2022, 12, 31, 2, 45)));
+        // the 13th of the month or any friday
+        let either = CronSchedule::from_str("0 0 13 * fri").unwrap();
+        assert_eq!(either.next_after(&start), Some(at(2023, 1, 6, 0, 0)));
+        assert_eq!(either.next_after(&at(2023, 1, 6, 0, 0)), Some(at(2023, 1, 13, 0, 0)));
+        let leap_day = CronSchedule::from_str("0 0 29 feb *").unwrap();
+        assert_eq!(leap_day.next_after(&start), Some(at(2024, 2, 29, 0, 0)));
+        let never = CronSchedule::from_str("0 0 31 feb *").unwrap();
+        assert_eq!(never.next_after(&start), None);
+    }
+
+    #[test]
+    fn test_expand_destination() {
+        use chrono::TimeZone;
+        let when = chrono::Local.from_local_datetime(&at(2022, 9, 1, 14, 5)).unwrap();
+        assert_eq!(
+            expand_destination("reports/frl-{date}-{time}.csv", &when),
+            "reports/frl-2022-09-01-140500.csv"
+        );
+    }
+}
@@ -0,0 +1,316 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Validation of the API keys that clients send with their requests.
+
+Adobe apps identify themselves to the licensing servers with an `X-Api-Key`
+header that is derived from their NGL app id (e.g., `ngl_photoshop1` for
+`Photoshop1`).  When validation is enabled, the proxy checks each incoming
+key against the set of known app ids (taken from the configured allowlist
+and from any deployed packages), and checks that the key agrees with the app id
+in the request body.  Requests that fail these checks are flagged and, if so
+configured, rejected.
+
+Requests are also checked for well-formed bodies, so that a client whose
+request is missing required fields gets told which ones, rather than getting
+an opaque error from Adobe.  Failures are counted per app version, to help
+spot packages that are broken.
+ */
+use std::collections::HashSet;
+
+use eyre::{Result, WrapErr};
+
+use adlu_base::Timestamp;
+use adlu_parse::admin::Configuration;
+use adlu_parse::protocol::{
+    FieldProblem, FrlActivationRequestBody, NulLicenseRequestBody, Request, RequestType,
+};
+
+use crate::settings::Settings;
+
+/// The api key used by the licensing toolkit when it deactivates FRL licenses.
+pub const TOOLKIT_API_KEY: &str = "adobe_licensing_toolkit";
+
+/// An api key that failed validation.
+#[derive(Debug, Clone)]
+pub struct InvalidKeyAttempt {
+    pub timestamp: Timestamp,
+    pub source_addr: String,
+    pub request_type: String,
+    pub request_id: String,
+    pub api_key: String,
+    pub app_id: String,
+    pub reason: String,
+    pub rejected: bool,
+    pub correlation_id: String,
+}
+
+/// A request whose body failed validation.
+#[derive(Debug, Clone)]
+pub struct ValidationFailure {
+    pub timestamp: Timestamp,
+    pub request_type: String,
+    pub app_id: String,
+    pub app_version: String,
+    pub problems: Vec<FieldProblem>,
+}
+
+impl ValidationFailure {
+    /// Check the body of a request, returning the problems found (if any).
+    /// The app id and version are taken from whatever part of the body
+    /// is readable, so failures can be attributed to the app that sent them.
+    pub fn check(req: &Request) -> Option<Self> {
+        let problems = req.validate_body();
+        if problems.is_empty() {
+            return None;
+        }
+        let details = req
+            .body
+            .as_ref()
+            .and_then(|body| serde_json::from_str::<serde_json::Value>(body).ok())
+            .map(|val| val["appDetails"].clone())
+            .unwrap_or_default();
+        let detail = |name: &str| details[name].as_str().unwrap_or_default().to_string();
+        Some(ValidationFailure {
+            timestamp: req.timestamp.clone(),
+            request_type: req.request_type.to_string(),
+            app_id: detail("nglAppId"),
+            app_version: detail("nglAppVersion"),
+            problems,
+        })
+    }
+
+    /// A one-line summary of the problems found.
+    pub fn summary(&self) -> String {
+        let problems: Vec<String> = self.problems.iter().map(|p| p.to_string()).collect();
+        problems.join("; ")
+    }
+}
+
+/// A request whose body couldn't be parsed as it was sent.  The body is
+/// kept so that changes in the protocol can be diagnosed.
+#[derive(Debug, Clone)]
+pub struct ParseFailure {
+    pub timestamp: Timestamp,
+    pub request_type: String,
+    pub correlation_id: String,
+    pub error: String,
+    pub body: String,
+    pub recovered: bool,
+}
+
+impl ParseFailure {
+    /// Check that the body of an FRL activation or NUL license request can be
+    /// parsed, returning the failure (if any).  When `lenient` is set, a body
+    /// that can't be parsed is rewritten (see [`Request::lenient_body`]), and
+    /// if the rewritten body can be parsed it replaces the original.
+    pub fn check(req: &mut Request, lenient: bool) -> Option<Self> {
+        let body = req.body.as_deref().filter(|body| !body.trim().is_empty())?;
+        let error = parse_error(&req.request_type, body)?;
+        let mut failure = ParseFailure {
+            timestamp: req.timestamp.clone(),
+            request_type: req.request_type.to_string(),
+            correlation_id: req.correlation_id.clone(),
+            error,
+            body: body.to_string(),
+            recovered: false,
+        };
+        if lenient {
+            if let Some(body) = req.lenient_body() {
+                if parse_error(&req.request_type, &body).is_none() {
+                    req.body = Some(body);
+                    failure.recovered = true;
+                }
+            }
+        }
+        Some(failure)
+    }
+}
+
+fn parse_error(request_type: &RequestType, body: &str) -> Option<String> {
+    let err = match request_type {
+        RequestType::FrlActivation => FrlActivationRequestBody::from_body(body).err(),
+        RequestType::NulLicense => NulLicenseRequestBody::from_body(body).err(),
+        _ => None,
+    }?;
+    Some(format!("{:#}", eyre::Report::new(err)))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeyValidator {
+    enabled: bool,
+    reject: bool,
+    known_apps: HashSet<String>,
+}
+
+impl ApiKeyValidator {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let security = &settings.security;
+        if !security.validate_api_keys {
+            return Ok(Default::default());
+        }
+        let mut known_apps: HashSet<String> =
+            security.allowed_app_ids.iter().map(|id| normalize(id)).collect();
+        if !security.packages_path.is_empty() {
+            let config = Configuration::from_path(&security.packages_path).wrap_err(
+                format!("Can't read packages at: {}", &security.packages_path),
+            )?;
+            known_apps.extend(app_ids(&config).iter().map(|id| normalize(id)));
+        }
+        Ok(ApiKeyValidator {
+            enabled: true,
+            reject: security.reject_invalid_api_keys,
+            known_apps,
+        })
+    }
+
+    /// Whether requests with invalid api keys should be refused.
+    pub fn rejects_invalid(&self) -> bool {
+        self.reject
+    }
+
+    /// Check the api key of a request, returning a description of the
+    /// problem if the key is not valid.
+    pub fn validate(&self, req: &Request) -> Option<InvalidKeyAttempt> {
+        if !self.enabled {
+            return None;
+        }
+        let app_id = body_app_id(req);
+        let reason = match req.api_key.as_deref() {
+            None if matches!(req.request_type, RequestType::Unknown) => return None,
+            None => "missing api key".to_string(),
+            Some(key) => self.check_key(&req.request_type, key, app_id.as_deref())?,
+        };
+        Some(InvalidKeyAttempt {
+            timestamp: req.timestamp.clone(),
+            source_addr: req
+                .source_ip
+                .map_or_else(|| "unknown".to_string(), |a| a.to_string()),
+            request_type: req.request_type.to_string(),
+            request_id: req.request_id.clone().unwrap_or_default(),
+            api_key: req.api_key.clone().unwrap_or_default(),
+            app_id: app_id.unwrap_or_default(),
+            reason,
+            rejected: self.reject,
+            correlation_id: req.correlation_id.clone(),
+        })
+    }
+
+    fn check_key(
+        &self,
+        request_type: &RequestType,
+        key: &str,
+        app_id: Option<&str>,
+    ) -> Option<String> {
+        if key == TOOLKIT_API_KEY {
+            return if matches!(request_type, RequestType::FrlDeactivation) {
+                None
+            } else {
+                Some(format!("{} api key used for {}", key, request_type))
+            };
+        }
+        if !key.starts_with("ngl_") {
+            return Some("api key is not an NGL key".to_string());
+        }
+        let key_app = normalize(key);
+        if !self.known_apps.is_empty() && !self.known_apps.contains(&key_app) {
+            return Some("api key is not for a known app".to_string());
+        }
+        match app_id {
+            Some(app_id) if normalize(app_id) != key_app => {
+                Some(format!("api key does not match app id {}", app_id))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reduce an app id or api key to a canonical form for comparison.  Api keys
+/// are lower case and sometimes separate the words of the app id with underscores,
+/// so we remove the `ngl_` prefix and all underscores and ignore case.
+fn normalize(id: &str) -> String {
+    let id = id.to_ascii_lowercase();
+    let id = id.strip_prefix("ngl_").unwrap_or(&id);
+    id.replace('_', "")
+}
+
+fn app_ids(config: &Configuration) -> Vec<String> {
+    match config {
+        Configuration::Packaged(pcs) => pcs
+            .iter()
+            .flat_map(|pc| pc.operating_configs.iter().map(|oc| oc.app_id()))
+            .collect(),
+        Configuration::Installed(ocs) => ocs.iter().map(|oc| oc.app_id()).collect(),
+    }
+}
+
+fn body_app_id(req: &Request) -> Option<String> {
+    let body = req.body.as_ref()?;
+    match req.request_type {
+        RequestType::FrlActivation => FrlActivationRequestBody::from_body(body)
+            .ok()
+            .map(|parse| parse.app_details.ngl_app_id),
+        RequestType::NulLicense => NulLicenseRequestBody::from_body(body)
+            .ok()
+            .map(|parse| parse.app_details.ngl_app_id),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, ApiKeyValidator};
+    use adlu_parse::protocol::RequestType;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("ngl_photoshop1"), normalize("Photoshop1"));
+        assert_eq!(normalize("ngl_premiere_pro1"), normalize("PremierePro1"));
+        assert_ne!(normalize("ngl_illustrator1"), normalize("Photoshop1"));
+    }
+
+    #[test]
+    fn test_check_key() {
+        let validator = ApiKeyValidator {
+            enabled: true,
+            reject: true,
+            known_apps: ["Photoshop1", "ngl_mock1"]
+                .iter()
+                .map(|id| normalize(id))
+                .collect(),
+        };
+        let activation = RequestType::FrlActivation;
+        let deactivation = RequestType::FrlDeactivation;
+        assert!(validator
+            .check_key(&activation, "ngl_photoshop1", Some("Photoshop1"))
+            .is_none());
+        assert!(validator.check_key(&activation, "ngl_mock1", None).is_none());
+        assert!(validator
+            .check_key(&deactivation, "adobe_licensing_toolkit", None)
+            .is_none());
+        assert!(validator
+            .check_key(&activation, "adobe_licensing_toolkit", None)
+            .is_some());
+        assert!(validator.check_key(&activation, "ngl_illustrator1", None).is_some());
+        assert!(validator
+            .check_key(&activation, "ngl_photoshop1", Some("Mock1"))
+            .is_some());
+        assert!(validator.check_key(&activation, "scanner", None).is_some());
+    }
+}
@@ -0,0 +1,120 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Fabricated responses for the proxy's simulate mode.
+
+In simulate mode the proxy never contacts Adobe and never touches its cache.
+Instead, it answers FRL activations with generated profiles, and it accepts
+FRL deactivations and log uploads, so that licensing workflows can be
+demonstrated end-to-end without real packages or an internet connection.
+
+Simulated profiles are deterministic: the same activation request always gets
+the same profile.  They are also clearly marked: their signatures, license id,
+and profile id all contain [`SIMULATED_MARKER`], so they can never be
+mistaken for (or accepted by apps as) real licenses.
+ */
+use eyre::{eyre, Result, WrapErr};
+use sha2::{Digest, Sha256};
+
+use adlu_base::Timestamp;
+use adlu_parse::protocol::{
+    FrlActivationRequestBody, FrlActivationResponseBody, FrlDeactivationResponseBody,
+};
+
+use crate::proxy::{proxy_id, Request, RequestType, Response};
+
+/// The marker that appears in every part of a simulated profile.
+pub const SIMULATED_MARKER: &str = "SIMULATED-NOT-A-LICENSE";
+
+/// Fabricate a response to the given request.
+pub fn response(req: &Request) -> Result<Response> {
+    let body = match req.request_type {
+        RequestType::FrlActivation => {
+            let body = req.body.as_ref().ok_or_else(|| eyre!("{} has no body", req))?;
+            let parse =
+                FrlActivationRequestBody::from_body(body).wrap_err(req.to_string())?;
+            Some(activation_profile(&parse, req.session_id.as_deref()).to_body())
+        }
+        RequestType::FrlDeactivation => {
+            Some(FrlDeactivationResponseBody::mock_from_device_id("").to_body())
+        }
+        RequestType::LogUpload => None,
+        _ => return Err(eyre!("Can't simulate a response to {}", req)),
+    };
+    Ok(Response {
+        timestamp: Timestamp::now(),
+        request_type: req.request_type.clone(),
+        status: http::StatusCode::OK,
+        content_type: body.as_ref().map(|_| "application/json".to_string()),
+        body,
+        server: Some(proxy_id()),
+        via: None,
+        request_id: req.request_id.clone(),
+        session_id: None,
+        headers: vec![],
+    })
+}
+
+/// A marked, fake profile for the given activation request.
+pub fn activation_profile(
+    parse: &FrlActivationRequestBody,
+    session_id: Option<&str>,
+) -> FrlActivationResponseBody {
+    let hash = hex::encode(Sha256::digest(parse.activation_id().as_bytes()));
+    let device = &parse.device_details;
+    let mut profile = FrlActivationResponseBody::mock_from_device_id(&device.device_id);
+    let adobe = &mut profile.adobe_cert_signed_values;
+    adobe.signatures.signature1 = SIMULATED_MARKER.to_string();
+    adobe.signatures.signature2 = SIMULATED_MARKER.to_string();
+    adobe.values.license_id = format!("{}-{}", SIMULATED_MARKER, &hash[..16]);
+    adobe.values.created_for_vdi =
+        (device.enable_vdi_marker_exists && device.is_virtual_environment).to_string();
+    let customer = &mut profile.customer_cert_signed_values;
+    customer.signatures.customer_signature1 = SIMULATED_MARKER.to_string();
+    customer.signatures.customer_signature2 = SIMULATED_MARKER.to_string();
+    customer.values.npd_id = parse.npd_id.clone();
+    customer.values.asnp_id = format!("{}-{}", SIMULATED_MARKER, &hash[16..48]);
+    customer.values.previous_asnp_id = parse.app_details.current_asnp_id.clone();
+    customer.values.os_user_id = device.os_user_id.clone();
+    customer.values.device_date = device.current_date.clone();
+    if let Some(session_id) = session_id {
+        customer.values.session_id = session_id.to_string();
+    }
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{activation_profile, SIMULATED_MARKER};
+    use adlu_parse::protocol::FrlActivationRequestBody;
+
+    #[test]
+    fn test_activation_profile() {
+        let body = FrlActivationRequestBody::mock_from_device_id("sim1");
+        let profile1 = activation_profile(&body, None).to_body();
+        let profile2 = activation_profile(&body, None).to_body();
+        assert_eq!(profile1, profile2, "Simulated profiles are not deterministic");
+        let other = FrlActivationRequestBody::mock_from_device_id("sim2");
+        assert_ne!(profile1, activation_profile(&other, None).to_body());
+        let profile = activation_profile(&body, None);
+        let values = &profile.customer_cert_signed_values.values;
+        assert!(values.asnp_id.starts_with(SIMULATED_MARKER));
+        assert_eq!(values.device_id, "sim1");
+    }
+}
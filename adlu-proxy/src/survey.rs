@@ -0,0 +1,367 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+A site survey: checks that a machine is ready to run the proxy.
+
+Before the proxy is installed at a new site, the survey probes the network
+and the machine the way the proxy will use them: it connects to the Adobe
+servers (through the upstream proxy, if there is one) and times the round
+trip, looks at who issued the certificate the servers present (so TLS
+interception by a firewall is noticed), makes sure the ports the proxy
+listens on are free, and checks that there is room for the cache.
+
+The results are written as a readable report that ends with a checksum of
+its contents, so a copy that's sent around can be checked for changes.
+ */
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use eyre::{eyre, Result, WrapErr};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+
+use adlu_base::{certificate_issuer, Timestamp};
+
+use crate::cache::Cache;
+use crate::proxy::{proxy_id, Config};
+use crate::settings::Settings;
+
+/// How long to wait for any one probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Round trips slower than this are flagged, because clients may time out.
+const SLOW_MILLIS: u128 = 2000;
+
+/// The space to ask for when the cache has no size limit.
+const DEFAULT_FREE_KB: u64 = 1024 * 1024;
+
+/// The certificate authorities that Adobe's servers use.  A certificate
+/// from anyone else means the connection is probably being intercepted.
+const PUBLIC_ISSUERS: [&str; 6] =
+    ["DigiCert", "Amazon", "GlobalSign", "Sectigo", "Entrust", "Let's Encrypt"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Pass => "PASS".fmt(f),
+            Outcome::Warn => "WARN".fmt(f),
+            Outcome::Fail => "FAIL".fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Check { name: name.to_string(), outcome, detail: detail.into() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Survey {
+    pub proxy_id: String,
+    pub hostname: String,
+    pub timestamp: String,
+    pub checks: Vec<Check>,
+}
+
+impl Survey {
+    /// The site is ready if nothing failed (warnings need a look, but
+    /// don't stop the proxy from working).
+    pub fn is_ready(&self) -> bool {
+        self.checks.iter().all(|check| check.outcome != Outcome::Fail)
+    }
+
+    /// The readable report, without its checksum line.
+    fn body(&self) -> String {
+        let mut lines = vec![
+            format!("Site survey by {}", &self.proxy_id),
+            format!("Host: {}", &self.hostname),
+            format!("Date: {}", &self.timestamp),
+            String::new(),
+        ];
+        for check in self.checks.iter() {
+            lines.push(format!("[{}] {}: {}", check.outcome, check.name, check.detail));
+        }
+        lines.push(String::new());
+        let verdict = if self.is_ready() { "READY" } else { "NOT READY" };
+        lines.push(format!("Readiness: {}", verdict));
+        lines.join("\n")
+    }
+
+    /// The readable report, ending with the checksum of what precedes it.
+    pub fn report(&self) -> String {
+        let body = self.body();
+        format!("{}\nChecksum (SHA-256): {}\n", body, checksum(&body))
+    }
+}
+
+fn checksum(body: &str) -> String {
+    hex::encode(Sha256::digest(body.as_bytes()))
+}
+
+/// Check that a report hasn't been changed since it was written.
+pub fn verify_report(report: &str) -> bool {
+    let report = report.trim_end();
+    match report.rsplit_once("\nChecksum (SHA-256): ") {
+        Some((body, sum)) => checksum(body) == sum.trim(),
+        None => false,
+    }
+}
+
+/// Check a saved report's checksum, failing if the report was changed.
+pub fn check_report_file(path: &str) -> Result<()> {
+    let report = std::fs::read_to_string(path)
+        .wrap_err(format!("Can't read survey report: {}", path))?;
+    if verify_report(&report) {
+        eprintln!("The survey report in {} is unchanged", path);
+        Ok(())
+    } else {
+        Err(eyre!("The survey report in {} has been changed (or has no checksum)", path))
+    }
+}
+
+/// Survey this machine and print the report, saving a copy to `to_path`
+/// if it's given.  The report is JSON if `json` is set.
+pub async fn survey(
+    settings: &Settings,
+    cache: &Cache,
+    to_path: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let config = Config::new(settings.clone(), cache.clone())?;
+    let mut checks = vec![];
+    for (name, url) in [("FRL", &config.frl_server), ("Log", &config.log_server)] {
+        checks.push(probe_reachability(&config.client, name, url).await);
+        checks.push(probe_certificate(settings, name, url).await);
+    }
+    checks.extend(check_ports(settings));
+    checks.extend(check_cache_space(settings));
+    let survey = Survey {
+        proxy_id: proxy_id(),
+        hostname: sys_info::hostname().unwrap_or_else(|_| "unknown".to_string()),
+        timestamp: Timestamp::now().format_rfc_3339(true),
+        checks,
+    };
+    let report = if json {
+        serde_json::to_string_pretty(&survey)? + "\n"
+    } else {
+        survey.report()
+    };
+    print!("{}", report);
+    if let Some(path) = to_path {
+        std::fs::write(path, &report)
+            .wrap_err(format!("Can't write survey report to {}", path))?;
+        eprintln!("Wrote survey report to: {}", path);
+    }
+    Ok(())
+}
+
+/// Send a request to the server and time the round trip.  Any response,
+/// even an error status, shows that the server can be reached.
+async fn probe_reachability(client: &reqwest::Client, name: &str, url: &str) -> Check {
+    let name = format!("{} server {}", name, url);
+    let start = Instant::now();
+    let result = client.get(url).timeout(PROBE_TIMEOUT).send().await;
+    let millis = start.elapsed().as_millis();
+    match result {
+        Ok(resp) if millis > SLOW_MILLIS => Check::new(
+            &name,
+            Outcome::Warn,
+            format!(
+                "reachable (status {}) but slow: {} ms",
+                resp.status().as_u16(),
+                millis
+            ),
+        ),
+        Ok(resp) => Check::new(
+            &name,
+            Outcome::Pass,
+            format!("reachable (status {}) in {} ms", resp.status().as_u16(), millis),
+        ),
+        Err(err) => Check::new(&name, Outcome::Fail, format!("unreachable: {}", err)),
+    }
+}
+
+/// Connect directly to the server and see who issued its certificate.
+/// Through an upstream proxy the certificate can't be seen, so the
+/// check is skipped.
+async fn probe_certificate(settings: &Settings, name: &str, url: &str) -> Check {
+    let name = format!("{} server certificate", name);
+    if settings.upstream.use_proxy {
+        let detail = "not checked, because connections go through the upstream proxy";
+        return Check::new(&name, Outcome::Warn, detail);
+    }
+    match tokio::time::timeout(PROBE_TIMEOUT, server_issuer(url)).await {
+        Ok(Ok(issuer)) if PUBLIC_ISSUERS.iter().any(|ca| issuer.contains(ca)) => {
+            Check::new(&name, Outcome::Pass, format!("issued by {}", issuer))
+        }
+        Ok(Ok(issuer)) => Check::new(
+            &name,
+            Outcome::Warn,
+            format!("issued by {}, so TLS is probably being intercepted", issuer),
+        ),
+        Ok(Err(err)) => Check::new(&name, Outcome::Fail, format!("{:#}", err)),
+        Err(_) => Check::new(&name, Outcome::Fail, "timed out"),
+    }
+}
+
+async fn server_issuer(url: &str) -> Result<String> {
+    let url = url::Url::parse(url).wrap_err(format!("Invalid server URL: {}", url))?;
+    let host = url.host_str().ok_or_else(|| eyre!("No host in server URL: {}", url))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let stream = TcpStream::connect((host, port))
+        .await
+        .wrap_err(format!("Can't connect to {}:{}", host, port))?;
+    let connector = tokio_native_tls::TlsConnector::from(
+        tokio_native_tls::native_tls::TlsConnector::new()?,
+    );
+    let tls = connector.connect(host, stream).await.wrap_err(
+        "TLS handshake failed (is the interceptor's root certificate trusted?)",
+    )?;
+    let cert = tls
+        .get_ref()
+        .peer_certificate()?
+        .ok_or_else(|| eyre!("The server presented no certificate"))?;
+    certificate_issuer(&cert.to_der()?)
+}
+
+/// Make sure the ports the proxy will listen on are free.
+fn check_ports(settings: &Settings) -> Vec<Check> {
+    let proxy = &settings.proxy;
+    let mut ports = vec![("HTTP port", &proxy.port)];
+    if proxy.ssl {
+        ports.push(("HTTPS port", &proxy.ssl_port))
+    }
+    ports
+        .into_iter()
+        .map(|(name, port)| {
+            let name = format!("{} {}", name, port);
+            let addr = format!("{}:{}", &proxy.host, port);
+            match TcpListener::bind(&addr) {
+                Ok(_) => Check::new(&name, Outcome::Pass, "available"),
+                Err(err) => {
+                    Check::new(&name, Outcome::Fail, format!("can't listen: {}", err))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Make sure the cache can be written and has room to grow.  The free
+/// space reported is that of the system disk.
+fn check_cache_space(settings: &Settings) -> Vec<Check> {
+    let db_path = Path::new(&settings.proxy.db_path);
+    let dir = match db_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => std::env::current_dir().unwrap_or_default(),
+    };
+    let probe = dir.join(format!(".adlu-proxy-survey-{}", std::process::id()));
+    let writable = match std::fs::write(&probe, b"survey") {
+        Ok(_) => {
+            std::fs::remove_file(&probe).ok();
+            Check::new(
+                "Cache directory",
+                Outcome::Pass,
+                format!("{} is writable", dir.display()),
+            )
+        }
+        Err(err) => Check::new(
+            "Cache directory",
+            Outcome::Fail,
+            format!("can't write to {}: {}", dir.display(), err),
+        ),
+    };
+    let needed_kb = match settings.proxy.db_max_size_kb {
+        0 => DEFAULT_FREE_KB,
+        kb => kb,
+    };
+    let space = match sys_info::disk_info() {
+        Ok(info) if info.free >= needed_kb => Check::new(
+            "Disk space",
+            Outcome::Pass,
+            format!("{} MB free, {} MB wanted", info.free / 1024, needed_kb / 1024),
+        ),
+        Ok(info) => Check::new(
+            "Disk space",
+            Outcome::Fail,
+            format!("only {} MB free, {} MB wanted", info.free / 1024, needed_kb / 1024),
+        ),
+        Err(err) => {
+            Check::new("Disk space", Outcome::Warn, format!("can't measure: {}", err))
+        }
+    };
+    vec![writable, space]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_ports, verify_report, Check, Outcome, Survey};
+    use crate::settings::{Settings, SettingsVal};
+
+    #[test]
+    fn test_check_ports() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut settings = SettingsVal::default_config();
+        settings.proxy.host = "127.0.0.1".to_string();
+        settings.proxy.port = port.to_string();
+        settings.proxy.ssl = false;
+        let checks = check_ports(&Settings::new(settings));
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].outcome, Outcome::Fail);
+        drop(listener);
+    }
+
+    #[test]
+    fn test_survey_report() {
+        let mut survey = Survey {
+            proxy_id: "adlu-proxy test".to_string(),
+            hostname: "school-server".to_string(),
+            timestamp: "2022-10-01T00:00:00.000Z".to_string(),
+            checks: vec![Check::new("Disk space", Outcome::Warn, "can't measure")],
+        };
+        assert!(survey.is_ready());
+        let report = survey.report();
+        assert!(report.contains("[WARN] Disk space: can't measure"));
+        assert!(report.contains("Readiness: READY"));
+        assert!(verify_report(&report));
+        assert!(!verify_report(&report.replace("WARN", "PASS")));
+        survey.checks.push(Check::new("HTTP port 8080", Outcome::Fail, "in use"));
+        assert!(!survey.is_ready());
+        assert!(survey.report().contains("Readiness: NOT READY"));
+    }
+}
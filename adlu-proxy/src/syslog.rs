@@ -0,0 +1,259 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Logging to a syslog server, in the format of RFC 5424.
+
+Messages can be sent over UDP (one message per datagram, as in RFC 5426),
+over TCP, or over TLS (RFC 5425).  Over TCP and TLS, messages are framed by
+prefixing their length (the octet counting of RFC 6587), and the connection
+is reopened if it fails.  The facility is configured; the severity of each
+message comes from its log level.
+ */
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use eyre::{eyre, Result, WrapErr};
+use log::{Level, Record};
+use log4rs::append::Append;
+use tokio_native_tls::native_tls::{TlsConnector, TlsStream};
+
+use crate::settings::{Logging, SyslogTransport};
+
+/// How long to wait when connecting to or writing to the server.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The syslog facilities, by name, in numeric order.
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+/// The number of a facility, given its name.
+pub fn facility_code(name: &str) -> Result<u8> {
+    let name = name.trim().to_ascii_lowercase();
+    match FACILITIES.iter().position(|f| *f == name) {
+        Some(code) => Ok(code as u8),
+        None => {
+            Err(eyre!("'{}' is not a syslog facility (such as daemon or local0)", name))
+        }
+    }
+}
+
+/// The syslog severity of a log level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Format a message as RFC 5424 specifies.  The message id is the module
+/// that logged the message, and there is no structured data.
+fn format_message(facility: u8, hostname: &str, record: &Record) -> String {
+    let pri = facility as u16 * 8 + severity(record.level()) as u16;
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let msg_id = header_field(record.target(), 32);
+    format!(
+        "<{}>1 {} {} {} {} {} - {}",
+        pri,
+        timestamp,
+        hostname,
+        env!("CARGO_PKG_NAME"),
+        std::process::id(),
+        msg_id,
+        record.args()
+    )
+}
+
+/// Header fields are printable ASCII with no spaces, of limited length,
+/// and `-` when empty.
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String =
+        value.chars().filter(|c| c.is_ascii_graphic()).take(max_len).collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// A log appender that sends messages to a syslog server.
+pub struct SyslogAppender {
+    address: String,
+    transport: SyslogTransport,
+    facility: u8,
+    hostname: String,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl std::fmt::Debug for SyslogAppender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyslogAppender")
+            .field("address", &self.address)
+            .field("transport", &self.transport)
+            .field("facility", &self.facility)
+            .finish()
+    }
+}
+
+impl SyslogAppender {
+    pub fn new(logging: &Logging) -> Result<Self> {
+        let address = logging.syslog_address.clone();
+        if address.to_socket_addrs().is_err() {
+            return Err(eyre!("Not a valid syslog address (host:port): {}", address));
+        }
+        let appender = SyslogAppender {
+            address,
+            transport: logging.syslog_transport.clone(),
+            facility: facility_code(&logging.syslog_facility)?,
+            hostname: header_field(&sys_info::hostname().unwrap_or_default(), 255),
+            connection: Mutex::new(None),
+        };
+        // find out now (rather than at the first message) if the server is unreachable
+        let connection = appender.connect().wrap_err("Can't connect to syslog server")?;
+        *appender.connection.lock().unwrap() = Some(connection);
+        Ok(appender)
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        match self.transport {
+            SyslogTransport::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(&self.address)?;
+                Ok(Connection::Udp(socket))
+            }
+            SyslogTransport::Tcp => Ok(Connection::Tcp(self.connect_tcp()?)),
+            SyslogTransport::Tls => {
+                let host = match self.address.rsplit_once(':') {
+                    Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+                    None => self.address.as_str(),
+                };
+                let stream = TlsConnector::new()?
+                    .connect(host, self.connect_tcp()?)
+                    .map_err(|err| eyre!("TLS handshake failed: {}", err))?;
+                Ok(Connection::Tls(Box::new(stream)))
+            }
+        }
+    }
+
+    fn connect_tcp(&self) -> Result<TcpStream> {
+        let addr = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre!("No address for {}", &self.address))?;
+        let stream = TcpStream::connect_timeout(&addr, NETWORK_TIMEOUT)?;
+        stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    fn send(&self, message: &str) -> Result<()> {
+        let mut guard = self.connection.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.connect()?);
+        }
+        let result = match guard.as_mut().unwrap() {
+            Connection::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Tcp(stream) => write_framed(stream, message),
+            Connection::Tls(stream) => write_framed(stream, message),
+        };
+        if result.is_err() {
+            // reconnect on the next message
+            *guard = None;
+        }
+        Ok(result?)
+    }
+}
+
+fn write_framed(stream: &mut impl Write, message: &str) -> std::io::Result<()> {
+    let frame = format!("{} {}", message.len(), message);
+    stream.write_all(frame.as_bytes())?;
+    stream.flush()
+}
+
+impl Append for SyslogAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let message = format_message(self.facility, &self.hostname, record);
+        self.send(&message).map_err(|err| anyhow::anyhow!("{:#}", err))
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{facility_code, format_message, header_field};
+
+    #[test]
+    fn test_format_message() {
+        assert_eq!(facility_code("local0").unwrap(), 16);
+        assert_eq!(facility_code("Daemon").unwrap(), 3);
+        assert!(facility_code("local9").is_err());
+        assert_eq!(header_field("adlu_proxy::proxy", 32), "adlu_proxy::proxy");
+        assert_eq!(header_field("", 32), "-");
+        let args = format_args!("Proxy started");
+        let record = log::Record::builder()
+            .args(args)
+            .level(log::Level::Warn)
+            .target("adlu_proxy::proxy")
+            .build();
+        let message = format_message(16, "school-server", &record);
+        // local0 (16) * 8 + warning (4)
+        assert!(message.starts_with("<132>1 "), "{}", message);
+        let fields: Vec<&str> = message.splitn(8, ' ').collect();
+        assert_eq!(fields[2], "school-server");
+        assert_eq!(fields[3], "adlu-proxy");
+        assert_eq!(fields[5], "adlu_proxy::proxy");
+        assert_eq!(fields[6], "-");
+        assert_eq!(fields[7], "Proxy started");
+    }
+}
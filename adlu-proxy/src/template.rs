@@ -0,0 +1,225 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Templates for exporting the licenses that devices hold.
+
+Adobe's tooling for returning seats (and other tools that admins feed) wants
+licenses in a specific CSV layout.  A template gives that layout as a list of
+columns, each with a header and either a license field or a fixed value, so
+the export can be filed as is.  The `adobe-return` template is built in, and
+templates in the `reporting` settings add to (or replace) the built-in ones.
+ */
+use std::collections::HashSet;
+
+use eyre::{eyre, Result, WrapErr};
+
+use crate::cache::{Cache, HeldLicense};
+use crate::settings::{ExportTemplate, SettingsVal, TemplateColumn};
+
+/// The license fields that template columns can contain.
+pub const FIELDS: [&str; 11] = [
+    "org_id",
+    "package_id",
+    "package_name",
+    "device_id",
+    "os_user_id",
+    "os_name",
+    "os_version",
+    "app_id",
+    "app_version",
+    "activation_date",
+    "activation_time",
+];
+
+/// The templates that every proxy has.
+pub fn builtin_templates() -> Vec<ExportTemplate> {
+    let column = |header: &str, field: &str| TemplateColumn {
+        header: header.to_string(),
+        field: field.to_string(),
+        value: "".to_string(),
+    };
+    vec![ExportTemplate {
+        name: "adobe-return".to_string(),
+        columns: vec![
+            column("Org ID", "org_id"),
+            column("Package ID", "package_id"),
+            column("Package Name", "package_name"),
+            column("Device ID", "device_id"),
+            column("OS User ID", "os_user_id"),
+            column("OS Name", "os_name"),
+            column("OS Version", "os_version"),
+            column("Product", "app_id"),
+            column("Product Version", "app_version"),
+            column("Activation Date", "activation_date"),
+        ],
+    }]
+}
+
+/// Check that templates are named uniquely and that each of their columns
+/// has a header and either a known field or a fixed value.
+pub fn validate(templates: &[ExportTemplate]) -> Result<()> {
+    let mut names = HashSet::new();
+    for template in templates {
+        let name = &template.name;
+        if name.is_empty() {
+            return Err(eyre!("An export template has no name"));
+        }
+        if !names.insert(name) {
+            return Err(eyre!("There is more than one export template named '{name}'"));
+        }
+        if template.columns.is_empty() {
+            return Err(eyre!("Export template '{name}' has no columns"));
+        }
+        for column in template.columns.iter() {
+            if column.header.is_empty() {
+                return Err(eyre!(
+                    "Export template '{name}' has a column with no header"
+                ));
+            }
+            let field = &column.field;
+            if !field.is_empty() && !FIELDS.contains(&field.as_str()) {
+                let fields = FIELDS.join(", ");
+                return Err(eyre!(
+                    "Export template '{name}' has an unknown field '{field}' \
+                    (use one of {fields})"
+                ));
+            }
+            if !field.is_empty() && !column.value.is_empty() {
+                let header = &column.header;
+                return Err(eyre!(
+                    "Column '{header}' of export template '{name}' \
+                    has both a field and a value"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The named template, preferring the configured templates to the built-in ones.
+pub fn find(settings: &SettingsVal, name: &str) -> Result<ExportTemplate> {
+    let configured = settings.reporting.export_templates.iter().cloned();
+    configured.chain(builtin_templates()).find(|t| t.name == name).ok_or_else(|| {
+        eyre!("There is no export template named '{name}' (see the reporting settings)")
+    })
+}
+
+/// Write the licenses to a CSV file laid out by the template.
+pub fn write_csv(
+    template: &ExportTemplate,
+    licenses: &[HeldLicense],
+    path: &str,
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_path(path)?;
+    writer.write_record(template.columns.iter().map(|c| c.header.as_str()))?;
+    for license in licenses {
+        let record = template.columns.iter().map(|column| {
+            if column.field.is_empty() {
+                column.value.clone()
+            } else {
+                field_value(license, &column.field)
+            }
+        });
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Export the licenses that devices hold (or, if there's an org, just the ones
+/// for its packages) to a CSV file laid out by the named template.
+pub async fn export(
+    settings: &SettingsVal,
+    cache: &Cache,
+    name: &str,
+    path: &str,
+    org_id: Option<&str>,
+) -> Result<()> {
+    let template = find(settings, name)?;
+    validate(std::slice::from_ref(&template))?;
+    let licenses = cache.held_licenses(org_id).await?;
+    write_csv(&template, &licenses, path)
+        .wrap_err(format!("Can't write export to {path}"))?;
+    eprintln!("Exported {} license(s) to {path} with template '{name}'", licenses.len());
+    Ok(())
+}
+
+fn field_value(license: &HeldLicense, field: &str) -> String {
+    match field {
+        "org_id" => license.org_id.clone(),
+        "package_id" => license.package_id.clone(),
+        "package_name" => license.package_name.clone(),
+        "device_id" => license.device_id.clone(),
+        "os_user_id" => license.os_user_id.clone(),
+        "os_name" => license.os_name.clone(),
+        "os_version" => license.os_version.clone(),
+        "app_id" => license.app_id.clone(),
+        "app_version" => license.app_version.clone(),
+        "activation_date" => license.activated.format_rfc_3339(false)[..10].to_string(),
+        "activation_time" => license.activated.format_rfc_3339(true),
+        _ => "".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use adlu_base::Timestamp;
+
+    use super::*;
+
+    #[test]
+    fn test_template_layout() {
+        validate(&builtin_templates()).expect("Built-in templates are invalid");
+        let mut settings = SettingsVal::default();
+        let template = ExportTemplate {
+            name: "adobe-return".to_string(),
+            columns: vec![
+                TemplateColumn {
+                    header: "Device".to_string(),
+                    field: "device_id".to_string(),
+                    ..Default::default()
+                },
+                TemplateColumn {
+                    header: "Reason".to_string(),
+                    value: "Seat return".to_string(),
+                    ..Default::default()
+                },
+            ],
+        };
+        settings.reporting.export_templates = vec![template];
+        // configured templates replace built-in templates of the same name
+        let template = find(&settings, "adobe-return").unwrap();
+        assert_eq!(template.columns.len(), 2);
+        assert!(find(&settings, "no-such-template").is_err());
+        let license = HeldLicense {
+            device_id: "dev1".to_string(),
+            activated: Timestamp::from_millis(0),
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join("adlu-proxy-template-test.csv");
+        let path = path.to_str().unwrap();
+        write_csv(&template, &[license], path).unwrap();
+        let content = std::fs::read_to_string(path).unwrap();
+        assert_eq!(content, "Device,Reason\ndev1,Seat return\n");
+        std::fs::remove_file(path).ok();
+        let mut bad = builtin_templates();
+        bad[0].columns[0].field = "no_such_field".to_string();
+        assert!(validate(&bad).is_err());
+    }
+}
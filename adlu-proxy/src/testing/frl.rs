@@ -16,12 +16,13 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
-use adlu_parse::protocol::{
-    FrlActivationRequestBody, FrlActivationResponseBody, FrlDeactivationQueryParams,
-    FrlDeactivationResponseBody,
-};
+use adlu_parse::protocol::{FrlActivationRequestBody, FrlDeactivationQueryParams};
 
-use super::{MockInfo, MockOutcome, MockRequestType};
+use adlu_base::Timestamp;
+
+use super::{request_id, MockInfo, MockOutcome, MockRequestType};
+use crate::mock;
+use crate::proxy::{Request, RequestType};
 
 pub fn mock_activation_request(
     ask: &MockOutcome,
@@ -42,20 +43,42 @@ pub fn mock_activation_request(
     builder.json(&body)
 }
 
+/// An activation request as it arrives at the cache (rather than at the proxy).
+pub fn mock_cache_activation_request(body: &FrlActivationRequestBody) -> Request {
+    let device_id = &body.device_details.device_id;
+    Request {
+        timestamp: Timestamp::now(),
+        request_type: RequestType::FrlActivation,
+        source_ip: None,
+        method: http::Method::POST,
+        host: None,
+        path: "/asnp/frl_connected/values/v2".to_string(),
+        query: None,
+        body: Some(body.to_body()),
+        content_type: Some("application/json".to_string()),
+        accept_type: None,
+        accept_language: None,
+        user_agent: None,
+        via: None,
+        api_key: Some("ngl_mock1".to_string()),
+        request_id: Some(format!("Req-Id-{}", device_id)),
+        session_id: Some(device_id.clone()),
+        authorization: None,
+        correlation_id: Request::new_correlation_id(),
+    }
+}
+
+/// Give a cached activation request ids that the mock Adobe server will
+/// answer with the given outcome when the request is forwarded.
+pub fn mock_forwardable_activation(req: &mut Request, ask: &MockOutcome) {
+    let mi = MockInfo::with_type_and_outcome(&MockRequestType::FrlActivation, ask);
+    req.request_id = Some(mi.request_id());
+    req.session_id = Some(mi.session_id());
+}
+
 pub fn mock_activation_response(req: reqwest::Request) -> reqwest::Response {
     let request_body = req.body().unwrap().as_bytes().unwrap();
-    let request_data: FrlActivationRequestBody =
-        serde_json::from_slice(request_body).unwrap();
-    let device_id = request_data.device_details.device_id.as_str();
-    let body = FrlActivationResponseBody::mock_from_device_id(device_id);
-    let mut builder = http::Response::builder()
-        .status(200)
-        .header("Content-Type", "application/json;encoding=utf-8");
-    builder = match req.headers().get("X-Request-Id") {
-        None => builder,
-        Some(val) => builder.header("X-Request-Id", val),
-    };
-    builder.body(body.to_body()).unwrap().into()
+    mock::frl_activation_response(request_body, request_id(&req)).into()
 }
 
 pub fn mock_deactivation_request(
@@ -78,14 +101,29 @@ pub fn mock_deactivation_request(
     builder.body("")
 }
 
+pub fn mock_cache_deactivation_request(params: &FrlDeactivationQueryParams) -> Request {
+    Request {
+        timestamp: Timestamp::now(),
+        request_type: RequestType::FrlDeactivation,
+        source_ip: None,
+        method: http::Method::DELETE,
+        host: None,
+        path: "/asnp/frl_connected/v1".to_string(),
+        query: Some(params.to_query()),
+        body: None,
+        content_type: None,
+        accept_type: None,
+        accept_language: None,
+        user_agent: None,
+        via: None,
+        api_key: Some("ngl_mock1".to_string()),
+        request_id: Some(format!("Req-Id-{}", &params.device_id)),
+        session_id: None,
+        authorization: None,
+        correlation_id: Request::new_correlation_id(),
+    }
+}
+
 pub fn mock_deactivation_response(req: reqwest::Request) -> reqwest::Response {
-    let body = FrlDeactivationResponseBody::mock_from_device_id("");
-    let mut builder = http::Response::builder()
-        .status(200)
-        .header("Content-Type", "application/json;encoding=utf-8");
-    builder = match req.headers().get("X-Request-Id") {
-        None => builder,
-        Some(val) => builder.header("X-Request-Id", val),
-    };
-    builder.body(body.to_body()).unwrap().into()
+    mock::frl_deactivation_response(request_id(&req)).into()
 }
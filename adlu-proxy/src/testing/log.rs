@@ -35,6 +35,7 @@ materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
 use super::{MockInfo, MockOutcome, MockRequestType};
+use crate::mock;
 use adlu_parse::protocol::LogSession;
 
 pub fn mock_log_upload_request(
@@ -52,5 +53,5 @@ pub fn mock_log_upload_request(
 }
 
 pub fn mock_log_response(_req: reqwest::Request) -> reqwest::Response {
-    http::Response::builder().status(200).body("").unwrap().into()
+    mock::log_upload_response().into()
 }
@@ -18,9 +18,10 @@ released.  That license is reproduced here in the LICENSE-MIT file.
 */
 use std::collections::HashMap;
 
-use eyre::{eyre, Result, WrapErr};
+use eyre::{Result, WrapErr};
 use uuid::Uuid;
 
+use super::faults::{self, FaultKind};
 use super::settings::{LogLevel, ProxyMode, Settings, SettingsVal};
 use super::{cache, logging, proxy, settings};
 
@@ -55,7 +56,9 @@ async fn init_logging_and_cache() -> cache::Cache {
             shared_cache.log_initialized = true;
         }
         let path = tempdir.join("proxy-cache.sqlite").to_str().unwrap().to_string();
-        let cache = cache::connect(&path).await.expect("Cache initialization failed");
+        let db_settings = settings::Proxy { db_path: path, ..Default::default() };
+        let cache =
+            cache::connect(&db_settings).await.expect("Cache initialization failed");
         cache.clear(true).await.expect("Cache clear failed");
         shared_cache.cache = Some(cache);
         shared_cache.count = 1;
@@ -123,6 +126,16 @@ pub async fn release_test_config(_config: proxy::Config) {
     release_cache().await;
 }
 
+/// A config that shares the cache of `conf`, with its settings changed by `change`.
+pub fn config_with(
+    conf: &proxy::Config,
+    change: impl FnOnce(&mut SettingsVal),
+) -> proxy::Config {
+    let mut settings = conf.settings.as_ref().clone();
+    change(&mut settings);
+    proxy::Config::new(Settings::new(settings), conf.cache.clone()).unwrap()
+}
+
 #[derive(Debug, Clone)]
 pub enum MockOutcome {
     Success,
@@ -130,9 +143,14 @@ pub enum MockOutcome {
     Unreachable,
     ParseFailure,
     ErrorStatus,
+    Throttled,
     FromAdobe,
+    /// Succeeds, but only after [`SLOW_RESPONSE_DELAY`].
+    Slow,
 }
 
+pub const SLOW_RESPONSE_DELAY: std::time::Duration = std::time::Duration::from_secs(3);
+
 #[derive(Debug, Clone)]
 enum MockRequestType {
     FrlActivation,
@@ -206,32 +224,8 @@ impl From<&reqwest::Request> for MockInfo {
     }
 }
 
-pub fn mock_error_status_response(req: reqwest::Request) -> Result<reqwest::Response> {
-    let body = r#"{"error": "Error response requested"}"#.as_bytes();
-    let mut builder = http::Response::builder()
-        .status(400)
-        .header("Content-Type", "application/json;encoding=utf-8");
-    for header_name in ["X-Request-Id", "X-Request-Name"] {
-        builder = match req.headers().get(header_name) {
-            None => builder,
-            Some(val) => builder.header(header_name, val),
-        };
-    }
-    let resp = builder.body(body).wrap_err("Can't build mock response")?;
-    Ok(resp.into())
-}
-
-pub fn mock_parse_failure_response(req: reqwest::Request) -> Result<reqwest::Response> {
-    let body = r#"{"invalid key": "invalid body"}"#.as_bytes();
-    let mut builder = http::Response::builder()
-        .status(200)
-        .header("Content-Type", "application/json;encoding=utf-8");
-    builder = match req.headers().get("X-Request-Id") {
-        None => builder,
-        Some(val) => builder.header("X-Request-Id", val),
-    };
-    let resp = builder.body(body).wrap_err("Can't build mock response")?;
-    Ok(resp.into())
+fn request_id(req: &reqwest::Request) -> Option<&str> {
+    req.headers().get("X-Request-Id").and_then(|val| val.to_str().ok())
 }
 
 pub async fn mock_adobe_server(
@@ -239,8 +233,11 @@ pub async fn mock_adobe_server(
     req: reqwest::Request,
 ) -> Result<reqwest::Response> {
     let mi: MockInfo = (&req).into();
+    if let MockOutcome::Slow = mi.outcome {
+        tokio::time::sleep(SLOW_RESPONSE_DELAY).await;
+    }
     match mi.outcome {
-        MockOutcome::Success => match mi.rtype {
+        MockOutcome::Success | MockOutcome::Slow => match mi.rtype {
             MockRequestType::FrlActivation => Ok(frl::mock_activation_response(req)),
             MockRequestType::FrlDeactivation => Ok(frl::mock_deactivation_response(req)),
             MockRequestType::NulActivation => {
@@ -249,11 +246,11 @@ pub async fn mock_adobe_server(
             MockRequestType::LogUpload => Ok(log::mock_log_response(req)),
         },
         MockOutcome::Isolated => panic!("request sent in Isolated mode"),
-        MockOutcome::Unreachable => {
-            Err(eyre!("NetworkError - server not reachable requested"))
-        }
-        MockOutcome::ParseFailure => mock_parse_failure_response(req),
-        MockOutcome::ErrorStatus => mock_error_status_response(req),
+        // failures are mocked the same way they are injected in staging
+        MockOutcome::Unreachable => faults::response(FaultKind::Unreachable, &req),
+        MockOutcome::ParseFailure => faults::response(FaultKind::ParseFailure, &req),
+        MockOutcome::ErrorStatus => faults::response(FaultKind::ErrorStatus, &req),
+        MockOutcome::Throttled => faults::response(FaultKind::Throttled, &req),
         MockOutcome::FromAdobe => {
             let result = conf.client.execute(req).await;
             result.wrap_err("Network error sending request to Adobe")
@@ -16,9 +16,10 @@ The files in those original works are copyright 2022 Adobe and the use of those
 materials in this work is permitted by the MIT license under which they were
 released.  That license is reproduced here in the LICENSE-MIT file.
 */
-use adlu_parse::protocol::{NulLicenseRequestBody, NulLicenseResponseBody};
+use adlu_parse::protocol::NulLicenseRequestBody;
 
-use super::{MockInfo, MockOutcome, MockRequestType};
+use super::{request_id, MockInfo, MockOutcome, MockRequestType};
+use crate::mock;
 
 pub fn mock_license_request(
     ask: &MockOutcome,
@@ -42,16 +43,5 @@ pub fn mock_license_request(
 
 pub fn mock_activation_response(req: reqwest::Request) -> reqwest::Response {
     let request_body = req.body().unwrap().as_bytes().unwrap();
-    let request_data: NulLicenseRequestBody =
-        serde_json::from_slice(request_body).unwrap();
-    let device_id = request_data.device_details.device_id.as_str();
-    let body = NulLicenseResponseBody::mock_from_device_id(device_id);
-    let mut builder = http::Response::builder()
-        .status(200)
-        .header("Content-Type", "application/json;encoding=utf-8");
-    builder = match req.headers().get("X-Request-Id") {
-        None => builder,
-        Some(val) => builder.header("X-Request-Id", val),
-    };
-    builder.body(body.to_body()).unwrap().into()
+    mock::nul_license_response(request_body, request_id(&req)).into()
 }
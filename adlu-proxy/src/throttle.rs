@@ -0,0 +1,127 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Tracking of Adobe's rate limiting.
+
+When Adobe is overloaded, or thinks we are sending too many requests, it
+replies with a 429 (Too Many Requests) or 503 (Service Unavailable) status,
+usually with a `Retry-After` header that says how long to wait.  We remember
+that deadline and don't forward anything to Adobe until it passes: clients get
+a cached response where the cache has one (and policy allows), and otherwise
+a reply with a `Retry-After` header of their own, so they back off too.
+ */
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use adlu_base::Timestamp;
+
+use crate::settings::Upstream;
+
+/// When (if ever) we may next send a request to Adobe.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    until_millis: AtomicI64,
+}
+
+impl Throttle {
+    /// The number of seconds (rounded up) until the current throttle ends,
+    /// if we are being throttled.
+    pub fn remaining_secs(&self) -> Option<u64> {
+        let remaining =
+            self.until_millis.load(Ordering::Relaxed) - Timestamp::now().to_millis();
+        if remaining > 0 {
+            Some((remaining as u64).div_ceil(1000))
+        } else {
+            None
+        }
+    }
+
+    /// Check an Adobe response for throttling.  If Adobe is throttling us,
+    /// start a throttle and return its length in seconds.
+    pub fn note_response(
+        &self,
+        resp: &reqwest::Response,
+        settings: &Upstream,
+    ) -> Option<u64> {
+        let status = resp.status();
+        if status != http::StatusCode::TOO_MANY_REQUESTS
+            && status != http::StatusCode::SERVICE_UNAVAILABLE
+        {
+            return None;
+        }
+        let retry_after = resp
+            .headers()
+            .get("Retry-After")
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| parse_retry_after(val, &Timestamp::now()));
+        let secs = retry_after
+            .unwrap_or(settings.throttle_default_secs)
+            .min(settings.throttle_max_secs);
+        if secs > 0 {
+            self.start(secs);
+            Some(secs)
+        } else {
+            None
+        }
+    }
+
+    fn start(&self, secs: u64) {
+        let until = Timestamp::now().to_millis() + (secs as i64) * 1000;
+        // never shorten a throttle that's already in effect
+        self.until_millis.fetch_max(until, Ordering::Relaxed);
+    }
+}
+
+/// Parse a `Retry-After` value, which is either a number of seconds or an
+/// HTTP date, into the number of seconds to wait after `now`.
+pub fn parse_retry_after(value: &str, now: &Timestamp) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(secs);
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let millis = date.timestamp_millis() - now.to_millis();
+    Some(if millis > 0 { (millis as u64).div_ceil(1000) } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_retry_after, Throttle};
+    use adlu_base::Timestamp;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = Timestamp::from_millis(1_445_412_480_000);
+        assert_eq!(parse_retry_after("120", &now), Some(120));
+        assert_eq!(parse_retry_after(" 0 ", &now), Some(0));
+        // that's 2015-10-21T07:28:00Z, so two minutes after `now`
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", &now), Some(120));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:20:00 GMT", &now), Some(0));
+        assert_eq!(parse_retry_after("soon", &now), None);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::default();
+        assert_eq!(throttle.remaining_secs(), None);
+        throttle.start(30);
+        assert_eq!(throttle.remaining_secs(), Some(30));
+        throttle.start(10);
+        assert_eq!(throttle.remaining_secs(), Some(30));
+    }
+}
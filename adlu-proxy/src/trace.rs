@@ -0,0 +1,295 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Targeted tracing of the traffic from particular machines.
+
+When debugging one machine, the normal logs are either too sparse (at the
+usual level) or far too busy (at the debug level, for every machine).  The
+`[trace]` settings name devices, by device id, or source IP addresses whose
+traffic should be captured in full.  Each request from a matching machine is
+appended as a line of JSON to the trace file, together with the proxy's reply
+to it, headers and bodies included, whatever the logging level.
+
+Authorization headers are never written, but the trace does hold request and
+response bodies, which identify devices and users.  (IMS token exchanges, whose
+bodies are credentials, are traced without them.)  Once the trace file reaches
+its maximum size, it's moved aside to a `.1` file (replacing any earlier one)
+and a new trace file is started.
+ */
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use eyre::{eyre, Result, WrapErr};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use adlu_base::Timestamp;
+
+use crate::events::request_details;
+use crate::proxy::Request;
+use crate::settings::Trace as TraceSettings;
+
+/// One traced exchange between a client and the proxy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Exchange {
+    pub traced: String,
+    pub correlation_id: String,
+    pub request_type: String,
+    pub source_ip: String,
+    pub device_id: String,
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Option<String>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String,
+}
+
+/// The machines being traced, and where their traffic is written.
+#[derive(Debug)]
+pub struct Tracer {
+    device_ids: HashSet<String>,
+    source_ips: Vec<IpAddr>,
+    path: String,
+    max_bytes: u64,
+    file: Mutex<()>,
+}
+
+impl Tracer {
+    /// The tracer configured by the settings, if any machines are traced.
+    pub fn new(settings: &TraceSettings) -> Result<Option<Self>> {
+        if !settings.is_enabled() {
+            return Ok(None);
+        }
+        let mut source_ips = Vec::new();
+        for ip in settings.source_ips.iter().filter(|ip| !ip.is_empty()) {
+            let addr = ip.parse().map_err(|_| {
+                eyre!("The trace source IP '{}' is not an IP address", ip)
+            })?;
+            source_ips.push(addr);
+        }
+        let device_ids: HashSet<String> =
+            settings.device_ids.iter().filter(|id| !id.is_empty()).cloned().collect();
+        info!(
+            "Tracing traffic from {} device(s) and {} address(es) to {}",
+            device_ids.len(),
+            source_ips.len(),
+            &settings.path
+        );
+        Ok(Some(Tracer {
+            device_ids,
+            source_ips,
+            path: settings.path.clone(),
+            max_bytes: settings.max_size_kb * 1024,
+            file: Mutex::new(()),
+        }))
+    }
+
+    /// The device id of a request, if the request is from a traced machine.
+    /// (Requests that don't carry a device id are matched by address only.)
+    pub fn matches(&self, req: &Request) -> Option<String> {
+        let (_, _, device_id) = request_details(req);
+        if !device_id.is_empty() && self.device_ids.contains(&device_id) {
+            return Some(device_id);
+        }
+        match req.source_ip {
+            Some(ip) if self.source_ips.contains(&ip) => Some(device_id),
+            _ => None,
+        }
+    }
+
+    /// Trace a request from a traced machine and the reply to it, returning
+    /// the reply so it can be sent as usual.  Failures to write the trace are
+    /// logged rather than affecting the reply.
+    pub async fn trace(
+        &self,
+        req: &Request,
+        reply: warp::reply::Response,
+    ) -> warp::reply::Response {
+        self.record(req, reply, true).await
+    }
+
+    /// Trace an exchange whose bodies hold credentials, leaving them out.
+    pub async fn trace_outline(
+        &self,
+        req: &Request,
+        reply: warp::reply::Response,
+    ) -> warp::reply::Response {
+        self.record(req, reply, false).await
+    }
+
+    async fn record(
+        &self,
+        req: &Request,
+        reply: warp::reply::Response,
+        with_bodies: bool,
+    ) -> warp::reply::Response {
+        let device_id = match self.matches(req) {
+            Some(device_id) => device_id,
+            None => return reply,
+        };
+        let (parts, body) = reply.into_parts();
+        let bytes = match hyper::body::to_bytes(body).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!("Can't trace the reply to {}: {}", req, err);
+                return http::Response::from_parts(parts, hyper::Body::empty());
+            }
+        };
+        let exchange = Exchange {
+            traced: Timestamp::now().format_rfc_3339(true),
+            correlation_id: req.correlation_id.clone(),
+            request_type: req.request_type.to_string(),
+            source_ip: req.source_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            device_id,
+            method: req.method.to_string(),
+            path: req.path.clone(),
+            query: req.query.clone(),
+            request_headers: request_headers(req),
+            request_body: match with_bodies {
+                true => req.body.clone(),
+                false => req.body.as_ref().map(|_| OBSCURED.to_string()),
+            },
+            status: parts.status.as_u16(),
+            response_headers: parts
+                .headers
+                .iter()
+                .filter_map(|(name, val)| {
+                    Some((name.to_string(), val.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            response_body: match with_bodies {
+                true => String::from_utf8_lossy(&bytes).to_string(),
+                false => OBSCURED.to_string(),
+            },
+        };
+        if let Err(err) = self.write(&exchange) {
+            warn!("Can't trace {}: {:?}", req, err);
+        }
+        http::Response::from_parts(parts, hyper::Body::from(bytes))
+    }
+
+    fn write(&self, exchange: &Exchange) -> Result<()> {
+        let mut line = serde_json::to_string(exchange)?;
+        line.push('\n');
+        let _guard = self.file.lock().unwrap();
+        self.rotate_if_full(line.len() as u64)?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .wrap_err(format!("Can't open trace file {}", &self.path))?;
+        file.write_all(line.as_bytes())
+            .wrap_err(format!("Can't write trace file {}", &self.path))
+    }
+
+    /// Move the trace file aside if writing `more` bytes would take it past
+    /// its maximum size (if it has one).
+    fn rotate_if_full(&self, more: u64) -> Result<()> {
+        if self.max_bytes == 0 {
+            return Ok(());
+        }
+        let size = std::fs::metadata(&self.path).map_or(0, |m| m.len());
+        if size == 0 || size + more <= self.max_bytes {
+            return Ok(());
+        }
+        let rotated = format!("{}.1", &self.path);
+        std::fs::rename(&self.path, &rotated)
+            .wrap_err(format!("Can't move full trace file to {}", &rotated))
+    }
+}
+
+/// What's written in place of credentials.
+const OBSCURED: &str = "[OBSCURED]";
+
+/// The headers the proxy kept from a request, with any authorization masked.
+fn request_headers(req: &Request) -> Vec<(String, String)> {
+    let masked = req.authorization.as_ref().map(|_| OBSCURED.to_string());
+    [
+        ("Host", &req.host),
+        ("Content-Type", &req.content_type),
+        ("Accept", &req.accept_type),
+        ("Accept-Language", &req.accept_language),
+        ("User-Agent", &req.user_agent),
+        ("Via", &req.via),
+        ("X-Api-Key", &req.api_key),
+        ("X-Request-Id", &req.request_id),
+        ("X-Session-Id", &req.session_id),
+        ("Authorization", &masked),
+    ]
+    .into_iter()
+    .filter_map(|(name, val)| Some((name.to_string(), val.clone()?)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use adlu_parse::protocol::FrlActivationRequestBody;
+
+    use super::*;
+    use crate::testing::frl::mock_cache_activation_request;
+
+    #[tokio::test]
+    async fn test_trace_matching_device() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("trace.jsonl");
+        let settings = TraceSettings {
+            device_ids: vec!["traced-device".to_string()],
+            source_ips: vec![],
+            path: path.to_str().unwrap().to_string(),
+            max_size_kb: 1,
+        };
+        let tracer = Tracer::new(&settings).unwrap().expect("No tracer");
+        for device_id in ["other-device", "traced-device"] {
+            let body = FrlActivationRequestBody::mock_from_device_id(device_id);
+            let mut req = mock_cache_activation_request(&body);
+            req.authorization = Some("Bearer secret".to_string());
+            let reply = http::Response::builder()
+                .status(200)
+                .header("Content-Type", "application/json")
+                .body(hyper::Body::from("{\"ok\":true}"))
+                .unwrap();
+            let reply = tracer.trace(&req, reply).await;
+            let body = hyper::body::to_bytes(reply.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"{\"ok\":true}");
+        }
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let exchange: Exchange = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(exchange.device_id, "traced-device");
+        assert_eq!(exchange.status, 200);
+        assert_eq!(exchange.response_body, "{\"ok\":true}");
+        assert!(exchange.request_body.unwrap().contains("traced-device"));
+        assert!(!lines[0].contains("secret"));
+        // a trace file that would go past its maximum size is moved aside
+        let body = FrlActivationRequestBody::mock_from_device_id("traced-device");
+        let req = mock_cache_activation_request(&body);
+        let reply = http::Response::new(hyper::Body::from("x".repeat(1024)));
+        tracer.trace(&req, reply).await;
+        let rotated = std::fs::read_to_string(format!("{}.1", path.display())).unwrap();
+        assert_eq!(rotated, content);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,231 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Transfer of cache exports between proxies over the network.
+
+A proxy with transfer tokens configured serves its cache at
+`/cache/v1/<datasource>`: a `GET` streams back an export, and a `POST`
+streams in an export to be imported.  The `export --to-url` and
+`import --from-url` commands are the other end of these transfers, so an
+isolated proxy and a connected proxy can exchange exports over a one-way
+HTTPS path rather than by carrying files between them.  The export format
+is the same one that's written to disk by the `export` command.
+ */
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use clap::ValueEnum;
+use eyre::{eyre, Result, WrapErr};
+use futures_util::{Stream, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::cache::Cache;
+use crate::cli::Datasource;
+use crate::settings::Settings;
+
+/// How big a piece of an export file is sent at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The content type of a transferred export.
+pub const EXPORT_CONTENT_TYPE: &str = "application/vnd.sqlite3";
+
+/// The datasource named in a transfer URL, such as `frl`.
+pub fn datasource(name: &str) -> Result<Datasource> {
+    Datasource::from_str(name, true).map_err(|_| eyre!("Unknown datasource: {}", name))
+}
+
+/// The transfer URL for a datasource on the proxy at `base_url`.
+pub fn transfer_url(base_url: &str, source: &Datasource) -> String {
+    let name = source.to_possible_value().expect("No name for datasource");
+    format!("{}/cache/v1/{}", base_url.trim_end_matches('/'), name.get_name())
+}
+
+/// A path for a transferred export that doesn't collide with any other.
+pub fn temp_path(kind: &str) -> PathBuf {
+    let nonce = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    std::env::temp_dir().join(format!(
+        "adlu-proxy-{}-{}-{}.sqlite",
+        kind,
+        std::process::id(),
+        nonce
+    ))
+}
+
+/// Stream an export file, removing it once it has all been read.
+pub fn file_stream(path: PathBuf) -> impl Stream<Item = std::io::Result<Bytes>> {
+    futures_util::stream::unfold(Some((None, path)), |state| async move {
+        let (file, path) = state?;
+        let mut file = match file {
+            Some(file) => file,
+            None => match tokio::fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(err) => return Some((Err(err), None)),
+            },
+        };
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => {
+                std::fs::remove_file(&path).ok();
+                None
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some((Some(file), path))))
+            }
+            Err(err) => {
+                std::fs::remove_file(&path).ok();
+                Some((Err(err), None))
+            }
+        }
+    })
+}
+
+/// Write a stream of export data to a file.
+pub async fn write_stream<S, B, E>(path: &Path, body: S) -> Result<u64>
+where
+    S: Stream<Item = std::result::Result<B, E>>,
+    B: Buf,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .wrap_err(format!("Can't create transfer file: {}", path.display()))?;
+    let mut total = 0;
+    futures_util::pin_mut!(body);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.wrap_err("Transfer interrupted")?;
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            file.write_all(bytes).await?;
+            total += bytes.len() as u64;
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+    }
+    file.flush().await?;
+    Ok(total)
+}
+
+/// Export from this proxy's cache and post the export to another proxy.
+pub async fn export_to_url(
+    settings: &Settings,
+    cache: &Cache,
+    source: &Datasource,
+    base_url: &str,
+    token: Option<String>,
+) -> Result<()> {
+    let token = transfer_token(settings, token)?;
+    let url = transfer_url(base_url, source);
+    let path = temp_path("export");
+    cache.export(source, path.to_str().unwrap(), None).await?;
+    let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or_default();
+    eprintln!("Sending export ({} bytes) to {}", size, &url);
+    let body = reqwest::Body::wrap_stream(file_stream(path));
+    let resp = transfer_client()?
+        .post(&url)
+        .bearer_auth(token)
+        .header("Content-Type", EXPORT_CONTENT_TYPE)
+        .body(body)
+        .send()
+        .await
+        .wrap_err(format!("Can't send export to {}", &url))?;
+    let status = resp.status();
+    let text = resp.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(eyre!("{} refused the export ({}): {}", &url, status, text));
+    }
+    eprintln!("Completed transfer of export to {}", &url);
+    Ok(())
+}
+
+/// Fetch an export from another proxy and import it into this proxy's cache.
+pub async fn import_from_url(
+    settings: &Settings,
+    cache: &Cache,
+    source: &Datasource,
+    base_url: &str,
+    token: Option<String>,
+) -> Result<()> {
+    let token = transfer_token(settings, token)?;
+    let url = transfer_url(base_url, source);
+    let resp = transfer_client()?
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .wrap_err(format!("Can't fetch export from {}", &url))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let text = resp.text().await.unwrap_or_default();
+        return Err(eyre!("{} refused to export ({}): {}", &url, status, text));
+    }
+    let path = temp_path("import");
+    let result = match write_stream(&path, resp.bytes_stream()).await {
+        Ok(size) => {
+            eprintln!("Received export ({} bytes) from {}", size, &url);
+            cache.import(source, path.to_str().unwrap()).await
+        }
+        Err(err) => Err(err),
+    };
+    std::fs::remove_file(&path).ok();
+    result
+}
+
+fn transfer_token(settings: &Settings, token: Option<String>) -> Result<String> {
+    match token.or_else(|| settings.transfer.tokens.first().cloned()) {
+        Some(token) if !token.is_empty() => Ok(token),
+        _ => Err(eyre!("A transfer token is required: use --token or configure one")),
+    }
+}
+
+/// Exports can be large, so there is no overall timeout on a transfer.
+fn transfer_client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(30))
+        .build()
+        .wrap_err("Can't create transfer client")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{datasource, file_stream, temp_path, transfer_url, write_stream};
+    use crate::cli::Datasource;
+
+    #[tokio::test]
+    async fn test_transfer_files() {
+        assert_eq!(datasource("FRL").unwrap(), Datasource::Frl);
+        assert!(datasource("nonesuch").is_err());
+        assert_eq!(
+            transfer_url("https://lab-proxy:8443/", &Datasource::Frl),
+            "https://lab-proxy:8443/cache/v1/frl"
+        );
+        let (sent, received) = (temp_path("sent"), temp_path("received"));
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&sent, &content).unwrap();
+        let size = write_stream(&received, file_stream(sent.clone())).await.unwrap();
+        assert_eq!(size, content.len() as u64);
+        assert_eq!(std::fs::read(&received).unwrap(), content);
+        assert!(!sent.exists(), "Streamed file was not removed");
+        std::fs::remove_file(&received).unwrap();
+    }
+}
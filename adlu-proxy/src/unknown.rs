@@ -0,0 +1,172 @@
+/*
+Copyright 2022 Daniel Brotsky. All rights reserved.
+
+All of the copyrighted work in this repository is licensed under the
+GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+
+Attribution:
+
+Some source files in this repository are derived from files in two Adobe Open
+Source projects: the Adobe License Decoder repository found at this URL:
+    https://github.com/adobe/adobe-license-decoder.rs
+and the FRL Online Proxy repository found at this URL:
+    https://github.com/adobe/frl-online-proxy
+
+The files in those original works are copyright 2022 Adobe and the use of those
+materials in this work is permitted by the MIT license under which they were
+released.  That license is reproduced here in the LICENSE-MIT file.
+*/
+/*!
+Routing of requests that aren't for a known licensing endpoint.
+
+Adobe apps occasionally call endpoints that the proxy doesn't understand.
+Depending on the `[unknown]` settings, such requests are sent to a default
+upstream (the FRL server, unless another is configured), rejected, or sent to
+the host that the client addressed.  In the last case only the hosts listed in
+the `host_addresses` settings are reachable, so clients can't use the proxy to
+reach arbitrary servers; requests for any other host are refused.  The client's
+`Host` is used both in the `Host` header and for TLS SNI, even though the host
+is mapped to a fixed address, so the upstream sees the request the client made.
+
+Whatever their routing, the paths of unknown requests are counted, so the
+status endpoint shows which endpoints apps are calling.
+ */
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use eyre::{eyre, Result};
+use serde_json::{json, Value};
+
+use adlu_parse::protocol::Request;
+
+use crate::settings::{Unknown, UnknownRouting};
+
+/// The most distinct paths that are counted; requests for any other
+/// path are counted together.
+const MAX_PATHS: usize = 1000;
+
+/// The label for the count of paths beyond the first [`MAX_PATHS`].
+const OTHER_PATHS: &str = "(other)";
+
+/// Counts of the unknown requests seen for each path.
+#[derive(Debug, Default)]
+pub struct UnknownPaths {
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl UnknownPaths {
+    /// Count a request for a path.
+    pub fn count(&self, path: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        let key = if counts.len() < MAX_PATHS || counts.contains_key(path) {
+            path
+        } else {
+            OTHER_PATHS
+        };
+        *counts.entry(key.to_string()).or_default() += 1;
+    }
+
+    /// The count for a path.
+    pub fn get(&self, path: &str) -> u64 {
+        self.counts.lock().unwrap().get(path).copied().unwrap_or_default()
+    }
+
+    /// The counts, as reported by the status endpoint.
+    pub fn to_json(&self) -> Value {
+        let counts = self.counts.lock().unwrap();
+        let counts: serde_json::Map<String, Value> =
+            counts.iter().map(|(path, count)| (path.clone(), json!(count))).collect();
+        counts.into()
+    }
+}
+
+/// The server an unknown request is sent to, given the default upstream.
+pub fn server_for(settings: &Unknown, upstream: &str, req: &Request) -> Result<String> {
+    match settings.routing {
+        UnknownRouting::Upstream => Ok(upstream.to_string()),
+        UnknownRouting::Host => match req.host.as_deref() {
+            Some(host) if allows_host(settings, host) => Ok(format!("https://{}", host)),
+            Some(host) if !host.is_empty() => {
+                Err(eyre!("{} is for host '{}', which is not routable", req, host))
+            }
+            _ => Err(eyre!("{} has no Host, so it can't be routed by host", req)),
+        },
+        UnknownRouting::Reject => Err(eyre!("Unknown requests are rejected")),
+    }
+}
+
+/// Whether host routing may send a request to the given `Host` (which may
+/// include a port).  Only the hosts with configured addresses are allowed.
+pub fn allows_host(settings: &Unknown, host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    !name.is_empty()
+        && settings.host_addresses.iter().any(|m| m.host.eq_ignore_ascii_case(name))
+}
+
+/// The addresses that mapped hosts are connected to, in place of the
+/// addresses their names resolve to.
+pub fn host_addresses(settings: &Unknown) -> Result<Vec<(String, std::net::SocketAddr)>> {
+    let mut result = vec![];
+    for mapping in settings.host_addresses.iter() {
+        let ip: std::net::IpAddr = mapping.address.parse().map_err(|_| {
+            eyre!(
+                "The address '{}' for host '{}' is not a numeric IP address",
+                mapping.address,
+                mapping.host
+            )
+        })?;
+        // the port is ignored: requests go to the https port of the host
+        result.push((mapping.host.to_ascii_lowercase(), (ip, 443).into()));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use adlu_parse::protocol::Request;
+
+    use super::{allows_host, server_for, UnknownPaths, MAX_PATHS, OTHER_PATHS};
+    use crate::settings::{HostAddress, Unknown, UnknownRouting};
+
+    #[tokio::test]
+    async fn test_server_for() {
+        let filter = Request::unknown_filter(32_000);
+        let req = warp::test::request()
+            .method("GET")
+            .path("/asnp/v2/unknown")
+            .header("Host", "lcs-entitlement.adobe.io")
+            .filter(&filter)
+            .await
+            .expect("Unknown request was rejected");
+        let upstream = "https://lcs-cops.adobe.io";
+        let mut settings = Unknown::default();
+        assert_eq!(server_for(&settings, upstream, &req).unwrap(), upstream);
+        settings.routing = UnknownRouting::Host;
+        assert!(server_for(&settings, upstream, &req).is_err());
+        settings.host_addresses = vec![HostAddress {
+            host: "LCS-Entitlement.adobe.io".to_string(),
+            address: "10.0.0.1".to_string(),
+        }];
+        let server = server_for(&settings, upstream, &req).unwrap();
+        assert_eq!(server, "https://lcs-entitlement.adobe.io");
+        assert!(allows_host(&settings, "lcs-entitlement.adobe.io:443"));
+        assert!(!allows_host(&settings, "internal.adobe.io.example.com"));
+        settings.routing = UnknownRouting::Reject;
+        assert!(server_for(&settings, upstream, &req).is_err());
+    }
+
+    #[test]
+    fn test_unknown_paths() {
+        let paths = UnknownPaths::default();
+        for i in 0..MAX_PATHS + 2 {
+            paths.count(&format!("/path/{}", i));
+        }
+        paths.count("/path/0");
+        assert_eq!(paths.get("/path/0"), 2);
+        assert_eq!(paths.get(OTHER_PATHS), 2);
+        assert_eq!(paths.to_json()["/path/0"], 2);
+    }
+}
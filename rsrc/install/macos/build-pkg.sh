@@ -0,0 +1,68 @@
+#!/bin/bash
+# Copyright 2022 Daniel Brotsky. All rights reserved.
+#
+# All of the copyrighted work in this repository is licensed under the
+# GNU Affero General Public License, reproduced in the LICENSE-AGPL file.
+#
+# Attribution:
+#
+# Some source files in this repository are derived from files in two Adobe Open
+# Source projects: the Adobe License Decoder repository found at this URL:
+#     https://github.com/adobe/adobe-license-decoder.rs
+# and the FRL Online Proxy repository found at this URL:
+#     https://github.com/adobe/frl-online-proxy
+#
+# The files in those original works are copyright 2022 Adobe and the use of those
+# materials in this work is permitted by the MIT license under which they were
+# released.  That license is reproduced here in the LICENSE-MIT file.
+#
+# Build a macOS installer package for the proxy.  Run this from the root of the
+# workspace after a release build, passing the target triple that was built.
+# The executable is signed with the ADLU_APP_IDENTITY identity and the package
+# with the ADLU_INSTALLER_IDENTITY identity, and it's an error if either is
+# missing.  For local testing, pass --unsigned before the target triple to
+# build a package without signing anything.
+set -e
+unsigned=no
+if [ "$1"x == "--unsigned"x ]; then
+  unsigned=yes
+  shift
+fi
+if [ "$1"x == ""x ]; then
+  echo "You must specify the target triple, such as aarch64-apple-darwin"
+  exit 1
+fi
+tgt=$1
+if [ $unsigned == no ]; then
+  if [ -z "$ADLU_APP_IDENTITY" ] || [ -z "$ADLU_INSTALLER_IDENTITY" ]; then
+    echo "ADLU_APP_IDENTITY and ADLU_INSTALLER_IDENTITY must both be set to sign the package"
+    echo "(use --unsigned to build an unsigned package for local testing)"
+    exit 1
+  fi
+fi
+version=$(sed -n 's/^version = "\(.*\)"/\1/p' adlu-proxy/Cargo.toml | head -1)
+out=target/$tgt/pkg
+root=$out/root
+rm -rf "$out"
+mkdir -p "$root/usr/local/bin" "$root/Library/LaunchDaemons" "$root/Library/Application Support/adlu-proxy"
+cp "target/$tgt/release/adlu-proxy" "$root/usr/local/bin/"
+cp rsrc/install/macos/io.clickonetwo.adlu-proxy.plist "$root/Library/LaunchDaemons/"
+cp rsrc/install/proxy-conf.toml.template "$root/Library/Application Support/adlu-proxy/"
+if [ $unsigned == no ]; then
+  codesign --force --options runtime --timestamp --sign "$ADLU_APP_IDENTITY" "$root/usr/local/bin/adlu-proxy"
+fi
+chmod a+x rsrc/install/macos/scripts/*
+pkgbuild --root "$root" \
+  --identifier io.clickonetwo.adlu-proxy \
+  --version "$version" \
+  --scripts rsrc/install/macos/scripts \
+  --ownership recommended \
+  "$out/adlu-proxy-unsigned.pkg"
+if [ $unsigned == no ]; then
+  productsign --sign "$ADLU_INSTALLER_IDENTITY" "$out/adlu-proxy-unsigned.pkg" "$out/adlu-proxy.pkg"
+  rm "$out/adlu-proxy-unsigned.pkg"
+else
+  echo "Building an unsigned package for local testing"
+  mv "$out/adlu-proxy-unsigned.pkg" "$out/adlu-proxy.pkg"
+fi
+echo "Built $out/adlu-proxy.pkg"
@@ -1,5 +1,5 @@
 $cmd = $args[0];
-$serviceName = "FRL Online Proxy";
+$serviceName = "ADLU Proxy";
 $nssm = Join-Path (Join-Path $PSScriptRoot bin) nssm.exe
 if (![System.IO.File]::Exists($nssm)) {
     echo "You must place this script next to the proxy executable and bin folder"
@@ -7,19 +7,24 @@ if (![System.IO.File]::Exists($nssm)) {
 
 switch ($cmd) {
     start {
-        $proxy = "$PSScriptRoot\frl-proxy.exe"
+        $proxy = "$PSScriptRoot\adlu-proxy.exe"
         if (![System.IO.File]::Exists($proxy)) {
             echo "You must place this script next to the proxy executable";
             exit 1;
         }
         $cfg = "$PSScriptRoot\proxy-conf.toml"
+        $template = "$PSScriptRoot\proxy-conf.toml.template"
+        if (![System.IO.File]::Exists($cfg) -and [System.IO.File]::Exists($template)) {
+            Copy-Item $template $cfg;
+        }
         if (![System.IO.File]::Exists($cfg)) {
-            echo "You must have run frl-proxy configure before this script";
+            echo "You must have run adlu-proxy configure before this script";
             exit 1;
         }
         $stdout = "$PSScriptRoot\proxy-service-stdout.log"
         $stderr = "$PSScriptRoot\proxy-service-stderr.log"
-        & $nssm install $serviceName $proxy start;
+        & $nssm install $serviceName $proxy serve;
+        & $nssm set $serviceName AppDirectory $PSScriptRoot
         & $nssm set $serviceName AppStdout $stdout
         & $nssm set $serviceName AppStderr $stderr
         & $nssm start $serviceName;